@@ -0,0 +1,553 @@
+//! # common::alarm
+//!
+//! `common::alarm` is the alarm model shared by all alarm sources: the alarms of the MHubX
+//! and the alarms evaluated locally, which are stored in the `alarm` table.
+//! Stations and sensors are referenced by their id without table, the alarms of a MHubX
+//! system belong to the station of the same id. Local alarms are raised by the alarm rules
+//! of `common::rules`, operators acknowledge, shelve and comment them.
+//! Every change of a local alarm is kept in its `AlarmHistory`.
+//!
+//! # Example
+//!
+//! ```
+//! # use common::alarm::{Alarm, AlarmFilter, AlarmSort, AlarmState};
+//! let mhubx: common::mhubx::Alarm = serde_json::from_str(
+//!     r#"{"system_id": "cps1", "id": 4711, "text": "Presse Not-Aus", "severity": 3, "state": "active", "time": 1682942400}"#,
+//! )
+//! .unwrap();
+//! let mut alarms = vec![Alarm::from_mhubx(mhubx, chrono::Utc::now())];
+//! let filter = AlarmFilter {
+//!     state: Some(AlarmState::Active),
+//!     min_severity: Some(2),
+//!     ..Default::default()
+//! };
+//! alarms.retain(|alarm| filter.matches(alarm));
+//! AlarmSort::Severity.sort(&mut alarms);
+//!
+//! assert_eq!(alarms[0].id, "mhubx:cps1:4711");
+//! assert_eq!(alarms[0].station.as_deref(), Some("cps1"));
+//! ```
+
+use std::cmp::Reverse;
+
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use surrealdb::{
+    engine::remote::ws::Client,
+    sql::{Datetime, Thing},
+    Surreal,
+};
+
+use crate::mhubx;
+
+type DB = Surreal<Client>;
+
+/// Where a alarm was raised
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum AlarmSource {
+    Mhubx,
+    Local,
+}
+
+/// The state of a alarm
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum AlarmState {
+    /// raised and not yet seen by an operator
+    Active,
+    /// seen by an operator, the condition is still met
+    Acknowledged,
+    /// the condition is no longer met
+    Cleared,
+}
+
+impl AlarmState {
+    /// Reads the state of a MHubX alarm, unknown states are active
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// # use common::alarm::AlarmState;
+    /// assert_eq!(AlarmState::from_mhubx(Some("Gone")), AlarmState::Cleared);
+    /// assert_eq!(AlarmState::from_mhubx(Some("raised")), AlarmState::Active);
+    /// assert_eq!(AlarmState::from_mhubx(Some("ACKNOWLEDGED")), AlarmState::Acknowledged);
+    /// assert_eq!(AlarmState::from_mhubx(Some("unacknowledged")), AlarmState::Active);
+    /// assert_eq!(AlarmState::from_mhubx(None), AlarmState::Active);
+    /// ```
+    pub fn from_mhubx(state: Option<&str>) -> Self {
+        let state = state.unwrap_or_default().to_lowercase();
+        if ["clear", "inactive", "gone", "resolved", "closed"]
+            .iter()
+            .any(|cleared| state.contains(cleared))
+        {
+            AlarmState::Cleared
+        } else if state.starts_with("ack") {
+            AlarmState::Acknowledged
+        } else {
+            AlarmState::Active
+        }
+    }
+}
+
+/// A alarm of any source
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Alarm {
+    /// `mhubx:{system_id}:{alarm_id}` for MHubX alarms, the record id for local alarms
+    pub id: String,
+    pub source: AlarmSource,
+    pub station: Option<String>,
+    pub sensor: Option<String>,
+    /// higher is more severe
+    pub severity: u32,
+    pub message: String,
+    pub state: AlarmState,
+    pub raised_at: DateTime<Utc>,
+    pub cleared_at: Option<DateTime<Utc>>,
+    #[serde(default)]
+    pub acknowledged_at: Option<DateTime<Utc>>,
+    /// the record id of the user or API key, who acknowledged the alarm
+    #[serde(default)]
+    pub acknowledged_by: Option<String>,
+    /// a shelved alarm is hidden until this time
+    #[serde(default)]
+    pub shelved_until: Option<DateTime<Utc>>,
+}
+
+impl Alarm {
+    /// Returns true if the alarm is shelved at the time
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// # use common::alarm::Alarm;
+    /// let mhubx: common::mhubx::Alarm =
+    ///     serde_json::from_str(r#"{"system_id": "cps1", "id": 4711}"#).unwrap();
+    /// let now = chrono::Utc::now();
+    /// let mut alarm = Alarm::from_mhubx(mhubx, now);
+    /// assert!(!alarm.is_shelved(now));
+    ///
+    /// alarm.shelved_until = Some(now + chrono::Duration::minutes(30));
+    /// assert!(alarm.is_shelved(now));
+    /// assert!(!alarm.is_shelved(now + chrono::Duration::hours(1)));
+    /// ```
+    pub fn is_shelved(&self, at: DateTime<Utc>) -> bool {
+        self.shelved_until.is_some_and(|until| until > at)
+    }
+
+    /// Converts a MHubX alarm, alarms without timestamp were raised at the time of the response.
+    /// A alarm referencing a measurement belongs to the sensor the poller imports it to
+    pub fn from_mhubx(alarm: mhubx::Alarm, received_at: DateTime<Utc>) -> Self {
+        let sensor = match alarm.extra.get("msm_id") {
+            Some(serde_json::Value::String(msm_id)) => {
+                Some(mhubx::sensor_id(&alarm.system_id, msm_id))
+            }
+            _ => None,
+        };
+        let cleared_at = match alarm.extra.get("cleared_at") {
+            Some(serde_json::Value::String(cleared_at)) => cleared_at.parse().ok(),
+            _ => None,
+        };
+        Alarm {
+            id: format!("mhubx:{}:{}", alarm.system_id, alarm.alarm_id),
+            source: AlarmSource::Mhubx,
+            station: Some(alarm.system_id).filter(|system_id| !system_id.is_empty()),
+            sensor,
+            severity: alarm.severity.unwrap_or_default(),
+            message: alarm.message.unwrap_or_default(),
+            state: AlarmState::from_mhubx(alarm.state.as_deref()),
+            raised_at: alarm.timestamp.unwrap_or(received_at),
+            cleared_at,
+            acknowledged_at: None,
+            acknowledged_by: None,
+            shelved_until: None,
+        }
+    }
+
+    /// Returns the local alarms of the filter in the order of sort, up to limit if set,
+    /// and the number of local alarms of the filter
+    pub async fn get_local(
+        db: &DB,
+        filter: &AlarmFilter,
+        sort: AlarmSort,
+        limit: Option<usize>,
+    ) -> Result<(Vec<Self>, usize), surrealdb::Error> {
+        if filter.source == Some(AlarmSource::Mhubx) {
+            return Ok((Vec::new(), 0));
+        }
+        let mut conditions = Vec::new();
+        if filter.station.is_some() {
+            conditions.push("station = $station");
+        }
+        if filter.sensor.is_some() {
+            conditions.push("sensor = $sensor");
+        }
+        if filter.state.is_some() {
+            conditions.push("state = $state");
+        }
+        if filter.min_severity.is_some() {
+            conditions.push("severity >= $min_severity");
+        }
+        if filter.max_severity.is_some() {
+            conditions.push("severity <= $max_severity");
+        }
+        if filter.from.is_some() {
+            conditions.push("raised_at >= $from");
+        }
+        if filter.to.is_some() {
+            conditions.push("raised_at <= $to");
+        }
+        match filter.shelved {
+            Some(true) => conditions.push("shelved_until > time::now()"),
+            Some(false) => {
+                conditions.push("(shelved_until = NONE OR shelved_until <= time::now())")
+            }
+            None => {}
+        }
+        let condition = match conditions.is_empty() {
+            true => String::new(),
+            false => format!(" WHERE {}", conditions.join(" AND ")),
+        };
+        let order = match sort {
+            AlarmSort::Newest => "raised_at DESC",
+            AlarmSort::Oldest => "raised_at ASC",
+            AlarmSort::Severity => "severity DESC, raised_at DESC",
+        };
+
+        let limited = match limit {
+            Some(_) => " LIMIT $limit",
+            None => "",
+        };
+
+        let mut response = db
+            .query(format!(
+                "SELECT * FROM alarm{condition} ORDER BY {order}{limited}; SELECT count() AS total FROM alarm{condition} GROUP ALL"
+            ))
+            .bind((
+                "station",
+                filter.station.as_deref().map(|id| Thing::from(("station", id))),
+            ))
+            .bind((
+                "sensor",
+                filter.sensor.as_deref().map(|id| Thing::from(("sensor", id))),
+            ))
+            .bind(("state", filter.state))
+            .bind(("min_severity", filter.min_severity))
+            .bind(("max_severity", filter.max_severity))
+            .bind(("from", filter.from.map(Datetime)))
+            .bind(("to", filter.to.map(Datetime)))
+            .bind(("limit", limit))
+            .await?;
+        let alarms: Vec<LocalAlarm> = response.take(0)?;
+        let total: Option<Total> = response.take(1)?;
+        Ok((
+            alarms.into_iter().map(Alarm::from).collect(),
+            total.map_or(0, |total| total.total),
+        ))
+    }
+}
+
+/// helper struct to deserialize the count of local alarms
+#[derive(Deserialize)]
+struct Total {
+    total: usize,
+}
+
+/// A alarm record of the `alarm` table
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct LocalAlarm {
+    pub id: Thing,
+    /// the rule which raised the alarm
+    #[serde(default)]
+    pub rule: Option<Thing>,
+    pub station: Option<Thing>,
+    pub sensor: Option<Thing>,
+    pub severity: u32,
+    pub message: String,
+    pub state: AlarmState,
+    pub raised_at: Datetime,
+    pub cleared_at: Option<Datetime>,
+    #[serde(default)]
+    pub acknowledged_at: Option<Datetime>,
+    #[serde(default)]
+    pub acknowledged_by: Option<Thing>,
+    #[serde(default)]
+    pub shelved_until: Option<Datetime>,
+}
+
+impl LocalAlarm {
+    /// Returns a local alarm by its id
+    pub async fn get(db: &DB, id: &str) -> Result<Option<Self>, surrealdb::Error> {
+        db.select(Thing::from(("alarm", id))).await
+    }
+
+    /// Acknowledges the alarm if it is active and records it in its history.
+    /// Returns None if the alarm is no longer active
+    pub async fn acknowledge(
+        &self,
+        db: &DB,
+        actor: &Actor,
+        comment: Option<&str>,
+    ) -> Result<Option<Self>, surrealdb::Error> {
+        let acknowledged: Option<Self> = db
+            .query("UPDATE $alarm SET state = $acknowledged, acknowledged_at = time::now(), acknowledged_by = $actor WHERE state = $active")
+            .bind(("alarm", &self.id))
+            .bind(("acknowledged", AlarmState::Acknowledged))
+            .bind(("active", AlarmState::Active))
+            .bind(("actor", &actor.id))
+            .await?
+            .take(0)?;
+        if let Some(alarm) = &acknowledged {
+            AlarmHistory::record(db, alarm, HistoryAction::Acknowledged, Some(actor), comment)
+                .await?;
+        }
+        Ok(acknowledged)
+    }
+
+    /// Shelves the alarm until the time unless it is cleared and records it in its history.
+    /// Shelving a shelved alarm again replaces the time. Returns None if the alarm is cleared
+    pub async fn shelve(
+        &self,
+        db: &DB,
+        until: DateTime<Utc>,
+        actor: &Actor,
+        comment: Option<&str>,
+    ) -> Result<Option<Self>, surrealdb::Error> {
+        let shelved: Option<Self> = db
+            .query("UPDATE $alarm SET shelved_until = $until WHERE state != $cleared")
+            .bind(("alarm", &self.id))
+            .bind(("until", Datetime(until)))
+            .bind(("cleared", AlarmState::Cleared))
+            .await?
+            .take(0)?;
+        if let Some(alarm) = &shelved {
+            AlarmHistory::record(db, alarm, HistoryAction::Shelved, Some(actor), comment).await?;
+        }
+        Ok(shelved)
+    }
+
+    /// Ends the shelving of the alarm and records it in its history.
+    /// Returns None if the alarm isn't shelved
+    pub async fn unshelve(
+        &self,
+        db: &DB,
+        actor: &Actor,
+        comment: Option<&str>,
+    ) -> Result<Option<Self>, surrealdb::Error> {
+        let unshelved: Option<Self> = db
+            .query("UPDATE $alarm SET shelved_until = NONE WHERE shelved_until > time::now()")
+            .bind(("alarm", &self.id))
+            .await?
+            .take(0)?;
+        if let Some(alarm) = &unshelved {
+            AlarmHistory::record(db, alarm, HistoryAction::Unshelved, Some(actor), comment).await?;
+        }
+        Ok(unshelved)
+    }
+}
+
+impl From<LocalAlarm> for Alarm {
+    fn from(alarm: LocalAlarm) -> Self {
+        Alarm {
+            id: alarm.id.to_string(),
+            source: AlarmSource::Local,
+            station: alarm.station.map(|station| station.id.to_raw()),
+            sensor: alarm.sensor.map(|sensor| sensor.id.to_raw()),
+            severity: alarm.severity,
+            message: alarm.message,
+            state: alarm.state,
+            raised_at: alarm.raised_at.0,
+            cleared_at: alarm.cleared_at.map(|cleared_at| cleared_at.0),
+            acknowledged_at: alarm
+                .acknowledged_at
+                .map(|acknowledged_at| acknowledged_at.0),
+            acknowledged_by: alarm.acknowledged_by.map(|actor| actor.to_string()),
+            shelved_until: alarm.shelved_until.map(|shelved_until| shelved_until.0),
+        }
+    }
+}
+
+/// Filters alarms, unset fields match every alarm. From and to limit the time it was raised
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct AlarmFilter {
+    /// whether the alarm is shelved now
+    pub shelved: Option<bool>,
+    pub source: Option<AlarmSource>,
+    pub station: Option<String>,
+    pub sensor: Option<String>,
+    pub state: Option<AlarmState>,
+    pub min_severity: Option<u32>,
+    pub max_severity: Option<u32>,
+    pub from: Option<DateTime<Utc>>,
+    pub to: Option<DateTime<Utc>>,
+}
+
+impl AlarmFilter {
+    /// Returns true if the alarm matches all fields of the filter
+    pub fn matches(&self, alarm: &Alarm) -> bool {
+        self.source.is_none_or(|source| alarm.source == source)
+            && self
+                .shelved
+                .is_none_or(|shelved| alarm.is_shelved(Utc::now()) == shelved)
+            && self
+                .station
+                .as_ref()
+                .is_none_or(|station| alarm.station.as_ref() == Some(station))
+            && self
+                .sensor
+                .as_ref()
+                .is_none_or(|sensor| alarm.sensor.as_ref() == Some(sensor))
+            && self.state.is_none_or(|state| alarm.state == state)
+            && self.min_severity.is_none_or(|min| alarm.severity >= min)
+            && self.max_severity.is_none_or(|max| alarm.severity <= max)
+            && self.from.is_none_or(|from| alarm.raised_at >= from)
+            && self.to.is_none_or(|to| alarm.raised_at <= to)
+    }
+}
+
+/// The order of alarms, ties are ordered by id so pages are stable
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum AlarmSort {
+    /// the latest raised alarm first
+    #[default]
+    Newest,
+    /// the earliest raised alarm first
+    Oldest,
+    /// the most severe alarm first, the latest first within a severity
+    Severity,
+}
+
+impl AlarmSort {
+    /// Sorts the alarms
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// # use common::alarm::{Alarm, AlarmSort};
+    /// let alarm = |id: &str, severity: u32, raised_at: &str| -> Alarm {
+    ///     serde_json::from_value(serde_json::json!({
+    ///         "id": id, "source": "local", "station": null, "sensor": null, "severity": severity,
+    ///         "message": "", "state": "active", "raised_at": raised_at, "cleared_at": null,
+    ///     }))
+    ///     .unwrap()
+    /// };
+    /// let mut alarms = vec![
+    ///     alarm("alarm:a", 1, "2023-05-01T12:00:00Z"),
+    ///     alarm("alarm:b", 3, "2023-05-01T10:00:00Z"),
+    ///     alarm("alarm:c", 3, "2023-05-01T11:00:00Z"),
+    /// ];
+    /// AlarmSort::Severity.sort(&mut alarms);
+    /// let ids: Vec<&str> = alarms.iter().map(|alarm| alarm.id.as_str()).collect();
+    ///
+    /// assert_eq!(ids, ["alarm:c", "alarm:b", "alarm:a"]);
+    /// ```
+    pub fn sort(&self, alarms: &mut [Alarm]) {
+        match self {
+            AlarmSort::Newest => alarms
+                .sort_by(|a, b| (Reverse(a.raised_at), &a.id).cmp(&(Reverse(b.raised_at), &b.id))),
+            AlarmSort::Oldest => {
+                alarms.sort_by(|a, b| (a.raised_at, &a.id).cmp(&(b.raised_at, &b.id)))
+            }
+            AlarmSort::Severity => alarms.sort_by(|a, b| {
+                (Reverse(a.severity), Reverse(a.raised_at), &a.id).cmp(&(
+                    Reverse(b.severity),
+                    Reverse(b.raised_at),
+                    &b.id,
+                ))
+            }),
+        }
+    }
+}
+
+/// A change of a local alarm
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum HistoryAction {
+    Raised,
+    Cleared,
+    Acknowledged,
+    Shelved,
+    Unshelved,
+    Commented,
+    /// notified to the next step of a escalation policy
+    Escalated,
+}
+
+/// A entry of the history of a local alarm, changes by rules have no actor
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct AlarmHistory {
+    pub id: Thing,
+    pub alarm: Thing,
+    pub station: Option<Thing>,
+    pub action: HistoryAction,
+    pub actor: Option<Thing>,
+    pub actor_name: Option<String>,
+    pub comment: Option<String>,
+    #[serde(default)]
+    pub shelved_until: Option<Datetime>,
+    pub created_at: Datetime,
+}
+
+/// The user or API key changing a alarm
+#[derive(Debug, Clone)]
+pub struct Actor {
+    pub id: Thing,
+    pub name: String,
+}
+
+impl AlarmHistory {
+    /// Adds a entry to the history of the alarm
+    pub async fn record(
+        db: &DB,
+        alarm: &LocalAlarm,
+        action: HistoryAction,
+        actor: Option<&Actor>,
+        comment: Option<&str>,
+    ) -> Result<Option<Self>, surrealdb::Error> {
+        db.query("CREATE alarm_history SET alarm = $alarm, station = $station, action = $action, actor = $actor, actor_name = $actor_name, comment = $comment, shelved_until = $shelved_until, created_at = time::now()")
+            .bind(("alarm", &alarm.id))
+            .bind(("station", &alarm.station))
+            .bind(("action", action))
+            .bind(("actor", actor.map(|actor| &actor.id)))
+            .bind(("actor_name", actor.map(|actor| &actor.name)))
+            .bind(("comment", comment))
+            .bind(("shelved_until", &alarm.shelved_until))
+            .await?
+            .take(0)
+    }
+
+    /// Returns the history of a alarm, the oldest entry first
+    pub async fn get_by_alarm(db: &DB, alarm: &Thing) -> Result<Vec<Self>, surrealdb::Error> {
+        db.query("SELECT * FROM alarm_history WHERE alarm = $alarm ORDER BY created_at ASC")
+            .bind(("alarm", alarm))
+            .await?
+            .take(0)
+    }
+
+    /// Returns the history of all alarms between from and to, the newest entry first.
+    /// Limited to the station and up to limit entries if set
+    pub async fn get_between(
+        db: &DB,
+        from: DateTime<Utc>,
+        to: DateTime<Utc>,
+        station: Option<&Thing>,
+        limit: Option<usize>,
+    ) -> Result<Vec<Self>, surrealdb::Error> {
+        let station_condition = match station {
+            Some(_) => " AND station = $station",
+            None => "",
+        };
+        let limited = match limit {
+            Some(_) => " LIMIT $limit",
+            None => "",
+        };
+        db.query(format!("SELECT * FROM alarm_history WHERE created_at >= $from AND created_at <= $to{station_condition} ORDER BY created_at DESC{limited}"))
+            .bind(("from", Datetime(from)))
+            .bind(("to", Datetime(to)))
+            .bind(("station", station))
+            .bind(("limit", limit))
+            .await?
+            .take(0)
+    }
+}
@@ -0,0 +1,325 @@
+//! # common::export
+//!
+//! `common::export` serializes sensor values into CSV, NDJSON or Parquet.
+//! The [`Exporter`] is fed one batch of rows at a time and writes into an in-memory buffer,
+//! which can be drained after every batch to stream the export.
+//!
+//! # Example
+//!
+//! ```
+//! # use common::export::{Exporter, ExportFormat, ExportRow};
+//! let mut exporter = Exporter::new(ExportFormat::Csv).unwrap();
+//! exporter
+//!     .write(&[ExportRow {
+//!         station: "presswerk".to_owned(),
+//!         sensor: "presse_pressenstatus".to_owned(),
+//!         timestamp: chrono::DateTime::parse_from_rfc3339("2023-05-01T12:00:00Z")
+//!             .unwrap()
+//!             .into(),
+//!         value: "1".to_owned(),
+//!     }])
+//!     .unwrap();
+//!
+//! let csv = String::from_utf8(exporter.finish().unwrap()).unwrap();
+//! assert_eq!(
+//!     csv,
+//!     "station,sensor,timestamp,value\npresswerk,presse_pressenstatus,2023-05-01T12:00:00+00:00,1\n"
+//! );
+//! ```
+
+use std::sync::Arc;
+
+use chrono::{DateTime, Utc};
+use parquet::{
+    data_type::{ByteArray, ByteArrayType, Int64Type},
+    errors::ParquetError,
+    file::{properties::WriterProperties, writer::SerializedFileWriter},
+    schema::parser::parse_message_type,
+};
+use serde::{Deserialize, Serialize};
+use surrealdb::sql::Thing;
+
+use crate::{Sensor, SensorValue, TimePeriod, DB};
+
+/// The parquet schema of an export, matching the fields of [`ExportRow`]
+const PARQUET_SCHEMA: &str = "
+message sensor_value {
+    REQUIRED BYTE_ARRAY station (UTF8);
+    REQUIRED BYTE_ARRAY sensor (UTF8);
+    REQUIRED INT64 timestamp (TIMESTAMP(MILLIS, true));
+    REQUIRED BYTE_ARRAY value (UTF8);
+}
+";
+
+/// Supported export formats
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ExportFormat {
+    #[default]
+    Csv,
+    Ndjson,
+    Parquet,
+}
+
+impl ExportFormat {
+    /// Returns the mime type of the format
+    pub fn content_type(&self) -> &'static str {
+        match self {
+            ExportFormat::Csv => "text/csv; charset=utf-8",
+            ExportFormat::Ndjson => "application/x-ndjson",
+            ExportFormat::Parquet => "application/vnd.apache.parquet",
+        }
+    }
+
+    /// Returns the file extension of the format
+    pub fn extension(&self) -> &'static str {
+        match self {
+            ExportFormat::Csv => "csv",
+            ExportFormat::Ndjson => "ndjson",
+            ExportFormat::Parquet => "parquet",
+        }
+    }
+}
+
+impl std::str::FromStr for ExportFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "csv" => Ok(ExportFormat::Csv),
+            "ndjson" => Ok(ExportFormat::Ndjson),
+            "parquet" => Ok(ExportFormat::Parquet),
+            other => Err(format!("Unknown export format: {other}")),
+        }
+    }
+}
+
+/// A single exported sensor value
+#[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
+pub struct ExportRow {
+    pub station: String,
+    pub sensor: String,
+    pub timestamp: DateTime<Utc>,
+    pub value: String,
+}
+
+impl ExportRow {
+    /// Creates a row from a sensor value of the given sensor
+    pub fn new(sensor: &Sensor, value: &SensorValue) -> Self {
+        ExportRow {
+            station: sensor.get_station().id.to_raw(),
+            sensor: sensor.get_id().id.to_raw(),
+            timestamp: value.get_timestamp().0,
+            value: value.get_value().to_owned(),
+        }
+    }
+}
+
+/// Errors which can occur during an export
+#[derive(Debug)]
+pub enum ExportError {
+    Database(surrealdb::Error),
+    Parquet(ParquetError),
+    Json(serde_json::Error),
+}
+
+impl std::fmt::Display for ExportError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ExportError::Database(err) => write!(f, "database error: {err}"),
+            ExportError::Parquet(err) => write!(f, "parquet error: {err}"),
+            ExportError::Json(err) => write!(f, "json error: {err}"),
+        }
+    }
+}
+
+impl std::error::Error for ExportError {}
+
+impl From<surrealdb::Error> for ExportError {
+    fn from(err: surrealdb::Error) -> Self {
+        ExportError::Database(err)
+    }
+}
+
+impl From<serde_json::Error> for ExportError {
+    fn from(err: serde_json::Error) -> Self {
+        ExportError::Json(err)
+    }
+}
+
+impl From<ParquetError> for ExportError {
+    fn from(err: ParquetError) -> Self {
+        ExportError::Parquet(err)
+    }
+}
+
+/// The sensors and stations to export, stations are expanded to all of their sensors
+#[derive(Debug, Default, Clone, Deserialize, Serialize)]
+pub struct Selection {
+    pub sensors: Vec<String>,
+    pub stations: Vec<String>,
+}
+
+impl Selection {
+    /// Returns the selected sensors, each sensor is only returned once
+    pub async fn resolve(&self, db: &DB) -> Result<Vec<Sensor>, ExportError> {
+        let mut sensors: Vec<Sensor> = Vec::new();
+        for station in self.stations.iter() {
+            sensors.extend(Sensor::get_by_station(db, station.clone()).await?);
+        }
+        for id in self.sensors.iter() {
+            if let Some(sensor) = Sensor::get(db, id.clone()).await? {
+                sensors.push(sensor);
+            }
+        }
+
+        let mut seen: Vec<Thing> = Vec::new();
+        sensors.retain(|sensor| {
+            if seen.contains(sensor.get_id()) {
+                return false;
+            }
+            seen.push(sensor.get_id().clone());
+            true
+        });
+        Ok(sensors)
+    }
+}
+
+/// Returns the rows to export for a single sensor within a time period
+pub async fn rows(
+    db: &DB,
+    sensor: &Sensor,
+    time_period: &TimePeriod,
+) -> Result<Vec<ExportRow>, surrealdb::Error> {
+    let values =
+        SensorValue::get_within_timeperiod(db, sensor.get_id().id.to_raw(), time_period).await?;
+    Ok(values
+        .iter()
+        .map(|value| ExportRow::new(sensor, value))
+        .collect())
+}
+
+/// the format specific writer state
+enum Writer {
+    Csv(Vec<u8>),
+    Ndjson(Vec<u8>),
+    Parquet(SerializedFileWriter<Vec<u8>>),
+}
+
+/// Serializes batches of [`ExportRow`]s into an in-memory buffer
+pub struct Exporter {
+    writer: Writer,
+}
+
+impl Exporter {
+    /// Creates a new exporter, for CSV the header is written immediately
+    pub fn new(format: ExportFormat) -> Result<Self, ExportError> {
+        let writer = match format {
+            ExportFormat::Csv => Writer::Csv(b"station,sensor,timestamp,value\n".to_vec()),
+            ExportFormat::Ndjson => Writer::Ndjson(Vec::new()),
+            ExportFormat::Parquet => Writer::Parquet(SerializedFileWriter::new(
+                Vec::new(),
+                Arc::new(parse_message_type(PARQUET_SCHEMA)?),
+                Arc::new(WriterProperties::builder().build()),
+            )?),
+        };
+        Ok(Exporter { writer })
+    }
+
+    /// Writes a batch of rows. For parquet every batch becomes its own row group
+    pub fn write(&mut self, rows: &[ExportRow]) -> Result<(), ExportError> {
+        match &mut self.writer {
+            Writer::Csv(buffer) => {
+                for row in rows {
+                    buffer.extend_from_slice(
+                        format!(
+                            "{},{},{},{}\n",
+                            csv_field(&row.station),
+                            csv_field(&row.sensor),
+                            row.timestamp.to_rfc3339(),
+                            csv_field(&row.value)
+                        )
+                        .as_bytes(),
+                    );
+                }
+            }
+            Writer::Ndjson(buffer) => {
+                for row in rows {
+                    serde_json::to_writer(&mut *buffer, row)?;
+                    buffer.push(b'\n');
+                }
+            }
+            Writer::Parquet(writer) => {
+                if rows.is_empty() {
+                    return Ok(());
+                }
+                let mut row_group = writer.next_row_group()?;
+                let text_columns: [fn(&ExportRow) -> &str; 2] =
+                    [|row| &row.station, |row| &row.sensor];
+                for column in text_columns {
+                    write_text_column(&mut row_group, rows, column)?;
+                }
+                if let Some(mut column) = row_group.next_column()? {
+                    let timestamps: Vec<i64> = rows
+                        .iter()
+                        .map(|row| row.timestamp.timestamp_millis())
+                        .collect();
+                    column
+                        .typed::<Int64Type>()
+                        .write_batch(&timestamps, None, None)?;
+                    column.close()?;
+                }
+                write_text_column(&mut row_group, rows, |row| &row.value)?;
+                row_group.close()?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Takes all bytes written so far out of the buffer
+    pub fn drain(&mut self) -> Vec<u8> {
+        match &mut self.writer {
+            Writer::Csv(buffer) | Writer::Ndjson(buffer) => std::mem::take(buffer),
+            Writer::Parquet(writer) => std::mem::take(writer.inner_mut()),
+        }
+    }
+
+    /// Finishes the export and returns the remaining bytes
+    pub fn finish(self) -> Result<Vec<u8>, ExportError> {
+        match self.writer {
+            Writer::Csv(buffer) | Writer::Ndjson(buffer) => Ok(buffer),
+            Writer::Parquet(writer) => Ok(writer.into_inner()?),
+        }
+    }
+}
+
+/// writes the next column of a parquet row group as UTF8 byte arrays
+fn write_text_column(
+    row_group: &mut parquet::file::writer::SerializedRowGroupWriter<'_, Vec<u8>>,
+    rows: &[ExportRow],
+    field: fn(&ExportRow) -> &str,
+) -> Result<(), ExportError> {
+    if let Some(mut column) = row_group.next_column()? {
+        let values: Vec<ByteArray> = rows.iter().map(|row| ByteArray::from(field(row))).collect();
+        column
+            .typed::<ByteArrayType>()
+            .write_batch(&values, None, None)?;
+        column.close()?;
+    }
+    Ok(())
+}
+
+/// Quotes a csv field if it contains a separator, quote or line break
+///
+/// # Example
+///
+/// ```
+/// assert_eq!(common::export::csv_field("a,b"), "\"a,b\"");
+/// ```
+pub fn csv_field(field: &str) -> String {
+    if field.contains([',', '"', '\n', '\r']) {
+        format!("\"{}\"", field.replace('"', "\"\""))
+    } else {
+        field.to_owned()
+    }
+}
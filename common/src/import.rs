@@ -0,0 +1,547 @@
+//! # common::import
+//!
+//! `common::import` reads historical sensor values from CSV or NDJSON files into `sensor_value`.
+//! Columns are mapped by name, timestamps without an offset are interpreted in a configurable
+//! timezone and values already stored for the same `[sensor, timestamp]` are skipped.
+//!
+//! # Example
+//!
+//! ```
+//! # use common::import::{parse, ImportOptions};
+//! let csv = "time;tag;value\n01.05.2023 14:00:00;dosenfuellstand;12\n";
+//! let options = ImportOptions {
+//!     delimiter: ';',
+//!     timestamp_column: "time".to_owned(),
+//!     sensor_column: "tag".to_owned(),
+//!     timezone: "Europe/Berlin".to_owned(),
+//!     ..Default::default()
+//! };
+//!
+//! let (records, errors) = parse(csv.as_bytes(), &options).unwrap();
+//! assert!(errors.is_empty());
+//! assert_eq!(records[0].sensor, "dosenfuellstand");
+//! assert_eq!(records[0].timestamp.to_rfc3339(), "2023-05-01T12:00:00+00:00");
+//!
+//! // csv only supports single byte delimiters
+//! let options = ImportOptions { delimiter: '§', ..options };
+//! assert!(parse(csv.as_bytes(), &options).is_err());
+//! ```
+
+use std::{
+    collections::{BTreeMap, HashSet},
+    str::FromStr,
+};
+
+use chrono::{DateTime, FixedOffset, NaiveDateTime, TimeZone, Utc};
+use serde::{Deserialize, Serialize};
+use surrealdb::sql::Thing;
+
+use crate::{Sensor, SensorValue, Station, TimePeriod, DB};
+
+/// upper bound of row errors listed in a report, further errors are only counted
+const MAX_REPORTED_ERRORS: usize = 100;
+
+/// timestamp formats tried for values without an explicit format, after RFC 3339
+const TIMESTAMP_FORMATS: [&str; 3] = [
+    "%Y-%m-%d %H:%M:%S%.f",
+    "%Y-%m-%dT%H:%M:%S%.f",
+    "%d.%m.%Y %H:%M:%S%.f",
+];
+
+/// Supported import formats
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ImportFormat {
+    #[default]
+    Csv,
+    Ndjson,
+}
+
+impl FromStr for ImportFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "csv" => Ok(ImportFormat::Csv),
+            "ndjson" => Ok(ImportFormat::Ndjson),
+            other => Err(format!("Unknown import format: {other}")),
+        }
+    }
+}
+
+/// Describes how a file is read and imported
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(default)]
+pub struct ImportOptions {
+    pub format: ImportFormat,
+    /// CSV field delimiter
+    pub delimiter: char,
+    /// name of the column holding the sensor id
+    pub sensor_column: String,
+    /// name of the column holding the station id
+    pub station_column: String,
+    /// name of the column holding the source timestamp
+    pub timestamp_column: String,
+    /// name of the column holding the value
+    pub value_column: String,
+    /// sensor id used for all rows, for files without a sensor column
+    pub sensor: Option<String>,
+    /// station id used for rows without a station column
+    pub station: Option<String>,
+    /// chrono format string of the timestamps, RFC 3339 and common formats are tried otherwise
+    pub timestamp_format: Option<String>,
+    /// IANA timezone or fixed offset (e.g. `+01:00`) of timestamps without an offset
+    pub timezone: String,
+    /// create unknown sensors for existing stations
+    pub create_sensors: bool,
+    /// only validate the file and report what would be imported
+    pub dry_run: bool,
+}
+
+impl Default for ImportOptions {
+    fn default() -> Self {
+        ImportOptions {
+            format: ImportFormat::Csv,
+            delimiter: ',',
+            sensor_column: "sensor".to_owned(),
+            station_column: "station".to_owned(),
+            timestamp_column: "timestamp".to_owned(),
+            value_column: "value".to_owned(),
+            sensor: None,
+            station: None,
+            timestamp_format: None,
+            timezone: "UTC".to_owned(),
+            create_sensors: false,
+            dry_run: false,
+        }
+    }
+}
+
+/// A single parsed row of an import file
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct Record {
+    pub line: usize,
+    pub station: Option<String>,
+    pub sensor: String,
+    pub timestamp: DateTime<Utc>,
+    pub value: String,
+}
+
+/// A row which could not be imported
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct RowError {
+    pub line: usize,
+    pub message: String,
+}
+
+/// Summary of an import
+#[derive(Debug, Default, Serialize)]
+pub struct ImportReport {
+    pub dry_run: bool,
+    /// number of data rows in the file
+    pub rows: usize,
+    /// number of values stored, or which would be stored on a dry run
+    pub imported: usize,
+    /// number of rows skipped, because a value for `[sensor, timestamp]` exists
+    pub duplicates: usize,
+    /// number of rows which could not be imported
+    pub invalid: usize,
+    /// sensors which were created, or which would be created on a dry run
+    pub created_sensors: Vec<String>,
+    pub from: Option<DateTime<Utc>>,
+    pub to: Option<DateTime<Utc>>,
+    /// the first row errors, see `invalid` for the total count
+    pub errors: Vec<RowError>,
+}
+
+impl ImportReport {
+    /// records an invalid row
+    fn reject(&mut self, line: usize, message: String) {
+        self.invalid += 1;
+        if self.errors.len() < MAX_REPORTED_ERRORS {
+            self.errors.push(RowError { line, message });
+        }
+    }
+}
+
+/// Errors which abort an import
+#[derive(Debug)]
+pub enum ImportError {
+    /// the options do not match the file, e.g. a mapped column is missing
+    Mapping(String),
+    Csv(csv::Error),
+    Database(surrealdb::Error),
+}
+
+impl std::fmt::Display for ImportError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ImportError::Mapping(err) => write!(f, "{err}"),
+            ImportError::Csv(err) => write!(f, "csv error: {err}"),
+            ImportError::Database(err) => write!(f, "database error: {err}"),
+        }
+    }
+}
+
+impl std::error::Error for ImportError {}
+
+impl From<csv::Error> for ImportError {
+    fn from(err: csv::Error) -> Self {
+        ImportError::Csv(err)
+    }
+}
+
+impl From<surrealdb::Error> for ImportError {
+    fn from(err: surrealdb::Error) -> Self {
+        ImportError::Database(err)
+    }
+}
+
+/// The timezone of source timestamps without an offset
+enum SourceZone {
+    Named(chrono_tz::Tz),
+    Fixed(FixedOffset),
+}
+
+impl FromStr for SourceZone {
+    type Err = ImportError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if let Ok(tz) = s.parse::<chrono_tz::Tz>() {
+            return Ok(SourceZone::Named(tz));
+        }
+        s.parse::<FixedOffset>()
+            .map(SourceZone::Fixed)
+            .map_err(|_| ImportError::Mapping(format!("Unknown timezone: {s}")))
+    }
+}
+
+impl SourceZone {
+    /// converts a local time into utc, ambiguous times during a DST change resolve to the earlier one
+    fn to_utc(&self, local: &NaiveDateTime) -> Option<DateTime<Utc>> {
+        match self {
+            SourceZone::Named(tz) => tz
+                .from_local_datetime(local)
+                .earliest()
+                .map(|time| time.with_timezone(&Utc)),
+            SourceZone::Fixed(offset) => offset
+                .from_local_datetime(local)
+                .earliest()
+                .map(|time| time.with_timezone(&Utc)),
+        }
+    }
+}
+
+/// parses a source timestamp
+fn parse_timestamp(
+    value: &str,
+    format: Option<&str>,
+    zone: &SourceZone,
+) -> Result<DateTime<Utc>, String> {
+    let value = value.trim();
+    if let Some(format) = format {
+        if let Ok(time) = DateTime::parse_from_str(value, format) {
+            return Ok(time.with_timezone(&Utc));
+        }
+        let local = NaiveDateTime::parse_from_str(value, format)
+            .map_err(|err| format!("Invalid timestamp {value}: {err}"))?;
+        return zone
+            .to_utc(&local)
+            .ok_or_else(|| format!("Timestamp {value} does not exist in the source timezone"));
+    }
+
+    if let Ok(time) = DateTime::parse_from_rfc3339(value) {
+        return Ok(time.with_timezone(&Utc));
+    }
+    TIMESTAMP_FORMATS
+        .iter()
+        .find_map(|format| NaiveDateTime::parse_from_str(value, format).ok())
+        .ok_or_else(|| format!("Invalid timestamp: {value}"))
+        .and_then(|local| {
+            zone.to_utc(&local)
+                .ok_or_else(|| format!("Timestamp {value} does not exist in the source timezone"))
+        })
+}
+
+/// converts a sensor name the same way the mqtt service does for topic paths
+fn normalize_sensor(sensor: &str) -> String {
+    sensor.trim().replace('/', "_")
+}
+
+/// the raw fields of a row before validation
+struct RawRow {
+    line: usize,
+    station: Option<String>,
+    sensor: Option<String>,
+    timestamp: Option<String>,
+    value: Option<String>,
+}
+
+/// reads the raw rows of a csv file
+fn read_csv(input: &[u8], options: &ImportOptions) -> Result<Vec<RawRow>, ImportError> {
+    if !options.delimiter.is_ascii() {
+        return Err(ImportError::Mapping(format!(
+            "The delimiter needs to be an ASCII character: {}",
+            options.delimiter
+        )));
+    }
+    let mut reader = csv::ReaderBuilder::new()
+        .delimiter(options.delimiter as u8)
+        .trim(csv::Trim::All)
+        .from_reader(input);
+    let headers = reader.headers()?.clone();
+    let column = |name: &str| headers.iter().position(|header| header == name);
+
+    let timestamp = column(&options.timestamp_column).ok_or_else(|| {
+        ImportError::Mapping(format!("Missing column: {}", options.timestamp_column))
+    })?;
+    let value = column(&options.value_column)
+        .ok_or_else(|| ImportError::Mapping(format!("Missing column: {}", options.value_column)))?;
+    let sensor = column(&options.sensor_column);
+    if sensor.is_none() && options.sensor.is_none() {
+        return Err(ImportError::Mapping(format!(
+            "Missing column {}, either map a sensor column or a fixed sensor",
+            options.sensor_column
+        )));
+    }
+    let station = column(&options.station_column);
+
+    let mut rows = Vec::new();
+    for (i, record) in reader.records().enumerate() {
+        let record = record?;
+        let field = |index: Option<usize>| {
+            index
+                .and_then(|index| record.get(index))
+                .filter(|field| !field.is_empty())
+                .map(str::to_owned)
+        };
+        rows.push(RawRow {
+            // the header is line 1
+            line: i + 2,
+            station: field(station),
+            sensor: field(sensor),
+            timestamp: field(Some(timestamp)),
+            value: field(Some(value)),
+        });
+    }
+    Ok(rows)
+}
+
+/// reads the raw rows of a ndjson file, invalid lines are returned as errors
+fn read_ndjson(input: &[u8], options: &ImportOptions) -> (Vec<RawRow>, Vec<RowError>) {
+    let mut rows = Vec::new();
+    let mut errors = Vec::new();
+    for (i, line) in String::from_utf8_lossy(input).lines().enumerate() {
+        if line.trim().is_empty() {
+            continue;
+        }
+        let object: serde_json::Map<String, serde_json::Value> = match serde_json::from_str(line) {
+            Ok(object) => object,
+            Err(err) => {
+                errors.push(RowError {
+                    line: i + 1,
+                    message: format!("Invalid json: {err}"),
+                });
+                continue;
+            }
+        };
+        let field = |name: &str| match object.get(name) {
+            Some(serde_json::Value::String(value)) => Some(value.clone()),
+            Some(serde_json::Value::Null) | None => None,
+            Some(value) => Some(value.to_string()),
+        };
+        rows.push(RawRow {
+            line: i + 1,
+            station: field(&options.station_column),
+            sensor: field(&options.sensor_column),
+            timestamp: field(&options.timestamp_column),
+            value: field(&options.value_column),
+        });
+    }
+    (rows, errors)
+}
+
+/// Parses and validates an import file without touching the database.
+/// Returns the valid records and the errors of the invalid rows
+pub fn parse(
+    input: &[u8],
+    options: &ImportOptions,
+) -> Result<(Vec<Record>, Vec<RowError>), ImportError> {
+    let zone: SourceZone = options.timezone.parse()?;
+    let (rows, mut errors) = match options.format {
+        ImportFormat::Csv => (read_csv(input, options)?, Vec::new()),
+        ImportFormat::Ndjson => read_ndjson(input, options),
+    };
+
+    let mut records = Vec::with_capacity(rows.len());
+    for row in rows {
+        let sensor = match row.sensor.or_else(|| options.sensor.clone()) {
+            Some(sensor) => normalize_sensor(&sensor),
+            None => {
+                errors.push(RowError {
+                    line: row.line,
+                    message: "Missing sensor".to_owned(),
+                });
+                continue;
+            }
+        };
+        let timestamp = match row.timestamp.map(|timestamp| {
+            parse_timestamp(&timestamp, options.timestamp_format.as_deref(), &zone)
+        }) {
+            Some(Ok(timestamp)) => timestamp,
+            Some(Err(message)) => {
+                errors.push(RowError {
+                    line: row.line,
+                    message,
+                });
+                continue;
+            }
+            None => {
+                errors.push(RowError {
+                    line: row.line,
+                    message: "Missing timestamp".to_owned(),
+                });
+                continue;
+            }
+        };
+        let Some(value) = row.value else {
+            errors.push(RowError {
+                line: row.line,
+                message: "Missing value".to_owned(),
+            });
+            continue;
+        };
+
+        records.push(Record {
+            line: row.line,
+            station: row.station.or_else(|| options.station.clone()),
+            sensor,
+            timestamp,
+            value,
+        });
+    }
+    errors.sort_by_key(|error| error.line);
+    Ok((records, errors))
+}
+
+/// Imports a CSV or NDJSON file into `sensor_value` and returns a summary.
+/// On a dry run nothing is written, the report shows what would have been imported
+pub async fn import(
+    db: &DB,
+    input: &[u8],
+    options: &ImportOptions,
+) -> Result<ImportReport, ImportError> {
+    let (records, errors) = parse(input, options)?;
+    import_records(db, records, errors, options).await
+}
+
+/// Imports the parsed records of a file, rejecting the rows of the errors, see `import`
+pub async fn import_records(
+    db: &DB,
+    records: Vec<Record>,
+    errors: Vec<RowError>,
+    options: &ImportOptions,
+) -> Result<ImportReport, ImportError> {
+    let mut report = ImportReport {
+        dry_run: options.dry_run,
+        rows: records.len() + errors.len(),
+        ..Default::default()
+    };
+    for error in errors {
+        report.reject(error.line, error.message);
+    }
+
+    let mut by_sensor: BTreeMap<String, Vec<Record>> = BTreeMap::new();
+    for record in records {
+        by_sensor
+            .entry(record.sensor.clone())
+            .or_default()
+            .push(record);
+    }
+
+    for (sensor_id, mut records) in by_sensor {
+        let sensor = match resolve_sensor(db, &sensor_id, &records, options, &mut report).await? {
+            Ok(sensor) => sensor,
+            Err(message) => {
+                for record in records {
+                    report.reject(record.line, message.clone());
+                }
+                continue;
+            }
+        };
+
+        records.sort_by_key(|record| record.timestamp);
+        let (Some(first), Some(last)) = (records.first(), records.last()) else {
+            continue;
+        };
+        let existing: HashSet<DateTime<Utc>> = SensorValue::get_within_timeperiod(
+            db,
+            sensor_id.clone(),
+            &TimePeriod::between(first.timestamp, last.timestamp),
+        )
+        .await?
+        .iter()
+        .map(|value| value.get_timestamp().0)
+        .collect();
+
+        let mut seen = HashSet::new();
+        for record in records {
+            if existing.contains(&record.timestamp) || !seen.insert(record.timestamp) {
+                report.duplicates += 1;
+                continue;
+            }
+            if !options.dry_run {
+                SensorValue::with_source_timestamp(record.value, sensor.clone(), record.timestamp)
+                    .save(db)
+                    .await?;
+            }
+            report.imported += 1;
+            report.from = Some(
+                report
+                    .from
+                    .map_or(record.timestamp, |from| from.min(record.timestamp)),
+            );
+            report.to = Some(
+                report
+                    .to
+                    .map_or(record.timestamp, |to| to.max(record.timestamp)),
+            );
+        }
+    }
+
+    Ok(report)
+}
+
+/// Returns the record id of the sensor, creating it if allowed.
+/// The inner error describes why the rows of this sensor can not be imported
+async fn resolve_sensor(
+    db: &DB,
+    sensor_id: &str,
+    records: &[Record],
+    options: &ImportOptions,
+    report: &mut ImportReport,
+) -> Result<Result<Thing, String>, ImportError> {
+    if let Some(sensor) = Sensor::get(db, sensor_id.to_owned()).await? {
+        return Ok(Ok(sensor.get_id().clone()));
+    }
+    if !options.create_sensors {
+        return Ok(Err(format!("Unknown sensor: {sensor_id}")));
+    }
+    let Some(station_id) = records.iter().find_map(|record| record.station.clone()) else {
+        return Ok(Err(format!(
+            "Unknown sensor {sensor_id} without a station to create it in"
+        )));
+    };
+    let Some(station) = Station::get(db, station_id.clone()).await? else {
+        return Ok(Err(format!("Unknown station: {station_id}")));
+    };
+
+    report.created_sensors.push(sensor_id.to_owned());
+    if options.dry_run {
+        return Ok(Ok(Thing::from(("sensor", sensor_id))));
+    }
+    match Sensor::create(db, sensor_id.to_owned(), station.get_id().clone()).await? {
+        Some(sensor) => Ok(Ok(sensor.get_id().clone())),
+        None => Ok(Err(format!("Unable to create sensor: {sensor_id}"))),
+    }
+}
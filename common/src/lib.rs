@@ -11,6 +11,14 @@ use surrealdb::{
     Surreal,
 };
 
+pub mod alarm;
+pub mod export;
+pub mod import;
+pub mod mhubx;
+pub mod resample;
+pub mod rules;
+pub mod schedule;
+
 type DB = Surreal<Client>;
 
 /// Station
@@ -18,19 +26,20 @@ type DB = Surreal<Client>;
 /// # Example
 ///
 /// ```
+/// # use common::Station;
+/// # use surrealdb::sql::Thing;
 /// let station = Station::new("palettenlager".to_owned());
 ///
-/// let answer = Station {
-///     id: Thing::from(("station", "palettenlager")),
-///     name: "palettenlager".to_owned(),
-/// };
-///
-/// assert_eq!(station, answer);
+/// assert_eq!(station.get_id(), &Thing::from(("station", "palettenlager")));
 /// ```
 #[derive(Debug, Deserialize, Serialize)]
 pub struct Station {
     id: Thing,
     name: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    display_name: Option<String>,
+    #[serde(default)]
+    archived: bool,
     sensors: Option<Vec<Sensor>>,
 }
 
@@ -41,23 +50,33 @@ impl Station {
     /// name: String -> needs to be unique
     ///
     /// ```
+    /// # use common::Station;
+    /// # use surrealdb::sql::Thing;
     /// let station = Station::new("palettenlager".to_owned());
     ///
-    /// let answer = Station {
-    ///     id: Thing::from(("station", "palettenlager")),
-    ///     name: "palettenlager".to_owned(),
-    /// };
-    ///
-    /// assert_eq!(station, answer);
+    /// assert_eq!(station.get_id(), &Thing::from(("station", "palettenlager")));
     /// ```
     pub fn new(name: String) -> Self {
         Station {
             id: Thing::from(("station", name.as_str())),
             name,
+            display_name: None,
+            archived: false,
             sensors: None,
         }
     }
 
+    /// Sets the name shown to users instead of the unique name
+    pub fn with_display_name(mut self, display_name: Option<String>) -> Self {
+        self.display_name = display_name;
+        self
+    }
+
+    /// Saves a new station to the database
+    pub async fn create(self, db: &DB) -> Result<Option<Self>, surrealdb::Error> {
+        db.create("station").content(self).await
+    }
+
     /// Returns a vector of all available stations
     pub async fn get_all(db: &DB) -> Result<Vec<Self>, surrealdb::Error> {
         db.select("station").await
@@ -68,9 +87,61 @@ impl Station {
         db.select(Thing::from(("station", id.as_str()))).await
     }
 
+    /// Changes the display name of a station, returns None if the id does not exist
+    pub async fn rename(
+        db: &DB,
+        id: String,
+        display_name: String,
+    ) -> Result<Option<Self>, surrealdb::Error> {
+        db.query("UPDATE $station SET display_name = $display_name")
+            .bind(("station", Thing::from(("station", id.as_str()))))
+            .bind(("display_name", display_name))
+            .await?
+            .take(0)
+    }
+
+    /// Archives or restores a station, returns None if the id does not exist
+    pub async fn set_archived(
+        db: &DB,
+        id: String,
+        archived: bool,
+    ) -> Result<Option<Self>, surrealdb::Error> {
+        db.query("UPDATE $station SET archived = $archived")
+            .bind(("station", Thing::from(("station", id.as_str()))))
+            .bind(("archived", archived))
+            .await?
+            .take(0)
+    }
+
+    /// Deletes a station and its sensors, returns the deleted station.
+    /// The values of the sensors are kept unless `cascade` is set,
+    /// so they reappear when a sensor with the same id is created again.
+    pub async fn delete(
+        db: &DB,
+        id: String,
+        cascade: bool,
+    ) -> Result<Option<Self>, surrealdb::Error> {
+        let mut query = String::new();
+        if cascade {
+            query.push_str("DELETE sensor_value WHERE sensor.station = $station;");
+        }
+        query.push_str("DELETE sensor WHERE station = $station;");
+        query.push_str("DELETE $station RETURN BEFORE;");
+
+        db.query(query)
+            .bind(("station", Thing::from(("station", id.as_str()))))
+            .await?
+            .take(if cascade { 2 } else { 1 })
+    }
+
     pub fn get_id(&self) -> &Thing {
         &self.id
     }
+
+    /// Returns whether this [`Station`] is archived.
+    pub fn is_archived(&self) -> bool {
+        self.archived
+    }
 }
 
 /// Sensor
@@ -78,22 +149,22 @@ impl Station {
 /// # Example
 ///
 /// ```
-/// let sensor = Sensor::new("dosenfuellstand", Thing::from(("station", "palettenlager")));
-///
-/// let answer = Sensor {
-///     id: Thing::from(("sensor", "dosenfuellstand")),
-///     station: Thing::from(("station", "palettenlager")),
-///     display_name: "dosenfuellstand".to_owned(),
-///     values: None,
-/// };
+/// # use common::Sensor;
+/// # use surrealdb::sql::Thing;
+/// let sensor = Sensor::new(
+///     "dosenfuellstand".to_owned(),
+///     Thing::from(("station", "palettenlager")),
+/// );
 ///
-/// assert_eq!(sensor, answer);
+/// assert_eq!(sensor.get_id(), &Thing::from(("sensor", "dosenfuellstand")));
 /// ```
 #[derive(Debug, Deserialize, Serialize)]
 pub struct Sensor {
     id: Thing,
     station: Thing,
     display_name: String,
+    #[serde(default)]
+    archived: bool,
     values: Option<Vec<SensorValue>>,
 }
 
@@ -103,18 +174,90 @@ impl Sensor {
         Sensor {
             id: Thing::from(("sensor", name.as_str())),
             station,
-            display_name: name.into(),
+            display_name: name,
+            archived: false,
             values: None,
         }
     }
 
+    /// Sets the name shown to users, defaults to the unique name
+    pub fn with_display_name(mut self, display_name: String) -> Self {
+        self.display_name = display_name;
+        self
+    }
+
     /// Creates a new Sensor struct an saves it to the database
     pub async fn create(
         db: &DB,
         name: String,
         station: Thing,
     ) -> Result<Option<Self>, surrealdb::Error> {
-        db.create("sensor").content(Self::new(name, station)).await
+        Self::new(name, station).save(db).await
+    }
+
+    /// Saves a new sensor to the database
+    pub async fn save(self, db: &DB) -> Result<Option<Self>, surrealdb::Error> {
+        db.create("sensor").content(self).await
+    }
+
+    /// Changes the display name of a sensor, returns None if the id does not exist
+    pub async fn rename(
+        db: &DB,
+        id: String,
+        display_name: String,
+    ) -> Result<Option<Self>, surrealdb::Error> {
+        db.query("UPDATE $sensor SET display_name = $display_name")
+            .bind(("sensor", Thing::from(("sensor", id.as_str()))))
+            .bind(("display_name", display_name))
+            .await?
+            .take(0)
+    }
+
+    /// Moves a sensor to another station, returns None if the id does not exist
+    pub async fn move_to_station(
+        db: &DB,
+        id: String,
+        station: Thing,
+    ) -> Result<Option<Self>, surrealdb::Error> {
+        db.query("UPDATE $sensor SET station = $station")
+            .bind(("sensor", Thing::from(("sensor", id.as_str()))))
+            .bind(("station", station))
+            .await?
+            .take(0)
+    }
+
+    /// Archives or restores a sensor, returns None if the id does not exist.
+    /// No values are stored for archived sensors.
+    pub async fn set_archived(
+        db: &DB,
+        id: String,
+        archived: bool,
+    ) -> Result<Option<Self>, surrealdb::Error> {
+        db.query("UPDATE $sensor SET archived = $archived")
+            .bind(("sensor", Thing::from(("sensor", id.as_str()))))
+            .bind(("archived", archived))
+            .await?
+            .take(0)
+    }
+
+    /// Deletes a sensor, returns the deleted sensor.
+    /// The values are kept unless `cascade` is set,
+    /// so they reappear when a sensor with the same id is created again.
+    pub async fn delete(
+        db: &DB,
+        id: String,
+        cascade: bool,
+    ) -> Result<Option<Self>, surrealdb::Error> {
+        let mut query = String::new();
+        if cascade {
+            query.push_str("DELETE sensor_value:[$sensor, NONE]..;");
+        }
+        query.push_str("DELETE $sensor RETURN BEFORE;");
+
+        db.query(query)
+            .bind(("sensor", Thing::from(("sensor", id.as_str()))))
+            .await?
+            .take(if cascade { 1 } else { 0 })
     }
 
     /// Retrive a single sensor, without values by its id
@@ -126,8 +269,7 @@ impl Sensor {
     pub async fn get_by_station(db: &DB, id: String) -> Result<Vec<Self>, surrealdb::Error> {
         db.query("SELECT *, (SELECT * FROM sensor_value:[$parent.id, NONE]..[$parent.id, time::now()] ORDER BY server_timestamp ASC LIMIT 1 ) AS values FROM sensor WHERE station = $station;")
             .bind(("station", Thing::from(("station", id.as_str()))))
-            .await
-            .expect("Error during query execution")
+            .await?
             .take(0)
     }
 
@@ -135,8 +277,13 @@ impl Sensor {
     ///
     /// # Example
     ///
-    /// ```
-    /// let sensor: Option<Sensor> = Sensor::get_values(&db, "dosenfuellstand").await.unwrap();
+    /// ```no_run
+    /// # use common::Sensor;
+    /// # async fn run(db: &surrealdb::Surreal<surrealdb::engine::remote::ws::Client>) {
+    /// let sensor: Option<Sensor> = Sensor::get_with_values(db, "dosenfuellstand".to_owned())
+    ///     .await
+    ///     .unwrap();
+    /// # }
     /// ```
     pub async fn get_with_values(db: &DB, id: String) -> Result<Option<Self>, surrealdb::Error> {
         db.query("SELECT *, (SELECT * FROM sensor_value:[$sensor, NONE]..) AS values FROM $sensor")
@@ -150,9 +297,15 @@ impl Sensor {
     ///
     /// # Example
     ///
-    /// ```
-    /// let time_period = TimeRange::new(chrono::Duration::Hour(5), Utc::now());
-    /// let sensor: Option<Sensor> = Sensor::get_values_within_timeperiod(&db, "dosenfuellstand", time_period).await.unwrap();
+    /// ```no_run
+    /// # use common::{Sensor, TimePeriod};
+    /// # async fn run(db: &surrealdb::Surreal<surrealdb::engine::remote::ws::Client>) {
+    /// let time_period = TimePeriod::between(chrono::Duration::hours(5), chrono::Utc::now());
+    /// let sensor: Option<Sensor> =
+    ///     Sensor::get_values_within_timeperiod(db, "dosenfuellstand".to_owned(), time_period)
+    ///         .await
+    ///         .unwrap();
+    /// # }
     /// ```
     pub async fn get_values_within_timeperiod(
         db: &DB,
@@ -172,6 +325,16 @@ impl Sensor {
     pub fn get_id(&self) -> &Thing {
         &self.id
     }
+
+    /// Returns the station of this [`Sensor`].
+    pub fn get_station(&self) -> &Thing {
+        &self.station
+    }
+
+    /// Returns whether this [`Sensor`] is archived.
+    pub fn is_archived(&self) -> bool {
+        self.archived
+    }
 }
 
 /// A time period within which to query data
@@ -181,9 +344,10 @@ impl Sensor {
 /// now = 15:00 => from = 10:00
 ///
 /// ```
+/// # use common::TimePeriod;
 /// let from = chrono::Duration::hours(5);
 /// let to = Some(chrono::Utc::now());
-/// let time_period = TimeRange::between(from, to);
+/// let time_period = TimePeriod::between(from, to);
 /// ```
 #[derive(Debug, Deserialize, Serialize)]
 pub struct TimePeriod {
@@ -200,6 +364,7 @@ impl TimePeriod {
     /// now = 15:00 => from = 10:00
     ///
     /// ```
+    /// # use common::TimePeriod;
     /// let from = chrono::Duration::hours(5);
     /// let to = Some(chrono::Utc::now());
     /// let time_period = TimePeriod::between(from, to);
@@ -217,8 +382,9 @@ impl TimePeriod {
     /// A period from five minutes ago to now
     ///
     /// ```
-    /// let from = Some(chrono::Utc::now() - chrono::Duration::Minutes(5))
-    /// let time_period = TimeRange::from(from);
+    /// # use common::TimePeriod;
+    /// let from = Some(chrono::Utc::now() - chrono::Duration::minutes(5));
+    /// let time_period = TimePeriod::from(from);
     /// ```
     pub fn from(from: impl ToDatetime) -> Self {
         Self {
@@ -235,10 +401,7 @@ pub trait ToDatetime {
 
 impl ToDatetime for Option<chrono::DateTime<Utc>> {
     fn to_datetime(self) -> Option<Datetime> {
-        match self {
-            Some(v) => Some(Datetime(v)),
-            None => None,
-        }
+        self.map(Datetime)
     }
 }
 
@@ -259,8 +422,10 @@ impl ToDatetime for chrono::DateTime<Utc> {
 /// # Example
 ///
 /// ```
+/// # use common::SensorValue;
+/// # use surrealdb::sql::Thing;
 /// let sensor = Thing::from(("sensor", "doesnfuellstand"));
-/// let value = SensorValue::new("12", sensor);
+/// let value = SensorValue::new("12".to_owned(), sensor);
 /// ```
 #[derive(Debug, Deserialize, Serialize)]
 pub struct SensorValue {
@@ -268,6 +433,8 @@ pub struct SensorValue {
     sensor: Thing,
     value: String,
     server_timestamp: Datetime,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    source_timestamp: Option<Datetime>,
 }
 
 impl SensorValue {
@@ -275,27 +442,116 @@ impl SensorValue {
     pub fn new(value: String, sensor: Thing) -> Self {
         let server_timestamp = Datetime(Utc::now());
         SensorValue {
-            id: Thing::from((
-                "sensor_value".to_owned(),
-                Id::from(vec![
-                    Value::Thing(sensor.clone()),
-                    Value::Datetime(server_timestamp.clone()),
-                ]),
-            )),
+            id: Self::id(&sensor, &server_timestamp),
             sensor,
             value,
             server_timestamp,
+            source_timestamp: None,
         }
     }
 
+    /// Creates a new sensor value struct for a value measured at the given time by its source,
+    /// e.g. historical data. The value is stored at the source time.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// # use common::SensorValue;
+    /// # use surrealdb::sql::Thing;
+    /// let measured = chrono::Utc::now() - chrono::Duration::days(30);
+    /// let value = SensorValue::with_source_timestamp(
+    ///     "12".to_owned(),
+    ///     Thing::from(("sensor", "dosenfuellstand")),
+    ///     measured,
+    /// );
+    ///
+    /// assert_eq!(value.get_timestamp().0, measured);
+    /// ```
+    pub fn with_source_timestamp(
+        value: String,
+        sensor: Thing,
+        source_timestamp: chrono::DateTime<Utc>,
+    ) -> Self {
+        let timestamp = Datetime(source_timestamp);
+        SensorValue {
+            id: Self::id(&sensor, &timestamp),
+            sensor,
+            value,
+            server_timestamp: timestamp.clone(),
+            source_timestamp: Some(timestamp),
+        }
+    }
+
+    /// Returns the record id of a value, consisting of the sensor and the time
+    fn id(sensor: &Thing, timestamp: &Datetime) -> Thing {
+        Thing::from((
+            "sensor_value".to_owned(),
+            Id::from(vec![
+                Value::Thing(sensor.clone()),
+                Value::Datetime(timestamp.clone()),
+            ]),
+        ))
+    }
+
     /// Creates a new sensor_value struct and saves it to the database
     pub async fn create(
         db: &DB,
         value: String,
         sensor: Thing,
     ) -> Result<Option<Self>, surrealdb::Error> {
-        db.create("sensor_value")
-            .content(Self::new(value, sensor))
-            .await
+        Self::new(value, sensor).save(db).await
+    }
+
+    /// Saves this sensor value to the database
+    pub async fn save(self, db: &DB) -> Result<Option<Self>, surrealdb::Error> {
+        db.create("sensor_value").content(self).await
+    }
+
+    /// Returns true if a value of the sensor is already stored at the same time
+    pub async fn exists(&self, db: &DB) -> Result<bool, surrealdb::Error> {
+        let existing: Option<Self> = db.select(self.id.clone()).await?;
+        Ok(existing.is_some())
+    }
+
+    /// Returns all values of a sensor within a time period, ordered by time
+    pub async fn get_within_timeperiod(
+        db: &DB,
+        id: String,
+        time_period: &TimePeriod,
+    ) -> Result<Vec<Self>, surrealdb::Error> {
+        db.query("SELECT * FROM sensor_value:[$sensor, $from]..=[$sensor, $to] ORDER BY server_timestamp ASC")
+            .bind(("sensor", Thing::from(("sensor".to_string(), id))))
+            .bind(("from", time_period.from.clone()))
+            .bind(("to", time_period.to.clone()))
+            .await?
+            .take(0)
+    }
+
+    /// Returns the latest value of a sensor before the given time or None if there is none
+    pub async fn get_latest_before(
+        db: &DB,
+        id: String,
+        time: impl ToDatetime,
+    ) -> Result<Option<Self>, surrealdb::Error> {
+        db.query("SELECT * FROM sensor_value:[$sensor, NONE]..[$sensor, $time] ORDER BY server_timestamp DESC LIMIT 1")
+            .bind(("sensor", Thing::from(("sensor".to_string(), id))))
+            .bind(("time", time.to_datetime()))
+            .await?
+            .take(0)
+    }
+
+    /// Returns the sensor of this [`SensorValue`].
+    pub fn get_sensor(&self) -> &Thing {
+        &self.sensor
+    }
+
+    /// Returns the value of this [`SensorValue`].
+    pub fn get_value(&self) -> &str {
+        &self.value
+    }
+
+    /// Returns the timestamp of this [`SensorValue`].
+    pub fn get_timestamp(&self) -> &Datetime {
+        &self.server_timestamp
     }
 }
@@ -0,0 +1,206 @@
+//! # common::mhubx::cache
+//!
+//! `common::mhubx::cache` keeps the responses of the MHubX for a short time, so dashboards
+//! polling the same data cause a single upstream call. Responses are cached per upstream call,
+//! the time range and severity filters are applied to the cached data.
+//! Identical calls arriving while a call is running wait for its response instead of calling
+//! the MHubX themselves. While the MHubX is unreachable, the last response is served stale.
+//!
+//! # Example
+//!
+//! ```no_run
+//! # use common::mhubx::{CacheStatus, MeasurementQuery, MhubxCache, MhubxClient};
+//! # use std::time::Duration;
+//! # async fn run(client: MhubxClient) {
+//! let cache = MhubxCache::new(client, Duration::from_secs(5), Duration::from_secs(300));
+//! let cached = cache
+//!     .measurements(&MeasurementQuery::system("cps1"))
+//!     .await
+//!     .unwrap();
+//! if cached.status == CacheStatus::Stale {
+//!     println!("the MHubX is down, the data is {:?} old", cached.age);
+//! }
+//! # }
+//! ```
+
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use serde::de::DeserializeOwned;
+use serde_json::Value;
+
+use super::{Alarm, AlarmQuery, Measurement, MeasurementQuery, MhubxClient, MhubxError, System};
+
+/// the number of cached calls, beyond which expired calls are dropped
+const MAX_ENTRIES: usize = 1024;
+
+/// How a response was served
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CacheStatus {
+    /// served from the cache
+    Hit,
+    /// fetched from the MHubX
+    Miss,
+    /// shared with an identical call, which was running
+    Coalesced,
+    /// served from the cache after the MHubX failed
+    Stale,
+}
+
+impl CacheStatus {
+    /// the value of the `X-Cache` header
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            CacheStatus::Hit => "HIT",
+            CacheStatus::Miss => "MISS",
+            CacheStatus::Coalesced => "COALESCED",
+            CacheStatus::Stale => "STALE",
+        }
+    }
+}
+
+/// A response and how it was served
+#[derive(Debug, Clone)]
+pub struct Cached<T> {
+    pub data: T,
+    pub status: CacheStatus,
+    /// the time since the MHubX sent the data
+    pub age: Duration,
+}
+
+/// the last response and failure of an upstream call
+#[derive(Default)]
+struct Entry {
+    data: Option<(Instant, Arc<Vec<Value>>)>,
+    failure: Option<(Instant, MhubxError)>,
+}
+
+type Key = Vec<(&'static str, String)>;
+
+/// A MHubX client sharing its responses, it is meant to be created once and shared
+pub struct MhubxCache {
+    client: MhubxClient,
+    ttl: Duration,
+    stale: Duration,
+    entries: Mutex<HashMap<Key, Arc<tokio::sync::Mutex<Entry>>>>,
+}
+
+impl MhubxCache {
+    /// Creates a cache keeping responses for ttl. While the MHubX is unreachable,
+    /// responses are served up to stale after they were fetched, a zero stale disables it
+    pub fn new(client: MhubxClient, ttl: Duration, stale: Duration) -> Self {
+        MhubxCache {
+            client,
+            ttl,
+            stale,
+            entries: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Returns the measurements of the query
+    pub async fn measurements(
+        &self,
+        query: &MeasurementQuery,
+    ) -> Result<Cached<Vec<Measurement>>, MhubxError> {
+        let mut cached: Cached<Vec<Measurement>> = self.call(query.params()).await?;
+        cached.data.retain(|measurement| query.matches(measurement));
+        Ok(cached)
+    }
+
+    /// Returns the alarms of the query
+    pub async fn alarms(&self, query: &AlarmQuery) -> Result<Cached<Vec<Alarm>>, MhubxError> {
+        let mut cached: Cached<Vec<Alarm>> = self.call(query.params()).await?;
+        cached.data.retain(|alarm| query.matches(alarm));
+        Ok(cached)
+    }
+
+    /// Returns the systems of the MHubX and the measurements they offer
+    pub async fn systems(&self) -> Result<Cached<Vec<System>>, MhubxError> {
+        let cached = self.measurements(&MeasurementQuery::default()).await?;
+        Ok(Cached {
+            data: System::from_measurements(cached.data),
+            status: cached.status,
+            age: cached.age,
+        })
+    }
+
+    /// returns the entry of a call, dropping expired entries once the cache is full
+    fn entry(&self, key: Key) -> Arc<tokio::sync::Mutex<Entry>> {
+        let mut entries = self.entries.lock().unwrap_or_else(|err| err.into_inner());
+        if entries.len() >= MAX_ENTRIES && !entries.contains_key(&key) {
+            let keep = self.ttl.max(self.stale);
+            entries.retain(|_, entry| match entry.try_lock() {
+                Ok(entry) => entry
+                    .data
+                    .as_ref()
+                    .is_some_and(|(fetched, _)| fetched.elapsed() < keep),
+                // a running call
+                Err(_) => true,
+            });
+        }
+        entries.entry(key).or_default().clone()
+    }
+
+    /// serves a call from the cache or the MHubX
+    async fn call<T: DeserializeOwned>(&self, key: Key) -> Result<Cached<Vec<T>>, MhubxError> {
+        let started = Instant::now();
+        let entry = self.entry(key.clone());
+        let (mut entry, waited) = match entry.clone().try_lock_owned() {
+            Ok(entry) => (entry, false),
+            Err(_) => (entry.lock_owned().await, true),
+        };
+
+        if let Some((fetched, data)) = &entry.data {
+            if fetched.elapsed() < self.ttl {
+                let status = match waited && *fetched >= started {
+                    true => CacheStatus::Coalesced,
+                    false => CacheStatus::Hit,
+                };
+                return decode(data, status, fetched.elapsed());
+            }
+        }
+        // the call we waited for failed, the MHubX isn't asked again right away
+        let failure = match &entry.failure {
+            Some((failed, err)) if waited && *failed >= started => Some(err.clone()),
+            _ => None,
+        };
+        let err = match failure {
+            Some(err) => err,
+            None => match self.client.call::<Value>(&key).await {
+                Ok(data) => {
+                    entry.failure = None;
+                    let data = Arc::new(data);
+                    entry.data = Some((Instant::now(), data.clone()));
+                    return decode(&data, CacheStatus::Miss, Duration::ZERO);
+                }
+                Err(err) => {
+                    entry.failure = Some((Instant::now(), err.clone()));
+                    err
+                }
+            },
+        };
+
+        match &entry.data {
+            Some((fetched, data)) if err.is_transient() && fetched.elapsed() < self.stale => {
+                decode(data, CacheStatus::Stale, fetched.elapsed())
+            }
+            _ => Err(err),
+        }
+    }
+}
+
+/// deserializes the cached response
+fn decode<T: DeserializeOwned>(
+    data: &[Value],
+    status: CacheStatus,
+    age: Duration,
+) -> Result<Cached<Vec<T>>, MhubxError> {
+    let data = data
+        .iter()
+        .map(|item| T::deserialize(item).map_err(|err| MhubxError::Decode(err.to_string())))
+        .collect::<Result<_, _>>()?;
+    Ok(Cached { data, status, age })
+}
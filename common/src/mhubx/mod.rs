@@ -0,0 +1,561 @@
+//! # common::mhubx
+//!
+//! `common::mhubx` is a client for the REST API of the MHubX, which collects the
+//! measurements and alarms of the plant. All calls go through the `Logic.Interface` page,
+//! the called function is selected by the `name` parameter.
+//! The MHubX filters by system and measurement ids, the time range and severity filters
+//! of the queries are applied to its responses.
+//! Failed calls are retried on timeouts, connection errors and server errors.
+//! `MhubxCache` shares the responses of the MHubX between callers, see `cache`.
+//!
+//! # Example
+//!
+//! ```no_run
+//! # use common::mhubx::{MhubxClient, MhubxConfig, MeasurementQuery};
+//! # async fn run() {
+//! let client = MhubxClient::new(MhubxConfig {
+//!     endpoint: "http://127.0.0.1:8001/mhubx-cc/module/juwi/action".to_owned(),
+//!     username: "system".to_owned(),
+//!     password: "changeit".to_owned(),
+//!     timeout: std::time::Duration::from_secs(10),
+//!     retries: 2,
+//! })
+//! .unwrap();
+//! let measurements = client
+//!     .measurements(&MeasurementQuery::system("cps1"))
+//!     .await
+//!     .unwrap();
+//! # }
+//! ```
+
+pub mod cache;
+
+pub use cache::{CacheStatus, Cached, MhubxCache};
+
+use std::{collections::BTreeMap, time::Duration};
+
+use chrono::{DateTime, NaiveDateTime, TimeZone, Utc};
+use serde::{de::DeserializeOwned, Deserialize, Deserializer, Serialize};
+use serde_json::{Map, Value};
+
+/// the page all functions of the MHubX are called on
+const INTERFACE_PAGE: &str = "Logic.Interface";
+
+/// the delay before the first retry, doubled for every further retry
+const RETRY_DELAY: Duration = Duration::from_millis(200);
+
+/// upper bound of the delay between two retries
+const MAX_RETRY_DELAY: Duration = Duration::from_secs(30);
+
+/// the delay before a retry, doubling with every attempt up to the maximum
+fn retry_delay(attempt: u32) -> Duration {
+    RETRY_DELAY
+        .saturating_mul(2u32.saturating_pow(attempt))
+        .min(MAX_RETRY_DELAY)
+}
+
+/// Connection details of the MHubX
+#[derive(Debug, Clone)]
+pub struct MhubxConfig {
+    /// the url of the action endpoint, e.g. `http://10.3.0.71:8080/mhubx-cc/module/juwi/action`
+    pub endpoint: String,
+    pub username: String,
+    pub password: String,
+    /// timeout of a single call, including the response body
+    pub timeout: Duration,
+    /// number of retries after a failed call
+    pub retries: u32,
+}
+
+/// Errors of a MHubX call, they are cloned for callers sharing a call
+#[derive(Debug, Clone)]
+pub enum MhubxError {
+    /// the MHubX didn't answer in time
+    Timeout,
+    /// the MHubX couldn't be reached
+    Connection(String),
+    /// the MHubX answered with a error status
+    Status(u16, String),
+    /// the MHubX reported a error within its response
+    Upstream(String),
+    /// the response doesn't match the expected structure
+    Decode(String),
+}
+
+impl std::fmt::Display for MhubxError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            MhubxError::Timeout => write!(f, "the MHubX didn't answer in time"),
+            MhubxError::Connection(err) => write!(f, "unable to reach the MHubX: {err}"),
+            MhubxError::Status(status, body) => {
+                write!(f, "the MHubX answered with status {status}: {body}")
+            }
+            MhubxError::Upstream(err) => write!(f, "the MHubX reported: {err}"),
+            MhubxError::Decode(err) => write!(f, "invalid response of the MHubX: {err}"),
+        }
+    }
+}
+
+impl std::error::Error for MhubxError {}
+
+impl From<reqwest::Error> for MhubxError {
+    fn from(err: reqwest::Error) -> Self {
+        if err.is_timeout() {
+            MhubxError::Timeout
+        } else if err.is_decode() {
+            MhubxError::Decode(err.to_string())
+        } else {
+            MhubxError::Connection(err.to_string())
+        }
+    }
+}
+
+impl MhubxError {
+    /// Returns true if a retry of the call may succeed
+    pub fn is_transient(&self) -> bool {
+        match self {
+            MhubxError::Timeout | MhubxError::Connection(_) => true,
+            MhubxError::Status(status, _) => *status >= 500,
+            MhubxError::Upstream(_) | MhubxError::Decode(_) => false,
+        }
+    }
+}
+
+/// A value of a measurement, the MHubX sends numbers, flags and texts
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(untagged)]
+pub enum MeasurementValue {
+    Number(f64),
+    Bool(bool),
+    Text(String),
+}
+
+impl std::fmt::Display for MeasurementValue {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            MeasurementValue::Number(number) => write!(f, "{number}"),
+            MeasurementValue::Bool(flag) => write!(f, "{flag}"),
+            MeasurementValue::Text(text) => write!(f, "{text}"),
+        }
+    }
+}
+
+/// A measurement of a system. Fields unknown to this client are kept in `extra`
+///
+/// # Example
+///
+/// ```
+/// # use common::mhubx::{Measurement, MeasurementValue};
+/// let measurement: Measurement = serde_json::from_str(
+///     r#"{"system_id": "cps1", "msm_id": "dosenfuellstand", "value": "42.5", "unit": "%", "timestamp": "2023-05-01 12:00:00"}"#,
+/// )
+/// .unwrap();
+///
+/// assert_eq!(measurement.value, Some(MeasurementValue::Number(42.5)));
+/// assert_eq!(measurement.timestamp.unwrap().to_rfc3339(), "2023-05-01T12:00:00+00:00");
+/// ```
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Measurement {
+    #[serde(default)]
+    pub system_id: String,
+    #[serde(alias = "id")]
+    pub msm_id: String,
+    #[serde(default)]
+    pub name: Option<String>,
+    #[serde(default, deserialize_with = "deserialize_value")]
+    pub value: Option<MeasurementValue>,
+    #[serde(default)]
+    pub unit: Option<String>,
+    #[serde(default, alias = "time", deserialize_with = "deserialize_timestamp")]
+    pub timestamp: Option<DateTime<Utc>>,
+    #[serde(flatten)]
+    pub extra: Map<String, Value>,
+}
+
+/// A alarm of a system. Fields unknown to this client are kept in `extra`
+///
+/// # Example
+///
+/// ```
+/// # use common::mhubx::Alarm;
+/// let alarm: Alarm = serde_json::from_str(
+///     r#"{"system_id": "cps1", "id": "4711", "text": "Presse Not-Aus", "severity": "3", "time": 1682942400}"#,
+/// )
+/// .unwrap();
+///
+/// assert_eq!(alarm.alarm_id, "4711");
+/// assert_eq!(alarm.severity, Some(3));
+/// assert_eq!(alarm.timestamp.unwrap().to_rfc3339(), "2023-05-01T12:00:00+00:00");
+/// ```
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Alarm {
+    #[serde(default)]
+    pub system_id: String,
+    #[serde(alias = "id", deserialize_with = "deserialize_id")]
+    pub alarm_id: String,
+    #[serde(default, alias = "text")]
+    pub message: Option<String>,
+    #[serde(default, deserialize_with = "deserialize_severity")]
+    pub severity: Option<u32>,
+    #[serde(default)]
+    pub state: Option<String>,
+    #[serde(default, alias = "time", deserialize_with = "deserialize_timestamp")]
+    pub timestamp: Option<DateTime<Utc>>,
+    #[serde(flatten)]
+    pub extra: Map<String, Value>,
+}
+
+/// reads numbers sent as text as numbers
+fn deserialize_value<'de, D>(deserializer: D) -> Result<Option<MeasurementValue>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    Ok(
+        match Option::<MeasurementValue>::deserialize(deserializer)? {
+            Some(MeasurementValue::Text(text)) => Some(
+                text.trim()
+                    .parse::<f64>()
+                    .map(MeasurementValue::Number)
+                    .unwrap_or(MeasurementValue::Text(text)),
+            ),
+            value => value,
+        },
+    )
+}
+
+/// reads ids sent as numbers or texts
+fn deserialize_id<'de, D>(deserializer: D) -> Result<String, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    match Value::deserialize(deserializer)? {
+        Value::String(id) => Ok(id),
+        Value::Number(id) => Ok(id.to_string()),
+        value => Err(serde::de::Error::custom(format!("invalid id {value}"))),
+    }
+}
+
+/// reads severities sent as numbers or texts
+fn deserialize_severity<'de, D>(deserializer: D) -> Result<Option<u32>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    Ok(match Option::<Value>::deserialize(deserializer)? {
+        Some(Value::Number(severity)) => severity.as_u64().map(|severity| severity as u32),
+        Some(Value::String(severity)) => severity.trim().parse().ok(),
+        _ => None,
+    })
+}
+
+/// reads timestamps sent as RFC 3339, as `YYYY-MM-DD hh:mm:ss` in UTC or as unix seconds
+fn deserialize_timestamp<'de, D>(deserializer: D) -> Result<Option<DateTime<Utc>>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    Ok(match Option::<Value>::deserialize(deserializer)? {
+        Some(Value::Number(seconds)) => seconds
+            .as_i64()
+            .and_then(|seconds| Utc.timestamp_opt(seconds, 0).single()),
+        Some(Value::String(timestamp)) => DateTime::parse_from_rfc3339(&timestamp)
+            .map(|timestamp| timestamp.with_timezone(&Utc))
+            .ok()
+            .or_else(|| {
+                NaiveDateTime::parse_from_str(&timestamp, "%Y-%m-%d %H:%M:%S")
+                    .ok()
+                    .map(|timestamp| Utc.from_utc_datetime(&timestamp))
+            }),
+        _ => None,
+    })
+}
+
+/// the responses of the MHubX, a plain list or a list wrapped into `data`
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum Envelope<T> {
+    Error { error: String },
+    List(Vec<T>),
+    Data { data: Vec<T> },
+}
+
+/// returns true if the timestamp is within the range, unknown timestamps are only
+/// within unbounded ranges
+fn within(
+    timestamp: Option<DateTime<Utc>>,
+    from: Option<DateTime<Utc>>,
+    to: Option<DateTime<Utc>>,
+) -> bool {
+    match timestamp {
+        Some(timestamp) => {
+            from.is_none_or(|from| timestamp >= from) && to.is_none_or(|to| timestamp <= to)
+        }
+        None => from.is_none() && to.is_none(),
+    }
+}
+
+/// Query of `getMeasurement`, `*` selects all systems, no ids select all measurements
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct MeasurementQuery {
+    pub system_id: String,
+    pub msm_ids: Vec<String>,
+    pub from: Option<DateTime<Utc>>,
+    pub to: Option<DateTime<Utc>>,
+}
+
+impl Default for MeasurementQuery {
+    fn default() -> Self {
+        MeasurementQuery {
+            system_id: "*".to_owned(),
+            msm_ids: Vec::new(),
+            from: None,
+            to: None,
+        }
+    }
+}
+
+impl MeasurementQuery {
+    /// all measurements of a system
+    pub fn system(system_id: &str) -> Self {
+        MeasurementQuery {
+            system_id: system_id.to_owned(),
+            ..Default::default()
+        }
+    }
+
+    /// Returns true if the measurement was taken within the time range
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// # use common::mhubx::{Measurement, MeasurementQuery};
+    /// let measurement: Measurement = serde_json::from_str(
+    ///     r#"{"system_id": "cps1", "msm_id": "dosenfuellstand", "timestamp": "2023-05-01T12:00:00Z"}"#,
+    /// )
+    /// .unwrap();
+    /// let query = MeasurementQuery {
+    ///     from: Some("2023-05-01T13:00:00Z".parse().unwrap()),
+    ///     ..MeasurementQuery::system("cps1")
+    /// };
+    ///
+    /// assert!(!query.matches(&measurement));
+    /// ```
+    pub fn matches(&self, measurement: &Measurement) -> bool {
+        within(measurement.timestamp, self.from, self.to)
+    }
+
+    pub(crate) fn params(&self) -> Vec<(&'static str, String)> {
+        let msm_ids = match self.msm_ids.is_empty() {
+            true => "*".to_owned(),
+            false => self.msm_ids.join(","),
+        };
+        vec![
+            ("name", "getMeasurement".to_owned()),
+            ("source", "system".to_owned()),
+            ("system_id", self.system_id.clone()),
+            ("msm_id", msm_ids),
+        ]
+    }
+}
+
+/// Query of `getAlarms`, `*` selects all systems. Alarms without severity
+/// only match queries without severity filter
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize, Default)]
+pub struct AlarmQuery {
+    pub system_id: String,
+    pub from: Option<DateTime<Utc>>,
+    pub to: Option<DateTime<Utc>>,
+    pub min_severity: Option<u32>,
+    pub max_severity: Option<u32>,
+}
+
+impl AlarmQuery {
+    /// all alarms of a system
+    pub fn system(system_id: &str) -> Self {
+        AlarmQuery {
+            system_id: system_id.to_owned(),
+            ..Default::default()
+        }
+    }
+
+    /// Returns true if the alarm is within the time range and severities
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// # use common::mhubx::{Alarm, AlarmQuery};
+    /// let alarm: Alarm =
+    ///     serde_json::from_str(r#"{"system_id": "cps1", "id": "4711", "severity": 2}"#).unwrap();
+    /// let query = AlarmQuery {
+    ///     min_severity: Some(3),
+    ///     ..AlarmQuery::system("cps1")
+    /// };
+    ///
+    /// assert!(!query.matches(&alarm));
+    /// assert!(AlarmQuery::system("cps1").matches(&alarm));
+    /// ```
+    pub fn matches(&self, alarm: &Alarm) -> bool {
+        let severity = match (alarm.severity, self.min_severity, self.max_severity) {
+            (_, None, None) => true,
+            (Some(severity), min, max) => {
+                min.is_none_or(|min| severity >= min) && max.is_none_or(|max| severity <= max)
+            }
+            (None, _, _) => false,
+        };
+        severity && within(alarm.timestamp, self.from, self.to)
+    }
+
+    pub(crate) fn params(&self) -> Vec<(&'static str, String)> {
+        vec![
+            ("name", "getAlarms".to_owned()),
+            ("system_id", self.system_id.clone()),
+        ]
+    }
+}
+
+/// Returns the id of the sensor a measurement of a system is imported to
+///
+/// # Example
+///
+/// ```
+/// # use common::mhubx::sensor_id;
+/// assert_eq!(sensor_id("cps1", "kugelfuellstand/rot"), "cps1_kugelfuellstand_rot");
+/// ```
+pub fn sensor_id(system_id: &str, msm_id: &str) -> String {
+    format!("{system_id}_{msm_id}").replace('/', "_")
+}
+
+/// A measurement offered by a system, without its value
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct MeasurementInfo {
+    pub msm_id: String,
+    pub name: Option<String>,
+    pub unit: Option<String>,
+}
+
+/// A system of the MHubX with its measurements
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct System {
+    pub system_id: String,
+    pub measurements: Vec<MeasurementInfo>,
+}
+
+impl System {
+    /// Groups the measurements by system, sorted by the ids
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// # use common::mhubx::{Measurement, System};
+    /// let measurements: Vec<Measurement> = serde_json::from_str(
+    ///     r#"[{"system_id": "cps2", "msm_id": "b"}, {"system_id": "cps1", "msm_id": "a"}, {"system_id": "cps2", "msm_id": "a"}]"#,
+    /// )
+    /// .unwrap();
+    /// let systems = System::from_measurements(measurements);
+    ///
+    /// assert_eq!(systems.len(), 2);
+    /// assert_eq!(systems[1].system_id, "cps2");
+    /// assert_eq!(systems[1].measurements[0].msm_id, "a");
+    /// ```
+    pub fn from_measurements(measurements: Vec<Measurement>) -> Vec<Self> {
+        let mut systems: BTreeMap<String, BTreeMap<String, MeasurementInfo>> = BTreeMap::new();
+        for measurement in measurements {
+            systems.entry(measurement.system_id).or_default().insert(
+                measurement.msm_id.clone(),
+                MeasurementInfo {
+                    msm_id: measurement.msm_id,
+                    name: measurement.name,
+                    unit: measurement.unit,
+                },
+            );
+        }
+        systems
+            .into_iter()
+            .map(|(system_id, measurements)| System {
+                system_id,
+                measurements: measurements.into_values().collect(),
+            })
+            .collect()
+    }
+}
+
+/// A client of the MHubX REST API, it is meant to be created once and shared
+pub struct MhubxClient {
+    http: reqwest::Client,
+    config: MhubxConfig,
+}
+
+impl MhubxClient {
+    pub fn new(config: MhubxConfig) -> Result<Self, MhubxError> {
+        let http = reqwest::Client::builder()
+            .timeout(config.timeout)
+            .connect_timeout(config.timeout)
+            .build()
+            .map_err(|err| MhubxError::Connection(err.to_string()))?;
+        Ok(MhubxClient { http, config })
+    }
+
+    /// Returns the measurements of the query
+    pub async fn measurements(
+        &self,
+        query: &MeasurementQuery,
+    ) -> Result<Vec<Measurement>, MhubxError> {
+        let mut measurements: Vec<Measurement> = self.call(&query.params()).await?;
+        measurements.retain(|measurement| query.matches(measurement));
+        Ok(measurements)
+    }
+
+    /// Returns the alarms of the query
+    pub async fn alarms(&self, query: &AlarmQuery) -> Result<Vec<Alarm>, MhubxError> {
+        let mut alarms: Vec<Alarm> = self.call(&query.params()).await?;
+        alarms.retain(|alarm| query.matches(alarm));
+        Ok(alarms)
+    }
+
+    /// Returns the systems of the MHubX and the measurements they offer
+    pub async fn systems(&self) -> Result<Vec<System>, MhubxError> {
+        Ok(System::from_measurements(
+            self.measurements(&MeasurementQuery::default()).await?,
+        ))
+    }
+
+    /// calls a function of the interface page, transient errors are retried
+    pub(crate) async fn call<T: DeserializeOwned>(
+        &self,
+        params: &[(&'static str, String)],
+    ) -> Result<Vec<T>, MhubxError> {
+        let mut attempt = 0;
+        loop {
+            match self.call_once(params).await {
+                Err(err) if err.is_transient() && attempt < self.config.retries => {
+                    tokio::time::sleep(retry_delay(attempt)).await;
+                    attempt += 1;
+                }
+                result => return result,
+            }
+        }
+    }
+
+    async fn call_once<T: DeserializeOwned>(
+        &self,
+        params: &[(&'static str, String)],
+    ) -> Result<Vec<T>, MhubxError> {
+        let response = self
+            .http
+            .get(&self.config.endpoint)
+            .query(&[("page", INTERFACE_PAGE)])
+            .query(params)
+            .basic_auth(&self.config.username, Some(&self.config.password))
+            .send()
+            .await?;
+        let status = response.status();
+        if !status.is_success() {
+            let body = response.text().await.unwrap_or_default();
+            return Err(MhubxError::Status(status.as_u16(), body));
+        }
+        let body = response.bytes().await?;
+        match serde_json::from_slice(&body) {
+            Ok(Envelope::List(items) | Envelope::Data { data: items }) => Ok(items),
+            Ok(Envelope::Error { error }) => Err(MhubxError::Upstream(error)),
+            Err(err) => Err(MhubxError::Decode(err.to_string())),
+        }
+    }
+}
@@ -0,0 +1,223 @@
+//! # common::resample
+//!
+//! `common::resample` aligns the values of one or more sensors onto a shared time grid,
+//! so that series with different sampling times can be compared row by row.
+//!
+
+use chrono::{DateTime, Duration, Utc};
+use serde::{Deserialize, Serialize};
+
+use crate::SensorValue;
+
+/// Strategy to fill a grid point which has no value of its own
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Fill {
+    /// repeat the last known value
+    #[default]
+    Previous,
+    /// interpolate linearly between the surrounding values.
+    /// Falls back to `Previous` for values which are not numeric
+    Linear,
+    /// leave the grid point empty
+    Null,
+}
+
+/// Shape of a resampled result
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Layout {
+    /// one row per sensor and grid point
+    #[default]
+    Long,
+    /// one row per grid point with a column per sensor
+    Wide,
+}
+
+/// A single resampled value of a sensor, used for the long layout
+#[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
+pub struct LongRow {
+    pub sensor: String,
+    pub timestamp: DateTime<Utc>,
+    pub value: Option<String>,
+}
+
+/// A single grid point with one value per sensor, used for the wide layout
+#[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
+pub struct WideRow {
+    pub timestamp: DateTime<Utc>,
+    pub values: Vec<Option<String>>,
+}
+
+/// A time-aligned table, the values of each row are in the same order as `sensors`
+#[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
+pub struct WideTable {
+    pub sensors: Vec<String>,
+    pub rows: Vec<WideRow>,
+}
+
+/// Returns the grid points between from and to (both inclusive)
+///
+/// # Example
+///
+/// ```
+/// # use common::resample::grid;
+/// let from = chrono::Utc::now();
+/// let to = from + chrono::Duration::minutes(1);
+///
+/// assert_eq!(grid(from, to, chrono::Duration::seconds(20)).len(), 4);
+/// ```
+pub fn grid(from: DateTime<Utc>, to: DateTime<Utc>, interval: Duration) -> Vec<DateTime<Utc>> {
+    let mut points = Vec::new();
+    if interval <= Duration::zero() {
+        return points;
+    }
+    let mut point = from;
+    while point <= to {
+        points.push(point);
+        point += interval;
+    }
+    points
+}
+
+/// Resamples a series of values onto the given grid.
+/// `values` needs to be sorted by time ascending.
+///
+/// A grid point takes the latest value since the previous grid point.
+/// If there is none, the point is filled according to `fill`.
+///
+/// # Example
+///
+/// ```
+/// # use common::resample::{resample, Fill};
+/// let start = chrono::Utc::now();
+/// let grid = vec![start, start + chrono::Duration::seconds(10), start + chrono::Duration::seconds(20)];
+/// let values = vec![
+///     (start, "0".to_owned()),
+///     (start + chrono::Duration::seconds(20), "10".to_owned()),
+/// ];
+///
+/// let linear = resample(&values, &grid, Fill::Linear);
+/// assert_eq!(linear[1], Some("5".to_owned()));
+///
+/// let previous = resample(&values, &grid, Fill::Previous);
+/// assert_eq!(previous[1], Some("0".to_owned()));
+///
+/// let null = resample(&values, &grid, Fill::Null);
+/// assert_eq!(null[1], None);
+/// ```
+pub fn resample(
+    values: &[(DateTime<Utc>, String)],
+    grid: &[DateTime<Utc>],
+    fill: Fill,
+) -> Vec<Option<String>> {
+    let mut result = Vec::with_capacity(grid.len());
+    // index of the first value after the current grid point
+    let mut next = 0;
+    // the first bucket reaches back one grid step
+    let mut bucket_start = match grid {
+        [first, second, ..] => *first - (*second - *first),
+        [first] => *first,
+        [] => return result,
+    };
+
+    for point in grid {
+        while next < values.len() && values[next].0 <= *point {
+            next += 1;
+        }
+        let previous = next.checked_sub(1).map(|i| &values[i]);
+        let in_bucket = previous.is_some_and(|(time, _)| *time > bucket_start || time == point);
+        bucket_start = *point;
+
+        if in_bucket {
+            result.push(previous.map(|(_, value)| value.clone()));
+            continue;
+        }
+
+        result.push(match fill {
+            Fill::Null => None,
+            Fill::Previous => previous.map(|(_, value)| value.clone()),
+            Fill::Linear => match (previous, values.get(next)) {
+                (Some(before), Some(after)) => {
+                    interpolate(before, after, *point).or_else(|| Some(before.1.clone()))
+                }
+                _ => None,
+            },
+        });
+    }
+
+    result
+}
+
+/// Resamples the values of several sensors and returns them as a long list of rows
+pub fn long(
+    series: &[(String, Vec<SensorValue>)],
+    grid: &[DateTime<Utc>],
+    fill: Fill,
+) -> Vec<LongRow> {
+    series
+        .iter()
+        .flat_map(|(sensor, values)| {
+            resample(&pairs(values), grid, fill)
+                .into_iter()
+                .zip(grid)
+                .map(move |(value, timestamp)| LongRow {
+                    sensor: sensor.clone(),
+                    timestamp: *timestamp,
+                    value,
+                })
+        })
+        .collect()
+}
+
+/// Resamples the values of several sensors and returns them as a time-aligned table
+pub fn wide(
+    series: &[(String, Vec<SensorValue>)],
+    grid: &[DateTime<Utc>],
+    fill: Fill,
+) -> WideTable {
+    let columns: Vec<Vec<Option<String>>> = series
+        .iter()
+        .map(|(_, values)| resample(&pairs(values), grid, fill))
+        .collect();
+
+    let rows = grid
+        .iter()
+        .enumerate()
+        .map(|(i, timestamp)| WideRow {
+            timestamp: *timestamp,
+            values: columns.iter().map(|column| column[i].clone()).collect(),
+        })
+        .collect();
+
+    WideTable {
+        sensors: series.iter().map(|(sensor, _)| sensor.clone()).collect(),
+        rows,
+    }
+}
+
+/// converts sensor values into sorted (time, value) pairs
+fn pairs(values: &[SensorValue]) -> Vec<(DateTime<Utc>, String)> {
+    let mut pairs: Vec<(DateTime<Utc>, String)> = values
+        .iter()
+        .map(|value| (value.get_timestamp().0, value.get_value().to_owned()))
+        .collect();
+    pairs.sort_by_key(|(time, _)| *time);
+    pairs
+}
+
+/// interpolates linearly between two numeric values, returns None if one of them is not a number
+fn interpolate(
+    before: &(DateTime<Utc>, String),
+    after: &(DateTime<Utc>, String),
+    point: DateTime<Utc>,
+) -> Option<String> {
+    let a: f64 = before.1.trim().parse().ok()?;
+    let b: f64 = after.1.trim().parse().ok()?;
+    let span = (after.0 - before.0).num_milliseconds() as f64;
+    if span <= 0.0 {
+        return Some(before.1.clone());
+    }
+    let ratio = (point - before.0).num_milliseconds() as f64 / span;
+    Some((a + (b - a) * ratio).to_string())
+}
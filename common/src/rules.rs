@@ -0,0 +1,495 @@
+//! # common::rules
+//!
+//! `common::rules` evaluates the alarm rules of a sensor as its values are ingested.
+//! A rule raises a local alarm, see `common::alarm`, once its condition is met for
+//! `delay_on_seconds` and clears it once the condition is no longer met for `delay_off_seconds`.
+//! Limits clear with hysteresis: a high limit of 80 with a hysteresis of 5 raises above 80
+//! and clears below 75. Stale data rules are evaluated by `check_stale` on a schedule,
+//! as a missing value is never ingested. Delays are measured with the time of the values.
+//!
+//! Raised alarms are `active` until they are acknowledged by an operator and `cleared` once
+//! the condition of the rule is no longer met.
+//!
+//! # Example
+//!
+//! ```no_run
+//! # use common::{rules, SensorValue};
+//! # use surrealdb::sql::Thing;
+//! # async fn run(db: &surrealdb::Surreal<surrealdb::engine::remote::ws::Client>) {
+//! let value = SensorValue::create(db, "93".to_owned(), Thing::from(("sensor", "presse_druck")))
+//!     .await
+//!     .unwrap()
+//!     .unwrap();
+//! for event in rules::evaluate(db, &value).await.unwrap() {
+//!     println!("{event:?}");
+//! }
+//! # }
+//! ```
+
+use chrono::{DateTime, TimeDelta, Utc};
+use serde::{Deserialize, Serialize};
+use surrealdb::{
+    engine::remote::ws::Client,
+    sql::{Datetime, Thing},
+    Surreal,
+};
+
+use crate::{
+    alarm::{AlarmHistory, AlarmState, HistoryAction, LocalAlarm},
+    Sensor, SensorValue,
+};
+
+type DB = Surreal<Client>;
+
+/// upper bound of the delays and the maximum age of a rule, 30 days
+pub const MAX_DELAY_SECONDS: u64 = 30 * 24 * 60 * 60;
+
+/// a duration of seconds, saturating at the largest duration
+fn seconds(seconds: u64) -> TimeDelta {
+    i64::try_from(seconds)
+        .ok()
+        .and_then(TimeDelta::try_seconds)
+        .unwrap_or(TimeDelta::MAX)
+}
+
+/// The condition of a alarm rule
+///
+/// # Example
+///
+/// ```
+/// # use common::rules::RuleCondition;
+/// let condition: RuleCondition =
+///     serde_json::from_str(r#"{"kind": "high", "limit": 80, "hysteresis": 5}"#).unwrap();
+///
+/// assert_eq!(condition.is_met("81", None, false), Some(true));
+/// // a active alarm clears below the limit minus the hysteresis
+/// assert_eq!(condition.is_met("78", None, true), Some(true));
+/// assert_eq!(condition.is_met("74", None, true), Some(false));
+/// assert_eq!(condition.is_met("offline", None, false), None);
+///
+/// let stale = RuleCondition::Stale { max_age_seconds: 10_u64.pow(16) };
+/// assert!(stale.validate().is_err());
+/// ```
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum RuleCondition {
+    /// the value is above the limit
+    High {
+        limit: f64,
+        #[serde(default)]
+        hysteresis: f64,
+    },
+    /// the value is below the limit
+    Low {
+        limit: f64,
+        #[serde(default)]
+        hysteresis: f64,
+    },
+    /// the value changes faster than the limit per second, in either direction
+    RateOfChange {
+        max_per_second: f64,
+        #[serde(default)]
+        hysteresis: f64,
+    },
+    /// the sensor sent no value for the maximum age
+    Stale { max_age_seconds: u64 },
+    /// the value equals the state, e.g. a fault state of `pressenstatus`
+    StateEquals { value: String },
+}
+
+impl RuleCondition {
+    /// Returns an error message if the condition can never be evaluated
+    pub fn validate(&self) -> Result<(), String> {
+        let positive = |name: &str, value: f64| match value.is_finite() && value >= 0.0 {
+            true => Ok(()),
+            false => Err(format!("{name} needs to be a positive number")),
+        };
+        match self {
+            RuleCondition::High { limit, hysteresis }
+            | RuleCondition::Low { limit, hysteresis } => match limit.is_finite() {
+                true => positive("hysteresis", *hysteresis),
+                false => Err("limit needs to be a number".to_owned()),
+            },
+            RuleCondition::RateOfChange {
+                max_per_second,
+                hysteresis,
+            } => {
+                positive("max_per_second", *max_per_second)?;
+                positive("hysteresis", *hysteresis)
+            }
+            RuleCondition::Stale { max_age_seconds } => {
+                match (1..=MAX_DELAY_SECONDS).contains(max_age_seconds) {
+                    true => Ok(()),
+                    false => Err(format!(
+                        "max_age_seconds needs to be between 1 and {MAX_DELAY_SECONDS}"
+                    )),
+                }
+            }
+            RuleCondition::StateEquals { value } => match value.trim().is_empty() {
+                true => Err("value must not be empty".to_owned()),
+                false => Ok(()),
+            },
+        }
+    }
+
+    /// Returns whether the condition is met by a ingested value, None if the value can't be
+    /// evaluated, e.g. a text for a limit. Previous is the value before and its time,
+    /// active whether the alarm of the rule is raised, which applies the hysteresis
+    pub fn is_met(
+        &self,
+        value: &str,
+        previous: Option<(&str, DateTime<Utc>, DateTime<Utc>)>,
+        active: bool,
+    ) -> Option<bool> {
+        let hysteresis = |hysteresis: f64| if active { hysteresis } else { 0.0 };
+        match self {
+            RuleCondition::High {
+                limit,
+                hysteresis: h,
+            } => Some(number(value)? > limit - hysteresis(*h)),
+            RuleCondition::Low {
+                limit,
+                hysteresis: h,
+            } => Some(number(value)? < limit + hysteresis(*h)),
+            RuleCondition::RateOfChange {
+                max_per_second,
+                hysteresis: h,
+            } => {
+                let (previous, previous_at, at) = previous?;
+                let seconds = (at - previous_at).num_milliseconds() as f64 / 1000.0;
+                if seconds <= 0.0 {
+                    return None;
+                }
+                let rate = (number(value)? - number(previous)?).abs() / seconds;
+                Some(rate > max_per_second - hysteresis(*h))
+            }
+            // a value was just ingested
+            RuleCondition::Stale { .. } => Some(false),
+            RuleCondition::StateEquals { value: state } => Some(value.trim() == state.trim()),
+        }
+    }
+}
+
+/// reads a value as number, flags are 1 and 0
+fn number(value: &str) -> Option<f64> {
+    match value.trim() {
+        "true" => Some(1.0),
+        "false" => Some(0.0),
+        value => value.parse().ok().filter(|value: &f64| value.is_finite()),
+    }
+}
+
+fn enabled() -> bool {
+    true
+}
+
+/// A alarm rule of a sensor
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct AlarmRule {
+    pub id: Thing,
+    pub sensor: Thing,
+    pub name: String,
+    pub severity: u32,
+    pub condition: RuleCondition,
+    #[serde(default)]
+    pub delay_on_seconds: u64,
+    #[serde(default)]
+    pub delay_off_seconds: u64,
+    #[serde(default = "enabled")]
+    pub enabled: bool,
+    /// since when the condition is met, while the alarm waits for the delay on
+    #[serde(default)]
+    pub pending_since: Option<Datetime>,
+    /// since when the condition is no longer met, while the alarm waits for the delay off
+    #[serde(default)]
+    pub clearing_since: Option<Datetime>,
+}
+
+/// A change of a alarm caused by a rule
+#[derive(Debug, Clone)]
+pub enum AlarmEvent {
+    Raised(LocalAlarm),
+    Cleared(LocalAlarm),
+}
+
+/// What a rule does with its alarm
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RuleAction {
+    Raise,
+    Clear,
+}
+
+/// The outcome of a rule at a time: the action on its alarm and the delays it waits for
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Transition {
+    pub action: Option<RuleAction>,
+    pub pending_since: Option<Datetime>,
+    pub clearing_since: Option<Datetime>,
+}
+
+impl AlarmRule {
+    /// Returns the enabled rules of a sensor
+    pub async fn get_by_sensor(db: &DB, sensor: &Thing) -> Result<Vec<Self>, surrealdb::Error> {
+        db.query("SELECT * FROM alarm_rule WHERE sensor = $sensor AND enabled = true")
+            .bind(("sensor", sensor))
+            .await?
+            .take(0)
+    }
+
+    /// Returns the raised alarm of the rule, which is not cleared yet
+    pub async fn open_alarm(&self, db: &DB) -> Result<Option<LocalAlarm>, surrealdb::Error> {
+        db.query("SELECT * FROM alarm WHERE rule = $rule AND state != \"cleared\" ORDER BY raised_at DESC LIMIT 1")
+            .bind(("rule", &self.id))
+            .await?
+            .take(0)
+    }
+
+    /// Returns what the rule does at the time, given whether its condition is met and whether
+    /// its alarm is open. The alarm is raised once the condition is met for the delay on and
+    /// cleared once it is no longer met for the delay off, meanwhile the rule waits
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// # use common::rules::{AlarmRule, RuleAction, RuleCondition, Transition};
+    /// # use surrealdb::sql::{Datetime, Thing};
+    /// let at = |time: &str| format!("2023-05-02T08:{time}Z").parse::<chrono::DateTime<chrono::Utc>>().unwrap();
+    /// let mut rule = AlarmRule {
+    ///     id: Thing::from(("alarm_rule", "druck_hoch")),
+    ///     sensor: Thing::from(("sensor", "presse_druck")),
+    ///     name: "Druck zu hoch".to_owned(),
+    ///     severity: 3,
+    ///     condition: RuleCondition::High { limit: 80.0, hysteresis: 5.0 },
+    ///     delay_on_seconds: 30,
+    ///     delay_off_seconds: 60,
+    ///     enabled: true,
+    ///     pending_since: None,
+    ///     clearing_since: None,
+    /// };
+    ///
+    /// // the condition is met, the rule waits for the delay on
+    /// let pending = rule.transition(true, false, at("00:00"));
+    /// assert_eq!(pending.action, None);
+    /// assert_eq!(pending.pending_since, Some(Datetime(at("00:00"))));
+    /// rule.pending_since = pending.pending_since;
+    /// assert_eq!(rule.transition(true, false, at("00:29")).action, None);
+    /// let raised = rule.transition(true, false, at("00:30"));
+    /// assert_eq!(raised, Transition { action: Some(RuleAction::Raise), ..Default::default() });
+    /// // the condition is no longer met before the delay on, the rule starts over
+    /// assert_eq!(rule.transition(false, false, at("00:10")), Transition::default());
+    /// rule.pending_since = None;
+    ///
+    /// // 78 is still above the limit minus the hysteresis, the alarm stays
+    /// let met = rule.condition.is_met("78", None, true).unwrap();
+    /// assert_eq!(rule.transition(met, true, at("01:00")), Transition::default());
+    /// // 74 is below, the rule waits for the delay off
+    /// let met = rule.condition.is_met("74", None, true).unwrap();
+    /// let clearing = rule.transition(met, true, at("01:00"));
+    /// assert_eq!(clearing.clearing_since, Some(Datetime(at("01:00"))));
+    /// rule.clearing_since = clearing.clearing_since;
+    /// assert_eq!(rule.transition(false, true, at("01:59")).action, None);
+    /// assert_eq!(rule.transition(false, true, at("02:00")).action, Some(RuleAction::Clear));
+    /// ```
+    ///
+    /// Values of rate of change rules without a time gap to the previous value are not
+    /// evaluated, and a ingested value clears a stale data alarm
+    ///
+    /// ```
+    /// # use common::rules::{AlarmRule, RuleAction, RuleCondition};
+    /// # use surrealdb::sql::Thing;
+    /// let at = |time: &str| format!("2023-05-02T08:{time}Z").parse::<chrono::DateTime<chrono::Utc>>().unwrap();
+    /// let rate = RuleCondition::RateOfChange { max_per_second: 2.0, hysteresis: 0.0 };
+    /// assert_eq!(rate.is_met("90", Some(("10", at("00:00"), at("00:00"))), false), None);
+    /// assert_eq!(rate.is_met("90", Some(("10", at("00:10"), at("00:00"))), false), None);
+    /// assert_eq!(rate.is_met("90", Some(("10", at("00:00"), at("00:10"))), false), Some(true));
+    ///
+    /// let stale = AlarmRule {
+    ///     id: Thing::from(("alarm_rule", "druck_veraltet")),
+    ///     sensor: Thing::from(("sensor", "presse_druck")),
+    ///     name: "Keine Druckwerte".to_owned(),
+    ///     severity: 2,
+    ///     condition: RuleCondition::Stale { max_age_seconds: 300 },
+    ///     delay_on_seconds: 0,
+    ///     delay_off_seconds: 0,
+    ///     enabled: true,
+    ///     pending_since: None,
+    ///     clearing_since: None,
+    /// };
+    /// let met = stale.condition.is_met("93", None, true).unwrap();
+    /// assert_eq!(stale.transition(met, true, at("00:00")).action, Some(RuleAction::Clear));
+    /// ```
+    pub fn transition(&self, met: bool, open: bool, at: DateTime<Utc>) -> Transition {
+        let elapsed = |since: &Option<Datetime>| since.as_ref().map_or(at, |since| since.0);
+        match (met, open) {
+            (true, false) => {
+                let since = elapsed(&self.pending_since);
+                match at - since >= seconds(self.delay_on_seconds) {
+                    true => Transition {
+                        action: Some(RuleAction::Raise),
+                        ..Default::default()
+                    },
+                    false => Transition {
+                        pending_since: Some(Datetime(since)),
+                        ..Default::default()
+                    },
+                }
+            }
+            (false, true) => {
+                let since = elapsed(&self.clearing_since);
+                match at - since >= seconds(self.delay_off_seconds) {
+                    true => Transition {
+                        action: Some(RuleAction::Clear),
+                        ..Default::default()
+                    },
+                    false => Transition {
+                        clearing_since: Some(Datetime(since)),
+                        ..Default::default()
+                    },
+                }
+            }
+            (true, true) | (false, false) => Transition::default(),
+        }
+    }
+
+    /// Moves the rule on with the condition at the time, raising or clearing its alarm
+    /// once the delay passed
+    async fn advance(
+        &self,
+        db: &DB,
+        met: bool,
+        at: DateTime<Utc>,
+        value: &str,
+        open: Option<LocalAlarm>,
+    ) -> Result<Option<AlarmEvent>, surrealdb::Error> {
+        let transition = self.transition(met, open.is_some(), at);
+        let event = match (transition.action, open) {
+            (Some(RuleAction::Raise), _) => match self.raise(db, at, value).await? {
+                Some(alarm) => {
+                    AlarmHistory::record(db, &alarm, HistoryAction::Raised, None, None).await?;
+                    Some(AlarmEvent::Raised(alarm))
+                }
+                None => None,
+            },
+            (Some(RuleAction::Clear), Some(alarm)) => {
+                clear(db, &alarm.id, at).await?.map(AlarmEvent::Cleared)
+            }
+            _ => None,
+        };
+
+        if transition.pending_since != self.pending_since
+            || transition.clearing_since != self.clearing_since
+        {
+            db.query(
+                "UPDATE $rule SET pending_since = $pending_since, clearing_since = $clearing_since",
+            )
+            .bind(("rule", &self.id))
+            .bind(("pending_since", transition.pending_since))
+            .bind(("clearing_since", transition.clearing_since))
+            .await?;
+        }
+        Ok(event)
+    }
+
+    /// raises the alarm of the rule in the station of the sensor
+    async fn raise(
+        &self,
+        db: &DB,
+        at: DateTime<Utc>,
+        value: &str,
+    ) -> Result<Option<LocalAlarm>, surrealdb::Error> {
+        let station = Sensor::get(db, self.sensor.id.to_raw())
+            .await?
+            .map(|sensor| sensor.get_station().clone());
+        db.query("CREATE alarm SET rule = $rule, station = $station, sensor = $sensor, severity = $severity, message = $message, state = $state, raised_at = $raised_at")
+            .bind(("rule", &self.id))
+            .bind(("station", station))
+            .bind(("sensor", &self.sensor))
+            .bind(("severity", self.severity))
+            .bind(("message", format!("{}: {}", self.name, value.trim())))
+            .bind(("state", AlarmState::Active))
+            .bind(("raised_at", Datetime(at)))
+            .await?
+            .take(0)
+    }
+}
+
+/// clears a alarm at the time and records it in its history
+pub async fn clear(
+    db: &DB,
+    alarm: &Thing,
+    at: DateTime<Utc>,
+) -> Result<Option<LocalAlarm>, surrealdb::Error> {
+    let cleared: Option<LocalAlarm> = db
+        .query("UPDATE $alarm SET state = $state, cleared_at = $cleared_at WHERE state != $state")
+        .bind(("alarm", alarm))
+        .bind(("state", AlarmState::Cleared))
+        .bind(("cleared_at", Datetime(at)))
+        .await?
+        .take(0)?;
+    if let Some(cleared) = &cleared {
+        AlarmHistory::record(db, cleared, HistoryAction::Cleared, None, None).await?;
+    }
+    Ok(cleared)
+}
+
+/// Evaluates the rules of the sensor of a ingested value, returns the raised and cleared alarms
+pub async fn evaluate(db: &DB, value: &SensorValue) -> Result<Vec<AlarmEvent>, surrealdb::Error> {
+    let rules = AlarmRule::get_by_sensor(db, value.get_sensor()).await?;
+    if rules.is_empty() {
+        return Ok(Vec::new());
+    }
+    let at = value.get_timestamp().0;
+    let previous = match rules
+        .iter()
+        .any(|rule| matches!(rule.condition, RuleCondition::RateOfChange { .. }))
+    {
+        true => SensorValue::get_latest_before(db, value.get_sensor().id.to_raw(), at).await?,
+        false => None,
+    };
+    let previous = previous
+        .as_ref()
+        .map(|previous| (previous.get_value(), previous.get_timestamp().0, at));
+
+    let mut events = Vec::new();
+    for rule in rules {
+        let open = rule.open_alarm(db).await?;
+        let Some(met) = rule
+            .condition
+            .is_met(value.get_value(), previous, open.is_some())
+        else {
+            continue;
+        };
+        if let Some(event) = rule.advance(db, met, at, value.get_value(), open).await? {
+            events.push(event);
+        }
+    }
+    Ok(events)
+}
+
+/// Evaluates the stale data rules at the current time, returns the raised and cleared alarms
+pub async fn check_stale(db: &DB) -> Result<Vec<AlarmEvent>, surrealdb::Error> {
+    let rules: Vec<AlarmRule> = db
+        .query("SELECT * FROM alarm_rule WHERE enabled = true AND condition.kind = \"stale\"")
+        .await?
+        .take(0)?;
+    let now = Utc::now();
+    let mut events = Vec::new();
+    for rule in rules {
+        let RuleCondition::Stale { max_age_seconds } = rule.condition else {
+            continue;
+        };
+        let latest = SensorValue::get_latest_before(db, rule.sensor.id.to_raw(), now).await?;
+        let met = latest
+            .as_ref()
+            .is_none_or(|latest| now - latest.get_timestamp().0 > seconds(max_age_seconds));
+        let open = rule.open_alarm(db).await?;
+        let value = match &latest {
+            Some(latest) => format!("last value at {}", latest.get_timestamp().0.to_rfc3339()),
+            None => "no value".to_owned(),
+        };
+        if let Some(event) = rule.advance(db, met, now, &value, open).await? {
+            events.push(event);
+        }
+    }
+    Ok(events)
+}
@@ -0,0 +1,195 @@
+//! # common::schedule
+//!
+//! `common::schedule` holds the on-call and shift schedules alarms are escalated along.
+//! A schedule repeats its shifts every week in the timezone of the schedule, a shift may end
+//! on the next day. Overrides replace the shifts for a while, e.g. during a vacation.
+//! The users on call are those of a override at the time, otherwise those of all shifts
+//! covering the time.
+//!
+//! # Example
+//!
+//! ```
+//! # use common::schedule::{Schedule, ScheduleOverride, Shift};
+//! # use surrealdb::sql::Thing;
+//! let weekdays = ["mon", "tue", "wed", "thu", "fri"].map(|day| day.parse().unwrap()).to_vec();
+//! let schedule = Schedule {
+//!     id: Thing::from(("schedule", "schichtleitung")),
+//!     name: "Schichtleitung Presswerk".to_owned(),
+//!     timezone: "Europe/Berlin".to_owned(),
+//!     shifts: vec![
+//!         Shift {
+//!             days: weekdays.clone(),
+//!             start: "06:00".parse().unwrap(),
+//!             end: "14:00".parse().unwrap(),
+//!             users: vec![Thing::from(("user", "anna"))],
+//!         },
+//!         Shift {
+//!             days: weekdays,
+//!             start: "22:00".parse().unwrap(),
+//!             end: "06:00".parse().unwrap(),
+//!             users: vec![Thing::from(("user", "hugo"))],
+//!         },
+//!     ],
+//!     overrides: vec![ScheduleOverride {
+//!         from: "2023-05-08T04:00:00Z".parse::<chrono::DateTime<chrono::Utc>>().unwrap().into(),
+//!         to: "2023-05-13T04:00:00Z".parse::<chrono::DateTime<chrono::Utc>>().unwrap().into(),
+//!         users: vec![Thing::from(("user", "lucy"))],
+//!     }],
+//! };
+//!
+//! // Tuesday 10:00 and Saturday 05:00 in Berlin, the night shift of friday lasts until saturday
+//! let tuesday = "2023-05-02T08:00:00Z".parse().unwrap();
+//! assert_eq!(schedule.on_call(tuesday), vec![Thing::from(("user", "anna"))]);
+//! let saturday = "2023-05-06T03:00:00Z".parse().unwrap();
+//! assert_eq!(schedule.on_call(saturday), vec![Thing::from(("user", "hugo"))]);
+//! let sunday = "2023-05-07T10:00:00Z".parse().unwrap();
+//! assert!(schedule.on_call(sunday).is_empty());
+//!
+//! let vacation = "2023-05-09T08:00:00Z".parse().unwrap();
+//! assert_eq!(schedule.on_call(vacation), vec![Thing::from(("user", "lucy"))]);
+//! ```
+
+use chrono::{DateTime, Datelike, NaiveDateTime, NaiveTime, Utc, Weekday};
+use serde::{Deserialize, Serialize};
+use surrealdb::{
+    engine::remote::ws::Client,
+    sql::{Datetime, Thing},
+    Surreal,
+};
+
+type DB = Surreal<Client>;
+
+/// A on-call or shift schedule
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Schedule {
+    pub id: Thing,
+    pub name: String,
+    /// the IANA timezone of the shift times, e.g. `Europe/Berlin`
+    pub timezone: String,
+    #[serde(default)]
+    pub shifts: Vec<Shift>,
+    #[serde(default)]
+    pub overrides: Vec<ScheduleOverride>,
+}
+
+/// A shift repeating every week, ending on the next day if end is not after start
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Shift {
+    /// the days the shift starts on, every day if empty
+    #[serde(default)]
+    pub days: Vec<Weekday>,
+    pub start: NaiveTime,
+    pub end: NaiveTime,
+    pub users: Vec<Thing>,
+}
+
+impl Shift {
+    /// Returns true if the shift covers the local time
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// # use common::schedule::Shift;
+    /// let night = Shift {
+    ///     days: vec![chrono::Weekday::Fri],
+    ///     start: "22:00".parse().unwrap(),
+    ///     end: "06:00".parse().unwrap(),
+    ///     users: vec![surrealdb::sql::Thing::from(("user", "hugo"))],
+    /// };
+    /// let at = |time: &str| chrono::NaiveDateTime::parse_from_str(time, "%Y-%m-%d %H:%M").unwrap();
+    ///
+    /// assert!(night.covers(at("2023-05-05 23:00")));
+    /// assert!(night.covers(at("2023-05-06 05:59")));
+    /// assert!(!night.covers(at("2023-05-06 06:00")));
+    /// assert!(!night.covers(at("2023-05-04 23:00")));
+    /// ```
+    pub fn covers(&self, local: NaiveDateTime) -> bool {
+        let time = local.time();
+        let day = local.weekday();
+        let starts_on = |day: Weekday| self.days.is_empty() || self.days.contains(&day);
+        if self.start < self.end {
+            starts_on(day) && self.start <= time && time < self.end
+        } else {
+            starts_on(day) && time >= self.start || starts_on(day.pred()) && time < self.end
+        }
+    }
+}
+
+/// Replaces the shifts of a schedule between from and to
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ScheduleOverride {
+    pub from: Datetime,
+    pub to: Datetime,
+    pub users: Vec<Thing>,
+}
+
+impl Schedule {
+    /// Returns the timezone of the schedule, UTC if it is unknown
+    pub fn tz(&self) -> chrono_tz::Tz {
+        self.timezone.parse().unwrap_or(chrono_tz::UTC)
+    }
+
+    /// Returns the users on call at the time, without duplicates
+    pub fn on_call(&self, at: DateTime<Utc>) -> Vec<Thing> {
+        if let Some(replaced) = self
+            .overrides
+            .iter()
+            .find(|replaced| replaced.from.0 <= at && at < replaced.to.0)
+        {
+            return replaced.users.clone();
+        }
+        let local = at.with_timezone(&self.tz()).naive_local();
+        let mut users = Vec::new();
+        for shift in self.shifts.iter().filter(|shift| shift.covers(local)) {
+            for user in &shift.users {
+                if !users.contains(user) {
+                    users.push(user.clone());
+                }
+            }
+        }
+        users
+    }
+
+    /// Returns a message for the first invalid field
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// # use common::schedule::Schedule;
+    /// let mut schedule = Schedule {
+    ///     id: surrealdb::sql::Thing::from(("schedule", "instandhaltung")),
+    ///     name: "Instandhaltung".to_owned(),
+    ///     timezone: "Europe/Berlin".to_owned(),
+    ///     shifts: Vec::new(),
+    ///     overrides: Vec::new(),
+    /// };
+    /// assert_eq!(schedule.validate(), Ok(()));
+    ///
+    /// schedule.timezone = "Mitteleuropa".to_owned();
+    /// assert!(schedule.validate().is_err());
+    /// ```
+    pub fn validate(&self) -> Result<(), String> {
+        if self.name.trim().is_empty() {
+            return Err("name can't be empty".to_owned());
+        }
+        if self.timezone.parse::<chrono_tz::Tz>().is_err() {
+            return Err(format!("Unknown timezone: {}", self.timezone));
+        }
+        if self.shifts.iter().any(|shift| shift.users.is_empty()) {
+            return Err("shifts need at least one user".to_owned());
+        }
+        if self
+            .overrides
+            .iter()
+            .any(|replaced| replaced.from >= replaced.to)
+        {
+            return Err("overrides need to start before they end".to_owned());
+        }
+        Ok(())
+    }
+
+    /// Returns a schedule by its id
+    pub async fn get(db: &DB, id: &str) -> Result<Option<Self>, surrealdb::Error> {
+        db.select(Thing::from(("schedule", id))).await
+    }
+}
@@ -0,0 +1,234 @@
+//! # mhubx_mock
+//!
+//! `mhubx_mock` is a stand-in for the MHubX REST API, to develop and test without the plant.
+//! It answers `GET {postfix}?page=Logic.Interface&name=...` with basic auth like the MHubX,
+//! implementing `getMeasurement` and `getAlarms` on fixture data.
+//! Calls can be delayed and failed to test timeouts and retries.
+//!
+//! # Example
+//!
+//! ```no_run
+//! # use mhubx_mock::MockConfig;
+//! # async fn run() -> std::io::Result<()> {
+//! let listener = std::net::TcpListener::bind("127.0.0.1:0")?;
+//! let address = listener.local_addr()?;
+//! actix_web::rt::spawn(mhubx_mock::server(listener, MockConfig::default())?);
+//! // the MHubX client connects to http://{address}/mhubx-cc/module/juwi/action
+//! # Ok(())
+//! # }
+//! ```
+
+use std::{net::TcpListener, time::Duration};
+
+use actix_web::{dev::Server, web, App, HttpResponse, HttpServer};
+use actix_web_httpauth::extractors::basic::BasicAuth;
+use rand::Rng;
+use serde::Deserialize;
+use serde_json::{json, Value};
+
+/// Configuration and fixture data of the mock
+///
+/// # Example
+///
+/// ```
+/// # use mhubx_mock::MockConfig;
+/// let config: MockConfig = serde_yaml::from_str(
+///     r#"
+/// latency_ms: 250
+/// failures: { rate: 0.1, status: 503 }
+/// measurements:
+///   - { system_id: cps1, msm_id: dosenfuellstand, name: Dosenfüllstand, value: 42.5, unit: "%" }
+/// alarms:
+///   - { system_id: cps1, id: 4711, text: Presse Not-Aus, severity: 3, time: 1682942400 }
+/// "#,
+/// )
+/// .unwrap();
+///
+/// assert_eq!(config.username, "system");
+/// assert_eq!(config.measurements.len(), 1);
+/// ```
+#[derive(Debug, Clone, Deserialize)]
+pub struct MockConfig {
+    #[serde(default = "default_address")]
+    pub address: String,
+    #[serde(default = "default_port")]
+    pub port: u16,
+    #[serde(default = "default_postfix")]
+    pub postfix: String,
+    #[serde(default = "default_username")]
+    pub username: String,
+    #[serde(default = "default_password")]
+    pub password: String,
+    /// delay of every call
+    #[serde(default)]
+    pub latency_ms: u64,
+    #[serde(default)]
+    pub failures: Failures,
+    /// the measurements as sent by the MHubX. Measurements without timestamp are sent with
+    /// the current time, so they change like live data
+    #[serde(default)]
+    pub measurements: Vec<Value>,
+    /// the alarms as sent by the MHubX
+    #[serde(default)]
+    pub alarms: Vec<Value>,
+}
+
+fn default_address() -> String {
+    "127.0.0.1".to_owned()
+}
+
+fn default_port() -> u16 {
+    8001
+}
+
+fn default_postfix() -> String {
+    "mhubx-cc/module/juwi/action".to_owned()
+}
+
+fn default_username() -> String {
+    "system".to_owned()
+}
+
+fn default_password() -> String {
+    "changeit".to_owned()
+}
+
+impl Default for MockConfig {
+    fn default() -> Self {
+        MockConfig {
+            address: default_address(),
+            port: default_port(),
+            postfix: default_postfix(),
+            username: default_username(),
+            password: default_password(),
+            latency_ms: 0,
+            failures: Failures::default(),
+            measurements: Vec::new(),
+            alarms: Vec::new(),
+        }
+    }
+}
+
+impl MockConfig {
+    /// Loads the config of a yaml file
+    pub fn load(path: &str) -> Result<Self, String> {
+        let file = std::fs::read_to_string(path).map_err(|err| format!("{path}: {err}"))?;
+        serde_yaml::from_str(&file).map_err(|err| format!("{path}: {err}"))
+    }
+}
+
+/// Failed calls, answered with the status instead of the data
+#[derive(Debug, Clone, Deserialize)]
+pub struct Failures {
+    /// share of the calls to fail, between 0 and 1
+    #[serde(default)]
+    pub rate: f64,
+    #[serde(default = "default_failure_status")]
+    pub status: u16,
+}
+
+fn default_failure_status() -> u16 {
+    500
+}
+
+impl Default for Failures {
+    fn default() -> Self {
+        Failures {
+            rate: 0.0,
+            status: default_failure_status(),
+        }
+    }
+}
+
+/// helper struct to deserialize the query of a call
+#[derive(Deserialize)]
+struct Call {
+    page: Option<String>,
+    name: Option<String>,
+    system_id: Option<String>,
+    msm_id: Option<String>,
+}
+
+/// Returns the server of the mock, it runs once awaited or spawned
+pub fn server(listener: TcpListener, config: MockConfig) -> std::io::Result<Server> {
+    let path = format!("/{}", config.postfix.trim_matches('/'));
+    let config = web::Data::new(config);
+    Ok(HttpServer::new(move || {
+        App::new()
+            .app_data(config.clone())
+            .route(&path, web::get().to(action))
+    })
+    .workers(1)
+    .listen(listener)?
+    .run())
+}
+
+/// returns true if the field matches the filter, `*` and no filter match everything
+fn selects(filter: &Option<String>, field: Option<&Value>) -> bool {
+    let Some(filter) = filter.as_deref().filter(|filter| *filter != "*") else {
+        return true;
+    };
+    let field = match field {
+        Some(Value::String(field)) => field.clone(),
+        Some(Value::Number(field)) => field.to_string(),
+        _ => return false,
+    };
+    filter.split(',').any(|id| id == field)
+}
+
+/// the action endpoint of the MHubX
+async fn action(
+    call: web::Query<Call>,
+    auth: Option<BasicAuth>,
+    config: web::Data<MockConfig>,
+) -> HttpResponse {
+    if config.latency_ms > 0 {
+        tokio::time::sleep(Duration::from_millis(config.latency_ms)).await;
+    }
+    let authorized = auth.is_some_and(|auth| {
+        auth.user_id() == config.username && auth.password() == Some(config.password.as_str())
+    });
+    if !authorized {
+        return HttpResponse::Unauthorized()
+            .insert_header(("WWW-Authenticate", "Basic realm=\"MHubX\""))
+            .finish();
+    }
+    if config.failures.rate > 0.0 && rand::thread_rng().gen_bool(config.failures.rate.min(1.0)) {
+        let status = actix_web::http::StatusCode::from_u16(config.failures.status)
+            .unwrap_or(actix_web::http::StatusCode::INTERNAL_SERVER_ERROR);
+        return HttpResponse::build(status).body("simulated failure");
+    }
+    if call.page.as_deref() != Some("Logic.Interface") {
+        return HttpResponse::NotFound().body("Unknown page");
+    }
+
+    let system = |item: &&Value| selects(&call.system_id, item.get("system_id"));
+    match call.name.as_deref() {
+        Some("getMeasurement") => {
+            let now = chrono::Utc::now().to_rfc3339();
+            let measurements: Vec<Value> = config
+                .measurements
+                .iter()
+                .filter(system)
+                .filter(|item| selects(&call.msm_id, item.get("msm_id").or_else(|| item.get("id"))))
+                .map(|item| {
+                    let mut item = item.clone();
+                    if let Some(item) = item.as_object_mut() {
+                        if !item.contains_key("timestamp") && !item.contains_key("time") {
+                            item.insert("timestamp".to_owned(), Value::String(now.clone()));
+                        }
+                    }
+                    item
+                })
+                .collect();
+            HttpResponse::Ok().json(measurements)
+        }
+        Some("getAlarms") => {
+            let alarms: Vec<&Value> = config.alarms.iter().filter(system).collect();
+            HttpResponse::Ok().json(alarms)
+        }
+        name => HttpResponse::Ok().json(json!({
+            "error": format!("Unknown function {}", name.unwrap_or_default())
+        })),
+    }
+}
@@ -0,0 +1,30 @@
+//! # mhubx-mock
+//!
+//! `mhubx-mock` serves the fixtures of a yaml file like the MHubX, so the web server
+//! runs without the plant. Point `restapi` of `web/config.yaml` to the address of the mock.
+//!
+//! # Example
+//!
+//! ```text
+//! > cargo run -p mhubx-mock -- mhubx-mock/fixtures.yaml
+//! ```
+
+use std::{env::args, net::TcpListener};
+
+use mhubx_mock::MockConfig;
+
+#[actix_web::main]
+async fn main() -> std::io::Result<()> {
+    let path = args()
+        .nth(1)
+        .unwrap_or_else(|| "mhubx-mock/fixtures.yaml".to_owned());
+    let config = MockConfig::load(&path).expect("unable to load the fixtures");
+
+    let listener = TcpListener::bind((config.address.as_str(), config.port))?;
+    println!(
+        "MHubX mock listening on http://{}/{}",
+        listener.local_addr()?,
+        config.postfix
+    );
+    mhubx_mock::server(listener, config)?.await
+}
@@ -2,6 +2,7 @@
 //!
 //! `mqtt` is a service to collect and store sensor values from a mqtt endpoint.
 //! It can be run in a testing mode, when providing the argument `testing` to simulate sensor data.
+//! The alarm rules of a sensor are evaluated on every stored value, see `common::rules`.
 //!
 //! # Example
 //!
@@ -43,10 +44,10 @@ async fn main() -> std::io::Result<()> {
     let (client, mut connection) = AsyncClient::new(mqttoptions, 10);
     client.subscribe("/i40/#", QoS::AtMostOnce).await.unwrap();
 
-    let testing = args().skip(1).next();
+    let testing = args().nth(1);
     match testing {
         Some(t) => {
-            if t.to_lowercase() == "testing".to_owned() {
+            if t.to_lowercase() == "testing" {
                 println!("Running in test mode!");
                 task::spawn(async move {
                     loop {
@@ -69,47 +70,47 @@ async fn main() -> std::io::Result<()> {
 
     loop {
         let notification = connection.poll().await.unwrap();
-        match notification {
-            rumqttc::Event::Incoming(incoming) => match incoming {
-                rumqttc::Packet::Publish(published) => {
-                    let val = Vec::from(published.payload);
-                    let val = String::from_utf8(val).unwrap();
+        if let rumqttc::Event::Incoming(rumqttc::Packet::Publish(published)) = notification {
+            let val = Vec::from(published.payload);
+            let val = String::from_utf8(val).unwrap();
 
-                    let x = published.topic.replace("/i40/fertigungsanlage/", "");
-                    let mut data = x.splitn(2, "/");
+            let x = published.topic.replace("/i40/fertigungsanlage/", "");
+            let mut data = x.splitn(2, "/");
 
-                    let station_name = data.next();
-                    let sensor_name = data.next().unwrap().replace("/", "_");
+            let station_name = data.next();
+            let sensor_name = data.next().unwrap().replace("/", "_");
 
-                    let record = common::Sensor::get(&db, sensor_name.clone())
-                        .await
-                        .expect("Error while retrieving sensor");
-
-                    match record {
-                        Some(r) => {
-                            println!("found record! inserting; {:?}", &r.get_id());
-                            let _ = common::SensorValue::create(&db, val, r.get_id().clone()).await;
-                        }
-                        None => {
-                            let station =
-                                common::Station::get(&db, station_name.unwrap().to_owned())
-                                    .await
-                                    .unwrap();
+            let record = common::Sensor::get(&db, sensor_name.clone())
+                .await
+                .expect("Error while retrieving sensor");
 
-                            if station.is_some() {
-                                let _ = common::Sensor::create(
-                                    &db,
-                                    sensor_name,
-                                    station.unwrap().get_id().clone(),
-                                )
-                                .await;
+            match record {
+                Some(r) if r.is_archived() => {
+                    println!("sensor is archived! skipping; {:?}", &r.get_id());
+                }
+                Some(r) => {
+                    println!("found record! inserting; {:?}", &r.get_id());
+                    match common::SensorValue::create(&db, val, r.get_id().clone()).await {
+                        Ok(Some(value)) => {
+                            if let Err(err) = common::rules::evaluate(&db, &value).await {
+                                println!("evaluating the alarm rules failed: {err}");
                             }
                         }
+                        Ok(None) => {}
+                        Err(err) => println!("storing the value failed: {err}"),
                     }
                 }
-                _ => {}
-            },
-            rumqttc::Event::Outgoing(_) => {}
+                None => {
+                    let station = common::Station::get(&db, station_name.unwrap().to_owned())
+                        .await
+                        .unwrap();
+
+                    if let Some(station) = station {
+                        let _ = common::Sensor::create(&db, sensor_name, station.get_id().clone())
+                            .await;
+                    }
+                }
+            }
         }
     }
 }
@@ -1,59 +1,428 @@
 //! # web::api
 //!
-//! `web::api` is the module handling all request to the MHubX rest API
+//! `web::api` is the module handling all request to the MHubX rest API.
+//! Errors of the MHubX are answered with 502, timeouts with 504.
+//! Responses are cached shortly, the `X-Cache` header shows if a response was a `HIT`,
+//! `MISS`, `COALESCED` with a running call or `STALE` as the MHubX is down.
+//! The `Age` header is the time in seconds since the MHubX sent the data.
+//! Systems are the stations of the same id, the data of systems the caller may not view
+//! is left out and querying such a system responds with 404.
 //!
+//! # Example
+//!
+//! ```text
+//! GET /api/v1/measurements?system_id=cps1&msm_id=dosenfuellstand,presse_pressenstatus&from=2023-05-01T00:00:00Z
+//! GET /api/v1/mhubx/alarms?system_id=cps1&min_severity=3&from=2023-05-01T00:00:00Z
+//! GET /api/v1/systems
+//! ```
+
+use crate::{
+    app::acl::{station_thing, Access, StationPermission},
+    auth::ApiScope,
+    middleware::scope::RequireScope,
+};
+use actix_web::{get, http::header::AGE, web, HttpResponse};
+use common::mhubx::{AlarmQuery, Cached, MeasurementQuery, MhubxCache, MhubxError};
+use serde::{Deserialize, Serialize};
+
+/// responds with the result of a MHubX call
+fn mhubx_response<T: Serialize>(result: Result<Cached<T>, MhubxError>) -> HttpResponse {
+    match result {
+        Ok(cached) => HttpResponse::Ok()
+            .insert_header(("X-Cache", cached.status.as_str()))
+            .insert_header((AGE, cached.age.as_secs().to_string()))
+            .json(cached.data),
+        Err(MhubxError::Timeout) => {
+            HttpResponse::GatewayTimeout().body(MhubxError::Timeout.to_string())
+        }
+        Err(err) => {
+            eprintln!("MHubX call failed: {err}");
+            HttpResponse::BadGateway().body(err.to_string())
+        }
+    }
+}
+
+/// responds with 404 unless the caller may view the system, all systems are permitted
+fn check_system(access: &Access, system_id: &str) -> Result<(), HttpResponse> {
+    if system_id != all_systems() && !permits_system(access, system_id) {
+        return Err(HttpResponse::NotFound().body(format!("System {system_id} not found")));
+    }
+    Ok(())
+}
+
+/// Returns true if the caller may view the station of the system
+fn permits_system(access: &Access, system_id: &str) -> bool {
+    access.permits(&station_thing(system_id), StationPermission::View)
+}
+
+/// keeps the data of the systems the caller may view
+fn visible<T>(
+    access: &Access,
+    result: Result<Cached<Vec<T>>, MhubxError>,
+    system_id: impl Fn(&T) -> &str,
+) -> Result<Cached<Vec<T>>, MhubxError> {
+    result.map(|mut cached| {
+        cached
+            .data
+            .retain(|data| permits_system(access, system_id(data)));
+        cached
+    })
+}
+
+fn all_systems() -> String {
+    "*".to_owned()
+}
+
+/// checks that from is before to
+fn check_range(
+    from: Option<chrono::DateTime<chrono::Utc>>,
+    to: Option<chrono::DateTime<chrono::Utc>>,
+) -> Result<(), HttpResponse> {
+    match (from, to) {
+        (Some(from), Some(to)) if from > to => {
+            Err(HttpResponse::BadRequest().body("from needs to be before to"))
+        }
+        _ => Ok(()),
+    }
+}
+
+/// helper struct to deserialize the measurement query.
+/// msm_id is a comma separated list of ids, all measurements without it
+#[derive(Deserialize)]
+pub struct MeasurementsQuery {
+    #[serde(default = "all_systems")]
+    system_id: String,
+    #[serde(default)]
+    msm_id: String,
+    from: Option<chrono::DateTime<chrono::Utc>>,
+    to: Option<chrono::DateTime<chrono::Utc>>,
+}
+
+/// endpoint to retrieve the measurements of the MHubX
+#[get("/measurements", wrap = "RequireScope(ApiScope::ReadSensors)")]
+async fn get_measurments(
+    query: web::Query<MeasurementsQuery>,
+    access: Access,
+    mhubx: web::Data<MhubxCache>,
+) -> HttpResponse {
+    let query = query.into_inner();
+    if let Err(response) = check_range(query.from, query.to) {
+        return response;
+    }
+    if let Err(response) = check_system(&access, &query.system_id) {
+        return response;
+    }
+    let query = MeasurementQuery {
+        system_id: query.system_id,
+        msm_ids: query
+            .msm_id
+            .split(',')
+            .map(str::trim)
+            .filter(|id| !id.is_empty())
+            .map(str::to_owned)
+            .collect(),
+        from: query.from,
+        to: query.to,
+    };
+    mhubx_response(visible(
+        &access,
+        mhubx.measurements(&query).await,
+        |measurement| &measurement.system_id,
+    ))
+}
+
+/// helper struct to deserialize the alarm query
+#[derive(Deserialize)]
+pub struct AlarmsQuery {
+    #[serde(default = "all_systems")]
+    system_id: String,
+    from: Option<chrono::DateTime<chrono::Utc>>,
+    to: Option<chrono::DateTime<chrono::Utc>>,
+    min_severity: Option<u32>,
+    max_severity: Option<u32>,
+}
+
+/// endpoint to retrieve the alarms of the MHubX as sent by it,
+/// `web::app::alarm` merges them with the local alarms
+#[get("/mhubx/alarms", wrap = "RequireScope(ApiScope::ReadSensors)")]
+async fn get_alarms(
+    query: web::Query<AlarmsQuery>,
+    access: Access,
+    mhubx: web::Data<MhubxCache>,
+) -> HttpResponse {
+    let query = query.into_inner();
+    if let Err(response) = check_range(query.from, query.to) {
+        return response;
+    }
+    if let Err(response) = check_system(&access, &query.system_id) {
+        return response;
+    }
+    if let (Some(min), Some(max)) = (query.min_severity, query.max_severity) {
+        if min > max {
+            return HttpResponse::BadRequest()
+                .body("min_severity needs to be less than max_severity");
+        }
+    }
+    let query = AlarmQuery {
+        system_id: query.system_id,
+        from: query.from,
+        to: query.to,
+        min_severity: query.min_severity,
+        max_severity: query.max_severity,
+    };
+    mhubx_response(visible(&access, mhubx.alarms(&query).await, |alarm| {
+        &alarm.system_id
+    }))
+}
+
+/// endpoint to discover the systems of the MHubX and their measurements
+#[get("/systems", wrap = "RequireScope(ApiScope::ReadSensors)")]
+async fn get_systems(access: Access, mhubx: web::Data<MhubxCache>) -> HttpResponse {
+    mhubx_response(visible(&access, mhubx.systems().await, |system| {
+        &system.system_id
+    }))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{net::TcpListener, time::Duration};
+
+    use actix_web::{
+        dev::{ServerHandle, Service},
+        http::StatusCode,
+        test, App, HttpMessage,
+    };
+    use common::mhubx::{Alarm, CacheStatus, Measurement, MhubxClient, MhubxConfig, System};
+    use mhubx_mock::{Failures, MockConfig};
+    use serde_json::json;
+    use surrealdb::sql::Thing;
+
+    use super::*;
+    use crate::auth::{Claims, Role};
+
+    fn fixtures() -> MockConfig {
+        MockConfig {
+            measurements: vec![
+                json!({"system_id": "cps1", "msm_id": "dosenfuellstand", "value": 42.5, "unit": "%", "timestamp": "2023-05-01T12:00:00Z"}),
+                json!({"system_id": "cps1", "msm_id": "presse_druck", "value": "118.2", "timestamp": "2023-05-01T12:00:00Z"}),
+                json!({"system_id": "cps2", "msm_id": "foerderband_modus", "value": "automatik", "timestamp": "2023-05-02T12:00:00Z"}),
+            ],
+            alarms: vec![
+                json!({"system_id": "cps1", "id": 4711, "text": "Presse Not-Aus", "severity": 3, "time": "2023-05-01T12:00:00Z"}),
+                json!({"system_id": "cps1", "id": 4712, "text": "Dosenfüllstand niedrig", "severity": 1, "time": "2023-05-01T13:00:00Z"}),
+                json!({"system_id": "cps2", "id": 815, "severity": 2, "time": "2023-05-01T14:00:00Z"}),
+            ],
+            ..Default::default()
+        }
+    }
+
+    /// starts the mock and returns a client for it
+    fn start(mock: MockConfig, password: &str) -> (MhubxClient, ServerHandle) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap();
+        let postfix = mock.postfix.clone();
+        let server = mhubx_mock::server(listener, mock).unwrap();
+        let handle = server.handle();
+        actix_web::rt::spawn(server);
+        let client = MhubxClient::new(MhubxConfig {
+            endpoint: format!("http://{address}/{postfix}"),
+            username: "system".to_owned(),
+            password: password.to_owned(),
+            timeout: Duration::from_millis(500),
+            retries: 0,
+        })
+        .unwrap();
+        (client, handle)
+    }
+
+    fn mhubx(mock: MockConfig, password: &str) -> web::Data<MhubxCache> {
+        let (client, _) = start(mock, password);
+        web::Data::new(MhubxCache::new(
+            client,
+            Duration::from_secs(5),
+            Duration::from_secs(300),
+        ))
+    }
+
+    async fn get(mhubx: web::Data<MhubxCache>, uri: &str) -> actix_web::dev::ServiceResponse {
+        let app = test::init_service(
+            App::new()
+                .wrap_fn(|req, srv| {
+                    req.extensions_mut().insert(Claims::new(
+                        &Thing::from(("user", "admin")),
+                        &Thing::from(("session", "admin")),
+                        "admin".to_owned(),
+                        String::new(),
+                        Role::Admin,
+                        false,
+                        chrono::Duration::minutes(5),
+                    ));
+                    srv.call(req)
+                })
+                .app_data(mhubx)
+                .service(get_measurments)
+                .service(get_alarms)
+                .service(get_systems),
+        )
+        .await;
+        test::call_service(&app, test::TestRequest::get().uri(uri).to_request()).await
+    }
+
+    fn cache_status(response: &actix_web::dev::ServiceResponse) -> &str {
+        response.headers().get("X-Cache").unwrap().to_str().unwrap()
+    }
+
+    #[actix_web::test]
+    async fn filters_measurements_by_system_ids_and_time() {
+        let response = get(
+            mhubx(fixtures(), "changeit"),
+            "/measurements?system_id=cps1&msm_id=presse_druck,foerderband_modus",
+        )
+        .await;
+        assert_eq!(response.status(), StatusCode::OK);
+        let measurements: Vec<Measurement> = test::read_body_json(response).await;
+        assert_eq!(measurements.len(), 1);
+        assert_eq!(measurements[0].msm_id, "presse_druck");
+
+        let response = get(
+            mhubx(fixtures(), "changeit"),
+            "/measurements?from=2023-05-02T00:00:00Z",
+        )
+        .await;
+        let measurements: Vec<Measurement> = test::read_body_json(response).await;
+        assert_eq!(measurements.len(), 1);
+        assert_eq!(measurements[0].system_id, "cps2");
+    }
+
+    #[actix_web::test]
+    async fn filters_alarms_by_severity_and_time() {
+        let response = get(
+            mhubx(fixtures(), "changeit"),
+            "/mhubx/alarms?min_severity=2&to=2023-05-01T13:30:00Z",
+        )
+        .await;
+        assert_eq!(response.status(), StatusCode::OK);
+        let alarms: Vec<Alarm> = test::read_body_json(response).await;
+        assert_eq!(alarms.len(), 1);
+        assert_eq!(alarms[0].alarm_id, "4711");
+
+        let response = get(
+            mhubx(fixtures(), "changeit"),
+            "/mhubx/alarms?min_severity=3&max_severity=1",
+        )
+        .await;
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+    }
+
+    #[actix_web::test]
+    async fn discovers_the_systems() {
+        let response = get(mhubx(fixtures(), "changeit"), "/systems").await;
+        assert_eq!(response.status(), StatusCode::OK);
+        let systems: Vec<System> = test::read_body_json(response).await;
+        let ids: Vec<&str> = systems
+            .iter()
+            .map(|system| system.system_id.as_str())
+            .collect();
+        assert_eq!(ids, ["cps1", "cps2"]);
+        assert_eq!(systems[0].measurements.len(), 2);
+    }
+
+    #[actix_web::test]
+    async fn maps_errors_of_the_mhubx() {
+        let response = get(mhubx(fixtures(), "wrong"), "/measurements").await;
+        assert_eq!(response.status(), StatusCode::BAD_GATEWAY);
+
+        let failing = MockConfig {
+            failures: Failures {
+                rate: 1.0,
+                status: 503,
+            },
+            ..fixtures()
+        };
+        let response = get(mhubx(failing, "changeit"), "/mhubx/alarms").await;
+        assert_eq!(response.status(), StatusCode::BAD_GATEWAY);
+
+        let slow = MockConfig {
+            latency_ms: 2000,
+            ..fixtures()
+        };
+        let response = get(mhubx(slow, "changeit"), "/measurements").await;
+        assert_eq!(response.status(), StatusCode::GATEWAY_TIMEOUT);
+    }
+
+    #[actix_web::test]
+    async fn shares_responses_between_callers() {
+        let slow = MockConfig {
+            latency_ms: 200,
+            ..fixtures()
+        };
+        let mhubx = mhubx(slow, "changeit");
+        let (first, second) = futures_util::join!(
+            get(mhubx.clone(), "/measurements?system_id=cps1"),
+            get(
+                mhubx.clone(),
+                "/measurements?system_id=cps1&to=2023-05-02T00:00:00Z"
+            ),
+        );
+        let mut statuses = [cache_status(&first), cache_status(&second)];
+        statuses.sort();
+        assert_eq!(statuses, ["COALESCED", "MISS"]);
+
+        // the filter is applied to the cached data
+        let response = get(
+            mhubx.clone(),
+            "/measurements?system_id=cps1&from=2023-05-02T00:00:00Z",
+        )
+        .await;
+        assert_eq!(cache_status(&response), "HIT");
+        let measurements: Vec<Measurement> = test::read_body_json(response).await;
+        assert!(measurements.is_empty());
+    }
+
+    #[actix_web::test]
+    async fn serves_stale_data_while_the_mhubx_is_down() {
+        let (client, mock) = start(fixtures(), "changeit");
+        let mhubx = web::Data::new(MhubxCache::new(
+            client,
+            Duration::ZERO,
+            Duration::from_secs(300),
+        ));
+        let response = get(mhubx.clone(), "/mhubx/alarms").await;
+        assert_eq!(cache_status(&response), "MISS");
+
+        mock.stop(false).await;
+        let response = get(mhubx.clone(), "/mhubx/alarms").await;
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(cache_status(&response), "STALE");
+        let alarms: Vec<Alarm> = test::read_body_json(response).await;
+        assert_eq!(alarms.len(), 3);
+
+        let response = get(mhubx, "/measurements").await;
+        assert_eq!(response.status(), StatusCode::BAD_GATEWAY);
+    }
+
+    #[actix_web::test]
+    async fn leaves_out_systems_the_caller_may_not_view() {
+        let access = Access::without(&["cps2"]);
+        assert!(check_system(&access, "cps1").is_ok());
+        assert!(check_system(&access, "*").is_ok());
+        assert_eq!(
+            check_system(&access, "cps2").unwrap_err().status(),
+            StatusCode::NOT_FOUND
+        );
 
-use actix_web::{get, web, HttpResponse};
-
-use crate::config::{AppState, RestApi};
-
-//constructs the url endpoint for the MHubx RestAPI
-fn get_endpoint(restapi: &RestApi, path: impl std::fmt::Display) -> String {
-    format!(
-        "{}:{}/{}{}",
-        restapi.base_url, restapi.port, restapi.postfix, path
-    )
-}
-
-//construcss the request and adds basic auth headers
-//path is supposed to be the path after the base url
-//e.g. "?page=Logic.Interface" becomes "http://example.com:8000/base/uri?page=Logic.Interface"
-fn get(restapi: &RestApi, path: impl std::fmt::Display) -> reqwest::RequestBuilder {
-    let client = reqwest::Client::new();
-    let url = get_endpoint(&restapi, path);
-    println!("{}", &url);
-    client
-        .get(url)
-        .basic_auth(&restapi.username, Some(&restapi.password))
-}
-
-//returns the measurments for all systems from the MHubx RestAPI
-#[get("/measurements")]
-async fn get_measurments(state: web::Data<AppState>) -> HttpResponse {
-    //system cps1 or * for all systems
-    let resp = get(
-        &state.restapi,
-        "?page=Logic.Interface&name=getMeasurement&source=system&system_id=*&msm_id=*",
-    )
-    .send()
-    .await
-    .unwrap();
-    dbg!(&resp);
-    let json: serde_json::value::Value = resp.json().await.unwrap();
-    HttpResponse::Ok().json(json)
-}
-
-//returns all alarms for all systems from the MHubx RestAPI
-#[get("/alarms")]
-async fn get_alarms(state: web::Data<AppState>) -> HttpResponse {
-    let resp = get(
-        &state.restapi,
-        "?page=Logic.Interface&name=getAlarms&system_id=cps1",
-    )
-    .send()
-    .await
-    .unwrap();
-
-    let json: serde_json::value::Value = resp.json().await.unwrap();
-    HttpResponse::Ok().json(json)
+        let alarms: Vec<Alarm> = fixtures()
+            .alarms
+            .into_iter()
+            .map(|alarm| serde_json::from_value(alarm).unwrap())
+            .collect();
+        let cached = Cached {
+            data: alarms,
+            status: CacheStatus::Hit,
+            age: Duration::ZERO,
+        };
+        let visible = visible(&access, Ok(cached), |alarm| &alarm.system_id).unwrap();
+        assert_eq!(visible.data.len(), 2);
+        assert!(visible.data.iter().all(|alarm| alarm.system_id == "cps1"));
+    }
 }
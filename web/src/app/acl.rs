@@ -0,0 +1,477 @@
+//! # web::acl
+//!
+//! `web::acl` is a module to restrict stations to some users and groups.
+//! A station without grants is open to every signed in user according to their role.
+//! Once a station has a grant, only its grantees see it. Admins see every station.
+//! The permissions build on each other: `view` reads the station, its sensors and values,
+//! `operate` works with its alarms and `manage` changes the station, its sensors and grants.
+//!
+//! # Example
+//! Restricting the `palettenlager` to the logistics group and a single contractor
+//!
+//! ```text
+//! POST /api/v1/station/palettenlager/grants {"group": "logistik", "permission": "operate"}
+//! POST /api/v1/station/palettenlager/grants {"user": "user:hugo", "permission": "view"}
+//! DELETE /api/v1/station-grant/8wnm3q2d9sxqp7ax3rkc
+//! ```
+
+use std::collections::{HashMap, HashSet};
+
+use actix_web::{
+    delete,
+    dev::Payload,
+    error::{ErrorInternalServerError, ErrorUnauthorized},
+    get, post, web, FromRequest, HttpMessage, HttpRequest, HttpResponse,
+};
+use futures_util::future::LocalBoxFuture;
+use serde::{Deserialize, Serialize};
+use surrealdb::{
+    engine::remote::ws::Client,
+    sql::{Datetime, Thing},
+    Surreal,
+};
+
+use crate::{
+    app::audit::Audit,
+    auth::{Claims, Role},
+    middleware::role::RequireRole,
+};
+
+/// The permissions on a station, each permission includes the permissions before it
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum StationPermission {
+    /// read the station, its sensors and their values
+    View,
+    /// work with the alarms of the station
+    Operate,
+    /// change the station, its sensors and grants
+    Manage,
+}
+
+/// A permission on a station granted to a user or API key, or to a group
+#[derive(Debug, Serialize, Deserialize)]
+pub struct StationGrant {
+    id: Thing,
+    station: Thing,
+    user: Option<Thing>,
+    group: Option<String>,
+    permission: StationPermission,
+    created_at: Datetime,
+    created_by: Option<Thing>,
+}
+
+/// extractor providing the stations the caller may access
+///
+/// # Example
+///
+/// ```text
+/// #[get("/station/{station}/thing")]
+/// async fn get_thing(access: Access, db: web::Data<Surreal<Client>>) -> HttpResponse
+/// ```
+pub struct Access {
+    /// admins may access every station
+    unrestricted: bool,
+    /// stations with grants, only their grantees may access them
+    restricted: HashSet<Thing>,
+    /// the highest permission of the caller per station
+    granted: HashMap<Thing, StationPermission>,
+}
+
+impl FromRequest for Access {
+    type Error = actix_web::Error;
+    type Future = LocalBoxFuture<'static, Result<Self, Self::Error>>;
+
+    fn from_request(req: &HttpRequest, _: &mut Payload) -> Self::Future {
+        let claims = req.extensions().get::<Claims>().cloned();
+        let db = req.app_data::<web::Data<Surreal<Client>>>().cloned();
+        let missing = claims.is_none().then(|| crate::auth::missing_claims(req));
+        Box::pin(async move {
+            if let Some(missing) = missing {
+                return Err(missing);
+            }
+            let Some(claims) = claims else {
+                return Err(ErrorUnauthorized("Unauthorized"));
+            };
+            if claims.role >= Role::Admin {
+                return Ok(Access::unrestricted());
+            }
+            let Some(db) = db else {
+                return Err(ErrorUnauthorized("Unauthorized"));
+            };
+            Access::load(&db, &claims)
+                .await
+                .map_err(ErrorInternalServerError)
+        })
+    }
+}
+
+impl Access {
+    /// Loads the grants of the caller and of its groups
+    pub async fn load(db: &Surreal<Client>, claims: &Claims) -> Result<Self, surrealdb::Error> {
+        let caller = surrealdb::sql::thing(&claims.sub).ok();
+        Access::load_for(db, caller, claims.role).await
+    }
+
+    /// Loads the grants of a user or API key with the role and of its groups,
+    /// e.g. to notify a user of a alarm
+    pub async fn load_for(
+        db: &Surreal<Client>,
+        caller: Option<Thing>,
+        role: Role,
+    ) -> Result<Self, surrealdb::Error> {
+        if role >= Role::Admin {
+            return Ok(Access::unrestricted());
+        }
+
+        let mut response = db
+            .query("SELECT * FROM station_grant; SELECT VALUE groups FROM $caller")
+            .bind(("caller", &caller))
+            .await?;
+        let grants: Vec<StationGrant> = response.take(0)?;
+        let groups: Option<Vec<String>> = response.take(1)?;
+        Ok(Access::from_grants(
+            caller.as_ref(),
+            &groups.unwrap_or_default(),
+            grants,
+        ))
+    }
+
+    /// the access of admins to every station
+    fn unrestricted() -> Self {
+        Access {
+            unrestricted: true,
+            restricted: HashSet::new(),
+            granted: HashMap::new(),
+        }
+    }
+
+    /// the access of a caller in the groups given all grants
+    fn from_grants(caller: Option<&Thing>, groups: &[String], grants: Vec<StationGrant>) -> Self {
+        let mut access = Access {
+            unrestricted: false,
+            restricted: HashSet::new(),
+            granted: HashMap::new(),
+        };
+        for grant in grants {
+            let applies = grant.user.is_some() && grant.user.as_ref() == caller
+                || grant.group.as_ref().is_some_and(|group| {
+                    groups
+                        .iter()
+                        .any(|member| member.eq_ignore_ascii_case(group))
+                });
+            if applies {
+                let permission = access
+                    .granted
+                    .entry(grant.station.clone())
+                    .or_insert(grant.permission);
+                *permission = (*permission).max(grant.permission);
+            }
+            access.restricted.insert(grant.station);
+        }
+        access
+    }
+
+    /// the access of a caller without grants on the restricted stations
+    #[cfg(test)]
+    pub fn without(restricted: &[&str]) -> Self {
+        Access {
+            unrestricted: false,
+            restricted: restricted
+                .iter()
+                .map(|station| station_thing(station))
+                .collect(),
+            granted: HashMap::new(),
+        }
+    }
+
+    /// Returns true if some stations are hidden from the caller
+    pub fn is_restricted(&self) -> bool {
+        !self.unrestricted && !self.restricted.is_empty()
+    }
+
+    /// Returns true if the caller has the permission on the station
+    pub fn permits(&self, station: &Thing, permission: StationPermission) -> bool {
+        self.unrestricted
+            || !self.restricted.contains(station)
+            || self
+                .granted
+                .get(station)
+                .is_some_and(|granted| *granted >= permission)
+    }
+
+    /// Returns true if the caller has the permission on the station of the sensor.
+    /// Unknown sensors are permitted, so the caller learns they don't exist
+    pub async fn permits_sensor(
+        &self,
+        db: &Surreal<Client>,
+        sensor: &str,
+        permission: StationPermission,
+    ) -> Result<bool, surrealdb::Error> {
+        if self.unrestricted || self.restricted.is_empty() {
+            return Ok(true);
+        }
+        Ok(common::Sensor::get(db, sensor.to_owned())
+            .await?
+            .is_none_or(|sensor| self.permits(sensor.get_station(), permission)))
+    }
+}
+
+/// the record of a station id
+pub fn station_thing(station: &str) -> Thing {
+    Thing::from(("station", station))
+}
+
+/// endpoint to list the grants of a station
+#[get("/station/{station}/grants", wrap = "RequireRole(Role::Maintainer)")]
+async fn get_station_grants(
+    station_id: web::Path<String>,
+    access: Access,
+    db: web::Data<Surreal<Client>>,
+) -> HttpResponse {
+    let station = station_thing(&station_id);
+    if !access.permits(&station, StationPermission::Manage) {
+        return HttpResponse::NotFound().body("Station not found");
+    }
+    let grants: Result<Vec<StationGrant>, surrealdb::Error> = async {
+        db.query("SELECT * FROM station_grant WHERE station = $station ORDER BY created_at")
+            .bind(("station", station))
+            .await?
+            .take(0)
+    }
+    .await;
+    match grants {
+        Ok(grants) => HttpResponse::Ok().json(grants),
+        Err(err) => HttpResponse::InternalServerError().body(err.to_string()),
+    }
+}
+
+/// helper struct to deserialize the payload to grant a permission,
+/// either user (a user or API key record) or group needs to be set
+#[derive(Deserialize)]
+pub struct CreateGrant {
+    user: Option<String>,
+    group: Option<String>,
+    permission: StationPermission,
+}
+
+/// endpoint to grant a permission on a station.
+/// The first grant restricts the station to its grantees
+#[post("/station/{station}/grants", wrap = "RequireRole(Role::Maintainer)")]
+async fn create_station_grant(
+    station_id: web::Path<String>,
+    json: web::Json<CreateGrant>,
+    claims: Claims,
+    access: Access,
+    audit: Audit,
+    db: web::Data<Surreal<Client>>,
+) -> HttpResponse {
+    let station = match common::Station::get(&db, station_id.into_inner()).await {
+        Ok(Some(station)) => station.get_id().clone(),
+        Ok(None) => return HttpResponse::NotFound().body("Station not found"),
+        Err(err) => return HttpResponse::InternalServerError().body(err.to_string()),
+    };
+    if !access.permits(&station, StationPermission::Manage) {
+        return HttpResponse::NotFound().body("Station not found");
+    }
+
+    let json = json.into_inner();
+    let group = json
+        .group
+        .map(|group| group.trim().to_owned())
+        .filter(|group| !group.is_empty());
+    let user = match json.user.as_deref().map(surrealdb::sql::thing) {
+        Some(Ok(user)) if ["user", "api_key"].contains(&user.tb.as_str()) => Some(user),
+        Some(_) => return HttpResponse::BadRequest().body("user needs to be a user or api_key"),
+        None => None,
+    };
+    if user.is_some() == group.is_some() {
+        return HttpResponse::BadRequest().body("Grant the permission either to a user or a group");
+    }
+
+    let created: Result<Option<StationGrant>, surrealdb::Error> = async {
+        db.query("CREATE station_grant SET station = $station, user = $user, group = $group, permission = $permission, created_at = time::now(), created_by = $created_by")
+            .bind(("station", station))
+            .bind(("user", user))
+            .bind(("group", group))
+            .bind(("permission", json.permission))
+            .bind(("created_by", surrealdb::sql::thing(&claims.sub).ok()))
+            .await?
+            .take(0)
+    }
+    .await;
+    match created {
+        Ok(Some(grant)) => {
+            audit
+                .record(&db, "station_grant.create", Some(&grant.id), (), &grant)
+                .await;
+            HttpResponse::Created().json(grant)
+        }
+        Ok(None) => HttpResponse::InternalServerError().body("Grant was not created"),
+        Err(err) => HttpResponse::InternalServerError().body(err.to_string()),
+    }
+}
+
+/// endpoint to revoke a grant, removing the last grant opens the station again
+#[delete("/station-grant/{grant}", wrap = "RequireRole(Role::Maintainer)")]
+async fn delete_station_grant(
+    grant_id: web::Path<String>,
+    access: Access,
+    audit: Audit,
+    db: web::Data<Surreal<Client>>,
+) -> HttpResponse {
+    let grant = Thing::from(("station_grant", grant_id.as_str()));
+    let existing: Option<StationGrant> = match db.select(grant.clone()).await {
+        Ok(existing) => existing,
+        Err(err) => return HttpResponse::InternalServerError().body(err.to_string()),
+    };
+    let Some(existing) = existing else {
+        return HttpResponse::NotFound().body("Grant not found");
+    };
+    if !access.permits(&existing.station, StationPermission::Manage) {
+        return HttpResponse::NotFound().body("Grant not found");
+    }
+
+    let deleted: Result<Option<StationGrant>, surrealdb::Error> = db.delete(grant).await;
+    match deleted {
+        Ok(Some(deleted)) => {
+            audit
+                .record(&db, "station_grant.delete", Some(&deleted.id), &deleted, ())
+                .await;
+            HttpResponse::NoContent().finish()
+        }
+        Ok(None) => HttpResponse::NotFound().body("Grant not found"),
+        Err(err) => HttpResponse::InternalServerError().body(err.to_string()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use surrealdb::sql::{Datetime, Thing};
+
+    use super::{station_thing, Access, StationGrant, StationPermission};
+
+    fn grant(
+        station: &str,
+        user: Option<&str>,
+        group: Option<&str>,
+        permission: StationPermission,
+    ) -> StationGrant {
+        StationGrant {
+            id: Thing::from(("station_grant", station)),
+            station: station_thing(station),
+            user: user.map(|user| Thing::from(("user", user))),
+            group: group.map(str::to_owned),
+            permission,
+            created_at: Datetime::default(),
+            created_by: None,
+        }
+    }
+
+    fn access(user: &str, groups: &[&str], grants: Vec<StationGrant>) -> Access {
+        let groups: Vec<String> = groups.iter().map(|group| group.to_string()).collect();
+        Access::from_grants(Some(&Thing::from(("user", user))), &groups, grants)
+    }
+
+    #[test]
+    fn stations_without_grants_are_open() {
+        let access = access(
+            "anna",
+            &[],
+            vec![grant(
+                "presswerk",
+                Some("hugo"),
+                None,
+                StationPermission::View,
+            )],
+        );
+        // the role of the caller decides on open stations
+        assert!(access.permits(&station_thing("palettenlager"), StationPermission::Manage));
+    }
+
+    #[test]
+    fn restricted_stations_need_a_grant() {
+        let access = access(
+            "anna",
+            &["logistik"],
+            vec![grant(
+                "presswerk",
+                Some("hugo"),
+                None,
+                StationPermission::Manage,
+            )],
+        );
+        assert!(access.is_restricted());
+        assert!(!access.permits(&station_thing("presswerk"), StationPermission::View));
+    }
+
+    #[test]
+    fn permissions_include_the_lower_ones() {
+        let access = access(
+            "hugo",
+            &[],
+            vec![grant(
+                "presswerk",
+                Some("hugo"),
+                None,
+                StationPermission::Operate,
+            )],
+        );
+        let presswerk = station_thing("presswerk");
+        assert!(access.permits(&presswerk, StationPermission::View));
+        assert!(access.permits(&presswerk, StationPermission::Operate));
+        assert!(!access.permits(&presswerk, StationPermission::Manage));
+    }
+
+    #[test]
+    fn the_highest_grant_counts() {
+        let access = access(
+            "hugo",
+            &["logistik"],
+            vec![
+                grant("presswerk", Some("hugo"), None, StationPermission::View),
+                grant(
+                    "presswerk",
+                    None,
+                    Some("logistik"),
+                    StationPermission::Manage,
+                ),
+            ],
+        );
+        assert!(access.permits(&station_thing("presswerk"), StationPermission::Manage));
+    }
+
+    #[test]
+    fn admins_bypass_grants() {
+        let access = Access::unrestricted();
+        assert!(!access.is_restricted());
+        assert!(access.permits(&station_thing("presswerk"), StationPermission::Manage));
+    }
+
+    #[test]
+    fn groups_match_case_insensitive() {
+        let member = access(
+            "lucy",
+            &["Logistik"],
+            vec![grant(
+                "palettenlager",
+                None,
+                Some("LOGISTIK"),
+                StationPermission::Operate,
+            )],
+        );
+        assert!(member.permits(&station_thing("palettenlager"), StationPermission::Operate));
+
+        let other = access(
+            "lucy",
+            &["logistik-extern"],
+            vec![grant(
+                "palettenlager",
+                None,
+                Some("logistik"),
+                StationPermission::Operate,
+            )],
+        );
+        assert!(!other.permits(&station_thing("palettenlager"), StationPermission::View));
+    }
+}
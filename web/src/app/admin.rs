@@ -0,0 +1,393 @@
+//! # web::admin
+//!
+//! `web::admin` is a module for the administration of user accounts.
+//! Invited users get a temporary password, which they have to change after signing in.
+//!
+//! # Example
+//!
+//! ```text
+//! GET /api/v1/users?role=operator&active=true&search=lucy
+//! POST /api/v1/users {"email": "hugo@mail.de", "name": "Hugo", "role": "operator"}
+//! POST /api/v1/user/hugo/deactivate
+//! ```
+
+use actix_web::{get, patch, post, web, HttpResponse};
+use rand::{distributions::Alphanumeric, Rng};
+use serde::{Deserialize, Serialize};
+use surrealdb::{
+    engine::remote::ws::Client,
+    sql::{Datetime, Thing},
+    Surreal,
+};
+
+use crate::{
+    app::audit::Audit,
+    auth::{Claims, Role},
+    config::{AppState, PasswordPolicy},
+    middleware::role::RequireRole,
+};
+
+fn active_default() -> bool {
+    true
+}
+
+/// A user account as seen by admins
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Account {
+    id: Thing,
+    email: String,
+    name: String,
+    icon: Option<String>,
+    #[serde(default)]
+    role: Role,
+    #[serde(default = "active_default")]
+    active: bool,
+    #[serde(default)]
+    must_change_password: bool,
+    /// the groups of the user, e.g. for station grants
+    #[serde(default)]
+    groups: Vec<String>,
+    created_at: Option<Datetime>,
+}
+
+/// the account before and after a update
+type Update = (Option<Account>, Option<Account>);
+
+/// records the update and responds with the account or 404 if it does not exist
+async fn account_response(
+    audit: &Audit,
+    db: &Surreal<Client>,
+    action: &str,
+    update: Result<Update, surrealdb::Error>,
+) -> HttpResponse {
+    match update {
+        Ok((Some(before), Some(after))) => {
+            audit
+                .record(db, action, Some(&after.id), &before, &after)
+                .await;
+            HttpResponse::Ok().json(after)
+        }
+        Ok(_) => HttpResponse::NotFound().body("User not found"),
+        Err(err) => HttpResponse::InternalServerError().body(err.to_string()),
+    }
+}
+
+/// returns true if a user with the email exists
+async fn email_exists(db: &Surreal<Client>, email: &str) -> Result<bool, surrealdb::Error> {
+    let existing: Option<Thing> = db
+        .query("SELECT VALUE id FROM user WHERE string::lowercase(email) = $email LIMIT 1")
+        .bind(("email", email))
+        .await?
+        .take(0)?;
+    Ok(existing.is_some())
+}
+
+/// generates a random temporary password which satisfies the policy
+fn generate_password(policy: &PasswordPolicy) -> String {
+    loop {
+        let mut password: String = rand::thread_rng()
+            .sample_iter(&Alphanumeric)
+            .take(policy.min_length.max(16))
+            .map(char::from)
+            .collect();
+        if policy.require_symbol {
+            password.push('#');
+        }
+        if policy.validate(&password, "").is_ok() {
+            return password;
+        }
+    }
+}
+
+/// helper struct to deserialize the listing filters
+#[derive(Deserialize)]
+pub struct UserQuery {
+    role: Option<Role>,
+    active: Option<bool>,
+    /// case insensitive search in name and email
+    search: Option<String>,
+}
+
+/// endpoint to list all users, optionally filtered by role, state or a search term
+#[get("/users", wrap = "RequireRole(Role::Admin)")]
+async fn get_users(query: web::Query<UserQuery>, db: web::Data<Surreal<Client>>) -> HttpResponse {
+    let mut conditions = Vec::new();
+    if query.role.is_some() {
+        conditions.push("role = $role");
+    }
+    if let Some(active) = query.active {
+        conditions.push(if active {
+            "active != false"
+        } else {
+            "active = false"
+        });
+    }
+    let search = query
+        .search
+        .as_deref()
+        .map(str::trim)
+        .filter(|search| !search.is_empty())
+        .map(str::to_lowercase);
+    if search.is_some() {
+        conditions.push(
+            "(string::lowercase(name) CONTAINS $search OR string::lowercase(email) CONTAINS $search)",
+        );
+    }
+
+    let mut sql = "SELECT * FROM user".to_owned();
+    if !conditions.is_empty() {
+        sql.push_str(" WHERE ");
+        sql.push_str(&conditions.join(" AND "));
+    }
+    sql.push_str(" ORDER BY email");
+
+    let accounts: Result<Vec<Account>, surrealdb::Error> = async {
+        db.query(sql)
+            .bind(("role", query.role))
+            .bind(("search", search))
+            .await?
+            .take(0)
+    }
+    .await;
+    match accounts {
+        Ok(accounts) => HttpResponse::Ok().json(accounts),
+        Err(err) => HttpResponse::InternalServerError().body(err.to_string()),
+    }
+}
+
+/// helper struct to deserialize the payload to create a user.
+/// Without a password the user is invited with a temporary password
+#[derive(Deserialize)]
+pub struct CreateUser {
+    email: String,
+    name: String,
+    #[serde(default)]
+    role: Role,
+    password: Option<String>,
+}
+
+/// helper struct to serialize a created user with its temporary password
+#[derive(Serialize)]
+struct CreatedUser {
+    #[serde(flatten)]
+    account: Account,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    temporary_password: Option<String>,
+}
+
+/// endpoint to create or invite a user, the email needs to be unique
+#[post("/users", wrap = "RequireRole(Role::Admin)")]
+async fn create_user(
+    json: web::Json<CreateUser>,
+    audit: Audit,
+    app_state: web::Data<AppState>,
+    db: web::Data<Surreal<Client>>,
+) -> HttpResponse {
+    let json = json.into_inner();
+    let email = json.email.trim().to_lowercase();
+    let name = json.name.trim().to_owned();
+    if !email.contains('@') || email.starts_with('@') || email.ends_with('@') {
+        return HttpResponse::BadRequest().body("Invalid email address");
+    }
+    if name.is_empty() {
+        return HttpResponse::BadRequest().body("The name must not be empty");
+    }
+    match email_exists(&db, &email).await {
+        Ok(false) => {}
+        Ok(true) => {
+            return HttpResponse::Conflict()
+                .body(format!("A user with the email {email} already exists"))
+        }
+        Err(err) => return HttpResponse::InternalServerError().body(err.to_string()),
+    }
+
+    let (password, temporary_password) = match json.password {
+        Some(password) => {
+            if let Err(message) = app_state.password_policy.validate(&password, &email) {
+                return HttpResponse::BadRequest().body(message);
+            }
+            (password, None)
+        }
+        None => {
+            let password = generate_password(&app_state.password_policy);
+            (password.clone(), Some(password))
+        }
+    };
+
+    let created: Result<Option<Account>, surrealdb::Error> = async {
+        db.query("CREATE user SET email = $email, name = $name, role = $role, password = crypto::argon2::generate($password), active = true, must_change_password = $temporary, created_at = time::now()")
+            .bind(("email", &email))
+            .bind(("name", name))
+            .bind(("role", json.role))
+            .bind(("password", password))
+            .bind(("temporary", temporary_password.is_some()))
+            .await?
+            .take(0)
+    }
+    .await;
+    match created {
+        Ok(Some(account)) => {
+            audit
+                .record(&db, "user.create", Some(&account.id), (), &account)
+                .await;
+            HttpResponse::Created().json(CreatedUser {
+                account,
+                temporary_password,
+            })
+        }
+        Ok(None) => HttpResponse::InternalServerError().body("User was not created"),
+        Err(err) => HttpResponse::InternalServerError().body(err.to_string()),
+    }
+}
+
+/// helper struct to deserialize the payload to update a user, missing fields stay untouched
+#[derive(Deserialize)]
+pub struct UpdateUser {
+    name: Option<String>,
+    role: Option<Role>,
+    groups: Option<Vec<String>>,
+}
+
+/// endpoint to rename a user or change its role or groups.
+/// Changing the role revokes the sessions of the user, so it signs in with the new role.
+/// The groups of external users are replaced on their next sign in.
+/// Admins can't demote themselves, so there is always at least one admin
+#[patch("/user/{user}", wrap = "RequireRole(Role::Admin)")]
+async fn update_user(
+    user_id: web::Path<String>,
+    json: web::Json<UpdateUser>,
+    claims: Claims,
+    audit: Audit,
+    db: web::Data<Surreal<Client>>,
+) -> HttpResponse {
+    let user = Thing::from(("user", user_id.as_str()));
+    let json = json.into_inner();
+
+    let mut changes = serde_json::Map::new();
+    if let Some(name) = json.name {
+        if name.trim().is_empty() {
+            return HttpResponse::BadRequest().body("The name must not be empty");
+        }
+        changes.insert("name".to_owned(), name.trim().into());
+    }
+    if let Some(role) = json.role {
+        if user.to_string() == claims.sub && role < Role::Admin {
+            return HttpResponse::BadRequest().body("You can't remove your own admin role");
+        }
+        changes.insert("role".to_owned(), role.to_string().into());
+    }
+    if let Some(groups) = json.groups {
+        let groups: Vec<String> = groups
+            .iter()
+            .map(|group| group.trim().to_owned())
+            .filter(|group| !group.is_empty())
+            .collect();
+        changes.insert("groups".to_owned(), groups.into());
+    }
+
+    let update = async {
+        let mut response = db
+            .query("SELECT * FROM $user; UPDATE $user MERGE $changes WHERE id = $user")
+            .bind(("user", &user))
+            .bind(("changes", changes))
+            .await?;
+        let update: Update = (response.take(0)?, response.take(1)?);
+        if let (Some(before), Some(after)) = &update {
+            if before.role != after.role {
+                crate::app::session::revoke_all(&db, &user, "role changed").await?;
+            }
+        }
+        Ok(update)
+    }
+    .await;
+    account_response(&audit, &db, "user.update", update).await
+}
+
+/// endpoint to deactivate a user, all of its sessions are revoked
+#[post("/user/{user}/deactivate", wrap = "RequireRole(Role::Admin)")]
+async fn deactivate_user(
+    user_id: web::Path<String>,
+    claims: Claims,
+    audit: Audit,
+    db: web::Data<Surreal<Client>>,
+) -> HttpResponse {
+    let user = Thing::from(("user", user_id.as_str()));
+    if user.to_string() == claims.sub {
+        return HttpResponse::BadRequest().body("You can't deactivate your own account");
+    }
+    let update = async {
+        let mut response = db
+            .query("SELECT * FROM $user; UPDATE $user SET active = false WHERE id = $user")
+            .bind(("user", &user))
+            .await?;
+        let update: Update = (response.take(0)?, response.take(1)?);
+        if update.1.is_some() {
+            crate::app::session::revoke_all(&db, &user, "account deactivated").await?;
+        }
+        Ok(update)
+    }
+    .await;
+    account_response(&audit, &db, "user.deactivate", update).await
+}
+
+/// endpoint to reactivate a deactivated user
+#[post("/user/{user}/reactivate", wrap = "RequireRole(Role::Admin)")]
+async fn reactivate_user(
+    user_id: web::Path<String>,
+    audit: Audit,
+    db: web::Data<Surreal<Client>>,
+) -> HttpResponse {
+    let user = Thing::from(("user", user_id.as_str()));
+    let update = async {
+        let mut response = db
+            .query("SELECT * FROM $user; UPDATE $user SET active = true WHERE id = $user")
+            .bind(("user", user))
+            .await?;
+        Ok((response.take(0)?, response.take(1)?))
+    }
+    .await;
+    account_response(&audit, &db, "user.reactivate", update).await
+}
+
+/// helper struct to serialize the temporary password of a forced reset
+#[derive(Serialize)]
+struct PasswordReset {
+    temporary_password: String,
+}
+
+/// endpoint to force a password reset. The user gets a temporary password,
+/// which has to be changed after the next sign in, all sessions are revoked
+#[post("/user/{user}/password-reset", wrap = "RequireRole(Role::Admin)")]
+async fn reset_password(
+    user_id: web::Path<String>,
+    audit: Audit,
+    app_state: web::Data<AppState>,
+    db: web::Data<Surreal<Client>>,
+) -> HttpResponse {
+    let user = Thing::from(("user", user_id.as_str()));
+    let temporary_password = generate_password(&app_state.password_policy);
+
+    let reset: Result<Update, surrealdb::Error> = async {
+        let mut response = db
+            .query("SELECT * FROM $user; UPDATE $user SET password = crypto::argon2::generate($password), must_change_password = true WHERE id = $user")
+            .bind(("user", &user))
+            .bind(("password", &temporary_password))
+            .await?;
+        let update: Update = (response.take(0)?, response.take(1)?);
+        if update.1.is_some() {
+            crate::app::session::revoke_all(&db, &user, "password reset").await?;
+        }
+        Ok(update)
+    }
+    .await;
+    match reset {
+        Ok((Some(before), Some(after))) => {
+            audit
+                .record(&db, "user.password_reset", Some(&after.id), &before, &after)
+                .await;
+            HttpResponse::Ok().json(PasswordReset { temporary_password })
+        }
+        Ok(_) => HttpResponse::NotFound().body("User not found"),
+        Err(err) => HttpResponse::InternalServerError().body(err.to_string()),
+    }
+}
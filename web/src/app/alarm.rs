@@ -0,0 +1,481 @@
+//! # web::alarm
+//!
+//! `web::alarm` is a module listing the alarms of the MHubX together with the local alarms
+//! in the model of `common::alarm`. The alarms are filtered, sorted and paged as one list.
+//! Only alarms of stations the caller may view are listed.
+//! If the MHubX is unavailable, the local alarms are listed and `mhubx` is `UNAVAILABLE`,
+//! otherwise it shows how the MHubX alarms were served, see `web::api`.
+//! Shelved alarms are hidden unless `include_shelved` is set.
+//!
+//! Operators who may operate the station of a local alarm acknowledge it, shelve it for a while
+//! and comment it. Acknowledging a alarm of `comment_required_severity` or above needs a comment.
+//! Every change is kept in the history of the alarm, `/alarm-history` lists the changes of
+//! all alarms of a shift for the handover. Acknowledging a alarm stops its escalation.
+//!
+//! # Example
+//!
+//! ```text
+//! GET /api/v1/alarms?state=active&min_severity=2&sort=severity&offset=50&limit=50
+//! GET /api/v1/alarms?source=local&station=palettenlager&from=2023-05-01T00:00:00Z
+//! POST /api/v1/alarm/8wnm3q2d9sxqp7ax3rkc/acknowledge {"comment": "Presse steht, Instandhaltung informiert"}
+//! POST /api/v1/alarm/8wnm3q2d9sxqp7ax3rkc/shelve {"minutes": 60, "comment": "Wartung"}
+//! GET /api/v1/alarm-history?from=2023-05-01T06:00:00Z&to=2023-05-01T14:00:00Z
+//! ```
+
+use actix_web::{delete, get, post, web, HttpResponse};
+use chrono::{DateTime, Utc};
+use common::{
+    alarm::{
+        Actor, Alarm, AlarmFilter, AlarmHistory, AlarmSort, AlarmSource, AlarmState, HistoryAction,
+        LocalAlarm,
+    },
+    mhubx::{AlarmQuery, MhubxCache},
+};
+use serde::{Deserialize, Serialize};
+use surrealdb::{engine::remote::ws::Client, Surreal};
+
+use crate::{
+    app::{
+        acl::{station_thing, Access, StationPermission},
+        audit::Audit,
+    },
+    auth::{Claims, Role},
+    config::AppState,
+    middleware::role::RequireRole,
+};
+
+/// the maximum number of alarms of a page
+const MAX_LIMIT: usize = 1000;
+
+/// the maximum offset of a page
+const MAX_OFFSET: usize = 1_000_000;
+
+/// helper struct to deserialize the alarm query, from and to limit the time it was raised
+#[derive(Deserialize)]
+pub struct AlarmsQuery {
+    source: Option<AlarmSource>,
+    station: Option<String>,
+    sensor: Option<String>,
+    state: Option<AlarmState>,
+    min_severity: Option<u32>,
+    max_severity: Option<u32>,
+    from: Option<chrono::DateTime<chrono::Utc>>,
+    to: Option<chrono::DateTime<chrono::Utc>>,
+    #[serde(default)]
+    include_shelved: bool,
+    #[serde(default)]
+    sort: AlarmSort,
+    #[serde(default)]
+    offset: usize,
+    #[serde(default = "default_limit")]
+    limit: usize,
+}
+
+fn default_limit() -> usize {
+    100
+}
+
+/// A page of alarms, total is the number of alarms of the filter
+#[derive(Serialize)]
+pub struct AlarmPage {
+    total: usize,
+    offset: usize,
+    limit: usize,
+    /// how the MHubX alarms were served, None if they weren't asked for
+    mhubx: Option<&'static str>,
+    alarms: Vec<Alarm>,
+}
+
+/// endpoint to list the alarms of all sources
+#[get("/alarms")]
+async fn get_alarms(
+    query: web::Query<AlarmsQuery>,
+    access: Access,
+    mhubx: web::Data<MhubxCache>,
+    db: web::Data<Surreal<Client>>,
+) -> HttpResponse {
+    let query = query.into_inner();
+    if let (Some(from), Some(to)) = (query.from, query.to) {
+        if from > to {
+            return HttpResponse::BadRequest().body("from needs to be before to");
+        }
+    }
+    if let (Some(min), Some(max)) = (query.min_severity, query.max_severity) {
+        if min > max {
+            return HttpResponse::BadRequest()
+                .body("min_severity needs to be less than max_severity");
+        }
+    }
+    if query.offset > MAX_OFFSET {
+        return HttpResponse::BadRequest()
+            .body(format!("The offset needs to be at most {MAX_OFFSET}"));
+    }
+    let limit = query.limit.min(MAX_LIMIT);
+    let filter = AlarmFilter {
+        shelved: (!query.include_shelved).then_some(false),
+        source: query.source,
+        station: query.station,
+        sensor: query.sensor,
+        state: query.state,
+        min_severity: query.min_severity,
+        max_severity: query.max_severity,
+        from: query.from,
+        to: query.to,
+    };
+    let visible = |alarm: &Alarm| {
+        alarm
+            .station
+            .as_deref()
+            .is_none_or(|station| access.permits(&station_thing(station), StationPermission::View))
+    };
+
+    // the local alarms before the page are needed to merge them with the MHubX alarms,
+    // all of them if some are hidden from the caller
+    let restricted = access.is_restricted();
+    let window = (!restricted).then_some(query.offset + limit);
+    let (mut alarms, mut total) = match Alarm::get_local(&db, &filter, query.sort, window).await {
+        Ok(local) => local,
+        Err(err) => return HttpResponse::InternalServerError().body(err.to_string()),
+    };
+    if restricted {
+        alarms.retain(visible);
+        total = alarms.len();
+    }
+
+    let mut status = None;
+    if filter.source != Some(AlarmSource::Local) {
+        let mhubx_query = AlarmQuery {
+            system_id: filter.station.clone().unwrap_or_else(|| "*".to_owned()),
+            from: filter.from,
+            to: filter.to,
+            min_severity: filter.min_severity,
+            max_severity: filter.max_severity,
+        };
+        match mhubx.alarms(&mhubx_query).await {
+            Ok(cached) => {
+                let received_at = chrono::Utc::now()
+                    - chrono::Duration::from_std(cached.age)
+                        .unwrap_or_else(|_| chrono::Duration::zero());
+                let before = alarms.len();
+                alarms.extend(
+                    cached
+                        .data
+                        .into_iter()
+                        .map(|alarm| Alarm::from_mhubx(alarm, received_at))
+                        .filter(|alarm| filter.matches(alarm) && visible(alarm)),
+                );
+                total += alarms.len() - before;
+                status = Some(cached.status.as_str());
+            }
+            Err(err) => {
+                eprintln!("MHubX alarms unavailable: {err}");
+                status = Some("UNAVAILABLE");
+            }
+        }
+    }
+
+    query.sort.sort(&mut alarms);
+    HttpResponse::Ok().json(AlarmPage {
+        total,
+        offset: query.offset,
+        limit,
+        mhubx: status,
+        alarms: alarms.into_iter().skip(query.offset).take(limit).collect(),
+    })
+}
+
+/// the actor of the caller, tokens of unknown subjects are rejected
+fn actor(claims: &Claims) -> Result<Actor, HttpResponse> {
+    match surrealdb::sql::thing(&claims.sub) {
+        Ok(id) => Ok(Actor {
+            id,
+            name: claims.name.clone(),
+        }),
+        Err(_) => Err(HttpResponse::Unauthorized().body("Unauthorized")),
+    }
+}
+
+/// responds with 404 unless the local alarm exists and the caller has the permission on its station.
+/// The id is accepted with and without the table
+pub async fn check_alarm(
+    db: &Surreal<Client>,
+    access: &Access,
+    alarm: &str,
+    permission: StationPermission,
+) -> Result<LocalAlarm, HttpResponse> {
+    let id = alarm.strip_prefix("alarm:").unwrap_or(alarm);
+    match LocalAlarm::get(db, id).await {
+        Ok(Some(alarm))
+            if alarm
+                .station
+                .as_ref()
+                .is_none_or(|station| access.permits(station, permission)) =>
+        {
+            Ok(alarm)
+        }
+        Ok(_) => Err(HttpResponse::NotFound().body("Alarm not found")),
+        Err(err) => Err(HttpResponse::InternalServerError().body(err.to_string())),
+    }
+}
+
+/// the trimmed comment, None if it is empty
+fn comment(comment: Option<String>) -> Option<String> {
+    comment
+        .map(|comment| comment.trim().to_owned())
+        .filter(|comment| !comment.is_empty())
+}
+
+/// helper struct to deserialize the payload to acknowledge a alarm
+#[derive(Deserialize)]
+pub struct Acknowledge {
+    comment: Option<String>,
+}
+
+/// endpoint to acknowledge a active alarm
+#[post("/alarm/{alarm}/acknowledge", wrap = "RequireRole(Role::Operator)")]
+async fn acknowledge_alarm(
+    alarm_id: web::Path<String>,
+    json: web::Json<Acknowledge>,
+    claims: Claims,
+    access: Access,
+    audit: Audit,
+    app_state: web::Data<AppState>,
+    db: web::Data<Surreal<Client>>,
+) -> HttpResponse {
+    let actor = match actor(&claims) {
+        Ok(actor) => actor,
+        Err(response) => return response,
+    };
+    let alarm = match check_alarm(&db, &access, &alarm_id, StationPermission::Operate).await {
+        Ok(alarm) => alarm,
+        Err(response) => return response,
+    };
+    if alarm.state != AlarmState::Active {
+        return HttpResponse::Conflict().body("Only active alarms can be acknowledged");
+    }
+    let comment = comment(json.into_inner().comment);
+    if comment.is_none() && alarm.severity >= app_state.alarms.comment_required_severity {
+        return HttpResponse::BadRequest().body(format!(
+            "Acknowledging alarms of severity {} and above needs a comment",
+            app_state.alarms.comment_required_severity
+        ));
+    }
+
+    match alarm.acknowledge(&db, &actor, comment.as_deref()).await {
+        Ok(Some(acknowledged)) => {
+            audit
+                .record(
+                    &db,
+                    "alarm.acknowledge",
+                    Some(&alarm.id),
+                    &alarm,
+                    &acknowledged,
+                )
+                .await;
+            if let Err(err) =
+                crate::notify::escalation::stop(&db, &alarm.id, "acknowledged", Some(&actor.id))
+                    .await
+            {
+                eprintln!("unable to stop the escalation of {}: {err}", alarm.id);
+            }
+            HttpResponse::Ok().json(Alarm::from(acknowledged))
+        }
+        Ok(None) => HttpResponse::Conflict().body("Only active alarms can be acknowledged"),
+        Err(err) => HttpResponse::InternalServerError().body(err.to_string()),
+    }
+}
+
+/// helper struct to deserialize the payload to shelve a alarm for some minutes
+#[derive(Deserialize)]
+pub struct Shelve {
+    minutes: u64,
+    comment: Option<String>,
+}
+
+/// endpoint to shelve a alarm, hiding it from the alarm list for a while
+#[post("/alarm/{alarm}/shelve", wrap = "RequireRole(Role::Operator)")]
+async fn shelve_alarm(
+    alarm_id: web::Path<String>,
+    json: web::Json<Shelve>,
+    claims: Claims,
+    access: Access,
+    audit: Audit,
+    app_state: web::Data<AppState>,
+    db: web::Data<Surreal<Client>>,
+) -> HttpResponse {
+    let actor = match actor(&claims) {
+        Ok(actor) => actor,
+        Err(response) => return response,
+    };
+    let json = json.into_inner();
+    let max_minutes = app_state.alarms.max_shelve_minutes;
+    if json.minutes == 0 || json.minutes > max_minutes {
+        return HttpResponse::BadRequest()
+            .body(format!("minutes needs to be between 1 and {max_minutes}"));
+    }
+    let alarm = match check_alarm(&db, &access, &alarm_id, StationPermission::Operate).await {
+        Ok(alarm) => alarm,
+        Err(response) => return response,
+    };
+    if alarm.state == AlarmState::Cleared {
+        return HttpResponse::Conflict().body("Cleared alarms can't be shelved");
+    }
+
+    let until = Utc::now() + chrono::Duration::minutes(json.minutes as i64);
+    let comment = comment(json.comment);
+    match alarm.shelve(&db, until, &actor, comment.as_deref()).await {
+        Ok(Some(shelved)) => {
+            audit
+                .record(&db, "alarm.shelve", Some(&alarm.id), &alarm, &shelved)
+                .await;
+            HttpResponse::Ok().json(Alarm::from(shelved))
+        }
+        Ok(None) => HttpResponse::Conflict().body("Cleared alarms can't be shelved"),
+        Err(err) => HttpResponse::InternalServerError().body(err.to_string()),
+    }
+}
+
+/// endpoint to end the shelving of a alarm before its time
+#[delete("/alarm/{alarm}/shelve", wrap = "RequireRole(Role::Operator)")]
+async fn unshelve_alarm(
+    alarm_id: web::Path<String>,
+    claims: Claims,
+    access: Access,
+    audit: Audit,
+    db: web::Data<Surreal<Client>>,
+) -> HttpResponse {
+    let actor = match actor(&claims) {
+        Ok(actor) => actor,
+        Err(response) => return response,
+    };
+    let alarm = match check_alarm(&db, &access, &alarm_id, StationPermission::Operate).await {
+        Ok(alarm) => alarm,
+        Err(response) => return response,
+    };
+
+    match alarm.unshelve(&db, &actor, None).await {
+        Ok(Some(unshelved)) => {
+            audit
+                .record(&db, "alarm.unshelve", Some(&alarm.id), &alarm, &unshelved)
+                .await;
+            HttpResponse::Ok().json(Alarm::from(unshelved))
+        }
+        Ok(None) => HttpResponse::Conflict().body("Alarm is not shelved"),
+        Err(err) => HttpResponse::InternalServerError().body(err.to_string()),
+    }
+}
+
+/// helper struct to deserialize the payload to comment a alarm
+#[derive(Deserialize)]
+pub struct CreateComment {
+    comment: String,
+}
+
+/// endpoint to add a comment to the history of a alarm
+#[post("/alarm/{alarm}/comments", wrap = "RequireRole(Role::Operator)")]
+async fn create_alarm_comment(
+    alarm_id: web::Path<String>,
+    json: web::Json<CreateComment>,
+    claims: Claims,
+    access: Access,
+    audit: Audit,
+    db: web::Data<Surreal<Client>>,
+) -> HttpResponse {
+    let actor = match actor(&claims) {
+        Ok(actor) => actor,
+        Err(response) => return response,
+    };
+    let Some(comment) = comment(Some(json.into_inner().comment)) else {
+        return HttpResponse::BadRequest().body("comment can't be empty");
+    };
+    let alarm = match check_alarm(&db, &access, &alarm_id, StationPermission::Operate).await {
+        Ok(alarm) => alarm,
+        Err(response) => return response,
+    };
+
+    let created = AlarmHistory::record(
+        &db,
+        &alarm,
+        HistoryAction::Commented,
+        Some(&actor),
+        Some(&comment),
+    )
+    .await;
+    match created {
+        Ok(Some(entry)) => {
+            audit
+                .record(&db, "alarm.comment", Some(&alarm.id), (), &entry)
+                .await;
+            HttpResponse::Created().json(entry)
+        }
+        Ok(None) => HttpResponse::InternalServerError().body("Comment was not created"),
+        Err(err) => HttpResponse::InternalServerError().body(err.to_string()),
+    }
+}
+
+/// endpoint to list the history of a alarm, the oldest entry first
+#[get("/alarm/{alarm}/history")]
+async fn get_alarm_history(
+    alarm_id: web::Path<String>,
+    access: Access,
+    db: web::Data<Surreal<Client>>,
+) -> HttpResponse {
+    let alarm = match check_alarm(&db, &access, &alarm_id, StationPermission::View).await {
+        Ok(alarm) => alarm,
+        Err(response) => return response,
+    };
+    match AlarmHistory::get_by_alarm(&db, &alarm.id).await {
+        Ok(history) => HttpResponse::Ok().json(history),
+        Err(err) => HttpResponse::InternalServerError().body(err.to_string()),
+    }
+}
+
+/// helper struct to deserialize the handover query, to defaults to now
+#[derive(Deserialize)]
+pub struct HandoverQuery {
+    from: DateTime<Utc>,
+    to: Option<DateTime<Utc>>,
+    station: Option<String>,
+    #[serde(default = "default_limit")]
+    limit: usize,
+}
+
+/// endpoint to list the changes of all alarms of a shift, the newest change first
+#[get("/alarm-history")]
+async fn get_handover(
+    query: web::Query<HandoverQuery>,
+    access: Access,
+    db: web::Data<Surreal<Client>>,
+) -> HttpResponse {
+    let query = query.into_inner();
+    let to = query.to.unwrap_or_else(Utc::now);
+    if query.from > to {
+        return HttpResponse::BadRequest().body("from needs to be before to");
+    }
+    let station = query.station.as_deref().map(station_thing);
+    if let Some(station) = &station {
+        if !access.permits(station, StationPermission::View) {
+            return HttpResponse::NotFound().body("Station not found");
+        }
+    }
+
+    // all entries are needed to limit them after hiding those of stations hidden from the caller
+    let limit = query.limit.min(MAX_LIMIT);
+    let restricted = access.is_restricted();
+    let window = (!restricted).then_some(limit);
+    match AlarmHistory::get_between(&db, query.from, to, station.as_ref(), window).await {
+        Ok(mut history) => {
+            if restricted {
+                history.retain(|entry| {
+                    entry
+                        .station
+                        .as_ref()
+                        .is_none_or(|station| access.permits(station, StationPermission::View))
+                });
+                history.truncate(limit);
+            }
+            HttpResponse::Ok().json(history)
+        }
+        Err(err) => HttpResponse::InternalServerError().body(err.to_string()),
+    }
+}
@@ -0,0 +1,264 @@
+//! # web::alarm_rule
+//!
+//! `web::alarm_rule` is a module to manage the alarm rules of sensors, see `common::rules`.
+//! Maintainers who may manage the station of a sensor define its rules.
+//! Changing a rule restarts its delays, deleting a rule clears its open alarm.
+//!
+//! # Example
+//! Raising a alarm once the pressure stays above 120 bar for 10 seconds, cleared below 115 bar
+//!
+//! ```text
+//! POST /api/v1/sensor/presse_druck/alarm-rules
+//! {"name": "Pressendruck hoch", "severity": 3, "delay_on_seconds": 10,
+//!  "condition": {"kind": "high", "limit": 120, "hysteresis": 5}}
+//! ```
+
+use std::time::Duration;
+
+use actix_web::{delete, get, post, put, web, HttpResponse};
+use common::rules::{AlarmRule, RuleCondition, MAX_DELAY_SECONDS};
+use serde::Deserialize;
+use surrealdb::{engine::remote::ws::Client, sql::Thing, Surreal};
+
+use crate::{
+    app::{
+        acl::{Access, StationPermission},
+        audit::Audit,
+    },
+    auth::Role,
+    middleware::role::RequireRole,
+};
+
+/// the interval at which stale data rules are evaluated
+const STALE_CHECK_INTERVAL: Duration = Duration::from_secs(30);
+
+/// Evaluates the stale data rules until the server stops
+pub async fn watch_stale(db: Surreal<Client>) {
+    let mut ticks = tokio::time::interval(STALE_CHECK_INTERVAL);
+    ticks.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+    loop {
+        ticks.tick().await;
+        if let Err(err) = common::rules::check_stale(&db).await {
+            eprintln!("stale data rules failed: {err}");
+        }
+    }
+}
+
+/// responds with 404 unless the sensor exists and the caller has the permission on its station
+async fn check_sensor(
+    db: &Surreal<Client>,
+    access: &Access,
+    sensor: &str,
+    permission: StationPermission,
+) -> Result<Thing, HttpResponse> {
+    match common::Sensor::get(db, sensor.to_owned()).await {
+        Ok(Some(sensor)) if access.permits(sensor.get_station(), permission) => {
+            Ok(sensor.get_id().clone())
+        }
+        Ok(_) => Err(HttpResponse::NotFound().body("Sensor not found")),
+        Err(err) => Err(HttpResponse::InternalServerError().body(err.to_string())),
+    }
+}
+
+/// returns the rule if it exists and the caller may manage the station of its sensor
+async fn find_rule(
+    db: &Surreal<Client>,
+    access: &Access,
+    id: &str,
+) -> Result<AlarmRule, HttpResponse> {
+    let rule: Option<AlarmRule> = match db.select(Thing::from(("alarm_rule", id))).await {
+        Ok(rule) => rule,
+        Err(err) => return Err(HttpResponse::InternalServerError().body(err.to_string())),
+    };
+    let Some(rule) = rule else {
+        return Err(HttpResponse::NotFound().body("Alarm rule not found"));
+    };
+    check_sensor(
+        db,
+        access,
+        &rule.sensor.id.to_raw(),
+        StationPermission::Manage,
+    )
+    .await
+    .map_err(|_| HttpResponse::NotFound().body("Alarm rule not found"))?;
+    Ok(rule)
+}
+
+/// helper struct to deserialize the payload to define a rule
+#[derive(Deserialize)]
+pub struct RulePayload {
+    name: String,
+    severity: u32,
+    condition: RuleCondition,
+    #[serde(default)]
+    delay_on_seconds: u64,
+    #[serde(default)]
+    delay_off_seconds: u64,
+    #[serde(default = "enabled")]
+    enabled: bool,
+}
+
+fn enabled() -> bool {
+    true
+}
+
+impl RulePayload {
+    /// trims the name and validates the condition and the delays
+    fn validate(mut self) -> Result<Self, HttpResponse> {
+        self.name = self.name.trim().to_owned();
+        if self.name.is_empty() {
+            return Err(HttpResponse::BadRequest().body("The name must not be empty"));
+        }
+        self.condition
+            .validate()
+            .map_err(|err| HttpResponse::BadRequest().body(err))?;
+        if self.delay_on_seconds.max(self.delay_off_seconds) > MAX_DELAY_SECONDS {
+            return Err(HttpResponse::BadRequest().body(format!(
+                "The delays must not be longer than {MAX_DELAY_SECONDS} seconds"
+            )));
+        }
+        Ok(self)
+    }
+}
+
+/// endpoint to list the rules of a sensor
+#[get("/sensor/{sensor}/alarm-rules")]
+async fn get_alarm_rules(
+    sensor_id: web::Path<String>,
+    access: Access,
+    db: web::Data<Surreal<Client>>,
+) -> HttpResponse {
+    let sensor = match check_sensor(&db, &access, &sensor_id, StationPermission::View).await {
+        Ok(sensor) => sensor,
+        Err(response) => return response,
+    };
+    let rules: Result<Vec<AlarmRule>, surrealdb::Error> = async {
+        db.query("SELECT * FROM alarm_rule WHERE sensor = $sensor ORDER BY name")
+            .bind(("sensor", sensor))
+            .await?
+            .take(0)
+    }
+    .await;
+    match rules {
+        Ok(rules) => HttpResponse::Ok().json(rules),
+        Err(err) => HttpResponse::InternalServerError().body(err.to_string()),
+    }
+}
+
+/// endpoint to define a rule of a sensor
+#[post("/sensor/{sensor}/alarm-rules", wrap = "RequireRole(Role::Maintainer)")]
+async fn create_alarm_rule(
+    sensor_id: web::Path<String>,
+    json: web::Json<RulePayload>,
+    access: Access,
+    audit: Audit,
+    db: web::Data<Surreal<Client>>,
+) -> HttpResponse {
+    let sensor = match check_sensor(&db, &access, &sensor_id, StationPermission::Manage).await {
+        Ok(sensor) => sensor,
+        Err(response) => return response,
+    };
+    let json = match json.into_inner().validate() {
+        Ok(json) => json,
+        Err(response) => return response,
+    };
+
+    let created: Result<Option<AlarmRule>, surrealdb::Error> = async {
+        db.query("CREATE alarm_rule SET sensor = $sensor, name = $name, severity = $severity, condition = $condition, delay_on_seconds = $delay_on_seconds, delay_off_seconds = $delay_off_seconds, enabled = $enabled")
+            .bind(("sensor", sensor))
+            .bind(("name", json.name))
+            .bind(("severity", json.severity))
+            .bind(("condition", json.condition))
+            .bind(("delay_on_seconds", json.delay_on_seconds))
+            .bind(("delay_off_seconds", json.delay_off_seconds))
+            .bind(("enabled", json.enabled))
+            .await?
+            .take(0)
+    }
+    .await;
+    match created {
+        Ok(Some(rule)) => {
+            audit
+                .record(&db, "alarm_rule.create", Some(&rule.id), (), &rule)
+                .await;
+            HttpResponse::Created().json(rule)
+        }
+        Ok(None) => HttpResponse::InternalServerError().body("Alarm rule was not created"),
+        Err(err) => HttpResponse::InternalServerError().body(err.to_string()),
+    }
+}
+
+/// endpoint to redefine a rule, its delays restart
+#[put("/alarm-rule/{rule}", wrap = "RequireRole(Role::Maintainer)")]
+async fn update_alarm_rule(
+    rule_id: web::Path<String>,
+    json: web::Json<RulePayload>,
+    access: Access,
+    audit: Audit,
+    db: web::Data<Surreal<Client>>,
+) -> HttpResponse {
+    let before = match find_rule(&db, &access, &rule_id).await {
+        Ok(rule) => rule,
+        Err(response) => return response,
+    };
+    let json = match json.into_inner().validate() {
+        Ok(json) => json,
+        Err(response) => return response,
+    };
+
+    let updated: Result<Option<AlarmRule>, surrealdb::Error> = async {
+        db.query("UPDATE $rule SET name = $name, severity = $severity, condition = $condition, delay_on_seconds = $delay_on_seconds, delay_off_seconds = $delay_off_seconds, enabled = $enabled, pending_since = NONE, clearing_since = NONE")
+            .bind(("rule", &before.id))
+            .bind(("name", json.name))
+            .bind(("severity", json.severity))
+            .bind(("condition", json.condition))
+            .bind(("delay_on_seconds", json.delay_on_seconds))
+            .bind(("delay_off_seconds", json.delay_off_seconds))
+            .bind(("enabled", json.enabled))
+            .await?
+            .take(0)
+    }
+    .await;
+    match updated {
+        Ok(Some(rule)) => {
+            audit
+                .record(&db, "alarm_rule.update", Some(&rule.id), &before, &rule)
+                .await;
+            HttpResponse::Ok().json(rule)
+        }
+        Ok(None) => HttpResponse::NotFound().body("Alarm rule not found"),
+        Err(err) => HttpResponse::InternalServerError().body(err.to_string()),
+    }
+}
+
+/// endpoint to delete a rule, its open alarm is cleared
+#[delete("/alarm-rule/{rule}", wrap = "RequireRole(Role::Maintainer)")]
+async fn delete_alarm_rule(
+    rule_id: web::Path<String>,
+    access: Access,
+    audit: Audit,
+    db: web::Data<Surreal<Client>>,
+) -> HttpResponse {
+    let rule = match find_rule(&db, &access, &rule_id).await {
+        Ok(rule) => rule,
+        Err(response) => return response,
+    };
+
+    let deleted: Result<(), surrealdb::Error> = async {
+        if let Some(alarm) = rule.open_alarm(&db).await? {
+            common::rules::clear(&db, &alarm.id, chrono::Utc::now()).await?;
+        }
+        let _: Option<AlarmRule> = db.delete(rule.id.clone()).await?;
+        Ok(())
+    }
+    .await;
+    match deleted {
+        Ok(()) => {
+            audit
+                .record(&db, "alarm_rule.delete", Some(&rule.id), &rule, ())
+                .await;
+            HttpResponse::NoContent().finish()
+        }
+        Err(err) => HttpResponse::InternalServerError().body(err.to_string()),
+    }
+}
@@ -0,0 +1,196 @@
+//! # web::api_key
+//!
+//! `web::api_key` is a module to manage API keys of machine-to-machine clients, e.g. the MES.
+//! A key is only shown once on creation and stored as sha256 hash.
+//! It is sent in the `X-API-Key` header instead of a Bearer JWT.
+//! Keys without the admin scope can only use routes declaring one of their scopes
+//! with the RequireScope middleware, e.g. the sensor reads and the import.
+//!
+//! # Example
+//!
+//! ```text
+//! POST /api/v1/api-keys {"name": "mes", "scopes": ["read_sensors"], "expires_at": "2024-01-01T00:00:00Z"}
+//! > curl -H "X-API-Key: i40_3fa9c2d1_..." http://127.0.0.1:8080/api/v1/stations
+//! ```
+
+use actix_web::{delete, get, post, web, HttpResponse};
+use rand::RngCore;
+use serde::{Deserialize, Serialize};
+use surrealdb::{
+    engine::remote::ws::Client,
+    sql::{Datetime, Thing},
+    Surreal,
+};
+
+use crate::{
+    app::audit::Audit,
+    auth::{ApiScope, Claims, Role},
+    middleware::role::RequireRole,
+};
+
+/// the header API keys are sent in
+pub const API_KEY_HEADER: &str = "X-API-Key";
+
+/// A API key, without the key itself
+#[derive(Debug, Serialize, Deserialize)]
+pub struct ApiKey {
+    id: Thing,
+    name: String,
+    /// the public part of the key to recognize it
+    prefix: String,
+    scopes: Vec<ApiScope>,
+    expires_at: Option<Datetime>,
+    last_used_at: Option<Datetime>,
+    created_at: Datetime,
+    created_by: Option<Thing>,
+    revoked_at: Option<Datetime>,
+}
+
+impl ApiKey {
+    /// Returns the claims of requests authorized by this key, expiring with the key
+    pub fn claims(&self) -> Claims {
+        let now = chrono::Utc::now();
+        Claims {
+            sub: self.id.to_string(),
+            sid: self.id.to_string(),
+            name: self.name.clone(),
+            email: String::new(),
+            role: ApiScope::role(&self.scopes),
+            iat: now.timestamp() as usize,
+            exp: self
+                .expires_at
+                .as_ref()
+                .map(|expires_at| expires_at.0.timestamp() as usize),
+            scopes: Some(self.scopes.clone()),
+            must_change_password: false,
+        }
+    }
+}
+
+/// returns a random hex string of the given number of bytes
+fn random_hex(bytes: usize) -> String {
+    let mut buffer = vec![0u8; bytes];
+    rand::thread_rng().fill_bytes(&mut buffer);
+    buffer.iter().map(|byte| format!("{byte:02x}")).collect()
+}
+
+/// Returns the key if it is neither revoked nor expired and updates its last usage
+pub async fn authenticate(
+    db: &Surreal<Client>,
+    key: &str,
+) -> Result<Option<ApiKey>, surrealdb::Error> {
+    db.query("UPDATE api_key SET last_used_at = time::now() WHERE key_hash = crypto::sha256($key) AND revoked_at = NONE AND (expires_at = NONE OR expires_at > time::now())")
+        .bind(("key", key))
+        .await?
+        .take(0)
+}
+
+/// endpoint to list all API keys
+#[get("/api-keys", wrap = "RequireRole(Role::Admin)")]
+async fn get_api_keys(db: web::Data<Surreal<Client>>) -> HttpResponse {
+    let keys: Result<Vec<ApiKey>, surrealdb::Error> = async {
+        db.query("SELECT * FROM api_key ORDER BY created_at DESC")
+            .await?
+            .take(0)
+    }
+    .await;
+    match keys {
+        Ok(keys) => HttpResponse::Ok().json(keys),
+        Err(err) => HttpResponse::InternalServerError().body(err.to_string()),
+    }
+}
+
+/// helper struct to deserialize the payload to create a API key, without expiry it never expires
+#[derive(Deserialize)]
+pub struct CreateApiKey {
+    name: String,
+    scopes: Vec<ApiScope>,
+    expires_at: Option<chrono::DateTime<chrono::Utc>>,
+}
+
+/// helper struct to serialize a created API key with the key itself
+#[derive(Serialize)]
+struct CreatedApiKey {
+    #[serde(flatten)]
+    api_key: ApiKey,
+    key: String,
+}
+
+/// endpoint to create a API key, the key is only part of this response
+#[post("/api-keys", wrap = "RequireRole(Role::Admin)")]
+async fn create_api_key(
+    json: web::Json<CreateApiKey>,
+    claims: Claims,
+    audit: Audit,
+    db: web::Data<Surreal<Client>>,
+) -> HttpResponse {
+    let json = json.into_inner();
+    let name = json.name.trim().to_owned();
+    if name.is_empty() {
+        return HttpResponse::BadRequest().body("The name must not be empty");
+    }
+    if json.scopes.is_empty() {
+        return HttpResponse::BadRequest().body("A API key needs at least one scope");
+    }
+    if json
+        .expires_at
+        .is_some_and(|expires_at| expires_at <= chrono::Utc::now())
+    {
+        return HttpResponse::BadRequest().body("expires_at needs to be in the future");
+    }
+
+    let prefix = random_hex(4);
+    let key = format!("i40_{prefix}_{}", random_hex(24));
+    let created_by = surrealdb::sql::thing(&claims.sub).ok();
+
+    let created: Result<Option<ApiKey>, surrealdb::Error> = async {
+        db.query("CREATE api_key SET name = $name, prefix = $prefix, key_hash = crypto::sha256($key), scopes = $scopes, expires_at = $expires_at, created_at = time::now(), created_by = $created_by")
+            .bind(("name", name))
+            .bind(("prefix", prefix))
+            .bind(("key", &key))
+            .bind(("scopes", json.scopes))
+            .bind(("expires_at", json.expires_at.map(Datetime)))
+            .bind(("created_by", created_by))
+            .await?
+            .take(0)
+    }
+    .await;
+    match created {
+        Ok(Some(api_key)) => {
+            audit
+                .record(&db, "api_key.create", Some(&api_key.id), (), &api_key)
+                .await;
+            HttpResponse::Created().json(CreatedApiKey { api_key, key })
+        }
+        Ok(None) => HttpResponse::InternalServerError().body("API key was not created"),
+        Err(err) => HttpResponse::InternalServerError().body(err.to_string()),
+    }
+}
+
+/// endpoint to revoke a API key, it stays listed for traceability
+#[delete("/api-key/{api_key}", wrap = "RequireRole(Role::Admin)")]
+async fn revoke_api_key(
+    api_key_id: web::Path<String>,
+    audit: Audit,
+    db: web::Data<Surreal<Client>>,
+) -> HttpResponse {
+    let api_key = Thing::from(("api_key", api_key_id.as_str()));
+    let revoked: Result<(Option<ApiKey>, Option<ApiKey>), surrealdb::Error> = async {
+        let mut response = db
+            .query("SELECT * FROM $api_key; UPDATE $api_key SET revoked_at = time::now() WHERE id = $api_key AND revoked_at = NONE")
+            .bind(("api_key", &api_key))
+            .await?;
+        Ok((response.take(0)?, response.take(1)?))
+    }
+    .await;
+    match revoked {
+        Ok((Some(before), Some(after))) => {
+            audit
+                .record(&db, "api_key.revoke", Some(&api_key), &before, &after)
+                .await;
+            HttpResponse::NoContent().finish()
+        }
+        Ok(_) => HttpResponse::NotFound().body("API key not found or already revoked"),
+        Err(err) => HttpResponse::InternalServerError().body(err.to_string()),
+    }
+}
@@ -0,0 +1,387 @@
+//! # web::audit
+//!
+//! `web::audit` is a module to record who changed what. Every mutating endpoint records a
+//! `audit_event` with the actor of the request, the action, the target record, the changed
+//! fields with their values before and after, the client ip and the time.
+//!
+//! # Example
+//! Recording the rename of a station within a handler, the `Audit` extractor provides the actor.
+//!
+//! ```text
+//! audit.record(&db, "station.update", Some(station.get_id()), &before, &after).await;
+//!
+//! GET /api/v1/audit-events?action=station.&from=2023-05-01T00:00:00Z&format=csv
+//! ```
+
+use std::future::{ready, Ready};
+
+use actix_web::{
+    dev::Payload,
+    get,
+    http::header::{ContentDisposition, DispositionParam, DispositionType},
+    web, FromRequest, HttpMessage, HttpRequest, HttpResponse,
+};
+use serde::{Deserialize, Serialize};
+use serde_json::{Map, Value};
+use surrealdb::{
+    engine::remote::ws::Client,
+    sql::{Datetime, Thing},
+    Surreal,
+};
+
+use crate::{
+    auth::{Claims, Role},
+    config::AppState,
+    middleware::{rate_limit::client_ip, role::RequireRole},
+};
+
+/// fields which are never written into the audit log
+const REDACTED_FIELDS: [&str; 6] = [
+    "password",
+    "jwt",
+    "refresh_token",
+    "key",
+    "key_hash",
+    "temporary_password",
+];
+
+/// A recorded action
+#[derive(Debug, Serialize, Deserialize)]
+pub struct AuditEvent {
+    id: Thing,
+    actor: Option<Thing>,
+    actor_name: String,
+    action: String,
+    target: Option<Thing>,
+    /// the changed fields, each with its value before and after
+    #[serde(default)]
+    changes: Map<String, Value>,
+    ip: String,
+    created_at: Datetime,
+}
+
+/// extractor providing the actor and the client ip of a request to record audit events
+///
+/// # Example
+///
+/// ```text
+/// #[delete("/thing/{thing}")]
+/// async fn delete_thing(audit: Audit, db: web::Data<Surreal<Client>>) -> HttpResponse
+/// ```
+pub struct Audit {
+    actor: Option<Thing>,
+    actor_name: String,
+    ip: String,
+}
+
+impl FromRequest for Audit {
+    type Error = actix_web::Error;
+    type Future = Ready<Result<Self, Self::Error>>;
+
+    fn from_request(req: &HttpRequest, _: &mut Payload) -> Self::Future {
+        let claims = req.extensions().get::<Claims>().cloned();
+        let trust_forwarded_for = req
+            .app_data::<web::Data<AppState>>()
+            .is_some_and(|app_state| app_state.rate_limits.trust_forwarded_for);
+        ready(Ok(Audit {
+            actor: claims
+                .as_ref()
+                .and_then(|claims| surrealdb::sql::thing(&claims.sub).ok()),
+            actor_name: claims.map(|claims| claims.name).unwrap_or_default(),
+            ip: client_ip(req, trust_forwarded_for),
+        }))
+    }
+}
+
+impl Audit {
+    /// Records a action on the target. before and after are the states of the target,
+    /// `()` for a state which does not exist, e.g. before the creation.
+    /// Errors are only logged, a failed audit record doesn't undo the action
+    pub async fn record(
+        &self,
+        db: &Surreal<Client>,
+        action: &str,
+        target: Option<&Thing>,
+        before: impl Serialize,
+        after: impl Serialize,
+    ) {
+        let changes = diff(
+            serde_json::to_value(before).unwrap_or_default(),
+            serde_json::to_value(after).unwrap_or_default(),
+        );
+        let result = db
+            .query("CREATE audit_event SET actor = $actor, actor_name = $actor_name, action = $action, target = $target, changes = $changes, ip = $ip, created_at = time::now()")
+            .bind(("actor", &self.actor))
+            .bind(("actor_name", &self.actor_name))
+            .bind(("action", action))
+            .bind(("target", target))
+            .bind(("changes", changes))
+            .bind(("ip", &self.ip))
+            .await
+            .and_then(|response| response.check());
+        if let Err(err) = result {
+            eprintln!("unable to record audit event {action}: {err}");
+        }
+    }
+}
+
+/// returns the changed top level fields with their value before and after.
+/// Values which aren't objects are compared as a whole under the field `value`
+fn diff(before: Value, after: Value) -> Map<String, Value> {
+    let into_fields = |value: Value| match value {
+        Value::Object(fields) => fields,
+        Value::Null => Map::new(),
+        value => Map::from_iter([("value".to_owned(), value)]),
+    };
+    let before = into_fields(before);
+    let mut after = into_fields(after);
+
+    let mut changes = Map::new();
+    for (field, mut old) in before {
+        let mut new = after.remove(&field).unwrap_or_default();
+        if old != new {
+            redact(&mut old);
+            redact(&mut new);
+            changes.insert(field, change(old, new));
+        }
+    }
+    for (field, mut new) in after {
+        if !new.is_null() {
+            redact(&mut new);
+            changes.insert(field, change(Value::Null, new));
+        }
+    }
+    for field in REDACTED_FIELDS {
+        if changes.contains_key(field) {
+            changes.insert(field.to_owned(), Value::String("<redacted>".to_owned()));
+        }
+    }
+    changes
+}
+
+/// redacts the fields of nested objects
+fn redact(value: &mut Value) {
+    match value {
+        Value::Object(fields) => {
+            for (field, value) in fields.iter_mut() {
+                match REDACTED_FIELDS.contains(&field.as_str()) {
+                    true => *value = Value::String("<redacted>".to_owned()),
+                    false => redact(value),
+                }
+            }
+        }
+        Value::Array(values) => values.iter_mut().for_each(redact),
+        _ => {}
+    }
+}
+
+/// a single changed field
+fn change(before: Value, after: Value) -> Value {
+    Value::Object(Map::from_iter([
+        ("before".to_owned(), before),
+        ("after".to_owned(), after),
+    ]))
+}
+
+/// Supported formats of the audit log
+#[derive(Debug, Default, Clone, Copy, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum AuditFormat {
+    #[default]
+    Json,
+    Csv,
+    Ndjson,
+}
+
+/// helper struct to deserialize the audit log query.
+/// actor and target are record ids, action also matches prefixes like `station.`
+#[derive(Deserialize)]
+pub struct AuditQuery {
+    actor: Option<String>,
+    action: Option<String>,
+    target: Option<String>,
+    from: Option<chrono::DateTime<chrono::Utc>>,
+    to: Option<chrono::DateTime<chrono::Utc>>,
+    #[serde(default = "default_limit")]
+    limit: usize,
+    #[serde(default)]
+    format: AuditFormat,
+}
+
+fn default_limit() -> usize {
+    100
+}
+
+/// parses a optional record id of the query
+fn parse_thing(field: &str, id: Option<&String>) -> Result<Option<Thing>, HttpResponse> {
+    id.map(|id| {
+        surrealdb::sql::thing(id)
+            .map_err(|_| HttpResponse::BadRequest().body(format!("Invalid {field}: {id}")))
+    })
+    .transpose()
+}
+
+/// endpoint to query and export the audit log, the newest events come first
+#[get("/audit-events", wrap = "RequireRole(Role::Admin)")]
+async fn get_audit_events(
+    query: web::Query<AuditQuery>,
+    db: web::Data<Surreal<Client>>,
+) -> HttpResponse {
+    let (actor, target) = match (
+        parse_thing("actor", query.actor.as_ref()),
+        parse_thing("target", query.target.as_ref()),
+    ) {
+        (Ok(actor), Ok(target)) => (actor, target),
+        (Err(response), _) | (_, Err(response)) => return response,
+    };
+
+    let mut conditions = Vec::new();
+    if actor.is_some() {
+        conditions.push("actor = $actor");
+    }
+    if query.action.is_some() {
+        conditions.push("string::startsWith(action, $action)");
+    }
+    if target.is_some() {
+        conditions.push("target = $target");
+    }
+    if query.from.is_some() {
+        conditions.push("created_at >= $from");
+    }
+    if query.to.is_some() {
+        conditions.push("created_at <= $to");
+    }
+    let mut sql = "SELECT * FROM audit_event".to_owned();
+    if !conditions.is_empty() {
+        sql.push_str(" WHERE ");
+        sql.push_str(&conditions.join(" AND "));
+    }
+    sql.push_str(" ORDER BY created_at DESC LIMIT $limit");
+
+    let events: Result<Vec<AuditEvent>, surrealdb::Error> = async {
+        db.query(sql)
+            .bind(("actor", actor))
+            .bind(("action", &query.action))
+            .bind(("target", target))
+            .bind(("from", query.from.map(Datetime)))
+            .bind(("to", query.to.map(Datetime)))
+            .bind(("limit", query.limit.min(10_000)))
+            .await?
+            .take(0)
+    }
+    .await;
+    let events = match events {
+        Ok(events) => events,
+        Err(err) => return HttpResponse::InternalServerError().body(err.to_string()),
+    };
+
+    let (content_type, extension, body) = match query.format {
+        AuditFormat::Json => return HttpResponse::Ok().json(events),
+        AuditFormat::Ndjson => (
+            "application/x-ndjson",
+            "ndjson",
+            events
+                .iter()
+                .filter_map(|event| serde_json::to_string(event).ok())
+                .map(|line| line + "\n")
+                .collect::<String>(),
+        ),
+        AuditFormat::Csv => {
+            let mut csv = "created_at,actor,actor_name,action,target,ip,changes\n".to_owned();
+            for event in events.iter() {
+                let fields = [
+                    event.created_at.0.to_rfc3339(),
+                    event
+                        .actor
+                        .as_ref()
+                        .map(Thing::to_string)
+                        .unwrap_or_default(),
+                    event.actor_name.clone(),
+                    event.action.clone(),
+                    event
+                        .target
+                        .as_ref()
+                        .map(Thing::to_string)
+                        .unwrap_or_default(),
+                    event.ip.clone(),
+                    Value::Object(event.changes.clone()).to_string(),
+                ];
+                let fields: Vec<String> = fields
+                    .iter()
+                    .map(|field| common::export::csv_field(field))
+                    .collect();
+                csv.push_str(&fields.join(","));
+                csv.push('\n');
+            }
+            ("text/csv; charset=utf-8", "csv", csv)
+        }
+    };
+    HttpResponse::Ok()
+        .content_type(content_type)
+        .insert_header(ContentDisposition {
+            disposition: DispositionType::Attachment,
+            parameters: vec![DispositionParam::Filename(format!(
+                "audit-{}.{extension}",
+                chrono::Utc::now().format("%Y%m%dT%H%M%S")
+            ))],
+        })
+        .body(body)
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::diff;
+
+    #[test]
+    fn redacts_secrets() {
+        let changes = diff(
+            json!({"name": "mes", "password": "$argon2id$old", "key_hash": "a1"}),
+            json!({"name": "mes", "password": "$argon2id$new", "key_hash": "b2", "jwt": "eyJ", "refresh_token": "f00", "key": "sk_live", "temporary_password": "Xy7#"}),
+        );
+        assert_eq!(changes.len(), 6);
+        for field in [
+            "password",
+            "key_hash",
+            "jwt",
+            "refresh_token",
+            "key",
+            "temporary_password",
+        ] {
+            assert_eq!(changes[field], "<redacted>", "{field}");
+        }
+        assert!(!serde_json::to_string(&changes).unwrap().contains("argon2"));
+    }
+
+    #[test]
+    fn records_only_changed_fields() {
+        let changes = diff(
+            json!({"name": "Presswerk", "archived": false, "location": null}),
+            json!({"name": "Presswerk", "archived": true, "location": null, "icon": null}),
+        );
+        assert_eq!(
+            serde_json::Value::Object(changes),
+            json!({"archived": {"before": false, "after": true}})
+        );
+        assert!(diff(json!({"name": "mes"}), json!({"name": "mes"})).is_empty());
+    }
+
+    #[test]
+    fn redacts_secrets_of_nested_objects() {
+        let changes = diff(
+            json!({"account": {"name": "anna", "password": "$argon2id$old"}}),
+            json!({"account": {"name": "hugo", "password": "$argon2id$new"}, "keys": [{"key": "sk_live"}]}),
+        );
+        assert_eq!(
+            serde_json::Value::Object(changes),
+            json!({
+                "account": {
+                    "before": {"name": "anna", "password": "<redacted>"},
+                    "after": {"name": "hugo", "password": "<redacted>"}
+                },
+                "keys": {"before": null, "after": [{"key": "<redacted>"}]}
+            })
+        );
+    }
+}
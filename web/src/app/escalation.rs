@@ -0,0 +1,783 @@
+//! # web::escalation
+//!
+//! `web::escalation` is a module to manage the on-call schedules and escalation policies,
+//! see `common::schedule` and `web::notify::escalation`, and to view the escalation of a alarm.
+//! Maintainers manage schedules and the policies of stations they may manage,
+//! policies of all stations are managed by admins. Steps of others email the users on call,
+//! other channels are set by admins.
+//! The timeline of a alarm lists its notified steps with their deliveries, the steps still due
+//! and why the escalation stopped.
+//!
+//! # Example
+//!
+//! ```text
+//! POST /api/v1/schedules
+//! {"name": "Schichtleitung Presswerk", "timezone": "Europe/Berlin", "shifts": [
+//!   {"days": ["mon", "tue", "wed", "thu", "fri"], "start": "06:00", "end": "14:00", "users": ["anna"]},
+//!   {"days": ["mon", "tue", "wed", "thu", "fri"], "start": "14:00", "end": "22:00", "users": ["hugo"]}]}
+//! GET /api/v1/schedule/schichtleitung/on-call?at=2023-05-02T08:00:00Z
+//! GET /api/v1/alarm/8wnm3q2d9sxqp7ax3rkc/escalation
+//! ```
+
+use actix_web::{delete, get, post, put, web, HttpResponse};
+use chrono::{DateTime, NaiveTime, Utc, Weekday};
+use common::schedule::{Schedule, ScheduleOverride, Shift};
+use serde::{Deserialize, Serialize};
+use surrealdb::{
+    engine::remote::ws::Client,
+    sql::{Datetime, Id, Thing},
+    Surreal,
+};
+
+use crate::{
+    app::{
+        acl::{station_thing, Access, StationPermission},
+        alarm::check_alarm,
+        audit::Audit,
+    },
+    auth::{Claims, Role},
+    config::AppState,
+    middleware::role::RequireRole,
+    notify::{
+        channel::Channel,
+        escalation::{
+            on_call, Escalation, EscalationPolicy, EscalationState, EscalationStep,
+            EscalationTarget, OnCall, MAX_STEP_DELAY_MINUTES,
+        },
+        Delivery,
+    },
+};
+
+/// the record of a user id, accepted with and without the table
+fn user_thing(user: &str) -> Result<Thing, HttpResponse> {
+    match surrealdb::sql::thing(user) {
+        Ok(user) if user.tb == "user" => Ok(user),
+        Ok(_) => Err(HttpResponse::BadRequest().body(format!("{user} is no user"))),
+        Err(_) => Ok(Thing::from(("user", user))),
+    }
+}
+
+/// responds with 400 unless all users exist
+async fn check_users(db: &Surreal<Client>, users: &[Thing]) -> Result<(), HttpResponse> {
+    if users.is_empty() {
+        return Ok(());
+    }
+    let existing: Result<Vec<Thing>, surrealdb::Error> = async {
+        db.query("SELECT VALUE id FROM $users")
+            .bind(("users", users))
+            .await?
+            .take(0)
+    }
+    .await;
+    match existing {
+        Ok(existing) => match users.iter().find(|user| !existing.contains(user)) {
+            Some(missing) => Err(HttpResponse::BadRequest().body(format!("{missing} not found"))),
+            None => Ok(()),
+        },
+        Err(err) => Err(HttpResponse::InternalServerError().body(err.to_string())),
+    }
+}
+
+/// endpoint to list the schedules
+#[get("/schedules", wrap = "RequireRole(Role::Viewer)")]
+async fn get_schedules(db: web::Data<Surreal<Client>>) -> HttpResponse {
+    let schedules: Result<Vec<Schedule>, surrealdb::Error> = async {
+        db.query("SELECT * FROM schedule ORDER BY name")
+            .await?
+            .take(0)
+    }
+    .await;
+    match schedules {
+        Ok(schedules) => HttpResponse::Ok().json(schedules),
+        Err(err) => HttpResponse::InternalServerError().body(err.to_string()),
+    }
+}
+
+/// helper struct to deserialize the on-call query, at defaults to now
+#[derive(Deserialize)]
+pub struct OnCallQuery {
+    at: Option<DateTime<Utc>>,
+}
+
+/// The users on call of a schedule at a time
+#[derive(Serialize)]
+pub struct OnCallResponse {
+    schedule: Thing,
+    at: DateTime<Utc>,
+    on_call: Vec<OnCall>,
+}
+
+/// endpoint to list the users on call of a schedule
+#[get("/schedule/{schedule}/on-call", wrap = "RequireRole(Role::Viewer)")]
+async fn get_on_call(
+    schedule_id: web::Path<String>,
+    query: web::Query<OnCallQuery>,
+    db: web::Data<Surreal<Client>>,
+) -> HttpResponse {
+    let schedule = Thing::from(("schedule", schedule_id.as_str()));
+    match Schedule::get(&db, &schedule_id).await {
+        Ok(Some(_)) => {}
+        Ok(None) => return HttpResponse::NotFound().body("Schedule not found"),
+        Err(err) => return HttpResponse::InternalServerError().body(err.to_string()),
+    }
+    let at = query.at.unwrap_or_else(Utc::now);
+    let target = EscalationTarget::Schedule {
+        schedule: schedule.clone(),
+    };
+    match on_call(&db, &target, at).await {
+        Ok(on_call) => HttpResponse::Ok().json(OnCallResponse {
+            schedule,
+            at,
+            on_call,
+        }),
+        Err(err) => HttpResponse::InternalServerError().body(err.to_string()),
+    }
+}
+
+/// helper struct to deserialize the payload to define a schedule, users with or without table
+#[derive(Deserialize)]
+pub struct SchedulePayload {
+    name: String,
+    timezone: String,
+    #[serde(default)]
+    shifts: Vec<ShiftPayload>,
+    #[serde(default)]
+    overrides: Vec<OverridePayload>,
+}
+
+/// helper struct to deserialize a shift, times as `HH:MM`
+#[derive(Deserialize)]
+pub struct ShiftPayload {
+    #[serde(default)]
+    days: Vec<Weekday>,
+    start: NaiveTime,
+    end: NaiveTime,
+    users: Vec<String>,
+}
+
+/// helper struct to deserialize a override of the shifts
+#[derive(Deserialize)]
+pub struct OverridePayload {
+    from: DateTime<Utc>,
+    to: DateTime<Utc>,
+    users: Vec<String>,
+}
+
+impl SchedulePayload {
+    /// converts the payload into a schedule with the id and validates it
+    async fn into_schedule(
+        self,
+        db: &Surreal<Client>,
+        id: Thing,
+    ) -> Result<Schedule, HttpResponse> {
+        let users = |users: Vec<String>| -> Result<Vec<Thing>, HttpResponse> {
+            users.iter().map(|user| user_thing(user)).collect()
+        };
+        let mut shifts = Vec::new();
+        for shift in self.shifts {
+            shifts.push(Shift {
+                days: shift.days,
+                start: shift.start,
+                end: shift.end,
+                users: users(shift.users)?,
+            });
+        }
+        let mut overrides = Vec::new();
+        for replaced in self.overrides {
+            overrides.push(ScheduleOverride {
+                from: Datetime(replaced.from),
+                to: Datetime(replaced.to),
+                users: users(replaced.users)?,
+            });
+        }
+        let schedule = Schedule {
+            id,
+            name: self.name.trim().to_owned(),
+            timezone: self.timezone,
+            shifts,
+            overrides,
+        };
+        schedule
+            .validate()
+            .map_err(|err| HttpResponse::BadRequest().body(err))?;
+
+        let mut all_users = Vec::new();
+        for user in schedule.shifts.iter().flat_map(|shift| &shift.users).chain(
+            schedule
+                .overrides
+                .iter()
+                .flat_map(|replaced| &replaced.users),
+        ) {
+            if !all_users.contains(user) {
+                all_users.push(user.clone());
+            }
+        }
+        check_users(db, &all_users).await?;
+        Ok(schedule)
+    }
+}
+
+/// endpoint to define a schedule
+#[post("/schedules", wrap = "RequireRole(Role::Maintainer)")]
+async fn create_schedule(
+    json: web::Json<SchedulePayload>,
+    audit: Audit,
+    db: web::Data<Surreal<Client>>,
+) -> HttpResponse {
+    let id = Thing::from(("schedule".to_owned(), Id::rand()));
+    let schedule = match json.into_inner().into_schedule(&db, id).await {
+        Ok(schedule) => schedule,
+        Err(response) => return response,
+    };
+
+    let created: Result<Option<Schedule>, surrealdb::Error> = async {
+        db.query("CREATE $schedule CONTENT $content")
+            .bind(("schedule", &schedule.id))
+            .bind(("content", &schedule))
+            .await?
+            .take(0)
+    }
+    .await;
+    match created {
+        Ok(Some(schedule)) => {
+            audit
+                .record(&db, "schedule.create", Some(&schedule.id), (), &schedule)
+                .await;
+            HttpResponse::Created().json(schedule)
+        }
+        Ok(None) => HttpResponse::InternalServerError().body("Schedule was not created"),
+        Err(err) => HttpResponse::InternalServerError().body(err.to_string()),
+    }
+}
+
+/// endpoint to redefine a schedule
+#[put("/schedule/{schedule}", wrap = "RequireRole(Role::Maintainer)")]
+async fn update_schedule(
+    schedule_id: web::Path<String>,
+    json: web::Json<SchedulePayload>,
+    audit: Audit,
+    db: web::Data<Surreal<Client>>,
+) -> HttpResponse {
+    let before = match Schedule::get(&db, &schedule_id).await {
+        Ok(Some(schedule)) => schedule,
+        Ok(None) => return HttpResponse::NotFound().body("Schedule not found"),
+        Err(err) => return HttpResponse::InternalServerError().body(err.to_string()),
+    };
+    let schedule = match json
+        .into_inner()
+        .into_schedule(&db, before.id.clone())
+        .await
+    {
+        Ok(schedule) => schedule,
+        Err(response) => return response,
+    };
+
+    let updated: Result<Option<Schedule>, surrealdb::Error> = async {
+        db.query("UPDATE $schedule CONTENT $content")
+            .bind(("schedule", &schedule.id))
+            .bind(("content", &schedule))
+            .await?
+            .take(0)
+    }
+    .await;
+    match updated {
+        Ok(Some(schedule)) => {
+            audit
+                .record(
+                    &db,
+                    "schedule.update",
+                    Some(&schedule.id),
+                    &before,
+                    &schedule,
+                )
+                .await;
+            HttpResponse::Ok().json(schedule)
+        }
+        Ok(None) => HttpResponse::NotFound().body("Schedule not found"),
+        Err(err) => HttpResponse::InternalServerError().body(err.to_string()),
+    }
+}
+
+/// endpoint to delete a schedule, schedules of escalation policies can't be deleted
+#[delete("/schedule/{schedule}", wrap = "RequireRole(Role::Maintainer)")]
+async fn delete_schedule(
+    schedule_id: web::Path<String>,
+    audit: Audit,
+    db: web::Data<Surreal<Client>>,
+) -> HttpResponse {
+    let schedule = Thing::from(("schedule", schedule_id.as_str()));
+    let policies: Result<Vec<EscalationPolicy>, surrealdb::Error> =
+        async { db.query("SELECT * FROM escalation_policy").await?.take(0) }.await;
+    let used = match policies {
+        Ok(policies) => policies.into_iter().find(|policy| {
+            policy.steps.iter().any(|step| {
+                step.target
+                    == EscalationTarget::Schedule {
+                        schedule: schedule.clone(),
+                    }
+            })
+        }),
+        Err(err) => return HttpResponse::InternalServerError().body(err.to_string()),
+    };
+    if let Some(policy) = used {
+        return HttpResponse::Conflict().body(format!(
+            "The schedule is used by the escalation policy {}",
+            policy.name
+        ));
+    }
+
+    let deleted: Result<Option<Schedule>, surrealdb::Error> = db.delete(schedule).await;
+    match deleted {
+        Ok(Some(deleted)) => {
+            audit
+                .record(&db, "schedule.delete", Some(&deleted.id), &deleted, ())
+                .await;
+            HttpResponse::NoContent().finish()
+        }
+        Ok(None) => HttpResponse::NotFound().body("Schedule not found"),
+        Err(err) => HttpResponse::InternalServerError().body(err.to_string()),
+    }
+}
+
+/// returns true if the caller may manage the policy of the stations
+fn may_manage(claims: &Claims, access: &Access, stations: &[Thing]) -> bool {
+    match stations.is_empty() {
+        true => claims.role >= Role::Admin,
+        false => stations
+            .iter()
+            .all(|station| access.permits(station, StationPermission::Manage)),
+    }
+}
+
+/// endpoint to list the escalation policies of the stations the caller may view
+#[get("/escalation-policies")]
+async fn get_escalation_policies(access: Access, db: web::Data<Surreal<Client>>) -> HttpResponse {
+    let policies: Result<Vec<EscalationPolicy>, surrealdb::Error> = async {
+        db.query("SELECT * FROM escalation_policy ORDER BY name")
+            .await?
+            .take(0)
+    }
+    .await;
+    match policies {
+        Ok(mut policies) => {
+            policies.retain(|policy| {
+                policy
+                    .stations
+                    .iter()
+                    .all(|station| access.permits(station, StationPermission::View))
+            });
+            HttpResponse::Ok().json(policies)
+        }
+        Err(err) => HttpResponse::InternalServerError().body(err.to_string()),
+    }
+}
+
+/// helper struct to deserialize the payload to define a escalation policy
+#[derive(Deserialize)]
+pub struct PolicyPayload {
+    name: String,
+    #[serde(default)]
+    stations: Vec<String>,
+    #[serde(default)]
+    min_severity: u32,
+    steps: Vec<StepPayload>,
+    #[serde(default = "enabled")]
+    enabled: bool,
+}
+
+fn enabled() -> bool {
+    true
+}
+
+/// helper struct to deserialize a step, emailing the users on call without channel
+#[derive(Deserialize)]
+pub struct StepPayload {
+    delay_minutes: u64,
+    target: TargetPayload,
+    channel: Option<Channel>,
+}
+
+/// helper struct to deserialize the target of a step, ids with or without table
+#[derive(Deserialize)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum TargetPayload {
+    Schedule { schedule: String },
+    User { user: String },
+}
+
+/// A validated escalation policy payload
+struct Policy {
+    name: String,
+    stations: Vec<Thing>,
+    min_severity: u32,
+    steps: Vec<EscalationStep>,
+    enabled: bool,
+}
+
+impl PolicyPayload {
+    /// validates the payload and checks the targets exist,
+    /// only admins notify addresses, webhooks and MQTT topics
+    async fn validate(
+        self,
+        db: &Surreal<Client>,
+        app_state: &AppState,
+        claims: &Claims,
+    ) -> Result<Policy, HttpResponse> {
+        let name = self.name.trim().to_owned();
+        if name.is_empty() {
+            return Err(HttpResponse::BadRequest().body("The name must not be empty"));
+        }
+        if self.steps.is_empty() {
+            return Err(HttpResponse::BadRequest().body("A policy needs at least one step"));
+        }
+        if self
+            .steps
+            .iter()
+            .any(|step| step.delay_minutes > MAX_STEP_DELAY_MINUTES)
+        {
+            return Err(HttpResponse::BadRequest().body(format!(
+                "The delays of the steps must not be longer than {MAX_STEP_DELAY_MINUTES} minutes"
+            )));
+        }
+        if self
+            .steps
+            .windows(2)
+            .any(|steps| steps[0].delay_minutes > steps[1].delay_minutes)
+        {
+            return Err(
+                HttpResponse::BadRequest().body("The delays of the steps must not decrease")
+            );
+        }
+
+        let mut steps = Vec::new();
+        for step in self.steps {
+            let channel = step.channel.unwrap_or(Channel::Email { address: None });
+            if claims.role < Role::Admin && channel != (Channel::Email { address: None }) {
+                return Err(HttpResponse::Forbidden()
+                    .body("Only admins notify addresses, webhooks and MQTT topics"));
+            }
+            channel
+                .validate(&app_state.notifications)
+                .map_err(|err| HttpResponse::BadRequest().body(err))?;
+            let target = match step.target {
+                TargetPayload::Schedule { schedule } => match Schedule::get(db, &schedule).await {
+                    Ok(Some(schedule)) => EscalationTarget::Schedule {
+                        schedule: schedule.id,
+                    },
+                    Ok(None) => {
+                        return Err(HttpResponse::BadRequest()
+                            .body(format!("Schedule {schedule} not found")))
+                    }
+                    Err(err) => {
+                        return Err(HttpResponse::InternalServerError().body(err.to_string()))
+                    }
+                },
+                TargetPayload::User { user } => {
+                    let user = user_thing(&user)?;
+                    check_users(db, std::slice::from_ref(&user)).await?;
+                    EscalationTarget::User { user }
+                }
+            };
+            steps.push(EscalationStep {
+                delay_minutes: step.delay_minutes,
+                target,
+                channel,
+            });
+        }
+        Ok(Policy {
+            name,
+            stations: self
+                .stations
+                .iter()
+                .map(|station| station_thing(station))
+                .collect(),
+            min_severity: self.min_severity,
+            steps,
+            enabled: self.enabled,
+        })
+    }
+}
+
+/// endpoint to define a escalation policy
+#[post("/escalation-policies", wrap = "RequireRole(Role::Maintainer)")]
+async fn create_escalation_policy(
+    json: web::Json<PolicyPayload>,
+    claims: Claims,
+    access: Access,
+    audit: Audit,
+    app_state: web::Data<AppState>,
+    db: web::Data<Surreal<Client>>,
+) -> HttpResponse {
+    let policy = match json.into_inner().validate(&db, &app_state, &claims).await {
+        Ok(policy) => policy,
+        Err(response) => return response,
+    };
+    if !may_manage(&claims, &access, &policy.stations) {
+        return HttpResponse::Forbidden()
+            .body("Policies of all stations are managed by admins, others need manage permission on their stations");
+    }
+
+    let created: Result<Option<EscalationPolicy>, surrealdb::Error> = async {
+        db.query("CREATE escalation_policy SET name = $name, stations = $stations, min_severity = $min_severity, steps = $steps, enabled = $enabled, created_at = time::now(), created_by = $created_by")
+            .bind(("name", policy.name))
+            .bind(("stations", policy.stations))
+            .bind(("min_severity", policy.min_severity))
+            .bind(("steps", policy.steps))
+            .bind(("enabled", policy.enabled))
+            .bind(("created_by", surrealdb::sql::thing(&claims.sub).ok()))
+            .await?
+            .take(0)
+    }
+    .await;
+    match created {
+        Ok(Some(policy)) => {
+            audit
+                .record(
+                    &db,
+                    "escalation_policy.create",
+                    Some(&policy.id),
+                    (),
+                    &policy,
+                )
+                .await;
+            HttpResponse::Created().json(policy)
+        }
+        Ok(None) => HttpResponse::InternalServerError().body("Escalation policy was not created"),
+        Err(err) => HttpResponse::InternalServerError().body(err.to_string()),
+    }
+}
+
+/// responds with 404 unless the policy exists and the caller may manage it
+async fn find_policy(
+    db: &Surreal<Client>,
+    claims: &Claims,
+    access: &Access,
+    policy_id: &str,
+) -> Result<EscalationPolicy, HttpResponse> {
+    let policy: Option<EscalationPolicy> = match db
+        .select(Thing::from(("escalation_policy", policy_id)))
+        .await
+    {
+        Ok(policy) => policy,
+        Err(err) => return Err(HttpResponse::InternalServerError().body(err.to_string())),
+    };
+    match policy {
+        Some(policy) if may_manage(claims, access, &policy.stations) => Ok(policy),
+        _ => Err(HttpResponse::NotFound().body("Escalation policy not found")),
+    }
+}
+
+/// endpoint to redefine a escalation policy, running escalations continue with its new steps
+#[put("/escalation-policy/{policy}", wrap = "RequireRole(Role::Maintainer)")]
+async fn update_escalation_policy(
+    policy_id: web::Path<String>,
+    json: web::Json<PolicyPayload>,
+    claims: Claims,
+    access: Access,
+    audit: Audit,
+    app_state: web::Data<AppState>,
+    db: web::Data<Surreal<Client>>,
+) -> HttpResponse {
+    let before = match find_policy(&db, &claims, &access, &policy_id).await {
+        Ok(policy) => policy,
+        Err(response) => return response,
+    };
+    let policy = match json.into_inner().validate(&db, &app_state, &claims).await {
+        Ok(policy) => policy,
+        Err(response) => return response,
+    };
+    if !may_manage(&claims, &access, &policy.stations) {
+        return HttpResponse::Forbidden()
+            .body("Policies of all stations are managed by admins, others need manage permission on their stations");
+    }
+
+    let updated: Result<Option<EscalationPolicy>, surrealdb::Error> = async {
+        db.query("UPDATE $policy SET name = $name, stations = $stations, min_severity = $min_severity, steps = $steps, enabled = $enabled")
+            .bind(("policy", &before.id))
+            .bind(("name", policy.name))
+            .bind(("stations", policy.stations))
+            .bind(("min_severity", policy.min_severity))
+            .bind(("steps", policy.steps))
+            .bind(("enabled", policy.enabled))
+            .await?
+            .take(0)
+    }
+    .await;
+    match updated {
+        Ok(Some(policy)) => {
+            audit
+                .record(
+                    &db,
+                    "escalation_policy.update",
+                    Some(&policy.id),
+                    &before,
+                    &policy,
+                )
+                .await;
+            HttpResponse::Ok().json(policy)
+        }
+        Ok(None) => HttpResponse::NotFound().body("Escalation policy not found"),
+        Err(err) => HttpResponse::InternalServerError().body(err.to_string()),
+    }
+}
+
+/// endpoint to delete a escalation policy, its running escalations stop
+#[delete("/escalation-policy/{policy}", wrap = "RequireRole(Role::Maintainer)")]
+async fn delete_escalation_policy(
+    policy_id: web::Path<String>,
+    claims: Claims,
+    access: Access,
+    audit: Audit,
+    db: web::Data<Surreal<Client>>,
+) -> HttpResponse {
+    let policy = match find_policy(&db, &claims, &access, &policy_id).await {
+        Ok(policy) => policy,
+        Err(response) => return response,
+    };
+
+    let deleted: Result<Option<EscalationPolicy>, surrealdb::Error> = async {
+        db.query("UPDATE escalation SET state = $stopped, next_at = NONE, stopped_at = time::now(), stopped_by = $by, stop_reason = 'policy removed' WHERE policy = $policy AND state = $running")
+            .bind(("stopped", EscalationState::Stopped))
+            .bind(("running", EscalationState::Running))
+            .bind(("by", surrealdb::sql::thing(&claims.sub).ok()))
+            .bind(("policy", &policy.id))
+            .await?
+            .check()?;
+        db.delete(policy.id.clone()).await
+    }
+    .await;
+    match deleted {
+        Ok(Some(deleted)) => {
+            audit
+                .record(
+                    &db,
+                    "escalation_policy.delete",
+                    Some(&deleted.id),
+                    &deleted,
+                    (),
+                )
+                .await;
+            HttpResponse::NoContent().finish()
+        }
+        Ok(None) => HttpResponse::NotFound().body("Escalation policy not found"),
+        Err(err) => HttpResponse::InternalServerError().body(err.to_string()),
+    }
+}
+
+/// A entry of the timeline of a escalation
+#[derive(Serialize)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum TimelineEntry {
+    Raised {
+        at: DateTime<Utc>,
+    },
+    /// a step was notified to the users on call
+    Notified {
+        at: DateTime<Utc>,
+        step: usize,
+        on_call: Vec<Thing>,
+        targets: Vec<String>,
+        deliveries: Vec<Delivery>,
+    },
+    /// a step of a running escalation still to be notified
+    Due {
+        at: DateTime<Utc>,
+        step: usize,
+        target: EscalationTarget,
+    },
+    Stopped {
+        at: Option<DateTime<Utc>>,
+        reason: Option<String>,
+        by: Option<Thing>,
+    },
+    Completed {
+        at: Option<DateTime<Utc>>,
+    },
+}
+
+/// The timeline of a escalation of a alarm
+#[derive(Serialize)]
+pub struct EscalationTimeline {
+    id: Thing,
+    policy: Thing,
+    policy_name: String,
+    state: EscalationState,
+    timeline: Vec<TimelineEntry>,
+}
+
+/// endpoint to view the escalations of a alarm as timelines
+#[get("/alarm/{alarm}/escalation")]
+async fn get_escalation_timeline(
+    alarm_id: web::Path<String>,
+    access: Access,
+    db: web::Data<Surreal<Client>>,
+) -> HttpResponse {
+    let alarm = match check_alarm(&db, &access, &alarm_id, StationPermission::View).await {
+        Ok(alarm) => alarm,
+        Err(response) => return response,
+    };
+
+    let timelines: Result<Vec<EscalationTimeline>, surrealdb::Error> = async {
+        let mut timelines = Vec::new();
+        for escalation in Escalation::get_by_alarm(&db, &alarm.id).await? {
+            let deliveries: Vec<Delivery> = db
+                .query("SELECT * FROM notification_delivery WHERE escalation = $escalation ORDER BY created_at ASC")
+                .bind(("escalation", &escalation.id))
+                .await?
+                .take(0)?;
+            let policy: Option<EscalationPolicy> = db.select(escalation.policy.clone()).await?;
+
+            let mut timeline = vec![TimelineEntry::Raised {
+                at: alarm.raised_at.0,
+            }];
+            for run in &escalation.runs {
+                timeline.push(TimelineEntry::Notified {
+                    at: run.at.0,
+                    step: run.step,
+                    on_call: run.on_call.clone(),
+                    targets: run.targets.clone(),
+                    deliveries: deliveries
+                        .iter()
+                        .filter(|delivery| run.event.as_ref() == Some(&delivery.event))
+                        .cloned()
+                        .collect(),
+                });
+            }
+            match (escalation.state, policy) {
+                (EscalationState::Running, Some(policy)) => {
+                    for (step, planned) in policy.steps.iter().enumerate().skip(escalation.next_step) {
+                        if let Some(at) = policy.due_at(step, escalation.started_at.0) {
+                            timeline.push(TimelineEntry::Due {
+                                at,
+                                step,
+                                target: planned.target.clone(),
+                            });
+                        }
+                    }
+                }
+                (EscalationState::Running, None) => {}
+                (EscalationState::Stopped, _) => timeline.push(TimelineEntry::Stopped {
+                    at: escalation.stopped_at.as_ref().map(|at| at.0),
+                    reason: escalation.stop_reason.clone(),
+                    by: escalation.stopped_by.clone(),
+                }),
+                (EscalationState::Completed, _) => timeline.push(TimelineEntry::Completed {
+                    at: escalation.runs.last().map(|run| run.at.0),
+                }),
+            }
+            timelines.push(EscalationTimeline {
+                id: escalation.id,
+                policy: escalation.policy,
+                policy_name: escalation.policy_name,
+                state: escalation.state,
+                timeline,
+            });
+        }
+        Ok(timelines)
+    }
+    .await;
+    match timelines {
+        Ok(timelines) => HttpResponse::Ok().json(timelines),
+        Err(err) => HttpResponse::InternalServerError().body(err.to_string()),
+    }
+}
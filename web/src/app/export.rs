@@ -0,0 +1,139 @@
+//! # web::export
+//!
+//! `web::export` is a module to stream sensor values as CSV, NDJSON or Parquet files
+//!
+//! # Example
+//!
+//! ```text
+//! GET /api/v1/export?format=csv&stations=presswerk&sensors=dosenfuellstand&from=2023-05-01T00:00:00Z
+//! ```
+
+use std::collections::VecDeque;
+
+use actix_web::{
+    get,
+    http::header::{ContentDisposition, DispositionParam, DispositionType},
+    web::{self, Bytes},
+    HttpResponse,
+};
+use common::export::{ExportError, ExportFormat, Exporter, Selection};
+use serde::Deserialize;
+use surrealdb::{engine::remote::ws::Client, Surreal};
+
+use crate::{
+    app::acl::{Access, StationPermission},
+    auth::ApiScope,
+    middleware::{rate_limit::RateLimit, scope::RequireScope},
+};
+
+/// helper struct to deserialize the export query.
+/// sensors and stations are comma separated lists of ids, to defaults to now
+#[derive(Deserialize)]
+pub struct ExportQuery {
+    #[serde(default)]
+    format: ExportFormat,
+    #[serde(default)]
+    sensors: String,
+    #[serde(default)]
+    stations: String,
+    from: chrono::DateTime<chrono::Utc>,
+    to: Option<chrono::DateTime<chrono::Utc>>,
+}
+
+/// state of a running export stream
+struct ExportStream {
+    db: web::Data<Surreal<Client>>,
+    sensors: VecDeque<common::Sensor>,
+    time_period: common::TimePeriod,
+    exporter: Option<Exporter>,
+}
+
+/// splits a comma separated list of ids
+fn split_ids(ids: &str) -> Vec<String> {
+    ids.split(',')
+        .map(str::trim)
+        .filter(|id| !id.is_empty())
+        .map(str::to_owned)
+        .collect()
+}
+
+/// endpoint to export the values of the selected sensors and stations within a time range.
+/// The file is streamed sensor by sensor, so large exports are not buffered in memory.
+/// Sensors of stations the caller may not view are left out
+#[get(
+    "/export",
+    wrap = "RateLimit::EXPORT",
+    wrap = "RequireScope(ApiScope::ReadSensors)"
+)]
+async fn export(
+    query: web::Query<ExportQuery>,
+    access: Access,
+    db: web::Data<Surreal<Client>>,
+) -> HttpResponse {
+    let selection = Selection {
+        sensors: split_ids(&query.sensors),
+        stations: split_ids(&query.stations),
+    };
+    if selection.sensors.is_empty() && selection.stations.is_empty() {
+        return HttpResponse::BadRequest().body("Select at least one sensor or station");
+    }
+
+    let to = query.to.unwrap_or_else(chrono::Utc::now);
+    if query.from > to {
+        return HttpResponse::BadRequest().body("from needs to be before to");
+    }
+
+    let sensors = match selection.resolve(&db).await.map(|mut sensors| {
+        sensors.retain(|sensor| access.permits(sensor.get_station(), StationPermission::View));
+        sensors
+    }) {
+        Ok(sensors) if sensors.is_empty() => {
+            return HttpResponse::NotFound().body("No sensors found for the selection")
+        }
+        Ok(sensors) => sensors,
+        Err(err) => return HttpResponse::InternalServerError().body(err.to_string()),
+    };
+
+    let exporter = match Exporter::new(query.format) {
+        Ok(exporter) => exporter,
+        Err(err) => return HttpResponse::InternalServerError().body(err.to_string()),
+    };
+
+    let state = ExportStream {
+        db,
+        sensors: sensors.into(),
+        time_period: common::TimePeriod::between(query.from, to),
+        exporter: Some(exporter),
+    };
+    let stream = futures_util::stream::unfold(state, |mut state| async move {
+        let mut exporter = state.exporter.take()?;
+        match state.sensors.pop_front() {
+            Some(sensor) => {
+                let chunk = match common::export::rows(&state.db, &sensor, &state.time_period).await
+                {
+                    Ok(rows) => exporter.write(&rows).map(|_| Bytes::from(exporter.drain())),
+                    Err(err) => Err(ExportError::from(err)),
+                };
+                // stop the stream after the first error
+                if chunk.is_ok() {
+                    state.exporter = Some(exporter);
+                }
+                Some((chunk, state))
+            }
+            None => Some((exporter.finish().map(Bytes::from), state)),
+        }
+    });
+
+    let filename = format!(
+        "export-{}.{}",
+        to.format("%Y%m%dT%H%M%S"),
+        query.format.extension()
+    );
+    HttpResponse::Ok()
+        .content_type(query.format.content_type())
+        .insert_header(ContentDisposition {
+            disposition: DispositionType::Attachment,
+            parameters: vec![DispositionParam::Filename(filename)],
+        })
+        .streaming(stream)
+}
@@ -0,0 +1,112 @@
+//! # web::import
+//!
+//! `web::import` is a module to import historical sensor values from CSV or NDJSON files.
+//! The caller needs the operate permission on the stations of all sensors of the file.
+//!
+//! # Example
+//! The file is sent as request body, the column mapping as query parameters.
+//!
+//! ```text
+//! POST /api/v1/import?format=csv&delimiter=;&timestamp_column=time&sensor_column=tag&station=presswerk&timezone=Europe/Berlin&create_sensors=true&dry_run=true
+//! ```
+
+use std::collections::BTreeSet;
+
+use actix_web::{post, web, HttpResponse};
+use common::import::{ImportError, ImportOptions, Record};
+use futures_util::StreamExt;
+use surrealdb::{engine::remote::ws::Client, Surreal};
+
+use crate::{
+    app::{
+        acl::{station_thing, Access, StationPermission},
+        audit::Audit,
+    },
+    auth::{ApiScope, Role},
+    middleware::{rate_limit::RateLimit, role::RequireRole, scope::RequireScope},
+};
+
+/// upper bound of the size of an uploaded file
+const MAX_IMPORT_SIZE: usize = 64 * 1024 * 1024;
+
+/// responds with 404 unless the caller may operate the stations of all sensors of the records,
+/// the stations of the records for unknown sensors if they are created
+async fn check_stations(
+    db: &Surreal<Client>,
+    access: &Access,
+    records: &[Record],
+    options: &ImportOptions,
+) -> Result<(), HttpResponse> {
+    let sensors: BTreeSet<&str> = records
+        .iter()
+        .map(|record| record.sensor.as_str())
+        .collect();
+    for sensor_id in sensors {
+        let sensor = common::Sensor::get(db, sensor_id.to_owned())
+            .await
+            .map_err(|err| HttpResponse::InternalServerError().body(err.to_string()))?;
+        let permitted = match sensor {
+            Some(sensor) => access.permits(sensor.get_station(), StationPermission::Operate),
+            None if options.create_sensors => records
+                .iter()
+                .filter(|record| record.sensor == sensor_id)
+                .filter_map(|record| record.station.as_deref())
+                .all(|station| access.permits(&station_thing(station), StationPermission::Operate)),
+            None => true,
+        };
+        if !permitted {
+            return Err(HttpResponse::NotFound().body(format!("Sensor {sensor_id} not found")));
+        }
+    }
+    Ok(())
+}
+
+/// endpoint to import a file into `sensor_value`, returns a summary of the import
+#[post(
+    "/import",
+    wrap = "RequireRole(Role::Maintainer)",
+    wrap = "RateLimit::IMPORT",
+    wrap = "RequireScope(ApiScope::WriteValues)"
+)]
+async fn import(
+    options: web::Query<ImportOptions>,
+    mut payload: web::Payload,
+    access: Access,
+    audit: Audit,
+    db: web::Data<Surreal<Client>>,
+) -> HttpResponse {
+    let mut body = web::BytesMut::new();
+    while let Some(chunk) = payload.next().await {
+        let chunk = match chunk {
+            Ok(chunk) => chunk,
+            Err(err) => return HttpResponse::BadRequest().body(err.to_string()),
+        };
+        if body.len() + chunk.len() > MAX_IMPORT_SIZE {
+            return HttpResponse::PayloadTooLarge().body(format!(
+                "The file exceeds the limit of {} MiB",
+                MAX_IMPORT_SIZE / 1024 / 1024
+            ));
+        }
+        body.extend_from_slice(&chunk);
+    }
+
+    let (records, errors) = match common::import::parse(&body, &options) {
+        Ok(parsed) => parsed,
+        Err(err) => return HttpResponse::BadRequest().body(err.to_string()),
+    };
+    if let Err(response) = check_stations(&db, &access, &records, &options).await {
+        return response;
+    }
+
+    match common::import::import_records(&db, records, errors, &options).await {
+        Ok(report) if report.dry_run => HttpResponse::Ok().json(report),
+        Ok(report) => {
+            audit.record(&db, "import", None, (), &report).await;
+            HttpResponse::Ok().json(report)
+        }
+        Err(err @ (ImportError::Mapping(_) | ImportError::Csv(_))) => {
+            HttpResponse::BadRequest().body(err.to_string())
+        }
+        Err(err) => HttpResponse::InternalServerError().body(err.to_string()),
+    }
+}
@@ -0,0 +1,306 @@
+//! # web::lockout
+//!
+//! `web::lockout` is a module to throttle failed sign in attempts.
+//! Failures are counted in memory per account and per client ip, every lockout
+//! of a account, ip or rate limited client is recorded as `lockout_event`.
+//!
+//! # Example
+//!
+//! ```text
+//! GET /api/v1/lockouts?limit=50
+//! DELETE /api/v1/lockout/lucy@cyber.night
+//! ```
+
+use std::{
+    collections::HashMap,
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+use actix_web::{delete, get, web, HttpResponse};
+use serde::{Deserialize, Serialize};
+use surrealdb::{
+    engine::remote::ws::Client,
+    sql::{Datetime, Thing},
+    Surreal,
+};
+
+use crate::{
+    app::audit::Audit, auth::Role, config::LoginThrottleConfig, middleware::role::RequireRole,
+};
+
+/// upper bound of tracked keys, stale entries are removed beyond it
+const MAX_TRACKED_KEYS: usize = 10_000;
+
+/// A recorded lockout
+#[derive(Debug, Serialize, Deserialize)]
+pub struct LockoutEvent {
+    id: Thing,
+    /// `account`, `ip` or `rate_limit`
+    kind: String,
+    /// the email, the client ip or the rate limited client
+    key: String,
+    /// the route group of a rate limit
+    group: Option<String>,
+    until: Datetime,
+    created_at: Datetime,
+}
+
+/// Records a lockout, errors are only logged as there is nobody to report them to
+pub async fn record(
+    db: &Surreal<Client>,
+    kind: &str,
+    key: &str,
+    group: Option<&str>,
+    duration: Duration,
+) {
+    let until = chrono::Utc::now() + chrono::Duration::from_std(duration).unwrap_or_default();
+    let result = db
+        .query("CREATE lockout_event SET kind = $kind, key = $key, group = $group, until = $until, created_at = time::now()")
+        .bind(("kind", kind))
+        .bind(("key", key))
+        .bind(("group", group))
+        .bind(("until", Datetime(until)))
+        .await
+        .and_then(|response| response.check());
+    if let Err(err) = result {
+        eprintln!("unable to record lockout of {key}: {err}");
+    }
+}
+
+/// failed attempts of a single account or ip
+struct Failures {
+    count: u32,
+    last: Instant,
+    locked_until: Option<Instant>,
+}
+
+/// In-memory counter of failed sign in attempts, shared by all workers
+#[derive(Default)]
+pub struct LoginThrottle {
+    failures: Mutex<HashMap<String, Failures>>,
+}
+
+impl LoginThrottle {
+    /// Returns how long the caller has to wait before the next attempt
+    /// for any of the keys, None if an attempt is allowed now
+    pub fn check(&self, config: &LoginThrottleConfig, keys: &[&str]) -> Option<Duration> {
+        self.check_at(config, keys, Instant::now())
+    }
+
+    /// checks the keys at the given time
+    fn check_at(
+        &self,
+        config: &LoginThrottleConfig,
+        keys: &[&str],
+        now: Instant,
+    ) -> Option<Duration> {
+        let failures = self.failures.lock().unwrap();
+        keys.iter()
+            .filter_map(|key| failures.get(*key))
+            .filter_map(|entry| {
+                let ready_at = match entry.locked_until {
+                    Some(until) if until > now => until,
+                    _ => entry.last + config.delay(entry.count),
+                };
+                ready_at.checked_duration_since(now)
+            })
+            .filter(|wait| !wait.is_zero())
+            .max()
+    }
+
+    /// Counts a failed attempt, returns the lockout duration if the key got locked
+    pub fn failure(
+        &self,
+        config: &LoginThrottleConfig,
+        key: &str,
+        max_failures: u32,
+    ) -> Option<Duration> {
+        self.failure_at(config, key, max_failures, Instant::now())
+    }
+
+    /// counts a failed attempt at the given time
+    fn failure_at(
+        &self,
+        config: &LoginThrottleConfig,
+        key: &str,
+        max_failures: u32,
+        now: Instant,
+    ) -> Option<Duration> {
+        let mut failures = self.failures.lock().unwrap();
+        if failures.len() >= MAX_TRACKED_KEYS {
+            failures.retain(|_, entry| now.duration_since(entry.last) < config.lockout());
+        }
+
+        let entry = failures.entry(key.to_owned()).or_insert(Failures {
+            count: 0,
+            last: now,
+            locked_until: None,
+        });
+        // failures are forgotten after a quiet lockout period
+        if now.duration_since(entry.last) >= config.lockout() {
+            entry.count = 0;
+        }
+        entry.count += 1;
+        entry.last = now;
+
+        if entry.count >= max_failures {
+            // continue with progressive delays once the lockout is over
+            entry.count = config.free_attempts;
+            entry.locked_until = Some(now + config.lockout());
+            return Some(config.lockout());
+        }
+        None
+    }
+
+    /// Forgets the failures of a key after a successful sign in
+    pub fn success(&self, key: &str) {
+        self.failures.lock().unwrap().remove(key);
+    }
+}
+
+/// the throttle key of a account
+pub fn account_key(email: &str) -> String {
+    format!("account:{}", email.trim().to_lowercase())
+}
+
+/// the throttle key of a client ip
+pub fn ip_key(ip: &str) -> String {
+    format!("ip:{ip}")
+}
+
+/// helper struct to deserialize the listing query
+#[derive(Deserialize)]
+pub struct LockoutQuery {
+    #[serde(default = "default_limit")]
+    limit: usize,
+}
+
+fn default_limit() -> usize {
+    100
+}
+
+/// endpoint to list the latest lockouts
+#[get("/lockouts", wrap = "RequireRole(Role::Admin)")]
+async fn get_lockouts(
+    query: web::Query<LockoutQuery>,
+    db: web::Data<Surreal<Client>>,
+) -> HttpResponse {
+    let events: Result<Vec<LockoutEvent>, surrealdb::Error> = async {
+        db.query("SELECT * FROM lockout_event ORDER BY created_at DESC LIMIT $limit")
+            .bind(("limit", query.limit.min(1000)))
+            .await?
+            .take(0)
+    }
+    .await;
+    match events {
+        Ok(events) => HttpResponse::Ok().json(events),
+        Err(err) => HttpResponse::InternalServerError().body(err.to_string()),
+    }
+}
+
+/// endpoint to lift the lockout of a account before it expires
+#[delete("/lockout/{email}", wrap = "RequireRole(Role::Admin)")]
+async fn unlock_account(
+    email: web::Path<String>,
+    audit: Audit,
+    throttle: web::Data<LoginThrottle>,
+    db: web::Data<Surreal<Client>>,
+) -> HttpResponse {
+    throttle.success(&account_key(&email));
+    let unlocked = serde_json::json!({ "email": email.as_str() });
+    audit
+        .record(&db, "lockout.unlock", None, (), unlocked)
+        .await;
+    HttpResponse::NoContent().finish()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::{Duration, Instant};
+
+    use super::LoginThrottle;
+    use crate::config::LoginThrottleConfig;
+
+    fn config() -> LoginThrottleConfig {
+        LoginThrottleConfig {
+            free_attempts: 3,
+            base_delay_seconds: 1,
+            max_delay_seconds: 60,
+            account_max_failures: 5,
+            ip_max_failures: 50,
+            lockout_minutes: 1,
+        }
+    }
+
+    #[test]
+    fn delays_attempts_after_the_free_attempts() {
+        let throttle = LoginThrottle::default();
+        let config = config();
+        let start = Instant::now();
+        let key = "account:lucy@cyber.night";
+
+        for _ in 0..3 {
+            assert_eq!(throttle.failure_at(&config, key, 5, start), None);
+        }
+        assert_eq!(throttle.check_at(&config, &[key], start), None);
+
+        assert_eq!(throttle.failure_at(&config, key, 5, start), None);
+        assert_eq!(
+            throttle.check_at(&config, &[key], start),
+            Some(Duration::from_secs(1))
+        );
+        assert_eq!(
+            throttle.check_at(&config, &[key], start + Duration::from_secs(1)),
+            None
+        );
+        // the longest wait of all keys counts
+        assert_eq!(
+            throttle.check_at(&config, &["ip:10.0.0.1", key], start),
+            Some(Duration::from_secs(1))
+        );
+    }
+
+    #[test]
+    fn locks_at_the_threshold_until_the_lockout_expires() {
+        let throttle = LoginThrottle::default();
+        let config = config();
+        let start = Instant::now();
+        let key = "account:lucy@cyber.night";
+
+        for _ in 0..4 {
+            assert_eq!(throttle.failure_at(&config, key, 5, start), None);
+        }
+        assert_eq!(
+            throttle.failure_at(&config, key, 5, start),
+            Some(Duration::from_secs(60))
+        );
+        assert_eq!(
+            throttle.check_at(&config, &[key], start + Duration::from_secs(30)),
+            Some(Duration::from_secs(30))
+        );
+        assert_eq!(
+            throttle.check_at(&config, &[key], start + Duration::from_secs(60)),
+            None
+        );
+
+        // failures are forgotten after a quiet lockout period
+        let later = start + Duration::from_secs(120);
+        assert_eq!(throttle.failure_at(&config, key, 5, later), None);
+        assert_eq!(throttle.check_at(&config, &[key], later), None);
+    }
+
+    #[test]
+    fn success_forgets_the_failures() {
+        let throttle = LoginThrottle::default();
+        let config = config();
+        let start = Instant::now();
+        let key = "account:lucy@cyber.night";
+
+        for _ in 0..4 {
+            throttle.failure_at(&config, key, 5, start);
+        }
+        throttle.success(key);
+        assert_eq!(throttle.check_at(&config, &[key], start), None);
+    }
+}
@@ -1 +1,15 @@
-pub mod user;
\ No newline at end of file
+pub mod acl;
+pub mod admin;
+pub mod alarm;
+pub mod alarm_rule;
+pub mod api_key;
+pub mod audit;
+pub mod escalation;
+pub mod export;
+pub mod import;
+pub mod lockout;
+pub mod notification;
+pub mod sensor;
+pub mod session;
+pub mod station;
+pub mod user;
@@ -0,0 +1,259 @@
+//! # web::notification
+//!
+//! `web::notification` is a module to manage the notification subscriptions and to read the
+//! delivery log, see `web::notify`. Every user subscribes themself by email.
+//! Admins also subscribe other users and roles, addresses and the webhook and MQTT channels.
+//! Users see their own subscriptions and deliveries, admins all of them.
+//!
+//! # Example
+//!
+//! ```text
+//! POST /api/v1/notification-subscriptions {"channel": {"kind": "email"}, "min_severity": 3}
+//! POST /api/v1/notification-subscriptions
+//! {"role": "operator", "stations": ["palettenlager"],
+//!  "channel": {"kind": "webhook", "url": "https://chat.plant.local/hooks/leitstand"}}
+//! GET /api/v1/notification-deliveries?status=failed&limit=50
+//! ```
+
+use actix_web::{delete, get, post, web, HttpResponse};
+use serde::Deserialize;
+use surrealdb::{engine::remote::ws::Client, sql::Thing, Surreal};
+
+use crate::{
+    app::{
+        acl::{station_thing, Access, StationPermission},
+        audit::Audit,
+    },
+    auth::{Claims, Role},
+    config::AppState,
+    notify::{channel::Channel, Delivery, DeliveryStatus, Subscription},
+};
+
+/// the maximum number of deliveries listed at once
+const MAX_LIMIT: usize = 1000;
+
+/// the caller if it is a user, API keys have no subscriptions
+fn caller(claims: &Claims) -> Option<Thing> {
+    surrealdb::sql::thing(&claims.sub)
+        .ok()
+        .filter(|caller| caller.tb == "user")
+}
+
+/// endpoint to list the subscriptions of the caller, admins list all subscriptions
+#[get("/notification-subscriptions")]
+async fn get_subscriptions(claims: Claims, db: web::Data<Surreal<Client>>) -> HttpResponse {
+    let subscriptions: Result<Vec<Subscription>, surrealdb::Error> = async {
+        match (claims.role >= Role::Admin, caller(&claims)) {
+            (true, _) => {
+                db.query("SELECT * FROM notification_subscription ORDER BY created_at")
+                    .await?
+                    .take(0)
+            }
+            (false, Some(caller)) => {
+                db.query("SELECT * FROM notification_subscription WHERE user = $caller ORDER BY created_at")
+                    .bind(("caller", caller))
+                    .await?
+                    .take(0)
+            }
+            (false, None) => Ok(Vec::new()),
+        }
+    }
+    .await;
+    match subscriptions {
+        Ok(subscriptions) => HttpResponse::Ok().json(subscriptions),
+        Err(err) => HttpResponse::InternalServerError().body(err.to_string()),
+    }
+}
+
+/// helper struct to deserialize the payload to subscribe, without user and role
+/// the caller subscribes themself
+#[derive(Deserialize)]
+pub struct CreateSubscription {
+    user: Option<String>,
+    role: Option<Role>,
+    channel: Channel,
+    #[serde(default)]
+    stations: Vec<String>,
+    #[serde(default)]
+    min_severity: u32,
+}
+
+/// endpoint to subscribe a user or role to alarms
+#[post("/notification-subscriptions")]
+async fn create_subscription(
+    json: web::Json<CreateSubscription>,
+    claims: Claims,
+    access: Access,
+    audit: Audit,
+    app_state: web::Data<AppState>,
+    db: web::Data<Surreal<Client>>,
+) -> HttpResponse {
+    let json = json.into_inner();
+    let admin = claims.role >= Role::Admin;
+    let own_email = json.user.is_none()
+        && json.role.is_none()
+        && json.channel == (Channel::Email { address: None });
+    if !admin && !own_email {
+        return HttpResponse::Forbidden()
+            .body("Only admins subscribe others, addresses, webhooks and MQTT topics");
+    }
+    if let Err(message) = json.channel.validate(&app_state.notifications) {
+        return HttpResponse::BadRequest().body(message);
+    }
+
+    let user = match (json.user.as_deref(), json.role) {
+        (Some(_), Some(_)) => {
+            return HttpResponse::BadRequest().body("Subscribe either a user or a role")
+        }
+        (Some(user), None) => match surrealdb::sql::thing(user) {
+            Ok(user) if user.tb == "user" => Some(user),
+            _ => return HttpResponse::BadRequest().body("user needs to be a user"),
+        },
+        (None, Some(_)) => None,
+        (None, None) => match caller(&claims) {
+            Some(caller) => Some(caller),
+            None => return HttpResponse::BadRequest().body("API keys can't subscribe themselves"),
+        },
+    };
+
+    let mut stations = Vec::new();
+    for station in &json.stations {
+        let station = station_thing(station);
+        if !access.permits(&station, StationPermission::View) {
+            return HttpResponse::NotFound().body("Station not found");
+        }
+        stations.push(station);
+    }
+
+    let created: Result<Option<Subscription>, surrealdb::Error> = async {
+        db.query("CREATE notification_subscription SET user = $user, role = $role, channel = $channel, stations = $stations, min_severity = $min_severity, enabled = true, created_at = time::now(), created_by = $created_by")
+            .bind(("user", user))
+            .bind(("role", json.role))
+            .bind(("channel", &json.channel))
+            .bind(("stations", stations))
+            .bind(("min_severity", json.min_severity))
+            .bind(("created_by", surrealdb::sql::thing(&claims.sub).ok()))
+            .await?
+            .take(0)
+    }
+    .await;
+    match created {
+        Ok(Some(subscription)) => {
+            audit
+                .record(
+                    &db,
+                    "notification_subscription.create",
+                    Some(&subscription.id),
+                    (),
+                    &subscription,
+                )
+                .await;
+            HttpResponse::Created().json(subscription)
+        }
+        Ok(None) => HttpResponse::InternalServerError().body("Subscription was not created"),
+        Err(err) => HttpResponse::InternalServerError().body(err.to_string()),
+    }
+}
+
+/// endpoint to unsubscribe, users delete their own subscriptions
+#[delete("/notification-subscription/{subscription}")]
+async fn delete_subscription(
+    subscription_id: web::Path<String>,
+    claims: Claims,
+    audit: Audit,
+    db: web::Data<Surreal<Client>>,
+) -> HttpResponse {
+    let subscription = Thing::from(("notification_subscription", subscription_id.as_str()));
+    let existing: Option<Subscription> = match db.select(subscription.clone()).await {
+        Ok(existing) => existing,
+        Err(err) => return HttpResponse::InternalServerError().body(err.to_string()),
+    };
+    let Some(existing) = existing else {
+        return HttpResponse::NotFound().body("Subscription not found");
+    };
+    if claims.role < Role::Admin && (existing.user.is_none() || existing.user != caller(&claims)) {
+        return HttpResponse::NotFound().body("Subscription not found");
+    }
+
+    let deleted: Result<Option<Subscription>, surrealdb::Error> = db.delete(subscription).await;
+    match deleted {
+        Ok(Some(deleted)) => {
+            audit
+                .record(
+                    &db,
+                    "notification_subscription.delete",
+                    Some(&deleted.id),
+                    &deleted,
+                    (),
+                )
+                .await;
+            HttpResponse::NoContent().finish()
+        }
+        Ok(None) => HttpResponse::NotFound().body("Subscription not found"),
+        Err(err) => HttpResponse::InternalServerError().body(err.to_string()),
+    }
+}
+
+/// helper struct to deserialize the delivery log query
+#[derive(Deserialize)]
+pub struct DeliveriesQuery {
+    subscription: Option<String>,
+    status: Option<DeliveryStatus>,
+    #[serde(default = "default_limit")]
+    limit: usize,
+}
+
+fn default_limit() -> usize {
+    100
+}
+
+/// endpoint to list the delivery log, the newest delivery first
+#[get("/notification-deliveries")]
+async fn get_deliveries(
+    query: web::Query<DeliveriesQuery>,
+    claims: Claims,
+    db: web::Data<Surreal<Client>>,
+) -> HttpResponse {
+    let query = query.into_inner();
+    let caller = caller(&claims);
+    let mut conditions = Vec::new();
+    if claims.role < Role::Admin {
+        if caller.is_none() {
+            return HttpResponse::Ok().json(Vec::<Delivery>::new());
+        }
+        conditions.push("subscription.user = $caller");
+    }
+    if query.subscription.is_some() {
+        conditions.push("subscription = $subscription");
+    }
+    if query.status.is_some() {
+        conditions.push("status = $status");
+    }
+    let condition = match conditions.is_empty() {
+        true => String::new(),
+        false => format!(" WHERE {}", conditions.join(" AND ")),
+    };
+
+    let deliveries: Result<Vec<Delivery>, surrealdb::Error> = async {
+        db.query(format!(
+            "SELECT * FROM notification_delivery{condition} ORDER BY created_at DESC LIMIT $limit"
+        ))
+        .bind(("caller", caller))
+        .bind((
+            "subscription",
+            query
+                .subscription
+                .as_deref()
+                .map(|id| Thing::from(("notification_subscription", id))),
+        ))
+        .bind(("status", query.status))
+        .bind(("limit", query.limit.min(MAX_LIMIT)))
+        .await?
+        .take(0)
+    }
+    .await;
+    match deliveries {
+        Ok(deliveries) => HttpResponse::Ok().json(deliveries),
+        Err(err) => HttpResponse::InternalServerError().body(err.to_string()),
+    }
+}
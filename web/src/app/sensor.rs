@@ -0,0 +1,214 @@
+//! # web::sensor
+//!
+//! `web::sensor` is a module to manage sensors
+//!
+
+use actix_web::{delete, patch, post, web, HttpResponse};
+use serde::Deserialize;
+use surrealdb::{engine::remote::ws::Client, sql::Thing, Surreal};
+
+use crate::{
+    app::{
+        acl::{Access, StationPermission},
+        audit::Audit,
+        station::DeleteQuery,
+    },
+    auth::Role,
+    middleware::role::RequireRole,
+};
+
+/// returns the station record if the station exists and the caller may manage it
+async fn find_station(
+    db: &Surreal<Client>,
+    access: &Access,
+    id: String,
+) -> Result<Option<Thing>, HttpResponse> {
+    match common::Station::get(db, id).await {
+        Ok(station) => Ok(station
+            .map(|station| station.get_id().clone())
+            .filter(|station| access.permits(station, StationPermission::Manage))),
+        Err(err) => Err(HttpResponse::InternalServerError().body(err.to_string())),
+    }
+}
+
+/// responds with 404 unless the caller may manage the station of the sensor
+async fn check_sensor(
+    db: &Surreal<Client>,
+    access: &Access,
+    sensor: &str,
+) -> Result<(), HttpResponse> {
+    match access
+        .permits_sensor(db, sensor, StationPermission::Manage)
+        .await
+    {
+        Ok(true) => Ok(()),
+        Ok(false) => Err(HttpResponse::NotFound().body("Sensor not found")),
+        Err(err) => Err(HttpResponse::InternalServerError().body(err.to_string())),
+    }
+}
+
+/// helper struct to deserialize the payload to create a sensor
+#[derive(Deserialize)]
+pub struct CreateSensor {
+    name: String,
+    station: String,
+    display_name: Option<String>,
+}
+
+/// endpoint to create a new sensor for an existing station, the name needs to be unique
+#[post("/sensors", wrap = "RequireRole(Role::Maintainer)")]
+async fn create_sensor(
+    json: web::Json<CreateSensor>,
+    access: Access,
+    audit: Audit,
+    db: web::Data<Surreal<Client>>,
+) -> HttpResponse {
+    let json = json.into_inner();
+    let name = json.name.trim().to_owned();
+    if name.is_empty() {
+        return HttpResponse::BadRequest().body("The name must not be empty");
+    }
+
+    let station = match find_station(&db, &access, json.station.clone()).await {
+        Ok(Some(station)) => station,
+        Ok(None) => {
+            return HttpResponse::BadRequest().body(format!("Unknown station: {}", json.station))
+        }
+        Err(response) => return response,
+    };
+
+    match common::Sensor::get(&db, name.clone()).await {
+        Ok(Some(_)) => {
+            return HttpResponse::Conflict().body(format!("Sensor {name} already exists"))
+        }
+        Ok(None) => {}
+        Err(err) => return HttpResponse::InternalServerError().body(err.to_string()),
+    }
+
+    let mut sensor = common::Sensor::new(name, station);
+    if let Some(display_name) = json.display_name {
+        sensor = sensor.with_display_name(display_name);
+    }
+    match sensor.save(&db).await {
+        Ok(sensor) => {
+            audit
+                .record(
+                    &db,
+                    "sensor.create",
+                    sensor.as_ref().map(common::Sensor::get_id),
+                    (),
+                    &sensor,
+                )
+                .await;
+            HttpResponse::Created().json(sensor)
+        }
+        Err(err) => HttpResponse::InternalServerError().body(err.to_string()),
+    }
+}
+
+/// helper struct to deserialize the payload to update a sensor, missing fields stay untouched
+#[derive(Deserialize)]
+pub struct UpdateSensor {
+    display_name: Option<String>,
+    station: Option<String>,
+    archived: Option<bool>,
+}
+
+/// applies the update, returns the sensor before and after or None if it does not exist
+async fn apply_update(
+    db: &Surreal<Client>,
+    id: String,
+    display_name: Option<String>,
+    station: Option<Thing>,
+    archived: Option<bool>,
+) -> Result<Option<(common::Sensor, common::Sensor)>, surrealdb::Error> {
+    let Some(before) = common::Sensor::get(db, id.clone()).await? else {
+        return Ok(None);
+    };
+    if let Some(display_name) = display_name {
+        common::Sensor::rename(db, id.clone(), display_name).await?;
+    }
+    if let Some(station) = station {
+        common::Sensor::move_to_station(db, id.clone(), station).await?;
+    }
+    if let Some(archived) = archived {
+        common::Sensor::set_archived(db, id.clone(), archived).await?;
+    }
+    Ok(common::Sensor::get(db, id)
+        .await?
+        .map(|sensor| (before, sensor)))
+}
+
+/// endpoint to rename, move, archive or restore a sensor
+#[patch("/sensor/{sensor}", wrap = "RequireRole(Role::Maintainer)")]
+async fn update_sensor(
+    sensor_id: web::Path<String>,
+    json: web::Json<UpdateSensor>,
+    access: Access,
+    audit: Audit,
+    db: web::Data<Surreal<Client>>,
+) -> HttpResponse {
+    if let Err(response) = check_sensor(&db, &access, &sensor_id).await {
+        return response;
+    }
+    let json = json.into_inner();
+    let station = match json.station {
+        Some(station_id) => match find_station(&db, &access, station_id.clone()).await {
+            Ok(Some(station)) => Some(station),
+            Ok(None) => {
+                return HttpResponse::BadRequest().body(format!("Unknown station: {station_id}"))
+            }
+            Err(response) => return response,
+        },
+        None => None,
+    };
+
+    match apply_update(
+        &db,
+        sensor_id.into_inner(),
+        json.display_name,
+        station,
+        json.archived,
+    )
+    .await
+    {
+        Ok(Some((before, sensor))) => {
+            audit
+                .record(
+                    &db,
+                    "sensor.update",
+                    Some(sensor.get_id()),
+                    &before,
+                    &sensor,
+                )
+                .await;
+            HttpResponse::Ok().json(sensor)
+        }
+        Ok(None) => HttpResponse::NotFound().body("Sensor not found"),
+        Err(err) => HttpResponse::InternalServerError().body(err.to_string()),
+    }
+}
+
+/// endpoint to delete a sensor
+#[delete("/sensor/{sensor}", wrap = "RequireRole(Role::Admin)")]
+async fn delete_sensor(
+    sensor_id: web::Path<String>,
+    query: web::Query<DeleteQuery>,
+    access: Access,
+    audit: Audit,
+    db: web::Data<Surreal<Client>>,
+) -> HttpResponse {
+    if let Err(response) = check_sensor(&db, &access, &sensor_id).await {
+        return response;
+    }
+    match common::Sensor::delete(&db, sensor_id.into_inner(), query.cascade).await {
+        Ok(Some(sensor)) => {
+            audit
+                .record(&db, "sensor.delete", Some(sensor.get_id()), &sensor, ())
+                .await;
+            HttpResponse::Ok().json(sensor)
+        }
+        Ok(None) => HttpResponse::NotFound().body("Sensor not found"),
+        Err(err) => HttpResponse::InternalServerError().body(err.to_string()),
+    }
+}
@@ -0,0 +1,350 @@
+//! # web::session
+//!
+//! `web::session` is a module to handle the server side sessions of signed in users.
+//! A session is created on sign in and referenced by the `sid` claim of its access tokens.
+//! Refresh tokens are only stored as sha256 hash and are rotated on every use,
+//! using a rotated refresh token again revokes the whole session.
+//!
+
+use actix_web::{delete, post, web, HttpResponse};
+use rand::RngCore;
+use serde::{Deserialize, Serialize};
+use surrealdb::{
+    engine::remote::ws::Client,
+    sql::{Datetime, Thing},
+    Surreal,
+};
+
+use crate::{
+    app::audit::Audit,
+    auth::{Claims, Role},
+    middleware::role::RequireRole,
+};
+
+/// A session of a signed in user
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Session {
+    pub id: Thing,
+    pub user: Thing,
+    pub created_at: Datetime,
+    pub expires_at: Datetime,
+    pub revoked_at: Option<Datetime>,
+    pub revoked_reason: Option<String>,
+}
+
+/// A stored refresh token
+#[derive(Debug, Serialize, Deserialize)]
+pub struct RefreshToken {
+    id: Thing,
+    session: Thing,
+    expires_at: Datetime,
+    used_at: Option<Datetime>,
+}
+
+/// The result of redeeming a refresh token
+pub enum Rotation {
+    /// the token was valid, contains the session and the new refresh token
+    Rotated(Session, String),
+    /// the token is unknown, expired or its session is revoked
+    Invalid,
+    /// the token was already used, the session has been revoked
+    Reused,
+}
+
+/// What redeeming a stored refresh token does
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Redemption {
+    /// issues a new refresh token for the session
+    Rotate,
+    /// rejects the token, it is unknown, expired or its session is revoked or expired
+    Reject,
+    /// revokes the session, the token was already used
+    Revoke,
+}
+
+/// Decides the redemption of the stored refresh token of the session at the time,
+/// the token or session being None if they don't exist
+pub fn redeem(
+    token: Option<&RefreshToken>,
+    session: Option<&Session>,
+    now: chrono::DateTime<chrono::Utc>,
+) -> Redemption {
+    let Some(token) = token else {
+        return Redemption::Reject;
+    };
+    if token.used_at.is_some() {
+        return Redemption::Revoke;
+    }
+    let active = session.is_some_and(|session| {
+        session.revoked_at.is_none() && session.expires_at.0 > now && session.id == token.session
+    });
+    match token.expires_at.0 > now && active {
+        true => Redemption::Rotate,
+        false => Redemption::Reject,
+    }
+}
+
+/// generates a random refresh token
+fn generate_refresh_token() -> String {
+    let mut bytes = [0u8; 32];
+    rand::thread_rng().fill_bytes(&mut bytes);
+    bytes.iter().map(|byte| format!("{byte:02x}")).collect()
+}
+
+/// stores a new refresh token for the session and returns it
+async fn issue_refresh_token(
+    db: &Surreal<Client>,
+    session: &Session,
+) -> Result<String, surrealdb::Error> {
+    let token = generate_refresh_token();
+    db.query("CREATE refresh_token SET session = $session, token_hash = crypto::sha256($token), expires_at = $expires_at")
+        .bind(("session", &session.id))
+        .bind(("token", &token))
+        .bind(("expires_at", &session.expires_at))
+        .await?
+        .check()?;
+    Ok(token)
+}
+
+/// Creates a new session for the user and returns it with its first refresh token,
+/// None if the database did not return the created session
+pub async fn create(
+    db: &Surreal<Client>,
+    user: &Thing,
+    lifetime: chrono::Duration,
+) -> Result<Option<(Session, String)>, surrealdb::Error> {
+    let session: Option<Session> = db
+        .query(
+            "CREATE session SET user = $user, created_at = time::now(), expires_at = $expires_at",
+        )
+        .bind(("user", user))
+        .bind(("expires_at", Datetime(chrono::Utc::now() + lifetime)))
+        .await?
+        .take(0)?;
+    let Some(session) = session else {
+        return Ok(None);
+    };
+    let token = issue_refresh_token(db, &session).await?;
+    Ok(Some((session, token)))
+}
+
+/// Redeems a refresh token and rotates it, see `redeem`
+pub async fn rotate(db: &Surreal<Client>, token: &str) -> Result<Rotation, surrealdb::Error> {
+    let stored: Option<RefreshToken> = db
+        .query("SELECT * FROM refresh_token WHERE token_hash = crypto::sha256($token)")
+        .bind(("token", token))
+        .await?
+        .take(0)?;
+    let Some(stored) = stored else {
+        return Ok(Rotation::Invalid);
+    };
+    let session: Option<Session> = db.select(stored.session.clone()).await?;
+    match redeem(Some(&stored), session.as_ref(), chrono::Utc::now()) {
+        Redemption::Rotate => {}
+        Redemption::Reject => return Ok(Rotation::Invalid),
+        Redemption::Revoke => {
+            revoke(db, &stored.session, "refresh token reuse").await?;
+            return Ok(Rotation::Reused);
+        }
+    }
+
+    // marking the token as used only succeeds once, even for concurrent requests
+    let redeemed: Option<RefreshToken> = db
+        .query("UPDATE $token SET used_at = time::now() WHERE used_at = NONE RETURN BEFORE")
+        .bind(("token", &stored.id))
+        .await?
+        .take(0)?;
+    match (redeemed, session) {
+        (Some(_), Some(session)) => {
+            let token = issue_refresh_token(db, &session).await?;
+            Ok(Rotation::Rotated(session, token))
+        }
+        _ => {
+            revoke(db, &stored.session, "refresh token reuse").await?;
+            Ok(Rotation::Reused)
+        }
+    }
+}
+
+/// Returns the session if it is neither revoked nor expired
+pub async fn get_active(
+    db: &Surreal<Client>,
+    session: &Thing,
+) -> Result<Option<Session>, surrealdb::Error> {
+    db.query("SELECT * FROM $session WHERE revoked_at = NONE AND expires_at > time::now()")
+        .bind(("session", session))
+        .await?
+        .take(0)
+}
+
+/// Revokes a single session
+pub async fn revoke(
+    db: &Surreal<Client>,
+    session: &Thing,
+    reason: &str,
+) -> Result<(), surrealdb::Error> {
+    db.query("UPDATE $session SET revoked_at = time::now(), revoked_reason = $reason WHERE revoked_at = NONE")
+        .bind(("session", session))
+        .bind(("reason", reason))
+        .await?
+        .check()?;
+    Ok(())
+}
+
+/// Revokes all active sessions of a user, returns the number of revoked sessions
+pub async fn revoke_all(
+    db: &Surreal<Client>,
+    user: &Thing,
+    reason: &str,
+) -> Result<usize, surrealdb::Error> {
+    let revoked: Vec<Session> = db
+        .query("UPDATE session SET revoked_at = time::now(), revoked_reason = $reason WHERE user = $user AND revoked_at = NONE")
+        .bind(("user", user))
+        .bind(("reason", reason))
+        .await?
+        .take(0)?;
+    Ok(revoked.len())
+}
+
+/// Revokes all active sessions of a user except the given one, returns the number of revoked sessions
+pub async fn revoke_others(
+    db: &Surreal<Client>,
+    user: &Thing,
+    except: Option<&Thing>,
+    reason: &str,
+) -> Result<usize, surrealdb::Error> {
+    let revoked: Vec<Session> = db
+        .query("UPDATE session SET revoked_at = time::now(), revoked_reason = $reason WHERE user = $user AND revoked_at = NONE AND id != $except")
+        .bind(("user", user))
+        .bind(("except", except))
+        .bind(("reason", reason))
+        .await?
+        .take(0)?;
+    Ok(revoked.len())
+}
+
+/// endpoint to sign out, revokes the session of the current access token
+#[post("/signout")]
+async fn sign_out(claims: Claims, audit: Audit, db: web::Data<Surreal<Client>>) -> HttpResponse {
+    if claims.scopes.is_some() {
+        return HttpResponse::BadRequest().body("API keys can't sign out, revoke them instead");
+    }
+    let Ok(session) = surrealdb::sql::thing(&claims.sid) else {
+        return HttpResponse::BadRequest().body("Invalid session");
+    };
+    match revoke(&db, &session, "signed out").await {
+        Ok(()) => {
+            audit
+                .record(&db, "session.sign_out", Some(&session), (), ())
+                .await;
+            HttpResponse::NoContent().finish()
+        }
+        Err(err) => HttpResponse::InternalServerError().body(err.to_string()),
+    }
+}
+
+/// helper struct to serialize the number of revoked sessions
+#[derive(Serialize)]
+struct RevokedSessions {
+    revoked: usize,
+}
+
+/// endpoint to revoke all sessions of a user, e.g. after a device got lost
+#[delete("/user/{user}/sessions", wrap = "RequireRole(Role::Admin)")]
+async fn revoke_user_sessions(
+    user_id: web::Path<String>,
+    audit: Audit,
+    db: web::Data<Surreal<Client>>,
+) -> HttpResponse {
+    let user = Thing::from(("user", user_id.as_str()));
+    match revoke_all(&db, &user, "revoked by admin").await {
+        Ok(revoked) => {
+            let revoked = RevokedSessions { revoked };
+            audit
+                .record(&db, "user.sessions_revoke", Some(&user), (), &revoked)
+                .await;
+            HttpResponse::Ok().json(revoked)
+        }
+        Err(err) => HttpResponse::InternalServerError().body(err.to_string()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::{DateTime, TimeDelta, TimeZone, Utc};
+    use surrealdb::sql::{Datetime, Thing};
+
+    use super::{redeem, Redemption, RefreshToken, Session};
+
+    fn now() -> DateTime<Utc> {
+        Utc.with_ymd_and_hms(2023, 6, 1, 12, 0, 0).unwrap()
+    }
+
+    fn session() -> Session {
+        Session {
+            id: Thing::from(("session", "s1")),
+            user: Thing::from(("user", "anna")),
+            created_at: Datetime(now() - TimeDelta::days(1)),
+            expires_at: Datetime(now() + TimeDelta::days(13)),
+            revoked_at: None,
+            revoked_reason: None,
+        }
+    }
+
+    fn token(used: bool) -> RefreshToken {
+        RefreshToken {
+            id: Thing::from(("refresh_token", "t1")),
+            session: Thing::from(("session", "s1")),
+            expires_at: Datetime(now() + TimeDelta::days(13)),
+            used_at: used.then(|| Datetime(now() - TimeDelta::minutes(5))),
+        }
+    }
+
+    #[test]
+    fn rotates_unused_tokens_of_active_sessions() {
+        assert_eq!(
+            redeem(Some(&token(false)), Some(&session()), now()),
+            Redemption::Rotate
+        );
+    }
+
+    #[test]
+    fn reusing_a_rotated_token_revokes_the_session() {
+        assert_eq!(
+            redeem(Some(&token(true)), Some(&session()), now()),
+            Redemption::Revoke
+        );
+        let mut revoked = session();
+        revoked.revoked_at = Some(Datetime(now()));
+        assert_eq!(
+            redeem(Some(&token(true)), Some(&revoked), now()),
+            Redemption::Revoke
+        );
+    }
+
+    #[test]
+    fn expired_or_revoked_sessions_do_not_rotate() {
+        let mut revoked = session();
+        revoked.revoked_at = Some(Datetime(now() - TimeDelta::minutes(1)));
+        assert_eq!(
+            redeem(Some(&token(false)), Some(&revoked), now()),
+            Redemption::Reject
+        );
+
+        let mut expired = session();
+        expired.expires_at = Datetime(now() - TimeDelta::minutes(1));
+        assert_eq!(
+            redeem(Some(&token(false)), Some(&expired), now()),
+            Redemption::Reject
+        );
+
+        let mut expired = token(false);
+        expired.expires_at = Datetime(now());
+        assert_eq!(
+            redeem(Some(&expired), Some(&session()), now()),
+            Redemption::Reject
+        );
+        assert_eq!(redeem(Some(&token(false)), None, now()), Redemption::Reject);
+        assert_eq!(redeem(None, Some(&session()), now()), Redemption::Reject);
+    }
+}
@@ -0,0 +1,155 @@
+//! # web::station
+//!
+//! `web::station` is a module to manage stations
+//!
+
+use actix_web::{delete, patch, post, web, HttpResponse};
+use serde::Deserialize;
+use surrealdb::{engine::remote::ws::Client, Surreal};
+
+use crate::{
+    app::{
+        acl::{station_thing, Access, StationPermission},
+        audit::Audit,
+    },
+    auth::Role,
+    middleware::role::RequireRole,
+};
+
+/// helper struct to deserialize the payload to create a station
+#[derive(Deserialize)]
+pub struct CreateStation {
+    name: String,
+    display_name: Option<String>,
+}
+
+/// endpoint to create a new station, the name needs to be unique
+#[post("/stations", wrap = "RequireRole(Role::Maintainer)")]
+async fn create_station(
+    json: web::Json<CreateStation>,
+    audit: Audit,
+    db: web::Data<Surreal<Client>>,
+) -> HttpResponse {
+    let json = json.into_inner();
+    let name = json.name.trim().to_owned();
+    if name.is_empty() {
+        return HttpResponse::BadRequest().body("The name must not be empty");
+    }
+
+    match common::Station::get(&db, name.clone()).await {
+        Ok(Some(_)) => {
+            return HttpResponse::Conflict().body(format!("Station {name} already exists"))
+        }
+        Ok(None) => {}
+        Err(err) => return HttpResponse::InternalServerError().body(err.to_string()),
+    }
+
+    match common::Station::new(name)
+        .with_display_name(json.display_name)
+        .create(&db)
+        .await
+    {
+        Ok(station) => {
+            audit
+                .record(
+                    &db,
+                    "station.create",
+                    station.as_ref().map(common::Station::get_id),
+                    (),
+                    &station,
+                )
+                .await;
+            HttpResponse::Created().json(station)
+        }
+        Err(err) => HttpResponse::InternalServerError().body(err.to_string()),
+    }
+}
+
+/// helper struct to deserialize the payload to update a station, missing fields stay untouched
+#[derive(Deserialize)]
+pub struct UpdateStation {
+    display_name: Option<String>,
+    archived: Option<bool>,
+}
+
+/// applies the update, returns the station before and after or None if it does not exist
+async fn apply_update(
+    db: &Surreal<Client>,
+    id: String,
+    update: UpdateStation,
+) -> Result<Option<(common::Station, common::Station)>, surrealdb::Error> {
+    let Some(before) = common::Station::get(db, id.clone()).await? else {
+        return Ok(None);
+    };
+    if let Some(display_name) = update.display_name {
+        common::Station::rename(db, id.clone(), display_name).await?;
+    }
+    if let Some(archived) = update.archived {
+        common::Station::set_archived(db, id.clone(), archived).await?;
+    }
+    Ok(common::Station::get(db, id)
+        .await?
+        .map(|station| (before, station)))
+}
+
+/// endpoint to rename, archive or restore a station
+#[patch("/station/{station}", wrap = "RequireRole(Role::Maintainer)")]
+async fn update_station(
+    station_id: web::Path<String>,
+    json: web::Json<UpdateStation>,
+    access: Access,
+    audit: Audit,
+    db: web::Data<Surreal<Client>>,
+) -> HttpResponse {
+    if !access.permits(&station_thing(&station_id), StationPermission::Manage) {
+        return HttpResponse::NotFound().body("Station not found");
+    }
+    match apply_update(&db, station_id.into_inner(), json.into_inner()).await {
+        Ok(Some((before, station))) => {
+            audit
+                .record(
+                    &db,
+                    "station.update",
+                    Some(station.get_id()),
+                    &before,
+                    &station,
+                )
+                .await;
+            HttpResponse::Ok().json(station)
+        }
+        Ok(None) => HttpResponse::NotFound().body("Station not found"),
+        Err(err) => HttpResponse::InternalServerError().body(err.to_string()),
+    }
+}
+
+/// helper struct to deserialize the delete query
+#[derive(Deserialize)]
+pub struct DeleteQuery {
+    /// also delete all values of the deleted sensors
+    #[serde(default)]
+    pub cascade: bool,
+}
+
+/// endpoint to delete a station and its sensors
+#[delete("/station/{station}", wrap = "RequireRole(Role::Admin)")]
+async fn delete_station(
+    station_id: web::Path<String>,
+    query: web::Query<DeleteQuery>,
+    access: Access,
+    audit: Audit,
+    db: web::Data<Surreal<Client>>,
+) -> HttpResponse {
+    if !access.permits(&station_thing(&station_id), StationPermission::Manage) {
+        return HttpResponse::NotFound().body("Station not found");
+    }
+    match common::Station::delete(&db, station_id.into_inner(), query.cascade).await {
+        Ok(Some(station)) => {
+            audit
+                .record(&db, "station.delete", Some(station.get_id()), &station, ())
+                .await;
+            HttpResponse::Ok().json(station)
+        }
+        Ok(None) => HttpResponse::NotFound().body("Station not found"),
+        Err(err) => HttpResponse::InternalServerError().body(err.to_string()),
+    }
+}
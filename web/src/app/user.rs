@@ -5,20 +5,125 @@
 //! # Example
 //!
 
-use actix_web::{post, web, HttpResponse};
+use actix_web::{
+    delete, get, http::header::RETRY_AFTER, patch, post, web, HttpRequest, HttpResponse,
+};
+use futures_util::StreamExt;
+use rand::RngCore;
 use serde::Deserialize;
 use surrealdb::{engine::remote::ws::Client, sql::Thing, Surreal};
 
-use crate::config::AppState;
+use crate::{
+    app::{
+        audit::Audit,
+        lockout::{self, LoginThrottle},
+        session::Rotation,
+    },
+    auth::{Claims, Role},
+    config::AppState,
+    jwt::JwtKeys,
+    middleware::rate_limit::{client_ip, RateLimit},
+    provider::AuthProviders,
+};
+
+/// upper bound of the size of an uploaded avatar
+const MAX_AVATAR_SIZE: usize = 2 * 1024 * 1024;
+
+/// path under which uploaded avatars are served
+const AVATAR_PATH: &str = "/avatars";
 
 /// A struct containing all necessary user data
 #[derive(Debug, serde::Serialize, serde::Deserialize)]
-struct User {
+pub struct User {
     id: Option<Thing>,
     email: String,
     name: String,
-    jwt: Option<String>,
+    pub jwt: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub refresh_token: Option<String>,
     icon: Option<String>,
+    #[serde(default)]
+    role: Role,
+    /// free form settings of the frontend, stored as json string
+    #[serde(default, deserialize_with = "deserialize_preferences")]
+    preferences: Option<serde_json::Value>,
+    #[serde(default = "active_default")]
+    active: bool,
+    /// set for temporary passwords, which should be changed on the next sign in
+    #[serde(default)]
+    must_change_password: bool,
+}
+
+/// reads the preferences, which are stored as json string in the database
+fn deserialize_preferences<'de, D>(deserializer: D) -> Result<Option<serde_json::Value>, D::Error>
+where
+    D: serde::Deserializer<'de>,
+{
+    let value: Option<serde_json::Value> = Option::deserialize(deserializer)?;
+    Ok(match value {
+        Some(serde_json::Value::String(json)) => serde_json::from_str(&json).ok(),
+        value => value,
+    })
+}
+
+fn active_default() -> bool {
+    true
+}
+
+/// starts a new session for the user and sets a access and refresh token
+async fn start_session(
+    app_state: &AppState,
+    keys: &JwtKeys,
+    db: &Surreal<Client>,
+    user: &mut User,
+) -> Result<(), HttpResponse> {
+    let Some(user_id) = user.id.clone() else {
+        return Err(HttpResponse::InternalServerError().body("User without id"));
+    };
+    let (session, refresh_token) =
+        crate::app::session::create(db, &user_id, app_state.tokens.refresh_token_lifetime())
+            .await
+            .map_err(|err| HttpResponse::InternalServerError().body(err.to_string()))?
+            .ok_or_else(|| {
+                HttpResponse::InternalServerError().body("Session could not be created")
+            })?;
+
+    let claims = Claims::new(
+        &user_id,
+        &session.id,
+        user.name.clone(),
+        user.email.clone(),
+        user.role,
+        user.must_change_password,
+        app_state.tokens.access_token_lifetime(),
+    );
+    let jwt = crate::auth::generate_token(keys, &claims)
+        .map_err(|err| HttpResponse::InternalServerError().body(err.to_string()))?;
+    user.jwt = Some(jwt);
+    user.refresh_token = Some(refresh_token);
+    Ok(())
+}
+
+/// Signs in a user verified by a provider: checks that the account is active
+/// and starts a session, the user contains the access and refresh token
+pub async fn open_session(
+    app_state: &AppState,
+    keys: &JwtKeys,
+    db: &Surreal<Client>,
+    user_id: &Thing,
+) -> Result<User, HttpResponse> {
+    let user: Option<User> = db
+        .select(user_id.clone())
+        .await
+        .map_err(|err| HttpResponse::InternalServerError().body(err.to_string()))?;
+    let Some(mut user) = user else {
+        return Err(HttpResponse::NotFound().body("User not found"));
+    };
+    if !user.active {
+        return Err(HttpResponse::Forbidden().body("The account is deactivated"));
+    }
+    start_session(app_state, keys, db, &mut user).await?;
+    Ok(user)
 }
 
 /// helper struct to deserialize the login form
@@ -28,81 +133,427 @@ pub struct SignInFormData {
     password: String,
 }
 
-/// route to login a existing user
-#[post("/signin")]
+/// responds with 429 and the seconds to wait in Retry-After
+fn too_many_attempts(wait: std::time::Duration) -> HttpResponse {
+    let seconds = wait.as_secs() + 1;
+    HttpResponse::TooManyRequests()
+        .insert_header((RETRY_AFTER, seconds.to_string()))
+        .body(format!(
+            "Too many failed attempts, retry after {seconds} seconds"
+        ))
+}
+
+/// route to login a user with the configured password providers, see `web::provider`.
+/// Failed attempts are throttled per account and per client ip, see `web::lockout`
+#[post("/signin", wrap = "RateLimit::AUTH")]
+#[allow(clippy::too_many_arguments)]
 pub async fn sign_in(
+    req: HttpRequest,
+    audit: Audit,
     app_state: web::Data<AppState>,
+    keys: web::Data<JwtKeys>,
+    providers: web::Data<AuthProviders>,
     db: web::Data<Surreal<Client>>,
+    throttle: web::Data<LoginThrottle>,
     form: web::Form<SignInFormData>,
 ) -> HttpResponse {
-    let user: Option<User> = db.query("SELECT * FROM user WHERE email = $email AND crypto::argon2::compare(password, $password)")
-    .bind(("email", form.email.clone()))
-    .bind(("password", form.password.clone()))
-    .await
-    .unwrap()
-    .take(0)
-    .unwrap();
-
-    if user.is_none() {
+    let config = &app_state.login_throttle;
+    let email = form.email.trim().to_lowercase();
+    let ip = client_ip(&req, app_state.rate_limits.trust_forwarded_for);
+    let (account_key, ip_key) = (lockout::account_key(&email), lockout::ip_key(&ip));
+    if let Some(wait) = throttle.check(config, &[&account_key, &ip_key]) {
+        return too_many_attempts(wait);
+    }
+
+    let identity = match providers.authenticate(&db, &email, &form.password).await {
+        Ok(identity) => identity,
+        Err(err) => return HttpResponse::ServiceUnavailable().body(err),
+    };
+    let Some(identity) = identity else {
+        let locks = [
+            ("account", &email, &account_key, config.account_max_failures),
+            ("ip", &ip, &ip_key, config.ip_max_failures),
+        ];
+        for (kind, key, throttle_key, max_failures) in locks {
+            if let Some(lockout) = throttle.failure(config, throttle_key, max_failures) {
+                lockout::record(&db, kind, key, None, lockout).await;
+            }
+        }
         return HttpResponse::NotFound().body("Wrong email or password");
+    };
+    throttle.success(&account_key);
+
+    let user_id = match crate::provider::provision(&app_state, &audit, &db, &identity).await {
+        Ok(user_id) => user_id,
+        Err(response) => return response,
+    };
+    match open_session(&app_state, &keys, &db, &user_id).await {
+        Ok(user) => HttpResponse::Ok().json(user),
+        Err(response) => response,
     }
+}
 
-    let mut user = user.unwrap();
-    user.jwt = Some(crate::auth::generate_token(
-        app_state.secret.clone(),
-        user.email.clone(),
-        1,
-    ));
-    HttpResponse::Ok().json(user)
+/// helper struct to deserialize the refresh form
+#[derive(Deserialize)]
+pub struct RefreshFormData {
+    refresh_token: String,
+}
+
+/// helper struct to serialize a rotated token pair
+#[derive(serde::Serialize)]
+struct TokenPair {
+    jwt: String,
+    refresh_token: String,
+}
+
+/// route to exchange a refresh token for a new access token and a new refresh token.
+/// A refresh token can only be used once, reusing it revokes the session.
+#[post("/refresh", wrap = "RateLimit::AUTH")]
+pub async fn refresh(
+    app_state: web::Data<AppState>,
+    keys: web::Data<JwtKeys>,
+    db: web::Data<Surreal<Client>>,
+    form: web::Form<RefreshFormData>,
+) -> HttpResponse {
+    let (session, refresh_token) = match crate::app::session::rotate(&db, &form.refresh_token).await
+    {
+        Ok(Rotation::Rotated(session, refresh_token)) => (session, refresh_token),
+        Ok(Rotation::Invalid) => {
+            return HttpResponse::Unauthorized().body("Invalid or expired refresh token")
+        }
+        Ok(Rotation::Reused) => {
+            return HttpResponse::Unauthorized()
+                .body("Refresh token was already used, the session has been revoked")
+        }
+        Err(err) => return HttpResponse::InternalServerError().body(err.to_string()),
+    };
+
+    let user: Option<User> = match db.select(session.user.clone()).await {
+        Ok(user) => user,
+        Err(err) => return HttpResponse::InternalServerError().body(err.to_string()),
+    };
+    let Some(user) = user else {
+        return HttpResponse::Unauthorized().body("User does not exist anymore");
+    };
+    if !user.active {
+        return HttpResponse::Unauthorized().body("The account is deactivated");
+    }
+
+    let claims = Claims::new(
+        &session.user,
+        &session.id,
+        user.name,
+        user.email,
+        user.role,
+        user.must_change_password,
+        app_state.tokens.access_token_lifetime(),
+    );
+    match crate::auth::generate_token(&keys, &claims) {
+        Ok(jwt) => HttpResponse::Ok().json(TokenPair { jwt, refresh_token }),
+        Err(err) => HttpResponse::InternalServerError().body(err.to_string()),
+    }
+}
+
+/// returns the record id of the calling user
+fn caller(claims: &Claims) -> Result<Thing, HttpResponse> {
+    if claims.scopes.is_some() {
+        return Err(HttpResponse::BadRequest().body("API keys don't have a profile"));
+    }
+    surrealdb::sql::thing(&claims.sub)
+        .map_err(|_| HttpResponse::BadRequest().body("Invalid user in token"))
+}
+
+/// responds with the user or 404 if it does not exist
+fn user_response(user: Result<Option<User>, surrealdb::Error>) -> HttpResponse {
+    match user {
+        Ok(Some(user)) => HttpResponse::Ok().json(user),
+        Ok(None) => HttpResponse::NotFound().body("User not found"),
+        Err(err) => HttpResponse::InternalServerError().body(err.to_string()),
+    }
+}
+
+/// records the change of the user and responds with the user after it
+async fn audited_response(
+    audit: &Audit,
+    db: &Surreal<Client>,
+    action: &str,
+    before: &User,
+    after: Result<Option<User>, surrealdb::Error>,
+) -> HttpResponse {
+    if let Ok(Some(after)) = &after {
+        audit
+            .record(db, action, after.id.as_ref(), before, after)
+            .await;
+    }
+    user_response(after)
+}
+
+/// endpoint to retrieve the profile of the calling user
+#[get("/me")]
+pub async fn get_me(claims: Claims, db: web::Data<Surreal<Client>>) -> HttpResponse {
+    let user = match caller(&claims) {
+        Ok(user) => user,
+        Err(response) => return response,
+    };
+    user_response(db.select(user).await)
 }
 
-/// helper struct to deserialize the User update form
+/// helper struct to deserialize a profile update, missing fields stay untouched
 #[derive(Deserialize)]
-pub struct UserFormData {
-    user: String,
-    name: String,
-    icon: String,
+pub struct ProfileUpdate {
+    name: Option<String>,
+    preferences: Option<serde_json::Map<String, serde_json::Value>>,
 }
 
-/// endpoint to update the user data
-#[post("/user/update")]
-pub async fn update_user(
+/// endpoint to update the name and preferences of the calling user
+#[patch("/me")]
+pub async fn update_me(
+    claims: Claims,
+    audit: Audit,
+    db: web::Data<Surreal<Client>>,
+    json: web::Json<ProfileUpdate>,
+) -> HttpResponse {
+    let user = match caller(&claims) {
+        Ok(user) => user,
+        Err(response) => return response,
+    };
+    let json = json.into_inner();
+
+    let mut changes = serde_json::Map::new();
+    if let Some(name) = json.name {
+        if name.trim().is_empty() {
+            return HttpResponse::BadRequest().body("The name must not be empty");
+        }
+        changes.insert("name".to_owned(), name.trim().into());
+    }
+    if let Some(preferences) = json.preferences {
+        changes.insert(
+            "preferences".to_owned(),
+            serde_json::Value::Object(preferences).to_string().into(),
+        );
+    }
+
+    let update: Result<(Option<User>, Option<User>), surrealdb::Error> = async {
+        let mut response = db
+            .query("SELECT * FROM $user; UPDATE $user MERGE $changes WHERE id = $user")
+            .bind(("user", user))
+            .bind(("changes", changes))
+            .await?;
+        Ok((response.take(0)?, response.take(1)?))
+    }
+    .await;
+    match update {
+        Ok((Some(before), after)) => {
+            audited_response(&audit, &db, "profile.update", &before, Ok(after)).await
+        }
+        Ok((None, _)) => HttpResponse::NotFound().body("User not found"),
+        Err(err) => HttpResponse::InternalServerError().body(err.to_string()),
+    }
+}
+
+/// helper struct to deserialize the password change
+#[derive(Deserialize)]
+pub struct PasswordChange {
+    current_password: String,
+    new_password: String,
+}
+
+/// endpoint to change the password of the calling user.
+/// Wrong current passwords count as failed sign ins of the account, see `web::lockout`.
+/// All other sessions of the user are revoked afterwards.
+/// Tokens of a temporary password only allow this change, a unrestricted token is
+/// obtained via /refresh afterwards.
+#[post("/me/password")]
+pub async fn change_password(
+    claims: Claims,
+    audit: Audit,
     app_state: web::Data<AppState>,
     db: web::Data<Surreal<Client>>,
-    form: web::Form<UserFormData>,
+    throttle: web::Data<LoginThrottle>,
+    json: web::Json<PasswordChange>,
 ) -> HttpResponse {
-    let user: Option<User> = db
-        .query("UPDATE $user SET name = $name, icon = $icon")
-        .bind(("user", &form.user))
-        .bind(("name", &form.name))
-        .bind(("icon", &form.icon))
-        .await
-        .unwrap()
-        .take(0)
-        .unwrap();
+    let user = match caller(&claims) {
+        Ok(user) => user,
+        Err(response) => return response,
+    };
+    let config = &app_state.login_throttle;
+    let account_key = lockout::account_key(&claims.email);
+    if let Some(wait) = throttle.check(config, &[&account_key]) {
+        return too_many_attempts(wait);
+    }
+    if let Err(message) = app_state
+        .password_policy
+        .validate(&json.new_password, &claims.email)
+    {
+        return HttpResponse::BadRequest().body(message);
+    }
 
-    if user.is_none() {
-        return HttpResponse::NotFound().body("Wrong email or password");
+    let verified: Result<Option<User>, surrealdb::Error> = async {
+        db.query("SELECT * FROM $user WHERE crypto::argon2::compare(password, $password)")
+            .bind(("user", &user))
+            .bind(("password", &json.current_password))
+            .await?
+            .take(0)
+    }
+    .await;
+    match verified {
+        Ok(Some(_)) => throttle.success(&account_key),
+        Ok(None) => {
+            let max_failures = config.account_max_failures;
+            if let Some(lockout) = throttle.failure(config, &account_key, max_failures) {
+                lockout::record(&db, "account", &claims.email, None, lockout).await;
+            }
+            return HttpResponse::Forbidden().body("The current password is wrong");
+        }
+        Err(err) => return HttpResponse::InternalServerError().body(err.to_string()),
     }
 
-    let mut user = user.unwrap();
-    user.jwt = Some(crate::auth::generate_token(
-        app_state.secret.clone(),
-        user.email.clone(),
-        1,
-    ));
-    HttpResponse::Ok().json(user)
-}
-
-/// endpoint to register a new user
-/// !!!dummy implementation
-#[post("/signup")]
-pub async fn sign_up(db: web::Data<Surreal<Client>>) -> HttpResponse {
-    let r = db
-    .query("CREATE user SET email = $email, name = 'Hugo', password = crypto::argon2::generate($password)")
-    .bind(("email", "test@mail.de"))
-    .bind(("password", "1234"))
-    .await.unwrap();
-    dbg!(r);
-    HttpResponse::Ok().body("1")
+    let updated = async {
+        db.query("UPDATE $user SET password = crypto::argon2::generate($password), must_change_password = false")
+            .bind(("user", &user))
+            .bind(("password", &json.new_password))
+            .await?
+            .check()?;
+        let current = surrealdb::sql::thing(&claims.sid).ok();
+        crate::app::session::revoke_others(&db, &user, current.as_ref(), "password changed").await
+    }
+    .await;
+    match updated {
+        Ok(_) => {
+            let after = serde_json::json!({ "password": "changed" });
+            audit
+                .record(&db, "profile.password_change", Some(&user), (), after)
+                .await;
+            HttpResponse::NoContent().finish()
+        }
+        Err(err) => HttpResponse::InternalServerError().body(err.to_string()),
+    }
+}
+
+/// returns the file extension if the bytes are a supported image
+fn image_extension(bytes: &[u8]) -> Option<&'static str> {
+    match bytes {
+        [0x89, b'P', b'N', b'G', ..] => Some("png"),
+        [0xFF, 0xD8, 0xFF, ..] => Some("jpg"),
+        [b'G', b'I', b'F', b'8', ..] => Some("gif"),
+        [b'R', b'I', b'F', b'F', _, _, _, _, b'W', b'E', b'B', b'P', ..] => Some("webp"),
+        _ => None,
+    }
+}
+
+/// removes a previously uploaded avatar file, external icons are left alone
+fn remove_avatar(app_state: &AppState, icon: Option<&str>) {
+    let Some(filename) = icon.and_then(|icon| icon.strip_prefix(&format!("{AVATAR_PATH}/"))) else {
+        return;
+    };
+    // only plain file names are written by upload_avatar
+    if filename.contains(['/', '\\']) || filename.starts_with('.') {
+        return;
+    }
+    let _ = std::fs::remove_file(std::path::Path::new(&app_state.avatars).join(filename));
+}
+
+/// endpoint to upload a avatar for the calling user, the image is sent as request body.
+/// Supported are png, jpeg, gif and webp images.
+#[post("/me/avatar")]
+pub async fn upload_avatar(
+    claims: Claims,
+    audit: Audit,
+    app_state: web::Data<AppState>,
+    db: web::Data<Surreal<Client>>,
+    mut payload: web::Payload,
+) -> HttpResponse {
+    let user_id = match caller(&claims) {
+        Ok(user) => user,
+        Err(response) => return response,
+    };
+
+    let mut body = web::BytesMut::new();
+    while let Some(chunk) = payload.next().await {
+        let chunk = match chunk {
+            Ok(chunk) => chunk,
+            Err(err) => return HttpResponse::BadRequest().body(err.to_string()),
+        };
+        if body.len() + chunk.len() > MAX_AVATAR_SIZE {
+            return HttpResponse::PayloadTooLarge().body(format!(
+                "The avatar exceeds the limit of {} MiB",
+                MAX_AVATAR_SIZE / 1024 / 1024
+            ));
+        }
+        body.extend_from_slice(&chunk);
+    }
+    let Some(extension) = image_extension(&body) else {
+        return HttpResponse::UnsupportedMediaType()
+            .body("The avatar needs to be a png, jpeg, gif or webp image");
+    };
+
+    let previous: Option<User> = match db.select(user_id.clone()).await {
+        Ok(user) => user,
+        Err(err) => return HttpResponse::InternalServerError().body(err.to_string()),
+    };
+    let Some(previous) = previous else {
+        return HttpResponse::NotFound().body("User not found");
+    };
+
+    let mut random = [0u8; 8];
+    rand::thread_rng().fill_bytes(&mut random);
+    let filename = format!(
+        "{}-{}.{extension}",
+        user_id
+            .id
+            .to_raw()
+            .chars()
+            .filter(char::is_ascii_alphanumeric)
+            .collect::<String>(),
+        random
+            .iter()
+            .map(|byte| format!("{byte:02x}"))
+            .collect::<String>()
+    );
+    let path = std::path::Path::new(&app_state.avatars).join(&filename);
+    if let Err(err) = std::fs::write(path, &body) {
+        return HttpResponse::InternalServerError().body(err.to_string());
+    }
+    remove_avatar(&app_state, previous.icon.as_deref());
+
+    let after = async {
+        db.query("UPDATE $user SET icon = $icon")
+            .bind(("user", user_id))
+            .bind(("icon", format!("{AVATAR_PATH}/{filename}")))
+            .await?
+            .take(0)
+    }
+    .await;
+    audited_response(&audit, &db, "profile.avatar_upload", &previous, after).await
+}
+
+/// endpoint to remove the avatar of the calling user
+#[delete("/me/avatar")]
+pub async fn delete_avatar(
+    claims: Claims,
+    audit: Audit,
+    app_state: web::Data<AppState>,
+    db: web::Data<Surreal<Client>>,
+) -> HttpResponse {
+    let user_id = match caller(&claims) {
+        Ok(user) => user,
+        Err(response) => return response,
+    };
+
+    let previous: Result<Option<User>, surrealdb::Error> = async {
+        db.query("UPDATE $user SET icon = NONE RETURN BEFORE")
+            .bind(("user", &user_id))
+            .await?
+            .take(0)
+    }
+    .await;
+    match previous {
+        Ok(Some(previous)) => {
+            remove_avatar(&app_state, previous.icon.as_deref());
+            let after = db.select(user_id).await;
+            audited_response(&audit, &db, "profile.avatar_delete", &previous, after).await
+        }
+        Ok(None) => HttpResponse::NotFound().body("User not found"),
+        Err(err) => HttpResponse::InternalServerError().body(err.to_string()),
+    }
 }
@@ -3,72 +3,256 @@
 //! `web::auth` is a module containing everything needed for the JWTAuthorization middleware
 //!
 
-use actix_web::{get, web};
+use std::future::{ready, Ready};
+
+use actix_web::{
+    dev::Payload,
+    error::{ErrorForbidden, ErrorUnauthorized},
+    get,
+    http::Method,
+    FromRequest, HttpMessage, HttpRequest, HttpResponse,
+};
 use serde::{Deserialize, Serialize};
+use surrealdb::sql::Thing;
 
-use crate::config::AppState;
+use crate::jwt::JwtKeys;
 
 /// A helper struct to send a Error response upon failed authorization
 #[derive(Serialize, Deserialize)]
-struct ErrorResponse {
-    error: String,
-    code: usize,
+pub struct ErrorResponse {
+    pub error: String,
+    pub code: usize,
+}
+
+/// The roles of a user, each role includes the permissions of the roles before it
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Role {
+    /// read access to stations, sensors and values
+    #[default]
+    Viewer,
+    /// operates the plant, e.g. works with alarms
+    Operator,
+    /// manages stations and sensors and imports data
+    Maintainer,
+    /// manages users
+    Admin,
+}
+
+impl std::fmt::Display for Role {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let role = match self {
+            Role::Viewer => "viewer",
+            Role::Operator => "operator",
+            Role::Maintainer => "maintainer",
+            Role::Admin => "admin",
+        };
+        write!(f, "{role}")
+    }
+}
+
+/// The scopes of a API key, admin includes all other scopes
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ApiScope {
+    /// read stations, sensors and their values, including exports
+    ReadSensors,
+    /// import sensor values
+    WriteValues,
+    /// everything a admin user is allowed to do
+    Admin,
+}
+
+impl std::fmt::Display for ApiScope {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let scope = match self {
+            ApiScope::ReadSensors => "read_sensors",
+            ApiScope::WriteValues => "write_values",
+            ApiScope::Admin => "admin",
+        };
+        write!(f, "{scope}")
+    }
+}
+
+impl ApiScope {
+    /// the role a API key with the scopes acts as
+    pub fn role(scopes: &[ApiScope]) -> Role {
+        if scopes.contains(&ApiScope::Admin) {
+            Role::Admin
+        } else if scopes.contains(&ApiScope::WriteValues) {
+            Role::Maintainer
+        } else {
+            Role::Viewer
+        }
+    }
 }
 
 /// This is actual JWT payload
-#[derive(Serialize, Deserialize)]
+/// sub contains the record id of the user, e.g. `user:lsd8f7g6`
+/// sid contains the record id of the session the token was issued for
+/// requests authorized by a API key use the record id of the key for both and carry its scopes,
+/// exp is only missing for API keys without expiry, access tokens always expire
+#[derive(Clone, Serialize, Deserialize)]
 pub struct Claims {
-    pub sub: usize,
+    pub sub: String,
+    pub sid: String,
     pub name: String,
+    #[serde(default)]
+    pub email: String,
+    /// tokens issued before roles existed are treated as viewer
+    #[serde(default)]
+    pub role: Role,
     pub iat: usize,
-    pub exp: usize,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub exp: Option<usize>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub scopes: Option<Vec<ApiScope>>,
+    /// set for temporary passwords, the token only allows to change the password
+    #[serde(default)]
+    pub must_change_password: bool,
 }
 
-/// this function generates the actual JWT encrypted with a given secret
-pub fn generate_token(secret: String, username: String, user_id: i32) -> String {
-    let sub: usize = user_id as usize;
-    let exp: usize = (chrono::Utc::now() + chrono::Duration::days(1)).timestamp() as usize;
-    let iat: usize = chrono::Utc::now().timestamp() as usize;
-    let claims = Claims {
-        sub,
-        name: username.clone(),
-        iat,
-        exp,
-    };
-    jsonwebtoken::encode(
-        &jsonwebtoken::Header::default(),
-        &claims,
-        &jsonwebtoken::EncodingKey::from_secret(secret.as_str().as_ref()),
-    )
-    .unwrap()
+/// The claims of a API key without the admin scope.
+/// They are only provided as claims by a RequireScope middleware of a route with one of its scopes,
+/// so routes without a declared scope are not available to the key
+pub struct ApiKeyClaims(pub Claims);
+
+/// extractor for handlers behind the JWTAuthorization middleware to get the calling user
+///
+/// # Example
+///
+/// ```text
+/// #[get("/whoami")]
+/// async fn whoami(claims: Claims) -> HttpResponse {
+///     HttpResponse::Ok().body(claims.sub)
+/// }
+/// ```
+impl FromRequest for Claims {
+    type Error = actix_web::Error;
+    type Future = Ready<Result<Self, Self::Error>>;
+
+    fn from_request(req: &HttpRequest, _: &mut Payload) -> Self::Future {
+        ready(
+            req.extensions()
+                .get::<Claims>()
+                .cloned()
+                .ok_or_else(|| missing_claims(req)),
+        )
+    }
+}
+
+/// the error for requests without claims, API keys lack the scope of the route
+pub fn missing_claims(req: &HttpRequest) -> actix_web::Error {
+    if req.extensions().contains::<ApiKeyClaims>() {
+        return ErrorForbidden("The API key is not allowed to use this endpoint");
+    }
+    ErrorUnauthorized("Missing authorization header!")
+}
+
+impl Claims {
+    /// Creates the claims of a access token which is valid for the given lifetime
+    pub fn new(
+        user_id: &Thing,
+        session_id: &Thing,
+        name: String,
+        email: String,
+        role: Role,
+        must_change_password: bool,
+        lifetime: chrono::Duration,
+    ) -> Self {
+        let now = chrono::Utc::now();
+        Claims {
+            sub: user_id.to_string(),
+            sid: session_id.to_string(),
+            name,
+            email,
+            role,
+            iat: now.timestamp() as usize,
+            exp: Some((now + lifetime).timestamp() as usize),
+            scopes: None,
+            must_change_password,
+        }
+    }
+
+    /// Returns true for API keys with the scope or the admin scope,
+    /// tokens of users don't have scopes
+    pub fn has_scope(&self, scope: ApiScope) -> bool {
+        self.scopes
+            .as_ref()
+            .is_some_and(|scopes| scopes.contains(&scope) || scopes.contains(&ApiScope::Admin))
+    }
+
+    /// Returns true if the token may be used for the request below /api/v1.
+    /// Tokens of temporary passwords only allow to read the profile and to change the password
+    pub fn permits(&self, method: &Method, path: &str) -> bool {
+        if !self.must_change_password {
+            return true;
+        }
+        let path = path.trim_start_matches("/api/v1");
+        matches!(
+            (method, path),
+            (&Method::GET, "/me") | (&Method::POST, "/me/password")
+        )
+    }
+}
+
+/// this function generates the actual JWT signed by the signing key
+pub fn generate_token(
+    keys: &JwtKeys,
+    claims: &Claims,
+) -> Result<String, jsonwebtoken::errors::Error> {
+    keys.encode(claims)
 }
 
 /// enpoint to check/validate the Authorization header/JWT
+/// the JWT is validated by the JWTAuthorization middleware, so only the claims are returned
 #[get("/token")]
-async fn decode(
-    req: actix_web::HttpRequest,
-    app_state: web::Data<AppState>,
-) -> actix_web::HttpResponse {
-    let auth_header = req.headers().get(actix_web::http::header::AUTHORIZATION);
-    let jwt = match auth_header {
-        Some(auth) => auth.to_str().unwrap(),
-        _ => {
-            return actix_web::HttpResponse::Unauthorized().json(ErrorResponse {
-                error: "Missing Authorization header!".to_string(),
-                code: 401,
-            });
-        }
-    };
-
-    match jsonwebtoken::decode::<Claims>(
-        &jwt.replace("Bearer ", ""),
-        &jsonwebtoken::DecodingKey::from_secret(app_state.secret.as_str().as_ref()),
-        &jsonwebtoken::Validation::new(jsonwebtoken::Algorithm::HS256),
-    ) {
-        Ok(token_data) => actix_web::HttpResponse::Ok().json(token_data.claims),
-        Err(err) => actix_web::HttpResponse::Unauthorized().json(ErrorResponse {
-            error: err.to_string(),
-            code: 401,
-        }),
+async fn decode(claims: Claims) -> HttpResponse {
+    HttpResponse::Ok().json(claims)
+}
+
+#[cfg(test)]
+mod tests {
+    use actix_web::http::Method;
+
+    use super::{Claims, Role};
+
+    fn claims(must_change_password: bool) -> Claims {
+        Claims::new(
+            &surrealdb::sql::Thing::from(("user", "anna")),
+            &surrealdb::sql::Thing::from(("session", "s1")),
+            "Anna".to_owned(),
+            "anna@example.com".to_owned(),
+            Role::Admin,
+            must_change_password,
+            chrono::Duration::minutes(15),
+        )
+    }
+
+    #[test]
+    fn temporary_passwords_only_allow_the_password_change() {
+        let temporary = claims(true);
+        assert!(temporary.permits(&Method::POST, "/api/v1/me/password"));
+        assert!(temporary.permits(&Method::GET, "/api/v1/me"));
+        assert!(!temporary.permits(&Method::PATCH, "/api/v1/me"));
+        assert!(!temporary.permits(&Method::GET, "/api/v1/stations"));
+        assert!(!temporary.permits(&Method::POST, "/api/v1/users"));
+
+        let changed = claims(false);
+        assert!(changed.permits(&Method::GET, "/api/v1/stations"));
+        assert!(changed.permits(&Method::POST, "/api/v1/users"));
+    }
+
+    #[test]
+    fn tokens_without_the_flag_are_not_restricted() {
+        let token = serde_json::json!({
+            "sub": "user:anna",
+            "sid": "session:s1",
+            "name": "Anna",
+            "iat": 0,
+            "exp": 0,
+        });
+        let claims: Claims = serde_json::from_value(token).unwrap();
+        assert!(claims.permits(&Method::DELETE, "/api/v1/station/presswerk"));
     }
 }
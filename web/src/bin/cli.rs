@@ -0,0 +1,216 @@
+//! # cli
+//!
+//! `cli` is a helper binary for offline tasks directly against the database
+//!
+//! # Example
+//! Exporting the values of a station and a sensor as parquet file.
+//! `--to` defaults to now, without `--output` the export is written to stdout.
+//!
+//! ```text
+//! > cargo run --bin cli -- export --format parquet --stations presswerk --sensors dosenfuellstand \
+//!     --from 2023-05-01T00:00:00Z --to 2023-05-02T00:00:00Z --output presswerk.parquet
+//! ```
+//!
+//! Importing a historian CSV file. Mapping options are named like the fields of
+//! `common::import::ImportOptions` with dashes, `--dry-run` only validates the file.
+//!
+//! ```text
+//! > cargo run --bin cli -- import --file historian.csv --delimiter ";" --timestamp-column time \
+//!     --sensor-column tag --station presswerk --timezone Europe/Berlin --create-sensors --dry-run
+//! ```
+//!
+
+use std::{collections::HashMap, env::args, io::Write};
+
+use common::{
+    export::{ExportFormat, Exporter, Selection},
+    import::ImportOptions,
+};
+use surrealdb::{engine::remote::ws::Ws, opt::auth::Root, Surreal};
+
+#[tokio::main]
+async fn main() -> std::io::Result<()> {
+    let mut args = args().skip(1);
+    let command = args.next();
+    let options = match parse_options(args) {
+        Ok(options) => options,
+        Err(err) => exit(&err),
+    };
+
+    match command.as_deref() {
+        Some("export") => export(options).await,
+        Some("import") => import(options).await,
+        _ => exit("Please specify a command: export, import"),
+    }
+}
+
+/// prints the message to stderr and exits with a failure
+fn exit(message: &str) -> ! {
+    eprintln!("{message}");
+    std::process::exit(1)
+}
+
+/// parses `--key value` pairs, a key without a value is a flag and set to `true`
+fn parse_options(args: impl Iterator<Item = String>) -> Result<HashMap<String, String>, String> {
+    let mut options = HashMap::new();
+    let mut args = args.peekable();
+    while let Some(arg) = args.next() {
+        let key = arg
+            .strip_prefix("--")
+            .ok_or_else(|| format!("Unexpected argument: {arg}"))?;
+        let value = args
+            .next_if(|value| !value.starts_with("--"))
+            .unwrap_or_else(|| "true".to_owned());
+        options.insert(key.to_owned(), value);
+    }
+    Ok(options)
+}
+
+/// splits a comma separated list of ids
+fn split_ids(ids: Option<&String>) -> Vec<String> {
+    ids.map(|ids| {
+        ids.split(',')
+            .map(str::trim)
+            .filter(|id| !id.is_empty())
+            .map(str::to_owned)
+            .collect()
+    })
+    .unwrap_or_default()
+}
+
+/// parses a RFC 3339 datetime option
+fn parse_datetime(value: &str) -> chrono::DateTime<chrono::Utc> {
+    chrono::DateTime::parse_from_rfc3339(value)
+        .unwrap_or_else(|err| exit(&format!("Invalid datetime {value}: {err}")))
+        .into()
+}
+
+/// connects to the local database
+async fn connect() -> Surreal<surrealdb::engine::remote::ws::Client> {
+    let db = Surreal::new::<Ws>("127.0.0.1:8000")
+        .await
+        .expect("Unable to connect to database");
+
+    db.signin(Root {
+        username: "root",
+        password: "root",
+    })
+    .await
+    .expect("Unable to sigin to the database");
+
+    db.use_ns("main")
+        .use_db("main")
+        .await
+        .expect("Either namespace or database main does not exist");
+    db
+}
+
+/// exports the selected sensors into a file or stdout
+async fn export(options: HashMap<String, String>) -> std::io::Result<()> {
+    let format: ExportFormat = options
+        .get("format")
+        .map(|format| format.parse().unwrap_or_else(|err: String| exit(&err)))
+        .unwrap_or_default();
+    let selection = Selection {
+        sensors: split_ids(options.get("sensors")),
+        stations: split_ids(options.get("stations")),
+    };
+    if selection.sensors.is_empty() && selection.stations.is_empty() {
+        exit("Select at least one sensor or station with --sensors or --stations");
+    }
+    let from = parse_datetime(
+        options
+            .get("from")
+            .unwrap_or_else(|| exit("Missing --from")),
+    );
+    let to = options
+        .get("to")
+        .map(|to| parse_datetime(to))
+        .unwrap_or_else(chrono::Utc::now);
+    let time_period = common::TimePeriod::between(from, to);
+
+    let mut output: Box<dyn Write> = match options.get("output") {
+        Some(path) => Box::new(std::fs::File::create(path)?),
+        None => Box::new(std::io::stdout().lock()),
+    };
+
+    let db = connect().await;
+    let sensors = selection
+        .resolve(&db)
+        .await
+        .unwrap_or_else(|err| exit(&err.to_string()));
+    let mut exporter = Exporter::new(format).unwrap_or_else(|err| exit(&err.to_string()));
+    for sensor in sensors.iter() {
+        let rows = common::export::rows(&db, sensor, &time_period)
+            .await
+            .unwrap_or_else(|err| exit(&err.to_string()));
+        exporter
+            .write(&rows)
+            .unwrap_or_else(|err| exit(&err.to_string()));
+        output.write_all(&exporter.drain())?;
+        eprintln!("Exported {}: {} values", sensor.get_id(), rows.len());
+    }
+    output.write_all(
+        &exporter
+            .finish()
+            .unwrap_or_else(|err| exit(&err.to_string())),
+    )?;
+    output.flush()
+}
+
+/// imports a CSV or NDJSON file and prints the report
+async fn import(mut options: HashMap<String, String>) -> std::io::Result<()> {
+    let path = options
+        .remove("file")
+        .unwrap_or_else(|| exit("Missing --file"));
+    let input = std::fs::read(path)?;
+
+    let mut import_options = ImportOptions::default();
+    for (key, value) in options {
+        match key.as_str() {
+            "format" => {
+                import_options.format = value.parse().unwrap_or_else(|err: String| exit(&err))
+            }
+            "delimiter" => {
+                import_options.delimiter = value
+                    .chars()
+                    .next()
+                    .unwrap_or_else(|| exit("Missing value for --delimiter"))
+            }
+            "sensor-column" => import_options.sensor_column = value,
+            "station-column" => import_options.station_column = value,
+            "timestamp-column" => import_options.timestamp_column = value,
+            "value-column" => import_options.value_column = value,
+            "sensor" => import_options.sensor = Some(value),
+            "station" => import_options.station = Some(value),
+            "timestamp-format" => import_options.timestamp_format = Some(value),
+            "timezone" => import_options.timezone = value,
+            "create-sensors" => import_options.create_sensors = value == "true",
+            "dry-run" => import_options.dry_run = value == "true",
+            other => exit(&format!("Unknown option: --{other}")),
+        }
+    }
+
+    let db = connect().await;
+    let report = common::import::import(&db, &input, &import_options)
+        .await
+        .unwrap_or_else(|err| exit(&err.to_string()));
+
+    if report.dry_run {
+        println!("Dry run, nothing was written!");
+    }
+    println!("Rows: {}", report.rows);
+    println!("Imported: {}", report.imported);
+    println!("Duplicates: {}", report.duplicates);
+    println!("Invalid: {}", report.invalid);
+    if !report.created_sensors.is_empty() {
+        println!("Created sensors: {}", report.created_sensors.join(", "));
+    }
+    if let (Some(from), Some(to)) = (report.from, report.to) {
+        println!("Time range: {} - {}", from.to_rfc3339(), to.to_rfc3339());
+    }
+    for error in report.errors.iter() {
+        println!("Line {}: {}", error.line, error.message);
+    }
+    Ok(())
+}
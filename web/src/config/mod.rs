@@ -11,6 +11,104 @@
 //!   port: 8001
 //!   username: "user"
 //!   password: "password"
+//!   timeout_seconds: 10
+//!   retries: 2
+//!   # responses are shared for cache_seconds, served stale for stale_seconds if the MHubX is down
+//!   cache_seconds: 5
+//!   stale_seconds: 300
+//!
+//! # imports the measurements of the MHubX as sensor values
+//! mhubx_poller:
+//!   enabled: true
+//!   interval_seconds: 60
+//!   systems: ["cps1", "cps2"]
+//!
+//! alarms:
+//!   comment_required_severity: 3
+//!   max_shelve_minutes: 480
+//!
+//! # notifications of raised and cleared alarms, channels without config can't be subscribed to
+//! notifications:
+//!   enabled: true
+//!   interval_seconds: 5
+//!   webhook:
+//!     timeout_seconds: 10
+//!     retries: 3
+//!     backoff_seconds: 2
+//!     max_retry_seconds: 30
+//!   smtp:
+//!     host: "mail.plant.local"
+//!     port: 587
+//!     tls: starttls
+//!     username: "i40"
+//!     password: "changeit"
+//!     from: "I40 Alarme <alarme@plant.local>"
+//!   mqtt:
+//!     host: "mqtt.plant.local"
+//!     port: 1883
+//!     client_id: "i40-notifications"
+//!
+//! # signing keys of the JWTs, without keys the secret is used for HS256 (dev only)
+//! jwt:
+//!   signing_kid: "2023-06"
+//!   keys:
+//!     - kid: "2023-06"
+//!       algorithm: EdDSA
+//!       private_key: "web/keys/2023-06.pem"
+//!       public_key: "web/keys/2023-06.pub.pem"
+//!     # a rotated key, which only verifies the tokens issued before the rotation
+//!     - kid: "2023-01"
+//!       algorithm: RS256
+//!       public_key: "web/keys/2023-01.pub.pem"
+//!
+//! tokens:
+//!   access_token_minutes: 15
+//!   refresh_token_days: 30
+//!
+//! avatars: "web/avatars"
+//!
+//! password_policy:
+//!   min_length: 10
+//!   require_letter: true
+//!   require_digit: true
+//!   require_symbol: false
+//!
+//! login_throttle:
+//!   free_attempts: 3
+//!   base_delay_seconds: 1
+//!   max_delay_seconds: 60
+//!   account_max_failures: 10
+//!   ip_max_failures: 50
+//!   lockout_minutes: 15
+//!
+//! rate_limits:
+//!   trust_forwarded_for: false
+//!   default: { requests: 600, seconds: 60 }
+//!   groups:
+//!     auth: { requests: 20, seconds: 60 }
+//!     export: { requests: 10, seconds: 60 }
+//!     import: { requests: 5, seconds: 60 }
+//!
+//! # external sign in, password sign ins try the providers in order
+//! auth_providers:
+//!   password: [local, ldap]
+//!   link_by_email: false
+//!   ldap:
+//!     url: "ldaps://ldap.plant.local"
+//!     bind_dn: "cn=i40,ou=services,dc=plant,dc=local"
+//!     bind_password: "changeit"
+//!     base_dn: "ou=people,dc=plant,dc=local"
+//!     user_filter: "(mail={email})"
+//!   oidc:
+//!     issuer: "https://login.plant.local/realms/plant"
+//!     client_id: "i40"
+//!     client_secret: "changeit"
+//!     redirect_uri: "http://127.0.0.1:8080/auth/oidc/callback"
+//!   role_mapping:
+//!     default_role: viewer
+//!     groups:
+//!       "cn=i40-admins,ou=groups,dc=plant,dc=local": admin
+//!       "i40-operators": operator
 //!
 //! web:
 //!   address: "0.0.0.0"
@@ -21,22 +119,58 @@
 //!   port: 8000
 //! ```
 
+use std::{collections::HashMap, time::Duration};
+
 use serde::Deserialize;
 
-/// struct containing the app state.
-/// the secret is used for the JWTAuthorization middleware
-/// restapi contains the MHubX rest API details
+use crate::auth::Role;
+
+/// struct containing the app state, the settings of the web server read from config.yaml.
+/// the secret signs the JWTs with HS256 as long as jwt contains no keys
 #[derive(Deserialize)]
 pub struct AppState {
     pub secret: String,
     pub restapi: RestApi,
+    #[serde(default)]
+    pub jwt: JwtConfig,
+    #[serde(default)]
+    pub tokens: TokenConfig,
+    #[serde(default = "default_avatars")]
+    pub avatars: String,
+    #[serde(default)]
+    pub password_policy: PasswordPolicy,
+    #[serde(default)]
+    pub login_throttle: LoginThrottleConfig,
+    #[serde(default)]
+    pub rate_limits: RateLimitConfig,
+    #[serde(default)]
+    pub auth_providers: AuthProviderConfig,
+    #[serde(default)]
+    pub mhubx_poller: MhubxPollerConfig,
+    #[serde(default)]
+    pub alarms: AlarmConfig,
+    #[serde(default)]
+    pub notifications: NotificationConfig,
+}
+
+fn default_avatars() -> String {
+    "web/avatars".to_owned()
 }
 
 impl AppState {
     pub fn load() -> Self {
         let file = std::fs::read_to_string("web/config.yaml").expect("config.yaml not found!");
 
-        serde_yaml::from_str(file.as_str()).expect("unable to parse config.yaml")
+        let app_state: Self =
+            serde_yaml::from_str(file.as_str()).expect("unable to parse config.yaml");
+        app_state.validate().expect("invalid config.yaml");
+        app_state
+    }
+
+    /// checks the settings which can't be checked while parsing
+    pub fn validate(&self) -> Result<(), String> {
+        self.tokens.validate()?;
+        self.login_throttle.validate()
     }
 }
 
@@ -62,7 +196,8 @@ pub struct ServerConfig {
     pub port: u16,
 }
 
-/// contains the MHubX rest API details
+/// contains the MHubX rest API details, a call is retried `retries` times, at most 10 times,
+/// if it fails with a timeout, a connection error or a server error
 #[derive(Deserialize)]
 pub struct RestApi {
     pub base_url: String,
@@ -70,4 +205,593 @@ pub struct RestApi {
     pub postfix: String,
     pub username: String,
     pub password: String,
+    #[serde(default = "default_restapi_timeout_seconds")]
+    pub timeout_seconds: u64,
+    #[serde(default = "default_restapi_retries")]
+    pub retries: u32,
+    #[serde(default = "default_restapi_cache_seconds")]
+    pub cache_seconds: u64,
+    #[serde(default = "default_restapi_stale_seconds")]
+    pub stale_seconds: u64,
+}
+
+fn default_restapi_timeout_seconds() -> u64 {
+    10
+}
+
+/// upper bound of the retries of a MHubX call
+const MAX_RESTAPI_RETRIES: u32 = 10;
+
+fn default_restapi_retries() -> u32 {
+    2
+}
+
+fn default_restapi_cache_seconds() -> u64 {
+    5
+}
+
+fn default_restapi_stale_seconds() -> u64 {
+    300
+}
+
+impl RestApi {
+    /// the connection details of the MHubX client
+    pub fn mhubx(&self) -> common::mhubx::MhubxConfig {
+        common::mhubx::MhubxConfig {
+            endpoint: format!("{}:{}/{}", self.base_url, self.port, self.postfix),
+            username: self.username.clone(),
+            password: self.password.clone(),
+            timeout: Duration::from_secs(self.timeout_seconds),
+            retries: self.retries.min(MAX_RESTAPI_RETRIES),
+        }
+    }
+
+    /// the MHubX client of the api, sharing responses between callers
+    pub fn mhubx_cache(&self) -> Result<common::mhubx::MhubxCache, common::mhubx::MhubxError> {
+        Ok(common::mhubx::MhubxCache::new(
+            common::mhubx::MhubxClient::new(self.mhubx())?,
+            Duration::from_secs(self.cache_seconds),
+            Duration::from_secs(self.stale_seconds),
+        ))
+    }
+}
+
+/// Importer of the MHubX measurements, see `web::poller`
+#[derive(Deserialize, Clone, Debug)]
+pub struct MhubxPollerConfig {
+    #[serde(default)]
+    pub enabled: bool,
+    #[serde(default = "default_poll_interval_seconds")]
+    pub interval_seconds: u64,
+    /// the systems to import, all systems if empty
+    #[serde(default)]
+    pub systems: Vec<String>,
+}
+
+fn default_poll_interval_seconds() -> u64 {
+    60
+}
+
+impl Default for MhubxPollerConfig {
+    fn default() -> Self {
+        MhubxPollerConfig {
+            enabled: false,
+            interval_seconds: default_poll_interval_seconds(),
+            systems: Vec::new(),
+        }
+    }
+}
+
+impl MhubxPollerConfig {
+    pub fn interval(&self) -> Duration {
+        Duration::from_secs(self.interval_seconds.max(1))
+    }
+}
+
+/// How operators work with alarms
+#[derive(Deserialize, Clone, Debug)]
+pub struct AlarmConfig {
+    /// acknowledging alarms of this severity and above needs a comment
+    #[serde(default = "default_comment_required_severity")]
+    pub comment_required_severity: u32,
+    #[serde(default = "default_max_shelve_minutes")]
+    pub max_shelve_minutes: u64,
+}
+
+fn default_comment_required_severity() -> u32 {
+    3
+}
+
+fn default_max_shelve_minutes() -> u64 {
+    8 * 60
+}
+
+impl Default for AlarmConfig {
+    fn default() -> Self {
+        AlarmConfig {
+            comment_required_severity: default_comment_required_severity(),
+            max_shelve_minutes: default_max_shelve_minutes(),
+        }
+    }
+}
+
+/// Notifications of raised and cleared alarms, see `web::notify`
+#[derive(Deserialize, Clone, Debug)]
+pub struct NotificationConfig {
+    #[serde(default)]
+    pub enabled: bool,
+    /// how often new alarm events are looked for
+    #[serde(default = "default_notification_interval_seconds")]
+    pub interval_seconds: u64,
+    #[serde(default)]
+    pub webhook: WebhookConfig,
+    pub smtp: Option<SmtpConfig>,
+    pub mqtt: Option<MqttConfig>,
+}
+
+fn default_notification_interval_seconds() -> u64 {
+    5
+}
+
+impl Default for NotificationConfig {
+    fn default() -> Self {
+        NotificationConfig {
+            enabled: false,
+            interval_seconds: default_notification_interval_seconds(),
+            webhook: WebhookConfig::default(),
+            smtp: None,
+            mqtt: None,
+        }
+    }
+}
+
+/// failed webhooks are retried up to retries times, waiting backoff_seconds before the first
+/// retry and twice as long before every further retry. Waiting for retries ends max_retry_seconds
+/// after the start of a notification interval, so a failing webhook can't hold up the others
+#[derive(Deserialize, Clone, Debug)]
+pub struct WebhookConfig {
+    #[serde(default = "default_webhook_timeout_seconds")]
+    pub timeout_seconds: u64,
+    #[serde(default = "default_webhook_retries")]
+    pub retries: u32,
+    #[serde(default = "default_webhook_backoff_seconds")]
+    pub backoff_seconds: u64,
+    #[serde(default = "default_webhook_max_retry_seconds")]
+    pub max_retry_seconds: u64,
+}
+
+fn default_webhook_timeout_seconds() -> u64 {
+    10
+}
+
+fn default_webhook_retries() -> u32 {
+    3
+}
+
+fn default_webhook_backoff_seconds() -> u64 {
+    2
+}
+
+fn default_webhook_max_retry_seconds() -> u64 {
+    30
+}
+
+impl Default for WebhookConfig {
+    fn default() -> Self {
+        WebhookConfig {
+            timeout_seconds: default_webhook_timeout_seconds(),
+            retries: default_webhook_retries(),
+            backoff_seconds: default_webhook_backoff_seconds(),
+            max_retry_seconds: default_webhook_max_retry_seconds(),
+        }
+    }
+}
+
+/// How the connection to the SMTP server is secured
+#[derive(Deserialize, Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum SmtpTls {
+    /// plain text, e.g. for a local SMTP sink during development
+    None,
+    #[default]
+    Starttls,
+    /// TLS from the start of the connection, usually on port 465
+    Tls,
+}
+
+/// the SMTP server alarm emails are sent through, without username they are sent unauthenticated
+#[derive(Deserialize, Clone, Debug)]
+pub struct SmtpConfig {
+    pub host: String,
+    #[serde(default = "default_smtp_port")]
+    pub port: u16,
+    #[serde(default)]
+    pub tls: SmtpTls,
+    pub username: Option<String>,
+    #[serde(default)]
+    pub password: String,
+    pub from: String,
+}
+
+fn default_smtp_port() -> u16 {
+    587
+}
+
+/// the MQTT broker alarms are published to
+#[derive(Deserialize, Clone, Debug)]
+pub struct MqttConfig {
+    pub host: String,
+    #[serde(default = "default_mqtt_port")]
+    pub port: u16,
+    #[serde(default = "default_mqtt_client_id")]
+    pub client_id: String,
+    pub username: Option<String>,
+    #[serde(default)]
+    pub password: String,
+}
+
+fn default_mqtt_port() -> u16 {
+    1883
+}
+
+fn default_mqtt_client_id() -> String {
+    "i40-notifications".to_owned()
+}
+
+/// the asymmetric algorithms to sign JWTs with
+#[derive(Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum JwtAlgorithm {
+    RS256,
+    EdDSA,
+}
+
+/// a signing key of the JWTs, the private key is only needed for the key which signs.
+/// Both keys are PEM files, the public key is published in /.well-known/jwks.json
+#[derive(Deserialize)]
+pub struct JwtKeyConfig {
+    pub kid: String,
+    pub algorithm: JwtAlgorithm,
+    pub private_key: Option<String>,
+    pub public_key: String,
+}
+
+/// the signing keys of the JWTs, signing_kid defaults to the first key with a private key
+#[derive(Deserialize, Default)]
+pub struct JwtConfig {
+    pub signing_kid: Option<String>,
+    #[serde(default)]
+    pub keys: Vec<JwtKeyConfig>,
+}
+
+/// contains the lifetimes of the issued tokens
+#[derive(Deserialize)]
+pub struct TokenConfig {
+    pub access_token_minutes: i64,
+    pub refresh_token_days: i64,
+}
+
+impl Default for TokenConfig {
+    fn default() -> Self {
+        TokenConfig {
+            access_token_minutes: 15,
+            refresh_token_days: 30,
+        }
+    }
+}
+
+impl TokenConfig {
+    /// checks the lifetimes are within 1 minute to a day and 1 to 365 days
+    pub fn validate(&self) -> Result<(), String> {
+        if !(1..=24 * 60).contains(&self.access_token_minutes) {
+            return Err("tokens.access_token_minutes needs to be within 1 and 1440".to_owned());
+        }
+        if !(1..=365).contains(&self.refresh_token_days) {
+            return Err("tokens.refresh_token_days needs to be within 1 and 365".to_owned());
+        }
+        Ok(())
+    }
+
+    /// lifetime of a access token (JWT)
+    pub fn access_token_lifetime(&self) -> chrono::Duration {
+        chrono::Duration::minutes(self.access_token_minutes)
+    }
+
+    /// lifetime of a session, a refresh token is valid at most as long as its session
+    pub fn refresh_token_lifetime(&self) -> chrono::Duration {
+        chrono::Duration::days(self.refresh_token_days)
+    }
+}
+
+/// rules a new password needs to follow
+#[derive(Deserialize)]
+pub struct PasswordPolicy {
+    pub min_length: usize,
+    pub require_letter: bool,
+    pub require_digit: bool,
+    pub require_symbol: bool,
+}
+
+impl Default for PasswordPolicy {
+    fn default() -> Self {
+        PasswordPolicy {
+            min_length: 10,
+            require_letter: true,
+            require_digit: true,
+            require_symbol: false,
+        }
+    }
+}
+
+impl PasswordPolicy {
+    /// checks a password against the policy, the email must not be part of the password
+    pub fn validate(&self, password: &str, email: &str) -> Result<(), String> {
+        if password.chars().count() < self.min_length {
+            return Err(format!(
+                "The password needs to be at least {} characters long",
+                self.min_length
+            ));
+        }
+        if password.len() > 1024 {
+            return Err("The password is too long".to_owned());
+        }
+        if self.require_letter && !password.chars().any(char::is_alphabetic) {
+            return Err("The password needs to contain a letter".to_owned());
+        }
+        if self.require_digit && !password.chars().any(|c| c.is_ascii_digit()) {
+            return Err("The password needs to contain a digit".to_owned());
+        }
+        if self.require_symbol && password.chars().all(char::is_alphanumeric) {
+            return Err("The password needs to contain a symbol".to_owned());
+        }
+        let local_part = email.split('@').next().unwrap_or_default().to_lowercase();
+        if local_part.len() >= 3 && password.to_lowercase().contains(&local_part) {
+            return Err("The password must not contain the email address".to_owned());
+        }
+        Ok(())
+    }
+}
+
+/// throttling of failed sign in attempts per account and per client ip.
+/// After the free attempts every failure doubles the delay until the next attempt,
+/// reaching the max failures locks the account or ip temporarily
+#[derive(Deserialize)]
+pub struct LoginThrottleConfig {
+    pub free_attempts: u32,
+    pub base_delay_seconds: u64,
+    pub max_delay_seconds: u64,
+    pub account_max_failures: u32,
+    pub ip_max_failures: u32,
+    pub lockout_minutes: u64,
+}
+
+impl Default for LoginThrottleConfig {
+    fn default() -> Self {
+        LoginThrottleConfig {
+            free_attempts: 3,
+            base_delay_seconds: 1,
+            max_delay_seconds: 60,
+            account_max_failures: 10,
+            ip_max_failures: 50,
+            lockout_minutes: 15,
+        }
+    }
+}
+
+impl LoginThrottleConfig {
+    /// the delay before the next attempt after the given number of failures
+    pub fn delay(&self, failures: u32) -> Duration {
+        if failures <= self.free_attempts {
+            return Duration::ZERO;
+        }
+        let exponent = (failures - self.free_attempts - 1).min(16);
+        Duration::from_secs(
+            self.base_delay_seconds
+                .saturating_mul(1 << exponent)
+                .min(self.max_delay_seconds),
+        )
+    }
+
+    /// checks the lockout is within 1 minute to a week
+    pub fn validate(&self) -> Result<(), String> {
+        if !(1..=7 * 24 * 60).contains(&self.lockout_minutes) {
+            return Err("login_throttle.lockout_minutes needs to be within 1 and 10080".to_owned());
+        }
+        Ok(())
+    }
+
+    /// duration of a lockout
+    pub fn lockout(&self) -> Duration {
+        Duration::from_secs(self.lockout_minutes.saturating_mul(60))
+    }
+}
+
+/// a limit of requests within a number of seconds
+#[derive(Deserialize, Clone, Copy)]
+pub struct Limit {
+    pub requests: u32,
+    pub seconds: u64,
+}
+
+/// rate limits per route group, groups without a own limit use the default.
+/// The client ip is only taken from X-Forwarded-For if trust_forwarded_for is set
+#[derive(Deserialize)]
+pub struct RateLimitConfig {
+    #[serde(default)]
+    pub trust_forwarded_for: bool,
+    pub default: Limit,
+    #[serde(default)]
+    pub groups: HashMap<String, Limit>,
+}
+
+impl Default for RateLimitConfig {
+    fn default() -> Self {
+        let limit = |requests| Limit {
+            requests,
+            seconds: 60,
+        };
+        RateLimitConfig {
+            trust_forwarded_for: false,
+            default: limit(600),
+            groups: HashMap::from([
+                ("auth".to_owned(), limit(20)),
+                ("export".to_owned(), limit(10)),
+                ("import".to_owned(), limit(5)),
+            ]),
+        }
+    }
+}
+
+impl RateLimitConfig {
+    /// returns the limit of a route group
+    pub fn limit(&self, group: &str) -> Limit {
+        self.groups.get(group).copied().unwrap_or(self.default)
+    }
+}
+
+/// the providers which verify the email and password of a sign in
+#[derive(Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum PasswordProviderKind {
+    Local,
+    Ldap,
+}
+
+/// the external identity providers. Password sign ins try the password providers in order,
+/// OIDC signs in through the redirect flow of /auth/oidc/login.
+/// Users of external providers are created on their first sign in, link_by_email allows
+/// to take over an existing account with the same email instead
+#[derive(Deserialize)]
+pub struct AuthProviderConfig {
+    #[serde(default = "default_password_providers")]
+    pub password: Vec<PasswordProviderKind>,
+    #[serde(default)]
+    pub link_by_email: bool,
+    pub ldap: Option<LdapConfig>,
+    pub oidc: Option<OidcConfig>,
+    #[serde(default)]
+    pub role_mapping: RoleMapping,
+}
+
+fn default_password_providers() -> Vec<PasswordProviderKind> {
+    vec![PasswordProviderKind::Local]
+}
+
+impl Default for AuthProviderConfig {
+    fn default() -> Self {
+        AuthProviderConfig {
+            password: default_password_providers(),
+            link_by_email: false,
+            ldap: None,
+            oidc: None,
+            role_mapping: RoleMapping::default(),
+        }
+    }
+}
+
+/// a LDAP directory, users are searched with the service account of bind_dn
+/// (anonymously without it) and verified by a bind with their own password.
+/// `{email}` in the user_filter is replaced by the escaped email
+#[derive(Deserialize)]
+pub struct LdapConfig {
+    pub url: String,
+    pub bind_dn: Option<String>,
+    #[serde(default)]
+    pub bind_password: String,
+    pub base_dn: String,
+    #[serde(default = "default_ldap_user_filter")]
+    pub user_filter: String,
+    #[serde(default = "default_ldap_email_attribute")]
+    pub email_attribute: String,
+    #[serde(default = "default_ldap_name_attribute")]
+    pub name_attribute: String,
+    #[serde(default = "default_ldap_group_attribute")]
+    pub group_attribute: String,
+    #[serde(default = "default_ldap_timeout_seconds")]
+    pub timeout_seconds: u64,
+}
+
+fn default_ldap_user_filter() -> String {
+    "(mail={email})".to_owned()
+}
+
+fn default_ldap_email_attribute() -> String {
+    "mail".to_owned()
+}
+
+fn default_ldap_name_attribute() -> String {
+    "cn".to_owned()
+}
+
+fn default_ldap_group_attribute() -> String {
+    "memberOf".to_owned()
+}
+
+fn default_ldap_timeout_seconds() -> u64 {
+    10
+}
+
+impl LdapConfig {
+    /// timeout of the connection and of every operation
+    pub fn timeout(&self) -> Duration {
+        Duration::from_secs(self.timeout_seconds)
+    }
+}
+
+/// a OpenID Connect provider using the authorization code flow. The endpoints are discovered
+/// from the issuer, redirect_uri needs to point to /auth/oidc/callback.
+/// After the sign in the browser is redirected to frontend_redirect with the tokens in the fragment
+#[derive(Deserialize)]
+pub struct OidcConfig {
+    pub issuer: String,
+    pub client_id: String,
+    pub client_secret: String,
+    pub redirect_uri: String,
+    #[serde(default = "default_oidc_scopes")]
+    pub scopes: Vec<String>,
+    #[serde(default = "default_oidc_groups_claim")]
+    pub groups_claim: String,
+    #[serde(default = "default_oidc_frontend_redirect")]
+    pub frontend_redirect: String,
+}
+
+fn default_oidc_scopes() -> Vec<String> {
+    vec![
+        "openid".to_owned(),
+        "email".to_owned(),
+        "profile".to_owned(),
+    ]
+}
+
+fn default_oidc_groups_claim() -> String {
+    "groups".to_owned()
+}
+
+fn default_oidc_frontend_redirect() -> String {
+    "/".to_owned()
+}
+
+/// maps the groups of external users to roles, the highest role of all groups wins.
+/// Groups are compared case insensitive, users without a mapped group get the default role
+#[derive(Deserialize, Default)]
+pub struct RoleMapping {
+    #[serde(default)]
+    pub default_role: Role,
+    #[serde(default)]
+    pub groups: HashMap<String, Role>,
+}
+
+impl RoleMapping {
+    /// returns the role of a user with the given groups
+    pub fn role<S: AsRef<str>>(&self, groups: &[S]) -> Role {
+        groups
+            .iter()
+            .filter_map(|group| {
+                self.groups
+                    .iter()
+                    .find(|(mapped, _)| mapped.eq_ignore_ascii_case(group.as_ref()))
+                    .map(|(_, role)| *role)
+            })
+            .max()
+            .unwrap_or(self.default_role)
+    }
 }
@@ -0,0 +1,315 @@
+//! # web::jwt
+//!
+//! `web::jwt` is a module to sign and verify the JWTs.
+//! With keys in the `jwt` config, tokens are signed RS256 or EdDSA by the signing key and
+//! carry its `kid`. All configured keys verify tokens, so a rotated key keeps its tokens valid
+//! until they expire. The public keys are published as JWKS for other services.
+//! Without keys the `secret` signs with HS256, which is only meant for development.
+//!
+//! # Example
+//! Creating a EdDSA key pair for the config
+//!
+//! ```text
+//! > openssl genpkey -algorithm ed25519 -out web/keys/2023-06.pem
+//! > openssl pkey -in web/keys/2023-06.pem -pubout -out web/keys/2023-06.pub.pem
+//!
+//! GET /.well-known/jwks.json
+//! ```
+
+use actix_web::{get, web, HttpResponse};
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine};
+use jsonwebtoken::{
+    errors::{Error, ErrorKind},
+    Algorithm, DecodingKey, EncodingKey, Header, Validation,
+};
+use rsa::{
+    pkcs1::DecodeRsaPublicKey, pkcs8::DecodePublicKey, traits::PublicKeyParts, RsaPublicKey,
+};
+use serde_json::{json, Value};
+
+use crate::{
+    auth::Claims,
+    config::{AppState, JwtAlgorithm, JwtConfig, JwtKeyConfig},
+};
+
+/// the DER prefix of a Ed25519 public key, followed by the 32 bytes of the key
+const ED25519_SPKI_PREFIX: [u8; 12] = [
+    0x30, 0x2a, 0x30, 0x05, 0x06, 0x03, 0x2b, 0x65, 0x70, 0x03, 0x21, 0x00,
+];
+
+/// a key to verify tokens with
+struct VerifyingKey {
+    kid: Option<String>,
+    algorithm: Algorithm,
+    key: DecodingKey,
+}
+
+/// The keys to sign and verify JWTs, loaded once on startup
+pub struct JwtKeys {
+    header: Header,
+    signing_key: EncodingKey,
+    verifying_keys: Vec<VerifyingKey>,
+    jwks: Value,
+}
+
+/// reads a PEM file of a key
+fn read_pem(kid: &str, path: &str) -> Result<Vec<u8>, String> {
+    std::fs::read(path).map_err(|err| format!("unable to read key {kid} from {path}: {err}"))
+}
+
+/// returns the public key as JWK
+fn jwk(key: &JwtKeyConfig, public_pem: &[u8]) -> Result<Value, String> {
+    let invalid = |err: String| format!("invalid public key {}: {err}", key.kid);
+    let pem = std::str::from_utf8(public_pem).map_err(|err| invalid(err.to_string()))?;
+    match key.algorithm {
+        JwtAlgorithm::RS256 => {
+            let public_key = RsaPublicKey::from_public_key_pem(pem)
+                .or_else(|_| RsaPublicKey::from_pkcs1_pem(pem))
+                .map_err(|err| invalid(err.to_string()))?;
+            Ok(json!({
+                "kty": "RSA",
+                "use": "sig",
+                "alg": "RS256",
+                "kid": key.kid,
+                "n": URL_SAFE_NO_PAD.encode(public_key.n().to_bytes_be()),
+                "e": URL_SAFE_NO_PAD.encode(public_key.e().to_bytes_be()),
+            }))
+        }
+        JwtAlgorithm::EdDSA => {
+            let der = pem::parse(pem).map_err(|err| invalid(err.to_string()))?;
+            let x = der
+                .contents
+                .strip_prefix(&ED25519_SPKI_PREFIX)
+                .filter(|x| x.len() == 32)
+                .ok_or_else(|| invalid("not a Ed25519 public key".to_owned()))?;
+            Ok(json!({
+                "kty": "OKP",
+                "crv": "Ed25519",
+                "use": "sig",
+                "alg": "EdDSA",
+                "kid": key.kid,
+                "x": URL_SAFE_NO_PAD.encode(x),
+            }))
+        }
+    }
+}
+
+impl JwtKeys {
+    /// Loads the keys of the config, the error names the key which couldn't be loaded
+    pub fn load(app_state: &AppState) -> Result<Self, String> {
+        JwtKeys::from_config(&app_state.jwt, &app_state.secret)
+    }
+
+    /// Loads the keys of the config, signing with the secret without keys
+    fn from_config(config: &JwtConfig, secret: &str) -> Result<Self, String> {
+        if config.keys.is_empty() {
+            return Ok(JwtKeys {
+                header: Header::new(Algorithm::HS256),
+                signing_key: EncodingKey::from_secret(secret.as_bytes()),
+                verifying_keys: vec![VerifyingKey {
+                    kid: None,
+                    algorithm: Algorithm::HS256,
+                    key: DecodingKey::from_secret(secret.as_bytes()),
+                }],
+                jwks: json!({ "keys": [] }),
+            });
+        }
+
+        let mut verifying_keys = Vec::new();
+        let mut public_keys = Vec::new();
+        for key in config.keys.iter() {
+            let public_pem = read_pem(&key.kid, &key.public_key)?;
+            let (algorithm, decoding_key) = match key.algorithm {
+                JwtAlgorithm::RS256 => (Algorithm::RS256, DecodingKey::from_rsa_pem(&public_pem)),
+                JwtAlgorithm::EdDSA => (Algorithm::EdDSA, DecodingKey::from_ed_pem(&public_pem)),
+            };
+            verifying_keys.push(VerifyingKey {
+                kid: Some(key.kid.clone()),
+                algorithm,
+                key: decoding_key
+                    .map_err(|err| format!("invalid public key {}: {err}", key.kid))?,
+            });
+            public_keys.push(jwk(key, &public_pem)?);
+        }
+
+        let signing = match &config.signing_kid {
+            Some(kid) => config.keys.iter().find(|key| &key.kid == kid),
+            None => config.keys.iter().find(|key| key.private_key.is_some()),
+        }
+        .ok_or("no signing key configured in jwt")?;
+        let private_key = signing
+            .private_key
+            .as_ref()
+            .ok_or_else(|| format!("the signing key {} has no private key", signing.kid))?;
+        let private_pem = read_pem(&signing.kid, private_key)?;
+        let (algorithm, signing_key) = match signing.algorithm {
+            JwtAlgorithm::RS256 => (Algorithm::RS256, EncodingKey::from_rsa_pem(&private_pem)),
+            JwtAlgorithm::EdDSA => (Algorithm::EdDSA, EncodingKey::from_ed_pem(&private_pem)),
+        };
+        let mut header = Header::new(algorithm);
+        header.kid = Some(signing.kid.clone());
+
+        Ok(JwtKeys {
+            header,
+            signing_key: signing_key
+                .map_err(|err| format!("invalid private key {}: {err}", signing.kid))?,
+            verifying_keys,
+            jwks: json!({ "keys": public_keys }),
+        })
+    }
+
+    /// Signs the claims with the signing key
+    pub fn encode(&self, claims: &Claims) -> Result<String, Error> {
+        jsonwebtoken::encode(&self.header, claims, &self.signing_key)
+    }
+
+    /// Verifies the token with the key of its kid and returns the claims.
+    /// The algorithm of the key is enforced, so a token can't pick a weaker one
+    pub fn decode(&self, token: &str) -> Result<Claims, Error> {
+        let header = jsonwebtoken::decode_header(token)?;
+        let key = self
+            .verifying_keys
+            .iter()
+            .find(|key| key.kid == header.kid)
+            .ok_or_else(|| Error::from(ErrorKind::InvalidToken))?;
+        jsonwebtoken::decode::<Claims>(token, &key.key, &Validation::new(key.algorithm))
+            .map(|token_data| token_data.claims)
+    }
+}
+
+/// endpoint to publish the public keys, so other services can verify our tokens
+#[get("/.well-known/jwks.json")]
+async fn jwks(keys: web::Data<JwtKeys>) -> HttpResponse {
+    HttpResponse::Ok().json(&keys.jwks)
+}
+
+#[cfg(test)]
+mod tests {
+    use jsonwebtoken::{Algorithm, EncodingKey, Header};
+    use surrealdb::sql::Thing;
+
+    use super::JwtKeys;
+    use crate::{
+        auth::{Claims, Role},
+        config::{JwtAlgorithm, JwtConfig, JwtKeyConfig},
+    };
+
+    fn testdata(file: &str) -> String {
+        format!("{}/testdata/{file}", env!("CARGO_MANIFEST_DIR"))
+    }
+
+    fn key(kid: &str, algorithm: JwtAlgorithm, file: &str, private: bool) -> JwtKeyConfig {
+        JwtKeyConfig {
+            kid: kid.to_owned(),
+            algorithm,
+            private_key: private.then(|| testdata(&format!("{file}.pem"))),
+            public_key: testdata(&format!("{file}.pub.pem")),
+        }
+    }
+
+    fn keys(keys: Vec<JwtKeyConfig>) -> JwtKeys {
+        JwtKeys::from_config(
+            &JwtConfig {
+                signing_kid: None,
+                keys,
+            },
+            "secret",
+        )
+        .unwrap()
+    }
+
+    fn claims() -> Claims {
+        Claims::new(
+            &Thing::from(("user", "anna")),
+            &Thing::from(("session", "s1")),
+            "anna".to_owned(),
+            "anna@plant.local".to_owned(),
+            Role::Operator,
+            false,
+            chrono::Duration::minutes(5),
+        )
+    }
+
+    #[test]
+    fn signs_and_verifies_with_each_algorithm() {
+        for keys in [
+            keys(vec![]),
+            keys(vec![key("rsa", JwtAlgorithm::RS256, "jwt_rs256", true)]),
+            keys(vec![key("ed", JwtAlgorithm::EdDSA, "jwt_ed25519", true)]),
+        ] {
+            let token = keys.encode(&claims()).unwrap();
+            assert_eq!(keys.decode(&token).unwrap().sub, claims().sub);
+        }
+    }
+
+    #[test]
+    fn verifies_tokens_of_the_rotated_key() {
+        let before = keys(vec![key(
+            "2023-05",
+            JwtAlgorithm::EdDSA,
+            "jwt_ed25519",
+            true,
+        )]);
+        let token = before.encode(&claims()).unwrap();
+
+        let after = keys(vec![
+            key("2023-06", JwtAlgorithm::EdDSA, "jwt_ed25519_rotated", true),
+            key("2023-05", JwtAlgorithm::EdDSA, "jwt_ed25519", false),
+        ]);
+        let header = jsonwebtoken::decode_header(&after.encode(&claims()).unwrap()).unwrap();
+        assert_eq!(header.kid.as_deref(), Some("2023-06"));
+        assert_eq!(after.decode(&token).unwrap().sub, claims().sub);
+    }
+
+    #[test]
+    fn rejects_tokens_of_other_algorithms_and_kids() {
+        let keys = keys(vec![key("rsa", JwtAlgorithm::RS256, "jwt_rs256", true)]);
+        let public_key = std::fs::read(testdata("jwt_rs256.pub.pem")).unwrap();
+        let hs256 = |kid: Option<&str>, secret: &[u8]| {
+            let mut header = Header::new(Algorithm::HS256);
+            header.kid = kid.map(str::to_owned);
+            jsonwebtoken::encode(&header, &claims(), &EncodingKey::from_secret(secret)).unwrap()
+        };
+        // signed with the public key as HMAC secret
+        assert!(keys.decode(&hs256(Some("rsa"), &public_key)).is_err());
+        assert!(keys.decode(&hs256(None, b"secret")).is_err());
+
+        let ed = JwtKeys::from_config(
+            &JwtConfig {
+                signing_kid: None,
+                keys: vec![key("unknown", JwtAlgorithm::EdDSA, "jwt_ed25519", true)],
+            },
+            "secret",
+        )
+        .unwrap();
+        assert!(keys.decode(&ed.encode(&claims()).unwrap()).is_err());
+    }
+
+    #[test]
+    fn publishes_the_public_keys() {
+        let keys = keys(vec![
+            key("rsa", JwtAlgorithm::RS256, "jwt_rs256", true),
+            key("ed", JwtAlgorithm::EdDSA, "jwt_ed25519", false),
+        ]);
+        let jwks = keys.jwks["keys"].as_array().unwrap();
+        assert_eq!(jwks.len(), 2);
+        assert_eq!(jwks[0]["kty"], "RSA");
+        assert_eq!(jwks[0]["kid"], "rsa");
+        assert_eq!(jwks[0]["alg"], "RS256");
+        assert_eq!(jwks[0]["e"], "AQAB");
+        assert!(jwks[0]["n"].as_str().is_some_and(|n| n.len() > 300));
+        assert_eq!(jwks[1]["kty"], "OKP");
+        assert_eq!(jwks[1]["kid"], "ed");
+        assert_eq!(jwks[1]["alg"], "EdDSA");
+        assert_eq!(jwks[1]["crv"], "Ed25519");
+        // the 32 bytes of the key
+        assert_eq!(jwks[1]["x"].as_str().map(str::len), Some(43));
+
+        assert_eq!(
+            JwtKeys::from_config(&JwtConfig::default(), "secret")
+                .unwrap()
+                .jwks["keys"],
+            serde_json::json!([])
+        );
+    }
+}
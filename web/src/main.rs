@@ -11,13 +11,17 @@
 //! ```
 
 use actix_cors::Cors;
-use actix_files::NamedFile;
+use actix_files::{Files, NamedFile};
 use std::path::Path;
 mod api;
 mod app;
 mod auth;
 mod config;
+mod jwt;
 mod middleware;
+mod notify;
+mod poller;
+mod provider;
 mod routes;
 
 use actix_web::{get, web, App, HttpServer};
@@ -44,15 +48,53 @@ async fn main() -> std::io::Result<()> {
         .await
         .expect("Either namespace or database main does not exist");
 
+    std::fs::create_dir_all(&app_state.avatars)?;
+    let jwt_keys =
+        web::Data::new(jwt::JwtKeys::load(&app_state).expect("unable to load the jwt keys"));
+    let auth_providers = web::Data::new(
+        provider::AuthProviders::load(&app_state).expect("unable to load the auth providers"),
+    );
+    let mhubx = web::Data::new(
+        app_state
+            .restapi
+            .mhubx_cache()
+            .expect("unable to create the MHubX client"),
+    );
+    if app_state.mhubx_poller.enabled {
+        let poller = poller::Poller::new(
+            common::mhubx::MhubxClient::new(app_state.restapi.mhubx())
+                .expect("unable to create the MHubX client"),
+            app_state.mhubx_poller.clone(),
+        );
+        actix_web::rt::spawn(poller.run(db.clone()));
+    }
+    actix_web::rt::spawn(app::alarm_rule::watch_stale(db.clone()));
+    if app_state.notifications.enabled {
+        let notifier = notify::Notifier::new(app_state.notifications.clone())
+            .expect("unable to create the notification channels");
+        actix_web::rt::spawn(notifier.run(db.clone()));
+    }
     let app_state = web::Data::new(app_state);
+    let rate_limiter = web::Data::new(middleware::rate_limit::RateLimiter::default());
+    let login_throttle = web::Data::new(app::lockout::LoginThrottle::default());
     HttpServer::new(move || {
         let cors = Cors::permissive();
         App::new()
             .app_data(web::Data::new(db.clone()))
             .app_data(app_state.clone())
+            .app_data(jwt_keys.clone())
+            .app_data(auth_providers.clone())
+            .app_data(mhubx.clone())
+            .app_data(rate_limiter.clone())
+            .app_data(login_throttle.clone())
             .wrap(cors)
             .service(app::user::sign_in)
+            .service(app::user::refresh)
+            .service(provider::oidc::oidc_login)
+            .service(provider::oidc::oidc_callback)
+            .service(jwt::jwks)
             .configure(routes::config)
+            .service(Files::new("/avatars", &app_state.avatars))
             .service(single_page_app)
     })
     .bind((config.web.address.as_str(), config.web.port))?
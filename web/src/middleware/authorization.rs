@@ -2,17 +2,32 @@
 //!
 //! `web::authorization::middleware` is a module containing the JWTAuthorization middleware
 //!
-use std::future::{ready, Ready};
+use std::{
+    future::{ready, Ready},
+    rc::Rc,
+};
 
 use actix_web::{
     dev::{forward_ready, Service, ServiceRequest, ServiceResponse, Transform},
-    error::ErrorUnauthorized,
+    error::{ErrorForbidden, ErrorInternalServerError, ErrorUnauthorized},
     http::header::AUTHORIZATION,
-    web, Error,
+    web, Error, HttpMessage,
 };
 use futures_util::future::LocalBoxFuture;
 
-use crate::{auth::Claims, config::AppState};
+use surrealdb::{engine::remote::ws::Client, Surreal};
+
+use crate::{
+    app::{api_key, session},
+    auth::{ApiKeyClaims, ApiScope, Claims},
+    jwt::JwtKeys,
+};
+
+/// the credentials of a request
+enum Credential {
+    Jwt(Claims),
+    ApiKey(String),
+}
 
 /// the actual middleware struct
 pub struct JWTAuthorization;
@@ -20,7 +35,7 @@ pub struct JWTAuthorization;
 /// implementation of the service factory for actix-web
 impl<S, B> Transform<S, ServiceRequest> for JWTAuthorization
 where
-    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = Error>,
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = Error> + 'static,
     S::Future: 'static,
     B: 'static,
 {
@@ -31,19 +46,21 @@ where
     type Future = Ready<Result<Self::Transform, Self::InitError>>;
 
     fn new_transform(&self, service: S) -> Self::Future {
-        ready(Ok(JWTAuthorizationMiddleware { service }))
+        ready(Ok(JWTAuthorizationMiddleware {
+            service: Rc::new(service),
+        }))
     }
 }
 
 /// implementation of the middleware service
 pub struct JWTAuthorizationMiddleware<S> {
-    service: S,
+    service: Rc<S>,
 }
 
 /// implementation of the axtix-web service model to handle request/response interaction
 impl<S, B> Service<ServiceRequest> for JWTAuthorizationMiddleware<S>
 where
-    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = Error>,
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = Error> + 'static,
     S::Future: 'static,
     B: 'static,
 {
@@ -54,39 +71,74 @@ where
     forward_ready!(service);
 
     /// this function handels the actual JWT authorization via a Bearer JWT token.
-    /// It decodes the JWT and validates it.
+    /// It decodes the JWT and validates it, including that its session has not been revoked.
+    /// Without a Authorization header a API key in the X-API-Key header is validated instead,
+    /// a API key without the admin scope is only allowed to use routes declaring one of its scopes
+    /// with the RequireScope middleware.
+    /// A token of a temporary password is only allowed to change the password.
+    /// The decoded claims are inserted into the request extensions for the following services.
     /// Upon failed decoding, a error resonse 401 is returned including the reason why it failed.
     fn call(&self, req: ServiceRequest) -> Self::Future {
-        let response = match req.headers().get(AUTHORIZATION) {
-            Some(auth_header) => {
+        let api_key = req
+            .headers()
+            .get(api_key::API_KEY_HEADER)
+            .map(|key| key.to_str().unwrap_or_default().trim().to_owned());
+        let response = match (req.headers().get(AUTHORIZATION), api_key) {
+            (Some(auth_header), _) => {
                 let jwt = auth_header
                     .to_str()
                     .unwrap_or_default()
                     .split("Bearer")
                     .collect::<String>();
 
-                let app_state = req.app_data::<web::Data<AppState>>().unwrap();
-                match jsonwebtoken::decode::<Claims>(
-                    jwt.as_str().trim(),
-                    &jsonwebtoken::DecodingKey::from_secret(app_state.secret.as_str().as_ref()),
-                    &jsonwebtoken::Validation::new(jsonwebtoken::Algorithm::HS256),
-                ) {
-                    Ok(claims) => Ok(claims),
+                let keys = req.app_data::<web::Data<JwtKeys>>().unwrap();
+                match keys.decode(jwt.as_str().trim()) {
+                    Ok(claims) => Ok(Credential::Jwt(claims)),
                     Err(err) => Err(ErrorUnauthorized(err)),
                 }
             }
-            None => Err(ErrorUnauthorized("Missing authorization header!")),
+            (None, Some(key)) => Ok(Credential::ApiKey(key)),
+            (None, None) => Err(ErrorUnauthorized("Missing authorization header!")),
         };
-        let fut = self.service.call(req);
+        let service = self.service.clone();
 
         Box::pin(async move {
-            match response {
-                Ok(_) => {
-                    let res = fut.await?;
-                    Ok(res)
+            let db = req
+                .app_data::<web::Data<Surreal<Client>>>()
+                .unwrap()
+                .clone();
+            let claims = match response? {
+                Credential::Jwt(claims) => {
+                    let session = surrealdb::sql::thing(&claims.sid)
+                        .map_err(|_| ErrorUnauthorized("Invalid session!"))?;
+                    match session::get_active(&db, &session).await {
+                        Ok(Some(_)) => claims,
+                        Ok(None) => return Err(ErrorUnauthorized("Session revoked or expired!")),
+                        Err(err) => return Err(ErrorInternalServerError(err)),
+                    }
                 }
-                Err(err) => Err(err),
+                Credential::ApiKey(key) => match api_key::authenticate(&db, &key).await {
+                    Ok(Some(api_key)) => api_key.claims(),
+                    Ok(None) => {
+                        return Err(ErrorUnauthorized("Invalid, revoked or expired API key!"))
+                    }
+                    Err(err) => return Err(ErrorInternalServerError(err)),
+                },
+            };
+
+            if !claims.permits(req.method(), req.path()) {
+                return Err(ErrorForbidden(
+                    "The password has to be changed before using the API",
+                ));
+            }
+
+            if claims.scopes.is_some() && !claims.has_scope(ApiScope::Admin) {
+                req.extensions_mut().insert(ApiKeyClaims(claims));
+            } else {
+                req.extensions_mut().insert(claims);
             }
+            let res = service.call(req).await?;
+            Ok(res)
         })
     }
 }
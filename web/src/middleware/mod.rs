@@ -1,2 +1,4 @@
 pub mod authorization;
-
+pub mod rate_limit;
+pub mod role;
+pub mod scope;
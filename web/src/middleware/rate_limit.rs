@@ -0,0 +1,305 @@
+//! # web::middleware::rate_limit
+//!
+//! `web::middleware::rate_limit` is a module containing the RateLimit middleware.
+//! Every client ip has a token bucket per route group, the limits of the groups are
+//! configured in `rate_limits` of the config. Exceeding a limit returns 429 with Retry-After.
+//!
+//! # Example
+//!
+//! ```text
+//! web::scope("/api/v1").wrap(RateLimit::API)
+//! #[get("/export", wrap = "RateLimit::EXPORT")]
+//! ```
+use std::{
+    collections::HashMap,
+    future::{ready, Ready},
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+use actix_web::{
+    dev::{forward_ready, Service, ServiceRequest, ServiceResponse, Transform},
+    error::InternalError,
+    http::header::RETRY_AFTER,
+    web, Error, HttpRequest, HttpResponse,
+};
+use futures_util::future::LocalBoxFuture;
+use surrealdb::{engine::remote::ws::Client, Surreal};
+
+use crate::{
+    app::lockout,
+    auth::ErrorResponse,
+    config::{AppState, Limit},
+};
+
+/// upper bound of tracked buckets, full buckets are removed beyond it
+const MAX_TRACKED_BUCKETS: usize = 10_000;
+
+/// returns the ip of the client, X-Forwarded-For is only used if it is trusted
+pub fn client_ip(req: &HttpRequest, trust_forwarded_for: bool) -> String {
+    let ip = if trust_forwarded_for {
+        req.connection_info()
+            .realip_remote_addr()
+            .map(str::to_owned)
+    } else {
+        req.peer_addr().map(|addr| addr.ip().to_string())
+    };
+    ip.unwrap_or_else(|| "unknown".to_owned())
+}
+
+/// a token bucket, refilled continuously up to the number of requests of the limit
+struct Bucket {
+    tokens: f64,
+    updated: Instant,
+    limited: bool,
+}
+
+/// The outcome of a rate limited request
+pub enum Decision {
+    Allowed,
+    /// the client has to wait, first is set for the first rejected request
+    Limited {
+        retry_after: Duration,
+        first: bool,
+    },
+}
+
+/// In-memory state of all token buckets, shared by all workers
+#[derive(Default)]
+pub struct RateLimiter {
+    buckets: Mutex<HashMap<(&'static str, String), Bucket>>,
+}
+
+impl RateLimiter {
+    /// takes a token from the bucket of the client in the group
+    pub fn take(&self, group: &'static str, client: String, limit: Limit) -> Decision {
+        self.take_at(group, client, limit, Instant::now())
+    }
+
+    /// takes a token at the given time
+    fn take_at(&self, group: &'static str, client: String, limit: Limit, now: Instant) -> Decision {
+        let capacity = f64::from(limit.requests.max(1));
+        let per_second = capacity / limit.seconds.max(1) as f64;
+
+        let mut buckets = self.buckets.lock().unwrap();
+        if buckets.len() >= MAX_TRACKED_BUCKETS {
+            buckets.retain(|_, bucket| {
+                bucket.tokens + now.duration_since(bucket.updated).as_secs_f64() * per_second
+                    < capacity
+            });
+        }
+
+        let bucket = buckets.entry((group, client)).or_insert(Bucket {
+            tokens: capacity,
+            updated: now,
+            limited: false,
+        });
+        bucket.tokens = (bucket.tokens
+            + now.duration_since(bucket.updated).as_secs_f64() * per_second)
+            .min(capacity);
+        bucket.updated = now;
+
+        if bucket.tokens >= 1.0 {
+            bucket.tokens -= 1.0;
+            bucket.limited = false;
+            return Decision::Allowed;
+        }
+        let first = !bucket.limited;
+        bucket.limited = true;
+        Decision::Limited {
+            retry_after: Duration::from_secs_f64((1.0 - bucket.tokens) / per_second),
+            first,
+        }
+    }
+}
+
+/// the value of the Retry-After header, the wait rounded up to whole seconds
+fn retry_after_seconds(wait: Duration) -> u64 {
+    (wait.as_secs() + u64::from(wait.subsec_nanos() > 0)).max(1)
+}
+
+/// the actual middleware struct, containing the name of the route group
+pub struct RateLimit(pub &'static str);
+
+impl RateLimit {
+    /// all routes of /api/v1
+    pub const API: Self = RateLimit("api");
+    /// sign in and token refresh
+    pub const AUTH: Self = RateLimit("auth");
+    pub const EXPORT: Self = RateLimit("export");
+    pub const IMPORT: Self = RateLimit("import");
+}
+
+/// implementation of the service factory for actix-web
+impl<S, B> Transform<S, ServiceRequest> for RateLimit
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = Error>,
+    S::Future: 'static,
+    B: 'static,
+{
+    type Response = ServiceResponse<B>;
+    type Error = Error;
+    type InitError = ();
+    type Transform = RateLimitMiddleware<S>;
+    type Future = Ready<Result<Self::Transform, Self::InitError>>;
+
+    fn new_transform(&self, service: S) -> Self::Future {
+        ready(Ok(RateLimitMiddleware {
+            service,
+            group: self.0,
+        }))
+    }
+}
+
+/// implementation of the middleware service
+pub struct RateLimitMiddleware<S> {
+    service: S,
+    group: &'static str,
+}
+
+/// implementation of the axtix-web service model to handle request/response interaction
+impl<S, B> Service<ServiceRequest> for RateLimitMiddleware<S>
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = Error>,
+    S::Future: 'static,
+    B: 'static,
+{
+    type Response = ServiceResponse<B>;
+    type Error = Error;
+    type Future = LocalBoxFuture<'static, Result<Self::Response, Self::Error>>;
+
+    forward_ready!(service);
+
+    /// this function takes a token of the client in the route group.
+    /// Without a token left a error response 429 is returned with the seconds to wait in Retry-After.
+    /// The first rejected request of a client records a lockout event.
+    fn call(&self, req: ServiceRequest) -> Self::Future {
+        let (Some(app_state), Some(limiter)) = (
+            req.app_data::<web::Data<AppState>>(),
+            req.app_data::<web::Data<RateLimiter>>(),
+        ) else {
+            return Box::pin(self.service.call(req));
+        };
+
+        let client = client_ip(req.request(), app_state.rate_limits.trust_forwarded_for);
+        let limit = app_state.rate_limits.limit(self.group);
+        let Decision::Limited { retry_after, first } =
+            limiter.take(self.group, client.clone(), limit)
+        else {
+            return Box::pin(self.service.call(req));
+        };
+
+        if first {
+            if let Some(db) = req.app_data::<web::Data<Surreal<Client>>>().cloned() {
+                let group = self.group;
+                let lockout = Duration::from_secs(limit.seconds);
+                actix_web::rt::spawn(async move {
+                    lockout::record(&db, "rate_limit", &client, Some(group), lockout).await
+                });
+            }
+        }
+
+        let seconds = retry_after_seconds(retry_after);
+        let error = format!("Too many requests, retry after {seconds} seconds");
+        let response = HttpResponse::TooManyRequests()
+            .insert_header((RETRY_AFTER, seconds.to_string()))
+            .json(ErrorResponse {
+                error: error.clone(),
+                code: 429,
+            });
+        Box::pin(async move { Err(InternalError::from_response(error, response).into()) })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::{Duration, Instant};
+
+    use super::{retry_after_seconds, Decision, RateLimiter};
+    use crate::config::Limit;
+
+    const LIMIT: Limit = Limit {
+        requests: 2,
+        seconds: 10,
+    };
+
+    fn limited(decision: Decision) -> Option<(Duration, bool)> {
+        match decision {
+            Decision::Allowed => None,
+            Decision::Limited { retry_after, first } => Some((retry_after, first)),
+        }
+    }
+
+    #[test]
+    fn buckets_refill_over_time() {
+        let limiter = RateLimiter::default();
+        let start = Instant::now();
+        let take = |after: u64| {
+            limited(limiter.take_at(
+                "api",
+                "10.0.0.1".to_owned(),
+                LIMIT,
+                start + Duration::from_secs(after),
+            ))
+        };
+
+        assert!(take(0).is_none());
+        assert!(take(0).is_none());
+        assert!(take(0).is_some());
+        // two requests per ten seconds refill one token every five seconds
+        assert!(take(5).is_none());
+        assert!(take(5).is_some());
+        assert!(take(20).is_none());
+        assert!(take(20).is_none());
+        assert!(take(20).is_some());
+    }
+
+    #[test]
+    fn buckets_are_kept_per_group_and_client() {
+        let limiter = RateLimiter::default();
+        let now = Instant::now();
+        let single = Limit {
+            requests: 1,
+            seconds: 60,
+        };
+        assert!(limited(limiter.take_at("api", "10.0.0.1".to_owned(), single, now)).is_none());
+        assert!(limited(limiter.take_at("api", "10.0.0.2".to_owned(), single, now)).is_none());
+        assert!(limited(limiter.take_at("export", "10.0.0.1".to_owned(), single, now)).is_none());
+        assert!(limited(limiter.take_at("api", "10.0.0.1".to_owned(), single, now)).is_some());
+    }
+
+    #[test]
+    fn first_is_only_set_once_per_limited_streak() {
+        let limiter = RateLimiter::default();
+        let start = Instant::now();
+        let take = |after: u64| {
+            limited(limiter.take_at(
+                "auth",
+                "10.0.0.1".to_owned(),
+                LIMIT,
+                start + Duration::from_secs(after),
+            ))
+        };
+
+        take(0);
+        take(0);
+        let (retry_after, first) = take(0).unwrap();
+        assert!(first);
+        assert_eq!(retry_after, Duration::from_secs(5));
+        let (retry_after, first) = take(1).unwrap();
+        assert!(!first);
+        assert_eq!(retry_after, Duration::from_secs(4));
+
+        // a allowed request ends the streak
+        assert!(take(5).is_none());
+        assert_eq!(take(5), Some((Duration::from_secs(5), true)));
+    }
+
+    #[test]
+    fn retry_after_rounds_up_to_whole_seconds() {
+        assert_eq!(retry_after_seconds(Duration::from_secs(5)), 5);
+        assert_eq!(retry_after_seconds(Duration::from_millis(4_200)), 5);
+        assert_eq!(retry_after_seconds(Duration::from_millis(10)), 1);
+        assert_eq!(retry_after_seconds(Duration::ZERO), 1);
+    }
+}
@@ -0,0 +1,147 @@
+//! # web::middleware::role
+//!
+//! `web::middleware::role` is a module containing the RequireRole middleware.
+//! It has to be placed behind the JWTAuthorization middleware, which provides the claims.
+//!
+//! # Example
+//!
+//! ```text
+//! #[post("/stations", wrap = "RequireRole(Role::Maintainer)")]
+//! ```
+use std::future::{ready, Ready};
+
+use actix_web::{
+    dev::{forward_ready, Service, ServiceRequest, ServiceResponse, Transform},
+    error::InternalError,
+    Error, HttpMessage, HttpResponse,
+};
+use futures_util::future::LocalBoxFuture;
+
+use crate::auth::{Claims, ErrorResponse, Role};
+
+/// the actual middleware struct, containing the minimum role needed
+pub struct RequireRole(pub Role);
+
+/// implementation of the service factory for actix-web
+impl<S, B> Transform<S, ServiceRequest> for RequireRole
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = Error>,
+    S::Future: 'static,
+    B: 'static,
+{
+    type Response = ServiceResponse<B>;
+    type Error = Error;
+    type InitError = ();
+    type Transform = RequireRoleMiddleware<S>;
+    type Future = Ready<Result<Self::Transform, Self::InitError>>;
+
+    fn new_transform(&self, service: S) -> Self::Future {
+        ready(Ok(RequireRoleMiddleware {
+            service,
+            role: self.0,
+        }))
+    }
+}
+
+/// implementation of the middleware service
+pub struct RequireRoleMiddleware<S> {
+    service: S,
+    role: Role,
+}
+
+/// implementation of the axtix-web service model to handle request/response interaction
+impl<S, B> Service<ServiceRequest> for RequireRoleMiddleware<S>
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = Error>,
+    S::Future: 'static,
+    B: 'static,
+{
+    type Response = ServiceResponse<B>;
+    type Error = Error;
+    type Future = LocalBoxFuture<'static, Result<Self::Response, Self::Error>>;
+
+    forward_ready!(service);
+
+    /// this function compares the role of the decoded JWT with the required role.
+    /// Upon an insufficient role, a error response 403 is returned naming the required role,
+    /// without claims 401 or 403 for API keys lacking the scope of the route.
+    fn call(&self, req: ServiceRequest) -> Self::Future {
+        let role = req.extensions().get::<Claims>().map(|claims| claims.role);
+        let Some(role) = role else {
+            let error = crate::auth::missing_claims(req.request());
+            return Box::pin(async move { Err(error) });
+        };
+        if role >= self.role {
+            let fut = self.service.call(req);
+            return Box::pin(fut);
+        }
+
+        let error = format!(
+            "Forbidden: this action requires the role {} or higher",
+            self.role
+        );
+        let response = HttpResponse::Forbidden().json(ErrorResponse {
+            error: error.clone(),
+            code: 403,
+        });
+        Box::pin(async move { Err(InternalError::from_response(error, response).into()) })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use actix_web::{dev::Service, get, http::StatusCode, test, App, HttpMessage, HttpResponse};
+    use surrealdb::sql::Thing;
+
+    use super::RequireRole;
+    use crate::auth::{Claims, Role};
+
+    #[get("/users", wrap = "RequireRole(Role::Maintainer)")]
+    async fn users() -> HttpResponse {
+        HttpResponse::Ok().finish()
+    }
+
+    async fn status(role: Option<Role>) -> StatusCode {
+        let app = test::init_service(
+            App::new()
+                .wrap_fn(move |req, srv| {
+                    if let Some(role) = role {
+                        req.extensions_mut().insert(Claims::new(
+                            &Thing::from(("user", "anna")),
+                            &Thing::from(("session", "s1")),
+                            "anna".to_owned(),
+                            "anna@plant.local".to_owned(),
+                            role,
+                            false,
+                            chrono::Duration::minutes(5),
+                        ));
+                    }
+                    srv.call(req)
+                })
+                .service(users),
+        )
+        .await;
+        let req = test::TestRequest::get().uri("/users").to_request();
+        match app.call(req).await {
+            Ok(res) => res.status(),
+            Err(err) => err.as_response_error().status_code(),
+        }
+    }
+
+    #[actix_web::test]
+    async fn lower_roles_are_forbidden() {
+        assert_eq!(status(Some(Role::Viewer)).await, StatusCode::FORBIDDEN);
+        assert_eq!(status(Some(Role::Operator)).await, StatusCode::FORBIDDEN);
+    }
+
+    #[actix_web::test]
+    async fn equal_and_higher_roles_pass() {
+        assert_eq!(status(Some(Role::Maintainer)).await, StatusCode::OK);
+        assert_eq!(status(Some(Role::Admin)).await, StatusCode::OK);
+    }
+
+    #[actix_web::test]
+    async fn requests_without_claims_are_unauthorized() {
+        assert_eq!(status(None).await, StatusCode::UNAUTHORIZED);
+    }
+}
@@ -0,0 +1,160 @@
+//! # web::middleware::scope
+//!
+//! `web::middleware::scope` is a module containing the RequireScope middleware.
+//! It declares the scope a API key needs for a route, keys without the admin scope can
+//! only use routes declaring one of their scopes. Requests with a Bearer JWT are not affected.
+//! It has to be placed behind the JWTAuthorization middleware and before a RequireRole middleware,
+//! so it has to be the last `wrap` of a route.
+//!
+//! # Example
+//!
+//! ```text
+//! #[get("/stations", wrap = "RequireScope(ApiScope::ReadSensors)")]
+//! ```
+use std::future::{ready, Ready};
+
+use actix_web::{
+    dev::{forward_ready, Service, ServiceRequest, ServiceResponse, Transform},
+    error::InternalError,
+    Error, HttpMessage, HttpResponse,
+};
+use futures_util::future::LocalBoxFuture;
+
+use crate::auth::{ApiKeyClaims, ApiScope, ErrorResponse};
+
+/// the actual middleware struct, containing the scope needed
+pub struct RequireScope(pub ApiScope);
+
+/// implementation of the service factory for actix-web
+impl<S, B> Transform<S, ServiceRequest> for RequireScope
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = Error>,
+    S::Future: 'static,
+    B: 'static,
+{
+    type Response = ServiceResponse<B>;
+    type Error = Error;
+    type InitError = ();
+    type Transform = RequireScopeMiddleware<S>;
+    type Future = Ready<Result<Self::Transform, Self::InitError>>;
+
+    fn new_transform(&self, service: S) -> Self::Future {
+        ready(Ok(RequireScopeMiddleware {
+            service,
+            scope: self.0,
+        }))
+    }
+}
+
+/// implementation of the middleware service
+pub struct RequireScopeMiddleware<S> {
+    service: S,
+    scope: ApiScope,
+}
+
+/// implementation of the axtix-web service model to handle request/response interaction
+impl<S, B> Service<ServiceRequest> for RequireScopeMiddleware<S>
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = Error>,
+    S::Future: 'static,
+    B: 'static,
+{
+    type Response = ServiceResponse<B>;
+    type Error = Error;
+    type Future = LocalBoxFuture<'static, Result<Self::Response, Self::Error>>;
+
+    forward_ready!(service);
+
+    /// this function compares the scopes of a API key with the required scope.
+    /// With the scope the claims of the key are provided to the following services,
+    /// otherwise a error response 403 is returned naming the required scope.
+    fn call(&self, req: ServiceRequest) -> Self::Future {
+        let api_key = req.extensions_mut().remove::<ApiKeyClaims>();
+        let claims = match api_key {
+            None => return Box::pin(self.service.call(req)),
+            Some(ApiKeyClaims(claims)) if claims.has_scope(self.scope) => claims,
+            Some(_) => {
+                let error = format!("Forbidden: the API key is missing the scope {}", self.scope);
+                let response = HttpResponse::Forbidden().json(ErrorResponse {
+                    error: error.clone(),
+                    code: 403,
+                });
+                return Box::pin(async move {
+                    Err(InternalError::from_response(error, response).into())
+                });
+            }
+        };
+
+        req.extensions_mut().insert(claims);
+        Box::pin(self.service.call(req))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use actix_web::{dev::Service, get, http::StatusCode, test, App, HttpMessage, HttpResponse};
+
+    use super::RequireScope;
+    use crate::auth::{ApiKeyClaims, ApiScope, Claims};
+
+    #[get("/stations", wrap = "RequireScope(ApiScope::ReadSensors)")]
+    async fn stations(claims: Claims) -> HttpResponse {
+        HttpResponse::Ok().body(claims.sub)
+    }
+
+    #[get("/audit-events")]
+    async fn audit_events(_claims: Claims) -> HttpResponse {
+        HttpResponse::Ok().finish()
+    }
+
+    fn api_key(scopes: Vec<ApiScope>) -> Claims {
+        let token = serde_json::json!({
+            "sub": "api_key:mes",
+            "sid": "api_key:mes",
+            "name": "mes",
+            "iat": 0,
+            "exp": 0,
+            "scopes": scopes,
+        });
+        serde_json::from_value(token).unwrap()
+    }
+
+    async fn status(scopes: Vec<ApiScope>, path: &str) -> StatusCode {
+        let app = test::init_service(
+            App::new()
+                .wrap_fn(move |req, srv| {
+                    req.extensions_mut()
+                        .insert(ApiKeyClaims(api_key(scopes.clone())));
+                    srv.call(req)
+                })
+                .service(stations)
+                .service(audit_events),
+        )
+        .await;
+        let req = test::TestRequest::get().uri(path).to_request();
+        match app.call(req).await {
+            Ok(res) => res.status(),
+            Err(err) => err.as_response_error().status_code(),
+        }
+    }
+
+    #[actix_web::test]
+    async fn api_keys_need_the_scope_of_the_route() {
+        assert_eq!(
+            status(vec![ApiScope::ReadSensors], "/stations").await,
+            StatusCode::OK
+        );
+        assert_eq!(
+            status(vec![ApiScope::WriteValues], "/stations").await,
+            StatusCode::FORBIDDEN
+        );
+    }
+
+    #[actix_web::test]
+    async fn routes_without_a_scope_are_not_available_to_api_keys() {
+        assert_eq!(
+            status(vec![ApiScope::ReadSensors], "/audit-events").await,
+            StatusCode::FORBIDDEN
+        );
+    }
+}
@@ -0,0 +1,456 @@
+//! # web::notify::channel
+//!
+//! `web::notify::channel` sends notifications through the channels of subscriptions:
+//! webhooks are posted the notification as JSON and retried with a growing backoff
+//! on connection errors, `429` and `5xx` until the deadline of the retries. Emails are sent through the configured SMTP server,
+//! MQTT messages are published to the topic of the subscription with QoS 1.
+
+use std::time::{Duration, Instant};
+
+use lettre::{
+    message::header::ContentType,
+    transport::smtp::{authentication::Credentials, Error as SmtpError},
+    AsyncSmtpTransport, AsyncTransport, Message, Tokio1Executor,
+};
+use rumqttc::{AsyncClient, EventLoop, MqttOptions, QoS};
+use serde::{Deserialize, Serialize};
+
+use crate::config::{MqttConfig, NotificationConfig, SmtpConfig, SmtpTls, WebhookConfig};
+
+/// the time a MQTT message may wait for the connection to the broker
+const PUBLISH_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// The channel of a subscription and its target
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum Channel {
+    /// mails the address, or the subscribed users without address
+    Email {
+        address: Option<String>,
+    },
+    Webhook {
+        url: String,
+    },
+    Mqtt {
+        topic: String,
+    },
+}
+
+impl Channel {
+    /// the kind of the channel as stored in the delivery log
+    pub fn kind(&self) -> &'static str {
+        match self {
+            Channel::Email { .. } => "email",
+            Channel::Webhook { .. } => "webhook",
+            Channel::Mqtt { .. } => "mqtt",
+        }
+    }
+
+    /// Returns a message for the first invalid target, or if the channel isn't configured
+    pub fn validate(&self, config: &NotificationConfig) -> Result<(), String> {
+        match self {
+            Channel::Email { address } => {
+                if config.smtp.is_none() {
+                    return Err("Email notifications are not configured".to_owned());
+                }
+                if let Some(address) = address {
+                    address
+                        .parse::<lettre::Address>()
+                        .map_err(|_| format!("{address} is no valid email address"))?;
+                }
+            }
+            Channel::Webhook { url } => {
+                let valid = reqwest::Url::parse(url)
+                    .is_ok_and(|url| ["http", "https"].contains(&url.scheme()));
+                if !valid {
+                    return Err("url needs to be a http or https URL".to_owned());
+                }
+            }
+            Channel::Mqtt { topic } => {
+                if config.mqtt.is_none() {
+                    return Err("MQTT notifications are not configured".to_owned());
+                }
+                if topic.is_empty() || topic.contains(['+', '#']) {
+                    return Err("topic needs to be a topic without wildcards".to_owned());
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+/// The outcome of a delivery, attempts counts the retries
+#[derive(Debug)]
+pub struct Outcome {
+    pub attempts: u32,
+    pub result: Result<(), String>,
+}
+
+impl Outcome {
+    /// the outcome of a channel without retries
+    pub fn once(result: Result<(), String>) -> Self {
+        Outcome {
+            attempts: 1,
+            result,
+        }
+    }
+}
+
+/// Posts the payload to the webhook, retrying failures which may be temporary.
+/// A retry is only started if its backoff ends before the deadline
+pub async fn send_webhook(
+    http: &reqwest::Client,
+    config: &WebhookConfig,
+    url: &str,
+    payload: &impl Serialize,
+    deadline: Instant,
+) -> Outcome {
+    let mut backoff = Duration::from_secs(config.backoff_seconds);
+    let mut attempts = 0;
+    loop {
+        attempts += 1;
+        let error = match http
+            .post(url)
+            .timeout(Duration::from_secs(config.timeout_seconds))
+            .json(payload)
+            .send()
+            .await
+        {
+            Ok(response) if response.status().is_success() => {
+                return Outcome {
+                    attempts,
+                    result: Ok(()),
+                }
+            }
+            Ok(response) => {
+                let status = response.status();
+                let error = format!("webhook responded with {status}");
+                if !(status.is_server_error() || status == reqwest::StatusCode::TOO_MANY_REQUESTS) {
+                    return Outcome {
+                        attempts,
+                        result: Err(error),
+                    };
+                }
+                error
+            }
+            Err(err) => err.to_string(),
+        };
+        if attempts > config.retries || Instant::now() + backoff > deadline {
+            return Outcome {
+                attempts,
+                result: Err(error),
+            };
+        }
+        tokio::time::sleep(backoff).await;
+        backoff = backoff.saturating_mul(2);
+    }
+}
+
+/// Creates the transport to the SMTP server, connections are opened per email
+pub fn smtp_transport(
+    config: &SmtpConfig,
+) -> Result<AsyncSmtpTransport<Tokio1Executor>, SmtpError> {
+    let builder = match config.tls {
+        SmtpTls::None => AsyncSmtpTransport::<Tokio1Executor>::builder_dangerous(&config.host),
+        SmtpTls::Starttls => AsyncSmtpTransport::<Tokio1Executor>::starttls_relay(&config.host)?,
+        SmtpTls::Tls => AsyncSmtpTransport::<Tokio1Executor>::relay(&config.host)?,
+    };
+    let builder = builder.port(config.port);
+    let builder = match &config.username {
+        Some(username) => {
+            builder.credentials(Credentials::new(username.clone(), config.password.clone()))
+        }
+        None => builder,
+    };
+    Ok(builder.build())
+}
+
+/// Sends a plain text email
+pub async fn send_email(
+    transport: &AsyncSmtpTransport<Tokio1Executor>,
+    from: &str,
+    to: &str,
+    subject: &str,
+    body: String,
+) -> Result<(), String> {
+    let from = from
+        .parse()
+        .map_err(|err| format!("invalid sender {from}: {err}"))?;
+    let to = to
+        .parse()
+        .map_err(|err| format!("invalid recipient {to}: {err}"))?;
+    let message = Message::builder()
+        .from(from)
+        .to(to)
+        .subject(subject)
+        .header(ContentType::TEXT_PLAIN)
+        .body(body)
+        .map_err(|err| err.to_string())?;
+    transport
+        .send(message)
+        .await
+        .map(|_| ())
+        .map_err(|err| err.to_string())
+}
+
+/// Creates the client of the MQTT broker, the event loop needs to be driven by `drive`
+pub fn mqtt_client(config: &MqttConfig) -> (AsyncClient, EventLoop) {
+    let mut options = MqttOptions::new(&config.client_id, &config.host, config.port);
+    options.set_keep_alive(Duration::from_secs(30));
+    if let Some(username) = &config.username {
+        options.set_credentials(username, &config.password);
+    }
+    AsyncClient::new(options, 100)
+}
+
+/// Drives the connection to the MQTT broker until the server stops, reconnecting after errors
+pub async fn drive(mut eventloop: EventLoop) {
+    loop {
+        if let Err(err) = eventloop.poll().await {
+            eprintln!("MQTT notifications disconnected: {err}");
+            tokio::time::sleep(Duration::from_secs(5)).await;
+        }
+    }
+}
+
+/// Publishes the payload as JSON to the topic
+pub async fn publish(
+    client: &AsyncClient,
+    topic: &str,
+    payload: &impl Serialize,
+) -> Result<(), String> {
+    let payload = serde_json::to_vec(payload).map_err(|err| err.to_string())?;
+    match tokio::time::timeout(
+        PUBLISH_TIMEOUT,
+        client.publish(topic, QoS::AtLeastOnce, false, payload),
+    )
+    .await
+    {
+        Ok(published) => published.map_err(|err| err.to_string()),
+        Err(_) => Err("MQTT broker not reachable".to_owned()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{
+        atomic::{AtomicU32, Ordering},
+        Arc,
+    };
+
+    use actix_web::{web, App, HttpResponse, HttpServer};
+    use serde_json::json;
+    use tokio::{
+        io::{AsyncBufReadExt, AsyncWriteExt, BufReader},
+        net::TcpListener,
+    };
+
+    use super::*;
+
+    /// starts a webhook responding with the statuses in order, then with 200
+    fn webhook(statuses: Vec<u16>) -> (String, Arc<AtomicU32>) {
+        let calls = Arc::new(AtomicU32::new(0));
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}/hook", listener.local_addr().unwrap());
+        let counter = calls.clone();
+        let server = HttpServer::new(move || {
+            let counter = counter.clone();
+            let statuses = statuses.clone();
+            App::new().route(
+                "/hook",
+                web::post().to(move || {
+                    let call = counter.fetch_add(1, Ordering::SeqCst) as usize;
+                    let status = statuses.get(call).copied().unwrap_or(200);
+                    async move {
+                        HttpResponse::build(actix_web::http::StatusCode::from_u16(status).unwrap())
+                            .finish()
+                    }
+                }),
+            )
+        })
+        .workers(1)
+        .listen(listener)
+        .unwrap()
+        .run();
+        actix_web::rt::spawn(server);
+        (url, calls)
+    }
+
+    fn webhook_config(retries: u32) -> WebhookConfig {
+        WebhookConfig {
+            timeout_seconds: 1,
+            retries,
+            backoff_seconds: 0,
+            max_retry_seconds: 30,
+        }
+    }
+
+    fn deadline() -> Instant {
+        Instant::now() + Duration::from_secs(30)
+    }
+
+    #[actix_web::test]
+    async fn webhook_retries_temporary_failures() {
+        let (url, calls) = webhook(vec![503, 429]);
+        let outcome = send_webhook(
+            &reqwest::Client::new(),
+            &webhook_config(3),
+            &url,
+            &json!({"event": "raised"}),
+            deadline(),
+        )
+        .await;
+
+        assert_eq!(outcome.result, Ok(()));
+        assert_eq!(outcome.attempts, 3);
+        assert_eq!(calls.load(Ordering::SeqCst), 3);
+    }
+
+    #[actix_web::test]
+    async fn webhook_gives_up() {
+        let (url, calls) = webhook(vec![500, 500, 500]);
+        let outcome = send_webhook(
+            &reqwest::Client::new(),
+            &webhook_config(1),
+            &url,
+            &(),
+            deadline(),
+        )
+        .await;
+        assert_eq!(outcome.attempts, 2);
+        assert!(outcome.result.is_err());
+
+        // client errors won't go away by retrying
+        let (url, calls_404) = webhook(vec![404]);
+        let outcome = send_webhook(
+            &reqwest::Client::new(),
+            &webhook_config(3),
+            &url,
+            &(),
+            deadline(),
+        )
+        .await;
+        assert_eq!(outcome.attempts, 1);
+        assert_eq!(
+            outcome.result,
+            Err("webhook responded with 404 Not Found".to_owned())
+        );
+        assert_eq!(calls.load(Ordering::SeqCst), 2);
+        assert_eq!(calls_404.load(Ordering::SeqCst), 1);
+    }
+
+    #[actix_web::test]
+    async fn webhook_stops_retrying_at_the_deadline() {
+        let (url, calls) = webhook(vec![503, 503]);
+        let config = WebhookConfig {
+            backoff_seconds: 5,
+            ..webhook_config(3)
+        };
+        // the backoff of the first retry would end after the deadline
+        let deadline = Instant::now() + Duration::from_secs(1);
+        let outcome = send_webhook(&reqwest::Client::new(), &config, &url, &(), deadline).await;
+
+        assert_eq!(outcome.attempts, 1);
+        assert!(outcome.result.is_err());
+        assert_eq!(calls.load(Ordering::SeqCst), 1);
+    }
+
+    /// a SMTP sink accepting a single email, returns its port and the received message
+    async fn smtp_sink() -> (u16, tokio::task::JoinHandle<String>) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let sink = tokio::spawn(async move {
+            let (stream, _) = listener.accept().await.unwrap();
+            let (reader, mut writer) = stream.into_split();
+            let mut lines = BufReader::new(reader).lines();
+            writer.write_all(b"220 sink ESMTP\r\n").await.unwrap();
+            let mut message = String::new();
+            let mut data = false;
+            while let Some(line) = lines.next_line().await.unwrap() {
+                if data {
+                    if line == "." {
+                        data = false;
+                        writer.write_all(b"250 queued\r\n").await.unwrap();
+                    } else {
+                        message.push_str(&line);
+                        message.push('\n');
+                    }
+                    continue;
+                }
+                let reply: &[u8] = match line.split(' ').next().unwrap().to_uppercase().as_str() {
+                    "EHLO" => b"250 sink\r\n",
+                    "DATA" => {
+                        data = true;
+                        b"354 go ahead\r\n"
+                    }
+                    "QUIT" => {
+                        writer.write_all(b"221 bye\r\n").await.unwrap();
+                        break;
+                    }
+                    _ => b"250 OK\r\n",
+                };
+                writer.write_all(reply).await.unwrap();
+            }
+            message
+        });
+        (port, sink)
+    }
+
+    #[actix_web::test]
+    async fn email_reaches_smtp_sink() {
+        let (port, sink) = smtp_sink().await;
+        let transport = smtp_transport(&SmtpConfig {
+            host: "127.0.0.1".to_owned(),
+            port,
+            tls: SmtpTls::None,
+            username: None,
+            password: String::new(),
+            from: "i40@localhost".to_owned(),
+        })
+        .unwrap();
+
+        send_email(
+            &transport,
+            "I40 Alarme <i40@localhost>",
+            "hugo@plant.local",
+            "Pressendruck hoch",
+            "Der Pressendruck liegt über 120 bar".to_owned(),
+        )
+        .await
+        .unwrap();
+
+        let message = sink.await.unwrap();
+        assert!(message.contains("To: hugo@plant.local"));
+        assert!(message.contains("Subject: Pressendruck hoch"));
+    }
+
+    #[test]
+    fn channels_need_config() {
+        let mut config = NotificationConfig::default();
+        let email = Channel::Email { address: None };
+        assert!(email.validate(&config).is_err());
+
+        config.smtp = Some(SmtpConfig {
+            host: "127.0.0.1".to_owned(),
+            port: 1025,
+            tls: SmtpTls::None,
+            username: None,
+            password: String::new(),
+            from: "i40@localhost".to_owned(),
+        });
+        assert_eq!(email.validate(&config), Ok(()));
+        let invalid = Channel::Email {
+            address: Some("hugo".to_owned()),
+        };
+        assert!(invalid.validate(&config).is_err());
+
+        let webhook = Channel::Webhook {
+            url: "ftp://plant.local/hook".to_owned(),
+        };
+        assert!(webhook.validate(&config).is_err());
+        let mqtt = Channel::Mqtt {
+            topic: "i40/alarms/#".to_owned(),
+        };
+        assert!(mqtt.validate(&config).is_err());
+    }
+}
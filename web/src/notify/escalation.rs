@@ -0,0 +1,639 @@
+//! # web::notify::escalation
+//!
+//! `web::notify::escalation` escalates raised alarms along escalation policies.
+//! A policy applies to the alarms of its stations from its minimum severity on, policies of
+//! some stations take precedence over policies of all stations. Its steps are notified in order,
+//! each `delay_minutes` after the alarm was raised, to a user or the users on call of a schedule
+//! at that time, see `common::schedule`. Every step is recorded in the alarm history.
+//!
+//! A escalation stops once the alarm is acknowledged or cleared and pauses while the alarm
+//! is shelved. Escalations are run by the `Notifier`.
+//!
+//! # Example
+//! The `presswerk` escalates from the line operator to the shift lead to maintenance
+//!
+//! ```text
+//! {"name": "Presswerk kritisch", "stations": ["presswerk"], "min_severity": 3, "steps": [
+//!   {"delay_minutes": 0, "target": {"kind": "schedule", "schedule": "linienfuehrer"}},
+//!   {"delay_minutes": 10, "target": {"kind": "schedule", "schedule": "schichtleitung"}},
+//!   {"delay_minutes": 30, "target": {"kind": "schedule", "schedule": "instandhaltung"},
+//!    "channel": {"kind": "webhook", "url": "https://chat.plant.local/hooks/instandhaltung"}}
+//! ]}
+//! ```
+
+use std::time::Instant;
+
+use chrono::{DateTime, TimeDelta, Utc};
+use common::{
+    alarm::{Alarm, AlarmHistory, AlarmState, HistoryAction, LocalAlarm},
+    schedule::Schedule,
+};
+use futures_util::future::join_all;
+use serde::{Deserialize, Serialize};
+use surrealdb::{
+    engine::remote::ws::Client,
+    sql::{Datetime, Thing},
+    Surreal,
+};
+
+use super::{channel::Channel, log_delivery, Notification, Notifier};
+
+/// upper bound of the delay of a step, a week
+pub const MAX_STEP_DELAY_MINUTES: u64 = 7 * 24 * 60;
+
+/// A escalation policy, see the module
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct EscalationPolicy {
+    pub id: Thing,
+    pub name: String,
+    /// the stations of the alarms, all stations if empty
+    #[serde(default)]
+    pub stations: Vec<Thing>,
+    #[serde(default)]
+    pub min_severity: u32,
+    pub steps: Vec<EscalationStep>,
+    pub enabled: bool,
+    pub created_at: Datetime,
+    pub created_by: Option<Thing>,
+}
+
+impl EscalationPolicy {
+    /// Returns true if the policy applies to the alarm
+    pub fn matches(&self, alarm: &LocalAlarm) -> bool {
+        self.enabled
+            && !self.steps.is_empty()
+            && alarm.severity >= self.min_severity
+            && (self.stations.is_empty()
+                || alarm
+                    .station
+                    .as_ref()
+                    .is_some_and(|station| self.stations.contains(station)))
+    }
+
+    /// the time the step is due for a alarm raised at the time,
+    /// None without the step or if the time is out of range
+    pub fn due_at(&self, step: usize, raised_at: DateTime<Utc>) -> Option<DateTime<Utc>> {
+        let minutes = i64::try_from(self.steps.get(step)?.delay_minutes).ok()?;
+        raised_at.checked_add_signed(TimeDelta::try_minutes(minutes)?)
+    }
+}
+
+/// A step of a escalation policy
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct EscalationStep {
+    /// the time after the alarm was raised
+    pub delay_minutes: u64,
+    pub target: EscalationTarget,
+    /// emails the users on call unless set
+    #[serde(default = "default_channel")]
+    pub channel: Channel,
+}
+
+fn default_channel() -> Channel {
+    Channel::Email { address: None }
+}
+
+/// Whom a step escalates to
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum EscalationTarget {
+    /// the users on call of the schedule
+    Schedule {
+        schedule: Thing,
+    },
+    User {
+        user: Thing,
+    },
+}
+
+/// The state of a escalation
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum EscalationState {
+    Running,
+    /// stopped before its last step, e.g. by the acknowledgement of the alarm
+    Stopped,
+    /// all steps were notified
+    Completed,
+}
+
+/// The escalation of a alarm along a policy
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Escalation {
+    pub id: Thing,
+    pub alarm: Thing,
+    pub policy: Thing,
+    pub policy_name: String,
+    pub state: EscalationState,
+    /// the time the alarm was raised, the delays of the steps start at
+    pub started_at: Datetime,
+    pub next_step: usize,
+    pub next_at: Option<Datetime>,
+    #[serde(default)]
+    pub runs: Vec<EscalationRun>,
+    pub stopped_at: Option<Datetime>,
+    pub stopped_by: Option<Thing>,
+    pub stop_reason: Option<String>,
+}
+
+/// A notified step of a escalation
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct EscalationRun {
+    pub step: usize,
+    pub at: Datetime,
+    /// the users on call, empty if nobody was
+    pub on_call: Vec<Thing>,
+    pub targets: Vec<String>,
+    /// the alarm history entry of the step, its deliveries reference it
+    pub event: Option<Thing>,
+}
+
+/// A user on call
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct OnCall {
+    pub id: Thing,
+    pub name: String,
+    pub email: Option<String>,
+}
+
+/// The step of a escalation in its notifications
+#[derive(Debug, Clone, Serialize)]
+pub struct Notice {
+    pub policy: String,
+    pub step: usize,
+    pub on_call: Vec<OnCall>,
+}
+
+impl Escalation {
+    /// Returns the escalations of a alarm, the first started first
+    pub async fn get_by_alarm(
+        db: &Surreal<Client>,
+        alarm: &Thing,
+    ) -> Result<Vec<Self>, surrealdb::Error> {
+        db.query("SELECT * FROM escalation WHERE alarm = $alarm ORDER BY started_at ASC")
+            .bind(("alarm", alarm))
+            .await?
+            .take(0)
+    }
+}
+
+/// Returns the policy escalating the alarm, the first matching policy of its station
+/// or else the first matching policy of all stations
+pub fn choose<'a>(
+    policies: &'a [EscalationPolicy],
+    alarm: &LocalAlarm,
+) -> Option<&'a EscalationPolicy> {
+    policies
+        .iter()
+        .filter(|policy| policy.matches(alarm))
+        .min_by_key(|policy| policy.stations.is_empty())
+}
+
+/// What a due escalation does next
+#[derive(Debug)]
+pub enum Decision<'a> {
+    /// notifies the step of the policy about the alarm
+    Notify {
+        alarm: &'a LocalAlarm,
+        policy: &'a EscalationPolicy,
+        step: &'a EscalationStep,
+    },
+    /// waits while the alarm is shelved
+    Pause,
+    /// all steps were notified
+    Complete,
+    Stop {
+        reason: &'static str,
+        by: Option<&'a Thing>,
+    },
+}
+
+/// Decides the next step of a escalation of the alarm along the policy at the time,
+/// the alarm or policy being None if they were deleted
+pub fn decide<'a>(
+    alarm: Option<&'a LocalAlarm>,
+    policy: Option<&'a EscalationPolicy>,
+    next_step: usize,
+    now: DateTime<Utc>,
+) -> Decision<'a> {
+    let alarm = match alarm {
+        None => {
+            return Decision::Stop {
+                reason: "alarm deleted",
+                by: None,
+            }
+        }
+        Some(alarm) if alarm.state == AlarmState::Acknowledged => {
+            return Decision::Stop {
+                reason: "acknowledged",
+                by: alarm.acknowledged_by.as_ref(),
+            }
+        }
+        Some(alarm) if alarm.state == AlarmState::Cleared => {
+            return Decision::Stop {
+                reason: "cleared",
+                by: None,
+            }
+        }
+        Some(alarm) => alarm,
+    };
+    if alarm
+        .shelved_until
+        .as_ref()
+        .is_some_and(|until| until.0 > now)
+    {
+        return Decision::Pause;
+    }
+    let Some(policy) = policy.filter(|policy| policy.enabled) else {
+        return Decision::Stop {
+            reason: "policy removed",
+            by: None,
+        };
+    };
+    match policy.steps.get(next_step) {
+        Some(step) => Decision::Notify {
+            alarm,
+            policy,
+            step,
+        },
+        None => Decision::Complete,
+    }
+}
+
+/// Stops the running escalations of a alarm, returns the stopped escalations
+pub async fn stop(
+    db: &Surreal<Client>,
+    alarm: &Thing,
+    reason: &str,
+    by: Option<&Thing>,
+) -> Result<Vec<Escalation>, surrealdb::Error> {
+    db.query("UPDATE escalation SET state = $stopped, next_at = NONE, stopped_at = time::now(), stopped_by = $by, stop_reason = $reason WHERE alarm = $alarm AND state = $running")
+        .bind(("stopped", EscalationState::Stopped))
+        .bind(("running", EscalationState::Running))
+        .bind(("by", by))
+        .bind(("reason", reason))
+        .bind(("alarm", alarm))
+        .await?
+        .take(0)
+}
+
+/// Returns the active users on call of the target at the time
+pub async fn on_call(
+    db: &Surreal<Client>,
+    target: &EscalationTarget,
+    at: DateTime<Utc>,
+) -> Result<Vec<OnCall>, surrealdb::Error> {
+    let users = match target {
+        EscalationTarget::User { user } => vec![user.clone()],
+        EscalationTarget::Schedule { schedule } => {
+            let schedule: Option<Schedule> = db.select(schedule.clone()).await?;
+            schedule.map_or_else(Vec::new, |schedule| schedule.on_call(at))
+        }
+    };
+    if users.is_empty() {
+        return Ok(Vec::new());
+    }
+    db.query("SELECT id, name, email FROM $users WHERE active != false")
+        .bind(("users", users))
+        .await?
+        .take(0)
+}
+
+impl Notifier {
+    /// starts the escalation of a raised alarm along the first matching policy
+    pub(super) async fn start_escalation(
+        &self,
+        db: &Surreal<Client>,
+        alarm: &LocalAlarm,
+    ) -> Result<(), surrealdb::Error> {
+        let policies: Vec<EscalationPolicy> = db
+            .query("SELECT * FROM escalation_policy WHERE enabled = true ORDER BY created_at ASC")
+            .await?
+            .take(0)?;
+        let Some(policy) = choose(&policies, alarm) else {
+            return Ok(());
+        };
+        db.query("CREATE escalation SET alarm = $alarm, policy = $policy, policy_name = $policy_name, state = $state, started_at = $started_at, next_step = 0, next_at = $next_at, runs = []")
+            .bind(("alarm", &alarm.id))
+            .bind(("policy", &policy.id))
+            .bind(("policy_name", &policy.name))
+            .bind(("state", EscalationState::Running))
+            .bind(("started_at", &alarm.raised_at))
+            .bind(("next_at", policy.due_at(0, alarm.raised_at.0).map(Datetime)))
+            .await?
+            .check()?;
+        Ok(())
+    }
+
+    /// Notifies the due steps of the running escalations, retrying webhooks until the deadline,
+    /// returns the number of steps
+    pub async fn escalate(
+        &self,
+        db: &Surreal<Client>,
+        deadline: Instant,
+    ) -> Result<usize, surrealdb::Error> {
+        let due: Vec<Escalation> = db
+            .query("SELECT * FROM escalation WHERE state = $running AND next_at <= time::now() ORDER BY next_at ASC")
+            .bind(("running", EscalationState::Running))
+            .await?
+            .take(0)?;
+        let mut notified = 0;
+        for escalation in due {
+            if self.advance(db, escalation, deadline).await? {
+                notified += 1;
+            }
+        }
+        Ok(notified)
+    }
+
+    /// notifies the next step of the escalation or stops it, returns true if a step was notified
+    async fn advance(
+        &self,
+        db: &Surreal<Client>,
+        escalation: Escalation,
+        deadline: Instant,
+    ) -> Result<bool, surrealdb::Error> {
+        let alarm: Option<LocalAlarm> = db.select(escalation.alarm.clone()).await?;
+        let policy: Option<EscalationPolicy> = db.select(escalation.policy.clone()).await?;
+        let now = Utc::now();
+        let (alarm, policy, step) =
+            match decide(alarm.as_ref(), policy.as_ref(), escalation.next_step, now) {
+                Decision::Notify {
+                    alarm,
+                    policy,
+                    step,
+                } => (alarm, policy, step),
+                Decision::Pause => return Ok(false),
+                Decision::Complete => {
+                    db.query("UPDATE $escalation SET state = $completed, next_at = NONE")
+                        .bind(("escalation", &escalation.id))
+                        .bind(("completed", EscalationState::Completed))
+                        .await?
+                        .check()?;
+                    return Ok(false);
+                }
+                Decision::Stop { reason, by } => {
+                    stop(db, &escalation.alarm, reason, by).await?;
+                    return Ok(false);
+                }
+            };
+
+        let on_call = on_call(db, &step.target, now).await?;
+        let mut targets = Vec::new();
+        let mut add = |target: Option<String>| {
+            if let Some(target) = target.filter(|target| !target.is_empty()) {
+                if !targets.contains(&target) {
+                    targets.push(target);
+                }
+            }
+        };
+        match &step.channel {
+            Channel::Email { address: None } => {
+                for user in &on_call {
+                    add(user.email.clone());
+                }
+            }
+            Channel::Email {
+                address: Some(address),
+            } => add(Some(address.clone())),
+            Channel::Webhook { url } => add(Some(url.clone())),
+            Channel::Mqtt { topic } => add(Some(topic.clone())),
+        }
+
+        let names: Vec<&str> = on_call.iter().map(|user| user.name.as_str()).collect();
+        let comment = match names.is_empty() {
+            true => format!(
+                "{}, step {}: nobody on call",
+                policy.name,
+                escalation.next_step + 1
+            ),
+            false => format!(
+                "{}, step {}: {}",
+                policy.name,
+                escalation.next_step + 1,
+                names.join(", ")
+            ),
+        };
+        let entry =
+            AlarmHistory::record(db, alarm, HistoryAction::Escalated, None, Some(&comment)).await?;
+
+        if let Some(entry) = &entry {
+            let notification = Notification {
+                event: HistoryAction::Escalated,
+                at: now,
+                alarm: Alarm::from(alarm.clone()),
+                escalation: Some(Notice {
+                    policy: policy.name.clone(),
+                    step: escalation.next_step,
+                    on_call: on_call.clone(),
+                }),
+            };
+            let outcomes = join_all(
+                targets
+                    .iter()
+                    .map(|target| self.send(&step.channel, target, &notification, deadline)),
+            )
+            .await;
+            for (target, outcome) in targets.iter().zip(outcomes) {
+                log_delivery(
+                    db,
+                    None,
+                    Some(&escalation.id),
+                    entry,
+                    &step.channel,
+                    target,
+                    outcome,
+                )
+                .await?;
+            }
+        }
+
+        let next_step = escalation.next_step + 1;
+        let next_at = policy.due_at(next_step, escalation.started_at.0);
+        let state = match next_at {
+            Some(_) => EscalationState::Running,
+            None => EscalationState::Completed,
+        };
+        let run = EscalationRun {
+            step: escalation.next_step,
+            at: Datetime(now),
+            on_call: on_call.into_iter().map(|user| user.id).collect(),
+            targets,
+            event: entry.map(|entry| entry.id),
+        };
+        db.query("UPDATE $escalation SET runs += $run, next_step = $next_step, next_at = $next_at, state = $state")
+            .bind(("escalation", &escalation.id))
+            .bind(("run", run))
+            .bind(("next_step", next_step))
+            .bind(("next_at", next_at.map(Datetime)))
+            .bind(("state", state))
+            .await?
+            .check()?;
+        Ok(true)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::{DateTime, TimeDelta, TimeZone, Utc};
+    use common::alarm::{AlarmState, LocalAlarm};
+    use surrealdb::sql::{Datetime, Thing};
+
+    use super::{choose, decide, Decision, EscalationPolicy, EscalationStep, EscalationTarget};
+    use crate::notify::channel::Channel;
+
+    fn raised_at() -> DateTime<Utc> {
+        Utc.with_ymd_and_hms(2023, 5, 2, 8, 0, 0).unwrap()
+    }
+
+    fn alarm(station: &str, state: AlarmState) -> LocalAlarm {
+        LocalAlarm {
+            id: Thing::from(("alarm", "a1")),
+            rule: None,
+            station: Some(Thing::from(("station", station))),
+            sensor: None,
+            severity: 3,
+            message: String::from("Druck zu hoch"),
+            state,
+            raised_at: Datetime(raised_at()),
+            cleared_at: None,
+            acknowledged_at: None,
+            acknowledged_by: None,
+            shelved_until: None,
+        }
+    }
+
+    fn policy(id: &str, stations: &[&str], delays: &[u64]) -> EscalationPolicy {
+        EscalationPolicy {
+            id: Thing::from(("escalation_policy", id)),
+            name: id.to_owned(),
+            stations: stations
+                .iter()
+                .map(|station| Thing::from(("station", *station)))
+                .collect(),
+            min_severity: 0,
+            steps: delays
+                .iter()
+                .map(|delay_minutes| EscalationStep {
+                    delay_minutes: *delay_minutes,
+                    target: EscalationTarget::User {
+                        user: Thing::from(("user", "anna")),
+                    },
+                    channel: Channel::Email { address: None },
+                })
+                .collect(),
+            enabled: true,
+            created_at: Datetime::default(),
+            created_by: None,
+        }
+    }
+
+    #[test]
+    fn policies_of_the_station_precede_policies_of_all_stations() {
+        let policies = vec![
+            policy("all", &[], &[0]),
+            policy("lackiererei", &["lackiererei"], &[0]),
+            policy("presswerk", &["presswerk"], &[0]),
+        ];
+        let alarm = alarm("presswerk", AlarmState::Active);
+        assert_eq!(choose(&policies, &alarm).unwrap().name, "presswerk");
+
+        let policies = vec![
+            policy("all", &[], &[0]),
+            policy("lackiererei", &["lackiererei"], &[0]),
+        ];
+        assert_eq!(choose(&policies, &alarm).unwrap().name, "all");
+
+        let mut disabled = policy("presswerk", &["presswerk"], &[0]);
+        disabled.enabled = false;
+        assert!(choose(&[disabled], &alarm).is_none());
+    }
+
+    #[test]
+    fn steps_are_due_after_their_delay() {
+        let policy = policy("presswerk", &[], &[0, 10, 30]);
+        assert_eq!(policy.due_at(0, raised_at()), Some(raised_at()));
+        assert_eq!(
+            policy.due_at(2, raised_at()),
+            Some(raised_at() + TimeDelta::minutes(30))
+        );
+        assert_eq!(policy.due_at(3, raised_at()), None);
+
+        let policy = EscalationPolicy {
+            steps: vec![EscalationStep {
+                delay_minutes: u64::MAX,
+                ..policy.steps[0].clone()
+            }],
+            ..policy
+        };
+        assert_eq!(policy.due_at(0, raised_at()), None);
+    }
+
+    #[test]
+    fn escalations_stop_once_the_alarm_is_acknowledged_or_cleared() {
+        let policy = policy("presswerk", &[], &[0, 10]);
+        let mut acknowledged = alarm("presswerk", AlarmState::Acknowledged);
+        acknowledged.acknowledged_by = Some(Thing::from(("user", "hugo")));
+        assert!(matches!(
+            decide(Some(&acknowledged), Some(&policy), 1, raised_at()),
+            Decision::Stop { reason: "acknowledged", by: Some(by) } if by.id.to_raw() == "hugo"
+        ));
+
+        let cleared = alarm("presswerk", AlarmState::Cleared);
+        assert!(matches!(
+            decide(Some(&cleared), Some(&policy), 1, raised_at()),
+            Decision::Stop {
+                reason: "cleared",
+                by: None
+            }
+        ));
+        assert!(matches!(
+            decide(None, Some(&policy), 1, raised_at()),
+            Decision::Stop {
+                reason: "alarm deleted",
+                ..
+            }
+        ));
+    }
+
+    #[test]
+    fn escalations_pause_while_the_alarm_is_shelved() {
+        let policy = policy("presswerk", &[], &[0, 10]);
+        let mut alarm = alarm("presswerk", AlarmState::Active);
+        alarm.shelved_until = Some(Datetime(raised_at() + TimeDelta::hours(1)));
+        assert!(matches!(
+            decide(Some(&alarm), Some(&policy), 1, raised_at()),
+            Decision::Pause
+        ));
+        assert!(matches!(
+            decide(
+                Some(&alarm),
+                Some(&policy),
+                1,
+                raised_at() + TimeDelta::hours(2)
+            ),
+            Decision::Notify { step, .. } if step.delay_minutes == 10
+        ));
+    }
+
+    #[test]
+    fn escalations_complete_after_the_last_step() {
+        let policy = policy("presswerk", &[], &[0, 10]);
+        let alarm = alarm("presswerk", AlarmState::Active);
+        assert!(matches!(
+            decide(Some(&alarm), Some(&policy), 1, raised_at()),
+            Decision::Notify { .. }
+        ));
+        assert!(matches!(
+            decide(Some(&alarm), Some(&policy), 2, raised_at()),
+            Decision::Complete
+        ));
+        assert!(matches!(
+            decide(Some(&alarm), None, 1, raised_at()),
+            Decision::Stop {
+                reason: "policy removed",
+                ..
+            }
+        ));
+    }
+}
@@ -0,0 +1,496 @@
+//! # web::notify
+//!
+//! `web::notify` notifies the subscribers of local alarms when they are raised or cleared.
+//! The raised and cleared entries of the alarm history, see `common::alarm`, serve as outbox:
+//! the notifier claims the entries without `notified_at` by setting it before sending them,
+//! so alarms raised by any service, e.g. the `mqtt` service, are notified at most once.
+//! Alarms cleared while shelved are not notified.
+//!
+//! A subscription belongs to a user or to every user of a role and filters by the stations
+//! and the minimum severity of the alarms. Subscribers are only notified of the alarms of
+//! stations they may view, role subscriptions of stations without grants.
+//! Email subscriptions without address mail the users, role subscriptions every active user
+//! of the role. Every notification is logged as a `Delivery`.
+//!
+//! Raised alarms are escalated along a escalation policy as well, see `escalation`.
+//!
+//! # Example
+//!
+//! ```text
+//! notifications:
+//!   enabled: true
+//!   smtp: { host: "127.0.0.1", port: 1025, tls: none, from: "i40@localhost" }
+//! ```
+
+pub mod channel;
+pub mod escalation;
+
+use std::time::{Duration, Instant};
+
+use chrono::{DateTime, Utc};
+use common::alarm::{Alarm, AlarmHistory, HistoryAction, LocalAlarm};
+use futures_util::future::{join, join_all};
+use lettre::{AsyncSmtpTransport, Tokio1Executor};
+use rumqttc::{AsyncClient, EventLoop};
+use serde::{Deserialize, Serialize};
+use surrealdb::{
+    engine::remote::ws::Client,
+    sql::{Datetime, Thing},
+    Surreal,
+};
+use tokio::time::{interval, MissedTickBehavior};
+
+use crate::{
+    app::acl::{Access, StationPermission},
+    auth::Role,
+    config::NotificationConfig,
+};
+
+use self::channel::{Channel, Outcome};
+
+/// the maximum number of alarm history entries sent per interval
+const BATCH_SIZE: usize = 100;
+
+/// Errors creating the notifier
+#[derive(Debug)]
+pub enum NotifyError {
+    Http(reqwest::Error),
+    Smtp(lettre::transport::smtp::Error),
+}
+
+impl std::fmt::Display for NotifyError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            NotifyError::Http(err) => write!(f, "webhook client: {err}"),
+            NotifyError::Smtp(err) => write!(f, "SMTP transport: {err}"),
+        }
+    }
+}
+
+impl std::error::Error for NotifyError {}
+
+impl From<reqwest::Error> for NotifyError {
+    fn from(err: reqwest::Error) -> Self {
+        NotifyError::Http(err)
+    }
+}
+
+impl From<lettre::transport::smtp::Error> for NotifyError {
+    fn from(err: lettre::transport::smtp::Error) -> Self {
+        NotifyError::Smtp(err)
+    }
+}
+
+/// A subscription of a user or of every user of a role to alarms
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Subscription {
+    pub id: Thing,
+    pub user: Option<Thing>,
+    pub role: Option<Role>,
+    pub channel: Channel,
+    /// the stations of the alarms, all stations if empty
+    #[serde(default)]
+    pub stations: Vec<Thing>,
+    #[serde(default)]
+    pub min_severity: u32,
+    pub enabled: bool,
+    pub created_at: Datetime,
+    pub created_by: Option<Thing>,
+}
+
+impl Subscription {
+    /// Returns true if the alarm is of the stations and severity of the subscription
+    pub fn matches(&self, alarm: &LocalAlarm) -> bool {
+        self.enabled
+            && alarm.severity >= self.min_severity
+            && (self.stations.is_empty()
+                || alarm
+                    .station
+                    .as_ref()
+                    .is_some_and(|station| self.stations.contains(station)))
+    }
+}
+
+/// Whether a notification reached its target
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum DeliveryStatus {
+    Delivered,
+    Failed,
+}
+
+/// A entry of the delivery log, sent for a subscription or a escalation
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Delivery {
+    pub id: Thing,
+    pub subscription: Option<Thing>,
+    #[serde(default)]
+    pub escalation: Option<Thing>,
+    pub alarm: Thing,
+    /// the alarm history entry which was notified
+    pub event: Thing,
+    pub channel: String,
+    pub target: String,
+    pub status: DeliveryStatus,
+    pub attempts: u32,
+    pub error: Option<String>,
+    pub created_at: Datetime,
+}
+
+/// The payload of webhooks and MQTT messages
+#[derive(Debug, Serialize)]
+pub struct Notification {
+    pub event: HistoryAction,
+    pub at: DateTime<Utc>,
+    pub alarm: Alarm,
+    /// the step of the escalation policy of escalated alarms
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub escalation: Option<escalation::Notice>,
+}
+
+impl Notification {
+    /// the subject of the email
+    fn subject(&self) -> String {
+        let event = match self.event {
+            HistoryAction::Cleared => "Cleared",
+            HistoryAction::Escalated => "Escalated",
+            _ => "Raised",
+        };
+        match &self.alarm.station {
+            Some(station) => format!("[{event}] {station}: {}", self.alarm.message),
+            None => format!("[{event}] {}", self.alarm.message),
+        }
+    }
+
+    /// the text of the email
+    fn body(&self) -> String {
+        let alarm = &self.alarm;
+        let mut body = format!(
+            "{}\n\nSeverity: {}\nStation: {}\nSensor: {}\nRaised: {}\n",
+            alarm.message,
+            alarm.severity,
+            alarm.station.as_deref().unwrap_or("-"),
+            alarm.sensor.as_deref().unwrap_or("-"),
+            alarm.raised_at.to_rfc3339(),
+        );
+        if let Some(cleared_at) = alarm.cleared_at {
+            body.push_str(&format!("Cleared: {}\n", cleared_at.to_rfc3339()));
+        }
+        body.push_str(&format!("Alarm: {}\n", alarm.id));
+        if let Some(notice) = &self.escalation {
+            let on_call: Vec<&str> = notice
+                .on_call
+                .iter()
+                .map(|user| user.name.as_str())
+                .collect();
+            body.push_str(&format!(
+                "\nEscalation: step {} of {}\nOn call: {}\n",
+                notice.step + 1,
+                notice.policy,
+                on_call.join(", "),
+            ));
+        }
+        body
+    }
+}
+
+/// A user notified by a subscription, role subscriptions to a webhook or MQTT topic
+/// are sent for the role without user
+#[derive(Debug, Deserialize)]
+struct Recipient {
+    id: Option<Thing>,
+    email: Option<String>,
+    #[serde(default)]
+    role: Role,
+    active: Option<bool>,
+}
+
+/// Sends the notifications of raised and cleared alarms
+pub struct Notifier {
+    config: NotificationConfig,
+    http: reqwest::Client,
+    smtp: Option<AsyncSmtpTransport<Tokio1Executor>>,
+    mqtt: Option<AsyncClient>,
+    /// the connection of the MQTT client, driven once the notifier runs
+    eventloop: Option<EventLoop>,
+}
+
+impl Notifier {
+    pub fn new(config: NotificationConfig) -> Result<Self, NotifyError> {
+        let http = reqwest::Client::builder().build()?;
+        let smtp = config
+            .smtp
+            .as_ref()
+            .map(channel::smtp_transport)
+            .transpose()?;
+        let (mqtt, eventloop) = match config.mqtt.as_ref().map(channel::mqtt_client) {
+            Some((client, eventloop)) => (Some(client), Some(eventloop)),
+            None => (None, None),
+        };
+        Ok(Notifier {
+            config,
+            http,
+            smtp,
+            mqtt,
+            eventloop,
+        })
+    }
+
+    /// Sends the notifications at the configured interval until the server stops
+    pub async fn run(mut self, db: Surreal<Client>) {
+        if let Some(eventloop) = self.eventloop.take() {
+            actix_web::rt::spawn(channel::drive(eventloop));
+        }
+        let mut ticks = interval(Duration::from_secs(self.config.interval_seconds.max(1)));
+        ticks.set_missed_tick_behavior(MissedTickBehavior::Delay);
+        loop {
+            ticks.tick().await;
+            let deadline =
+                Instant::now() + Duration::from_secs(self.config.webhook.max_retry_seconds);
+            let (dispatched, escalated) =
+                join(self.dispatch(&db, deadline), self.escalate(&db, deadline)).await;
+            if let Err(err) = dispatched {
+                eprintln!("alarm notifications failed: {err}");
+            }
+            if let Err(err) = escalated {
+                eprintln!("alarm escalations failed: {err}");
+            }
+        }
+    }
+
+    /// Sends the notifications of the alarm history entries not yet notified,
+    /// retrying webhooks until the deadline, returns the number of entries
+    pub async fn dispatch(
+        &self,
+        db: &Surreal<Client>,
+        deadline: Instant,
+    ) -> Result<usize, surrealdb::Error> {
+        let pending: Vec<AlarmHistory> = db
+            .query("SELECT * FROM alarm_history WHERE notified_at = NONE AND action INSIDE $actions ORDER BY created_at ASC LIMIT $limit")
+            .bind(("actions", [HistoryAction::Raised, HistoryAction::Cleared]))
+            .bind(("limit", BATCH_SIZE))
+            .await?
+            .take(0)?;
+        if pending.is_empty() {
+            return Ok(0);
+        }
+        let subscriptions: Vec<Subscription> = db
+            .query("SELECT * FROM notification_subscription WHERE enabled = true")
+            .await?
+            .take(0)?;
+
+        let mut dispatched = 0;
+        for entry in &pending {
+            // a entry is claimed before sending, a failure afterwards must not send it again
+            let claimed: Option<AlarmHistory> = db
+                .query("UPDATE $entry SET notified_at = time::now() WHERE notified_at = NONE")
+                .bind(("entry", &entry.id))
+                .await?
+                .take(0)?;
+            if claimed.is_none() {
+                continue;
+            }
+            dispatched += 1;
+            let sent = async {
+                let alarm: Option<LocalAlarm> = db.select(entry.alarm.clone()).await?;
+                let Some(alarm) = alarm else {
+                    return Ok(());
+                };
+                if entry.action == HistoryAction::Raised {
+                    self.start_escalation(db, &alarm).await?;
+                }
+                let shelved = alarm
+                    .shelved_until
+                    .as_ref()
+                    .is_some_and(|until| until.0 > Utc::now());
+                match shelved {
+                    true => Ok(()),
+                    false => {
+                        self.notify(db, &subscriptions, entry, alarm, deadline)
+                            .await
+                    }
+                }
+            }
+            .await;
+            if let Err(err) = sent {
+                eprintln!("notification of {} failed: {err}", entry.id);
+            }
+        }
+        Ok(dispatched)
+    }
+
+    /// sends the entry to the targets of all matching subscriptions and logs the deliveries
+    async fn notify(
+        &self,
+        db: &Surreal<Client>,
+        subscriptions: &[Subscription],
+        entry: &AlarmHistory,
+        alarm: LocalAlarm,
+        deadline: Instant,
+    ) -> Result<(), surrealdb::Error> {
+        let mut deliveries = Vec::new();
+        for subscription in subscriptions
+            .iter()
+            .filter(|subscription| subscription.matches(&alarm))
+        {
+            for target in self.targets(db, subscription, &alarm).await? {
+                deliveries.push((subscription, target));
+            }
+        }
+        if deliveries.is_empty() {
+            return Ok(());
+        }
+
+        let notification = Notification {
+            event: entry.action,
+            at: entry.created_at.0,
+            alarm: Alarm::from(alarm.clone()),
+            escalation: None,
+        };
+        let outcomes = join_all(deliveries.iter().map(|(subscription, target)| {
+            self.send(&subscription.channel, target, &notification, deadline)
+        }))
+        .await;
+
+        for ((subscription, target), outcome) in deliveries.iter().zip(outcomes) {
+            log_delivery(
+                db,
+                Some(&subscription.id),
+                None,
+                entry,
+                &subscription.channel,
+                target,
+                outcome,
+            )
+            .await?;
+        }
+        Ok(())
+    }
+
+    /// the targets of the subscription permitted to view the alarm, without duplicates
+    async fn targets(
+        &self,
+        db: &Surreal<Client>,
+        subscription: &Subscription,
+        alarm: &LocalAlarm,
+    ) -> Result<Vec<String>, surrealdb::Error> {
+        let recipients: Vec<Recipient> = match (&subscription.user, subscription.role) {
+            (Some(user), _) => {
+                db.query("SELECT id, email, role, active FROM $user")
+                    .bind(("user", user))
+                    .await?
+                    .take(0)?
+            }
+            (None, Some(role)) if subscription.channel == (Channel::Email { address: None }) => {
+                db.query("SELECT id, email, role, active FROM user WHERE role = $role AND active != false")
+                    .bind(("role", role))
+                    .await?
+                    .take(0)?
+            }
+            (None, Some(role)) => vec![Recipient {
+                id: None,
+                email: None,
+                role,
+                active: None,
+            }],
+            (None, None) => Vec::new(),
+        };
+
+        let mut targets = Vec::new();
+        for recipient in recipients {
+            if recipient.active == Some(false) {
+                continue;
+            }
+            if let Some(station) = &alarm.station {
+                let access = Access::load_for(db, recipient.id, recipient.role).await?;
+                if !access.permits(station, StationPermission::View) {
+                    continue;
+                }
+            }
+            let target = match &subscription.channel {
+                Channel::Email {
+                    address: Some(address),
+                } => Some(address.clone()),
+                Channel::Email { address: None } => {
+                    recipient.email.filter(|email| !email.is_empty())
+                }
+                Channel::Webhook { url } => Some(url.clone()),
+                Channel::Mqtt { topic } => Some(topic.clone()),
+            };
+            if let Some(target) = target {
+                if !targets.contains(&target) {
+                    targets.push(target);
+                }
+            }
+        }
+        Ok(targets)
+    }
+
+    /// sends the notification through the channel to the target
+    async fn send(
+        &self,
+        channel: &Channel,
+        target: &str,
+        notification: &Notification,
+        deadline: Instant,
+    ) -> Outcome {
+        match channel {
+            Channel::Webhook { .. } => {
+                channel::send_webhook(
+                    &self.http,
+                    &self.config.webhook,
+                    target,
+                    notification,
+                    deadline,
+                )
+                .await
+            }
+            Channel::Email { .. } => match (&self.smtp, &self.config.smtp) {
+                (Some(transport), Some(smtp)) => Outcome::once(
+                    channel::send_email(
+                        transport,
+                        &smtp.from,
+                        target,
+                        &notification.subject(),
+                        notification.body(),
+                    )
+                    .await,
+                ),
+                _ => Outcome::once(Err("Email notifications are not configured".to_owned())),
+            },
+            Channel::Mqtt { .. } => match &self.mqtt {
+                Some(client) => Outcome::once(channel::publish(client, target, notification).await),
+                None => Outcome::once(Err("MQTT notifications are not configured".to_owned())),
+            },
+        }
+    }
+}
+
+/// adds the outcome of a notification of the alarm history entry to the delivery log
+async fn log_delivery(
+    db: &Surreal<Client>,
+    subscription: Option<&Thing>,
+    escalation: Option<&Thing>,
+    entry: &AlarmHistory,
+    channel: &Channel,
+    target: &str,
+    outcome: Outcome,
+) -> Result<(), surrealdb::Error> {
+    let (status, error) = match outcome.result {
+        Ok(()) => (DeliveryStatus::Delivered, None),
+        Err(err) => (DeliveryStatus::Failed, Some(err)),
+    };
+    db.query("CREATE notification_delivery SET subscription = $subscription, escalation = $escalation, alarm = $alarm, event = $event, channel = $channel, target = $target, status = $status, attempts = $attempts, error = $error, created_at = time::now()")
+        .bind(("subscription", subscription))
+        .bind(("escalation", escalation))
+        .bind(("alarm", &entry.alarm))
+        .bind(("event", &entry.id))
+        .bind(("channel", channel.kind()))
+        .bind(("target", target))
+        .bind(("status", status))
+        .bind(("attempts", outcome.attempts))
+        .bind(("error", error))
+        .await?
+        .check()?;
+    Ok(())
+}
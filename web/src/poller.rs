@@ -0,0 +1,328 @@
+//! # web::poller
+//!
+//! `web::poller` imports the measurements of the MHubX into the database, so their history
+//! can be charted next to the MQTT sensors. Every system becomes a station with the id of
+//! the system, every measurement a sensor with the id `{system_id}_{msm_id}`.
+//! Missing stations and sensors are created, archived sensors are skipped.
+//! Values are stored at their source timestamp, measurements without one are skipped.
+//! As the record id of a value consists of the sensor and the time, a value polled twice,
+//! e.g. after a restart, is only stored once. The alarm rules of the sensors are evaluated
+//! on every stored value.
+//!
+//! # Example
+//!
+//! ```text
+//! mhubx_poller:
+//!   enabled: true
+//!   interval_seconds: 60
+//!   systems: ["cps1"]
+//! ```
+
+use std::collections::{HashMap, HashSet};
+
+use chrono::{DateTime, Utc};
+use common::{
+    mhubx::{sensor_id, Measurement, MeasurementQuery, MhubxClient, MhubxError},
+    Sensor, SensorValue, Station,
+};
+use surrealdb::{engine::remote::ws::Client, sql::Thing, Surreal};
+use tokio::time::{interval, MissedTickBehavior};
+
+use crate::config::MhubxPollerConfig;
+
+/// Errors of a single poll
+#[derive(Debug)]
+pub enum PollError {
+    Mhubx(MhubxError),
+    Database(surrealdb::Error),
+}
+
+impl std::fmt::Display for PollError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            PollError::Mhubx(err) => write!(f, "{err}"),
+            PollError::Database(err) => write!(f, "database error: {err}"),
+        }
+    }
+}
+
+impl std::error::Error for PollError {}
+
+impl From<MhubxError> for PollError {
+    fn from(err: MhubxError) -> Self {
+        PollError::Mhubx(err)
+    }
+}
+
+impl From<surrealdb::Error> for PollError {
+    fn from(err: surrealdb::Error) -> Self {
+        PollError::Database(err)
+    }
+}
+
+/// Summary of a single poll
+#[derive(Debug, Default)]
+pub struct PollReport {
+    pub imported: usize,
+    pub duplicates: usize,
+    /// measurements without value or timestamp and of archived sensors
+    pub skipped: usize,
+    pub created_stations: Vec<String>,
+    pub created_sensors: Vec<String>,
+}
+
+/// A measurement mapped onto its station and sensor
+#[derive(Debug, PartialEq)]
+pub struct Polled {
+    pub station: String,
+    pub sensor: String,
+    pub display_name: String,
+    pub value: String,
+    pub timestamp: DateTime<Utc>,
+}
+
+impl Polled {
+    /// Maps the measurement onto the station of its system and its sensor,
+    /// None without value or timestamp
+    pub fn from_measurement(measurement: &Measurement) -> Option<Self> {
+        let (Some(value), Some(timestamp)) = (&measurement.value, measurement.timestamp) else {
+            return None;
+        };
+        Some(Polled {
+            station: measurement.system_id.clone(),
+            sensor: sensor_id(&measurement.system_id, &measurement.msm_id),
+            display_name: measurement
+                .name
+                .clone()
+                .unwrap_or_else(|| measurement.msm_id.clone()),
+            value: value.to_string(),
+            timestamp,
+        })
+    }
+}
+
+/// What the poller does with a polled value
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Decision {
+    /// the sensor is archived
+    Skip,
+    /// the value was stored by a previous poll
+    Duplicate,
+    /// stores the value, unless the database already has it
+    Store,
+}
+
+/// Decides about a value of the sensor at the timestamp, given the timestamp of the last value
+/// stored by the poller
+pub fn decide(
+    archived: bool,
+    latest: Option<&DateTime<Utc>>,
+    timestamp: DateTime<Utc>,
+) -> Decision {
+    if archived {
+        Decision::Skip
+    } else if latest == Some(&timestamp) {
+        Decision::Duplicate
+    } else {
+        Decision::Store
+    }
+}
+
+/// Imports the measurements of the MHubX, remembering the last stored value of each sensor
+pub struct Poller {
+    mhubx: MhubxClient,
+    config: MhubxPollerConfig,
+    /// the timestamp of the last value stored per sensor, to skip unchanged measurements
+    /// without asking the database
+    latest: HashMap<Thing, DateTime<Utc>>,
+}
+
+impl Poller {
+    pub fn new(mhubx: MhubxClient, config: MhubxPollerConfig) -> Self {
+        Poller {
+            mhubx,
+            config,
+            latest: HashMap::new(),
+        }
+    }
+
+    /// Polls at the configured interval until the server stops, failed polls are logged
+    pub async fn run(mut self, db: Surreal<Client>) {
+        let mut ticks = interval(self.config.interval());
+        ticks.set_missed_tick_behavior(MissedTickBehavior::Delay);
+        loop {
+            ticks.tick().await;
+            match self.poll(&db).await {
+                Ok(report) => {
+                    if !report.created_stations.is_empty() || !report.created_sensors.is_empty() {
+                        println!(
+                            "MHubX import created the stations {:?} and sensors {:?}",
+                            report.created_stations, report.created_sensors
+                        );
+                    }
+                }
+                Err(err) => eprintln!("MHubX import failed: {err}"),
+            }
+        }
+    }
+
+    /// Imports the current measurements of the configured systems
+    pub async fn poll(&mut self, db: &Surreal<Client>) -> Result<PollReport, PollError> {
+        let queries = if self.config.systems.is_empty() {
+            vec![MeasurementQuery::default()]
+        } else {
+            self.config
+                .systems
+                .iter()
+                .map(|system| MeasurementQuery::system(system))
+                .collect()
+        };
+        let mut measurements = Vec::new();
+        for query in queries {
+            measurements.extend(self.mhubx.measurements(&query).await?);
+        }
+
+        let mut report = PollReport::default();
+        let mut stations = HashSet::new();
+        for measurement in measurements {
+            let Some(polled) = Polled::from_measurement(&measurement) else {
+                report.skipped += 1;
+                continue;
+            };
+            if !stations.contains(&polled.station) {
+                ensure_station(db, &polled.station, &mut report).await?;
+                stations.insert(polled.station.clone());
+            }
+            let Some(sensor) = ensure_sensor(db, &polled, &mut report).await? else {
+                report.skipped += 1;
+                continue;
+            };
+            let id = sensor.get_id().clone();
+
+            match decide(sensor.is_archived(), self.latest.get(&id), polled.timestamp) {
+                Decision::Skip => {
+                    report.skipped += 1;
+                    continue;
+                }
+                Decision::Duplicate => {
+                    report.duplicates += 1;
+                    continue;
+                }
+                Decision::Store => {}
+            }
+            let value =
+                SensorValue::with_source_timestamp(polled.value, id.clone(), polled.timestamp);
+            if value.exists(db).await? {
+                report.duplicates += 1;
+            } else if let Some(value) = value.save(db).await? {
+                if let Err(err) = common::rules::evaluate(db, &value).await {
+                    eprintln!("evaluating the alarm rules failed: {err}");
+                }
+                report.imported += 1;
+            }
+            self.latest.insert(id, polled.timestamp);
+        }
+        Ok(report)
+    }
+}
+
+/// creates the station of a system, if it doesn't exist yet
+async fn ensure_station(
+    db: &Surreal<Client>,
+    system_id: &str,
+    report: &mut PollReport,
+) -> Result<(), surrealdb::Error> {
+    if Station::get(db, system_id.to_owned()).await?.is_none() {
+        Station::new(system_id.to_owned()).create(db).await?;
+        report.created_stations.push(system_id.to_owned());
+    }
+    Ok(())
+}
+
+/// returns the sensor of a polled measurement, creating it if it doesn't exist yet.
+/// Returns None if the sensor could not be created
+async fn ensure_sensor(
+    db: &Surreal<Client>,
+    polled: &Polled,
+    report: &mut PollReport,
+) -> Result<Option<Sensor>, surrealdb::Error> {
+    if let Some(sensor) = Sensor::get(db, polled.sensor.clone()).await? {
+        return Ok(Some(sensor));
+    }
+    let sensor = Sensor::new(
+        polled.sensor.clone(),
+        Thing::from(("station", polled.station.as_str())),
+    )
+    .with_display_name(polled.display_name.clone())
+    .save(db)
+    .await?;
+    report.created_sensors.push(polled.sensor.clone());
+    Ok(sensor)
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::{DateTime, TimeZone, Utc};
+    use common::mhubx::Measurement;
+    use serde_json::json;
+
+    use super::{decide, Decision, Polled};
+
+    fn timestamp() -> DateTime<Utc> {
+        Utc.with_ymd_and_hms(2023, 5, 1, 12, 0, 0).unwrap()
+    }
+
+    fn measurement(value: serde_json::Value) -> Measurement {
+        serde_json::from_value(value).unwrap()
+    }
+
+    #[test]
+    fn maps_systems_to_stations_and_measurements_to_sensors() {
+        let polled = Polled::from_measurement(&measurement(json!({
+            "system_id": "cps1", "msm_id": "presse/druck", "name": "Pressendruck",
+            "value": 118.2, "unit": "bar", "timestamp": "2023-05-01T12:00:00Z"
+        })));
+        assert_eq!(
+            polled,
+            Some(Polled {
+                station: "cps1".to_owned(),
+                sensor: "cps1_presse_druck".to_owned(),
+                display_name: "Pressendruck".to_owned(),
+                value: "118.2".to_owned(),
+                timestamp: timestamp(),
+            })
+        );
+
+        let polled = Polled::from_measurement(&measurement(json!({
+            "system_id": "cps2", "msm_id": "foerderband_modus",
+            "value": "automatik", "timestamp": "2023-05-01T12:00:00Z"
+        })))
+        .unwrap();
+        assert_eq!(polled.display_name, "foerderband_modus");
+        assert_eq!(polled.value, "automatik");
+    }
+
+    #[test]
+    fn skips_measurements_without_value_or_timestamp() {
+        for measurement in [
+            measurement(
+                json!({"system_id": "cps1", "msm_id": "druck", "timestamp": "2023-05-01T12:00:00Z"}),
+            ),
+            measurement(json!({"system_id": "cps1", "msm_id": "druck", "value": 1})),
+        ] {
+            assert_eq!(Polled::from_measurement(&measurement), None);
+        }
+    }
+
+    #[test]
+    fn stores_new_values_of_active_sensors_once() {
+        assert_eq!(decide(false, None, timestamp()), Decision::Store);
+        assert_eq!(
+            decide(false, Some(&timestamp()), timestamp()),
+            Decision::Duplicate
+        );
+        let later = timestamp() + chrono::TimeDelta::minutes(1);
+        assert_eq!(decide(false, Some(&timestamp()), later), Decision::Store);
+        assert_eq!(decide(true, None, later), Decision::Skip);
+    }
+}
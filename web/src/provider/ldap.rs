@@ -0,0 +1,356 @@
+//! # web::provider::ldap
+//!
+//! `web::provider::ldap` verifies users against a LDAP directory, e.g. the Active Directory
+//! of the plant. The user is searched by email with the service account and verified by a
+//! bind with the entered password. The groups of the entry are mapped to a role.
+
+use futures_util::future::LocalBoxFuture;
+use ldap3::{ldap_escape, LdapConnAsync, LdapConnSettings, LdapError, Scope, SearchEntry};
+use surrealdb::{engine::remote::ws::Client, Surreal};
+
+use super::{Identity, PasswordProvider};
+use crate::config::LdapConfig;
+
+/// the provider name of LDAP users
+pub const LDAP: &str = "ldap";
+
+/// the result code of a bind with wrong credentials
+const INVALID_CREDENTIALS: u32 = 49;
+
+/// A LDAP directory
+pub struct LdapProvider {
+    url: String,
+    bind_dn: Option<String>,
+    bind_password: String,
+    base_dn: String,
+    user_filter: String,
+    email_attribute: String,
+    name_attribute: String,
+    group_attribute: String,
+    timeout: std::time::Duration,
+}
+
+impl LdapProvider {
+    pub fn new(config: &LdapConfig) -> Self {
+        LdapProvider {
+            url: config.url.clone(),
+            bind_dn: config.bind_dn.clone(),
+            bind_password: config.bind_password.clone(),
+            base_dn: config.base_dn.clone(),
+            user_filter: config.user_filter.clone(),
+            email_attribute: config.email_attribute.clone(),
+            name_attribute: config.name_attribute.clone(),
+            group_attribute: config.group_attribute.clone(),
+            timeout: config.timeout(),
+        }
+    }
+
+    /// searches the user and binds as it, returns None for unknown users and wrong passwords
+    async fn verify(&self, email: &str, password: &str) -> Result<Option<Identity>, LdapError> {
+        let settings = LdapConnSettings::new().set_conn_timeout(self.timeout);
+        let (conn, mut ldap) = LdapConnAsync::with_settings(settings, &self.url).await?;
+        ldap3::drive!(conn);
+
+        if let Some(bind_dn) = &self.bind_dn {
+            ldap.with_timeout(self.timeout)
+                .simple_bind(bind_dn, &self.bind_password)
+                .await?
+                .success()?;
+        }
+        let filter = self.user_filter.replace("{email}", &ldap_escape(email));
+        let attributes = [
+            self.email_attribute.as_str(),
+            self.name_attribute.as_str(),
+            self.group_attribute.as_str(),
+        ];
+        let (entries, _) = ldap
+            .with_timeout(self.timeout)
+            .search(&self.base_dn, Scope::Subtree, &filter, attributes.to_vec())
+            .await?
+            .success()?;
+        // a ambiguous filter must not sign in as any of the entries
+        let [entry] = entries.as_slice() else {
+            let _ = ldap.unbind().await;
+            return Ok(None);
+        };
+        let entry = SearchEntry::construct(entry.clone());
+
+        let bind = ldap
+            .with_timeout(self.timeout)
+            .simple_bind(&entry.dn, password)
+            .await?;
+        let _ = ldap.unbind().await;
+        if bind.rc == INVALID_CREDENTIALS {
+            return Ok(None);
+        }
+        bind.success()?;
+
+        let first = |attribute: &str| {
+            entry
+                .attrs
+                .get(attribute)
+                .and_then(|values| values.first())
+                .cloned()
+        };
+        let email = first(&self.email_attribute)
+            .unwrap_or_else(|| email.to_owned())
+            .to_lowercase();
+        Ok(Some(Identity {
+            provider: LDAP,
+            name: first(&self.name_attribute).unwrap_or_else(|| email.clone()),
+            email,
+            groups: entry
+                .attrs
+                .get(&self.group_attribute)
+                .cloned()
+                .unwrap_or_default(),
+            subject: entry.dn,
+        }))
+    }
+}
+
+impl PasswordProvider for LdapProvider {
+    fn authenticate<'a>(
+        &'a self,
+        _db: &'a Surreal<Client>,
+        email: &'a str,
+        password: &'a str,
+    ) -> LocalBoxFuture<'a, Result<Option<Identity>, String>> {
+        Box::pin(async move {
+            self.verify(email, password)
+                .await
+                .map_err(|err| format!("ldap {}: {err}", self.url))
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use actix_web::web::BytesMut;
+    use ldap3::asn1::{
+        parse_tag, write, ASNTag, Enumerated, Integer, OctetString, Sequence, Set, StructureTag,
+        Tag, TagClass, PL,
+    };
+    use tokio::{
+        io::{AsyncReadExt, AsyncWriteExt},
+        net::{TcpListener, TcpStream},
+    };
+
+    use super::*;
+
+    const SERVICE_DN: &str = "cn=i40,ou=services,dc=plant,dc=local";
+    const SERVICE_PASSWORD: &str = "service-secret";
+    const LUCY_DN: &str = "uid=lucy,ou=people,dc=plant,dc=local";
+    const LUCY_PASSWORD: &str = "lucy-secret";
+    const ADMINS: &str = "cn=i40-admins,ou=groups,dc=plant,dc=local";
+
+    /// a minimal LDAP server, which knows the service account and lucy
+    async fn mock_directory() -> String {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("ldap://{}", listener.local_addr().unwrap());
+        tokio::spawn(async move {
+            while let Ok((stream, _)) = listener.accept().await {
+                tokio::spawn(serve(stream));
+            }
+        });
+        url
+    }
+
+    fn octet_string(value: &str) -> Tag {
+        Tag::OctetString(OctetString {
+            inner: value.as_bytes().to_vec(),
+            ..Default::default()
+        })
+    }
+
+    fn application(id: u64, inner: Vec<Tag>) -> Tag {
+        Tag::Sequence(Sequence {
+            id,
+            class: TagClass::Application,
+            inner,
+        })
+    }
+
+    /// a LDAPResult with the result code
+    fn result(id: u64, rc: i64) -> Tag {
+        application(
+            id,
+            vec![
+                Tag::Enumerated(Enumerated {
+                    inner: rc,
+                    ..Default::default()
+                }),
+                octet_string(""),
+                octet_string(""),
+            ],
+        )
+    }
+
+    fn lucy() -> Tag {
+        let attribute = |name: &str, values: &[&str]| {
+            Tag::Sequence(Sequence {
+                inner: vec![
+                    octet_string(name),
+                    Tag::Set(Set {
+                        inner: values.iter().map(|value| octet_string(value)).collect(),
+                        ..Default::default()
+                    }),
+                ],
+                ..Default::default()
+            })
+        };
+        application(
+            4,
+            vec![
+                octet_string(LUCY_DN),
+                Tag::Sequence(Sequence {
+                    inner: vec![
+                        attribute("mail", &["Lucy@cyber.night"]),
+                        attribute("cn", &["Lucy"]),
+                        attribute(
+                            "memberOf",
+                            &[ADMINS, "cn=staff,ou=groups,dc=plant,dc=local"],
+                        ),
+                    ],
+                    ..Default::default()
+                }),
+            ],
+        )
+    }
+
+    fn primitive(tag: StructureTag) -> String {
+        String::from_utf8(tag.expect_primitive().unwrap_or_default()).unwrap()
+    }
+
+    /// answers the requests of a single connection
+    async fn serve(mut stream: TcpStream) {
+        let mut buffer = Vec::new();
+        let mut chunk = [0u8; 4096];
+        loop {
+            let Ok(read) = stream.read(&mut chunk).await else {
+                return;
+            };
+            if read == 0 {
+                return;
+            }
+            buffer.extend_from_slice(&chunk[..read]);
+            while let Ok((rest, message)) = parse_tag(&buffer) {
+                let consumed = buffer.len() - rest.len();
+                let mut message = message.expect_constructed().unwrap().into_iter();
+                let id = message.next().unwrap().expect_primitive().unwrap();
+                let id = id.iter().fold(0i64, |id, byte| id << 8 | *byte as i64);
+                let request = message.next().unwrap();
+                let mut fields = match request.payload {
+                    PL::C(fields) => fields.into_iter(),
+                    PL::P(_) => Vec::new().into_iter(),
+                };
+
+                let responses = match request.id {
+                    // bind
+                    0 => {
+                        let dn = primitive(fields.nth(1).unwrap());
+                        let password = primitive(fields.next().unwrap());
+                        let valid = (dn == SERVICE_DN && password == SERVICE_PASSWORD)
+                            || (dn == LUCY_DN && password == LUCY_PASSWORD);
+                        vec![result(1, if valid { 0 } else { 49 })]
+                    }
+                    // search by equality filter
+                    3 => {
+                        let filter = fields.nth(6).unwrap().expect_constructed().unwrap();
+                        let value = primitive(filter.into_iter().nth(1).unwrap());
+                        let mut responses = Vec::new();
+                        if value.eq_ignore_ascii_case("lucy@cyber.night") {
+                            responses.push(lucy());
+                        }
+                        responses.push(result(5, 0));
+                        responses
+                    }
+                    // unbind
+                    _ => return,
+                };
+                let mut bytes = BytesMut::new();
+                for response in responses {
+                    let envelope = Tag::Sequence(Sequence {
+                        inner: vec![
+                            Tag::Integer(Integer {
+                                inner: id,
+                                ..Default::default()
+                            }),
+                            response,
+                        ],
+                        ..Default::default()
+                    });
+                    write::encode_into(&mut bytes, envelope.into_structure()).unwrap();
+                }
+                if stream.write_all(&bytes).await.is_err() {
+                    return;
+                }
+                buffer.drain(..consumed);
+            }
+        }
+    }
+
+    fn provider(url: String) -> LdapProvider {
+        LdapProvider::new(&LdapConfig {
+            url,
+            bind_dn: Some(SERVICE_DN.to_owned()),
+            bind_password: SERVICE_PASSWORD.to_owned(),
+            base_dn: "ou=people,dc=plant,dc=local".to_owned(),
+            user_filter: "(mail={email})".to_owned(),
+            email_attribute: "mail".to_owned(),
+            name_attribute: "cn".to_owned(),
+            group_attribute: "memberOf".to_owned(),
+            timeout_seconds: 5,
+        })
+    }
+
+    #[actix_web::test]
+    async fn binds_as_the_found_user() {
+        let provider = provider(mock_directory().await);
+        let identity = provider
+            .verify("lucy@cyber.night", LUCY_PASSWORD)
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(identity.provider, LDAP);
+        assert_eq!(identity.subject, LUCY_DN);
+        assert_eq!(identity.email, "lucy@cyber.night");
+        assert_eq!(identity.name, "Lucy");
+        assert_eq!(identity.groups.len(), 2);
+        assert!(identity.groups.contains(&ADMINS.to_owned()));
+    }
+
+    #[actix_web::test]
+    async fn rejects_wrong_passwords_and_unknown_users() {
+        let provider = provider(mock_directory().await);
+        assert_eq!(
+            provider.verify("lucy@cyber.night", "wrong").await.unwrap(),
+            None
+        );
+        assert_eq!(
+            provider.verify("david@cyber.night", "wrong").await.unwrap(),
+            None
+        );
+    }
+
+    #[actix_web::test]
+    async fn fails_with_a_wrong_service_account() {
+        let mut provider = provider(mock_directory().await);
+        provider.bind_password = "wrong".to_owned();
+        assert!(provider
+            .verify("lucy@cyber.night", LUCY_PASSWORD)
+            .await
+            .is_err());
+    }
+
+    #[actix_web::test]
+    async fn fails_for_a_unreachable_directory() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("ldap://{}", listener.local_addr().unwrap());
+        drop(listener);
+        assert!(provider(url)
+            .verify("lucy@cyber.night", LUCY_PASSWORD)
+            .await
+            .is_err());
+    }
+}
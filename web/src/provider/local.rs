@@ -0,0 +1,48 @@
+//! # web::provider::local
+//!
+//! `web::provider::local` verifies the argon2 password hashes of the users in the database.
+//! Users of external providers are excluded, their local password is never handed out.
+
+use futures_util::future::LocalBoxFuture;
+use serde::Deserialize;
+use surrealdb::{engine::remote::ws::Client, sql::Thing, Surreal};
+
+use super::{Identity, PasswordProvider, LOCAL};
+
+/// The users of the database
+pub struct LocalProvider;
+
+/// helper struct to deserialize a verified user
+#[derive(Deserialize)]
+struct LocalUser {
+    id: Thing,
+    email: String,
+    name: String,
+}
+
+impl PasswordProvider for LocalProvider {
+    fn authenticate<'a>(
+        &'a self,
+        db: &'a Surreal<Client>,
+        email: &'a str,
+        password: &'a str,
+    ) -> LocalBoxFuture<'a, Result<Option<Identity>, String>> {
+        Box::pin(async move {
+            let user: Option<LocalUser> = db
+                .query("SELECT id, email, name FROM user WHERE email = $email AND (provider = NONE OR provider = $provider) AND crypto::argon2::compare(password, $password)")
+                .bind(("email", email))
+                .bind(("provider", LOCAL))
+                .bind(("password", password))
+                .await
+                .and_then(|mut response| response.take(0))
+                .map_err(|err| err.to_string())?;
+            Ok(user.map(|user| Identity {
+                provider: LOCAL,
+                subject: user.id.to_string(),
+                email: user.email,
+                name: user.name,
+                groups: Vec::new(),
+            }))
+        })
+    }
+}
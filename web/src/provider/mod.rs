@@ -0,0 +1,243 @@
+//! # web::provider
+//!
+//! `web::provider` is a module to authenticate users against identity providers.
+//! Password sign ins try the configured password providers in order: the local users of the
+//! database and a LDAP directory. OIDC signs in with the authorization code flow.
+//! Users of external providers are created on their first sign in and their role is synced
+//! from their groups on every sign in, see `RoleMapping`.
+//!
+//! # Example
+//!
+//! ```text
+//! POST /signin email=lucy@cyber.night&password=...
+//! GET /auth/oidc/login
+//! ```
+
+pub mod ldap;
+pub mod local;
+pub mod oidc;
+
+use actix_web::HttpResponse;
+use futures_util::future::LocalBoxFuture;
+use rand::{distributions::Alphanumeric, Rng};
+use serde::Deserialize;
+use surrealdb::{engine::remote::ws::Client, sql::Thing, Surreal};
+
+use crate::{
+    app::audit::Audit,
+    config::{AppState, PasswordProviderKind},
+};
+
+/// the name of the local provider, users without a provider are local users
+pub const LOCAL: &str = "local";
+
+/// A user verified by a provider
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Identity {
+    /// the name of the provider
+    pub provider: &'static str,
+    /// the id of the user at the provider, the record id for local users
+    pub subject: String,
+    pub email: String,
+    pub name: String,
+    /// the groups at the provider, mapped to a role on sign in
+    pub groups: Vec<String>,
+}
+
+/// A provider which verifies the email and password of a sign in
+pub trait PasswordProvider: Send + Sync {
+    /// Returns the identity for valid credentials, None for wrong credentials.
+    /// The error describes why the provider couldn't be asked, e.g. a unreachable directory
+    fn authenticate<'a>(
+        &'a self,
+        db: &'a Surreal<Client>,
+        email: &'a str,
+        password: &'a str,
+    ) -> LocalBoxFuture<'a, Result<Option<Identity>, String>>;
+}
+
+/// The configured providers, loaded once on startup
+pub struct AuthProviders {
+    password: Vec<Box<dyn PasswordProvider>>,
+    pub oidc: Option<oidc::OidcProvider>,
+}
+
+impl AuthProviders {
+    /// Creates the providers of the config, the error names the missing configuration
+    pub fn load(app_state: &AppState) -> Result<Self, String> {
+        let config = &app_state.auth_providers;
+        let mut password: Vec<Box<dyn PasswordProvider>> = Vec::new();
+        for kind in config.password.iter() {
+            match kind {
+                PasswordProviderKind::Local => password.push(Box::new(local::LocalProvider)),
+                PasswordProviderKind::Ldap => {
+                    let ldap = config
+                        .ldap
+                        .as_ref()
+                        .ok_or("the password provider ldap needs a ldap config")?;
+                    password.push(Box::new(ldap::LdapProvider::new(ldap)));
+                }
+            }
+        }
+        Ok(AuthProviders {
+            password,
+            oidc: config.oidc.as_ref().map(oidc::OidcProvider::new),
+        })
+    }
+
+    /// Asks the password providers in order and returns the first identity.
+    /// Errors of a provider are logged and only returned if no provider verified the user
+    pub async fn authenticate(
+        &self,
+        db: &Surreal<Client>,
+        email: &str,
+        password: &str,
+    ) -> Result<Option<Identity>, String> {
+        // a empty password would be an anonymous bind for LDAP
+        if password.is_empty() {
+            return Ok(None);
+        }
+        let mut error = None;
+        for provider in self.password.iter() {
+            match provider.authenticate(db, email, password).await {
+                Ok(Some(identity)) => return Ok(Some(identity)),
+                Ok(None) => {}
+                Err(err) => {
+                    eprintln!("authentication provider failed: {err}");
+                    error = Some(err);
+                }
+            }
+        }
+        match error {
+            Some(_) => Err("The authentication provider is unavailable".to_owned()),
+            None => Ok(None),
+        }
+    }
+}
+
+/// helper struct to deserialize the user of a identity
+#[derive(Deserialize)]
+struct ProvisionedUser {
+    id: Thing,
+    provider: Option<String>,
+}
+
+/// Returns the user of the identity. Users of external providers are created on their first
+/// sign in, afterwards their email, name, role and groups are updated from the identity
+pub async fn provision(
+    app_state: &AppState,
+    audit: &Audit,
+    db: &Surreal<Client>,
+    identity: &Identity,
+) -> Result<Thing, HttpResponse> {
+    let internal_error =
+        |err: surrealdb::Error| HttpResponse::InternalServerError().body(err.to_string());
+    if identity.provider == LOCAL {
+        return surrealdb::sql::thing(&identity.subject)
+            .map_err(|_| HttpResponse::InternalServerError().body("Invalid local user"));
+    }
+
+    let config = &app_state.auth_providers;
+    let role = config.role_mapping.role(&identity.groups);
+    let mut response = db
+        .query("SELECT id, provider FROM user WHERE provider = $provider AND subject = $subject; SELECT id, provider FROM user WHERE email = $email")
+        .bind(("provider", identity.provider))
+        .bind(("subject", &identity.subject))
+        .bind(("email", &identity.email))
+        .await
+        .map_err(internal_error)?;
+    let known: Option<ProvisionedUser> = response.take(0).map_err(internal_error)?;
+    let by_email: Option<ProvisionedUser> = response.take(1).map_err(internal_error)?;
+
+    let user = match (known, by_email) {
+        (Some(user), _) => Some(user.id),
+        (None, Some(user)) if config.link_by_email => Some(user.id),
+        (None, Some(user)) => {
+            let owner = user.provider.unwrap_or_else(|| LOCAL.to_owned());
+            return Err(HttpResponse::Conflict().body(format!(
+                "The email {} is already used by a {owner} account",
+                identity.email
+            )));
+        }
+        (None, None) => None,
+    };
+
+    let created = user.is_none();
+    let provisioned: Option<ProvisionedUser> = match user {
+        Some(user) => db
+            .query("UPDATE $user SET email = $email, name = $name, role = $role, groups = $groups, provider = $provider, subject = $subject WHERE id = $user")
+            .bind(("user", user)),
+        // external users never sign in with the random local password
+        None => db
+            .query("CREATE user SET email = $email, name = $name, role = $role, groups = $groups, provider = $provider, subject = $subject, password = crypto::argon2::generate($password), active = true, must_change_password = false, created_at = time::now()")
+            .bind(("password", random_password())),
+    }
+    .bind(("email", &identity.email))
+    .bind(("name", &identity.name))
+    .bind(("role", role))
+    .bind(("groups", &identity.groups))
+    .bind(("provider", identity.provider))
+    .bind(("subject", &identity.subject))
+    .await
+    .and_then(|mut response| response.take(0))
+    .map_err(internal_error)?;
+    let Some(provisioned) = provisioned else {
+        return Err(HttpResponse::InternalServerError().body("User was not provisioned"));
+    };
+    if created {
+        let after = serde_json::json!({
+            "email": identity.email,
+            "name": identity.name,
+            "role": role,
+            "groups": identity.groups,
+            "provider": identity.provider,
+            "subject": identity.subject,
+        });
+        audit
+            .record(db, "user.provision", Some(&provisioned.id), (), after)
+            .await;
+    }
+    Ok(provisioned.id)
+}
+
+/// a unguessable password for users, who only sign in at their provider
+fn random_password() -> String {
+    rand::thread_rng()
+        .sample_iter(&Alphanumeric)
+        .take(48)
+        .map(char::from)
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use crate::{auth::Role, config::RoleMapping};
+
+    #[test]
+    fn maps_the_highest_role_of_the_groups() {
+        let mapping = RoleMapping {
+            default_role: Role::Viewer,
+            groups: HashMap::from([
+                (
+                    "cn=i40-admins,ou=groups,dc=plant,dc=local".to_owned(),
+                    Role::Admin,
+                ),
+                ("i40-operators".to_owned(), Role::Operator),
+                ("i40-maintainers".to_owned(), Role::Maintainer),
+            ]),
+        };
+        assert_eq!(mapping.role(&["I40-Operators", "staff"]), Role::Operator);
+        assert_eq!(
+            mapping.role(&["i40-maintainers", "i40-operators"]),
+            Role::Maintainer
+        );
+        assert_eq!(
+            mapping.role(&["CN=i40-admins,OU=groups,DC=plant,DC=local"]),
+            Role::Admin
+        );
+        assert_eq!(mapping.role(&["staff"]), Role::Viewer);
+        assert_eq!(mapping.role::<&str>(&[]), Role::Viewer);
+    }
+}
@@ -0,0 +1,551 @@
+//! # web::provider::oidc
+//!
+//! `web::provider::oidc` signs users in with a OpenID Connect provider, e.g. Keycloak or
+//! Azure AD, using the authorization code flow. The endpoints and keys of the provider are
+//! discovered from its issuer. The id token is verified against the published keys, the
+//! issuer, the client id as audience and the nonce of the login.
+//!
+//! # Example
+//! The browser is redirected to the provider and back to the callback, which redirects
+//! to the frontend with the tokens in the fragment.
+//!
+//! ```text
+//! GET /auth/oidc/login
+//! GET /auth/oidc/callback?code=...&state=...
+//! > 302 /#jwt=...&refresh_token=...
+//! ```
+
+use std::{
+    collections::HashMap,
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+use actix_web::{get, http::header::LOCATION, web, HttpResponse};
+use jsonwebtoken::{jwk::JwkSet, Algorithm, DecodingKey, Validation};
+use rand::{distributions::Alphanumeric, Rng};
+use reqwest::Url;
+use serde::Deserialize;
+use serde_json::Value;
+use surrealdb::{engine::remote::ws::Client, Surreal};
+
+use super::{AuthProviders, Identity};
+use crate::{
+    app::audit::Audit, config::AppState, config::OidcConfig, jwt::JwtKeys,
+    middleware::rate_limit::RateLimit,
+};
+
+/// the provider name of OIDC users
+pub const OIDC: &str = "oidc";
+
+/// time to complete a login at the provider
+const LOGIN_TIMEOUT: Duration = Duration::from_secs(10 * 60);
+
+/// upper bound of pending logins, expired logins are removed beyond it
+const MAX_PENDING_LOGINS: usize = 10_000;
+
+/// the endpoints of the discovery document
+#[derive(Clone, Deserialize)]
+struct Metadata {
+    issuer: String,
+    authorization_endpoint: String,
+    token_endpoint: String,
+    jwks_uri: String,
+}
+
+/// the response of the token endpoint
+#[derive(Deserialize)]
+struct TokenResponse {
+    id_token: String,
+}
+
+/// the claims of a id token
+#[derive(Deserialize)]
+struct IdTokenClaims {
+    sub: String,
+    email: Option<String>,
+    email_verified: Option<bool>,
+    name: Option<String>,
+    preferred_username: Option<String>,
+    nonce: Option<String>,
+    /// contains the configured groups claim
+    #[serde(flatten)]
+    other: HashMap<String, Value>,
+}
+
+/// a started login, which waits for its callback
+struct PendingLogin {
+    nonce: String,
+    started: Instant,
+}
+
+/// A OpenID Connect provider
+pub struct OidcProvider {
+    issuer: String,
+    client_id: String,
+    client_secret: String,
+    redirect_uri: String,
+    scopes: Vec<String>,
+    groups_claim: String,
+    frontend_redirect: String,
+    http: reqwest::Client,
+    metadata: Mutex<Option<Metadata>>,
+    jwks: Mutex<Option<JwkSet>>,
+    pending: Mutex<HashMap<String, PendingLogin>>,
+}
+
+/// returns a random url safe string
+fn random_token() -> String {
+    rand::thread_rng()
+        .sample_iter(&Alphanumeric)
+        .take(32)
+        .map(char::from)
+        .collect()
+}
+
+impl OidcProvider {
+    pub fn new(config: &OidcConfig) -> Self {
+        OidcProvider {
+            issuer: config.issuer.trim_end_matches('/').to_owned(),
+            client_id: config.client_id.clone(),
+            client_secret: config.client_secret.clone(),
+            redirect_uri: config.redirect_uri.clone(),
+            scopes: config.scopes.clone(),
+            groups_claim: config.groups_claim.clone(),
+            frontend_redirect: config.frontend_redirect.clone(),
+            http: reqwest::Client::new(),
+            metadata: Mutex::new(None),
+            jwks: Mutex::new(None),
+            pending: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// returns the discovery document, it is only fetched once
+    async fn metadata(&self) -> Result<Metadata, String> {
+        if let Some(metadata) = self.metadata.lock().unwrap().as_ref() {
+            return Ok(metadata.clone());
+        }
+        let url = format!("{}/.well-known/openid-configuration", self.issuer);
+        let metadata: Metadata = self
+            .http
+            .get(&url)
+            .send()
+            .await
+            .and_then(|response| response.error_for_status())
+            .map_err(|err| format!("unable to discover {url}: {err}"))?
+            .json()
+            .await
+            .map_err(|err| format!("invalid discovery document {url}: {err}"))?;
+        if metadata.issuer.trim_end_matches('/') != self.issuer {
+            return Err(format!(
+                "the discovery document names the issuer {}",
+                metadata.issuer
+            ));
+        }
+        *self.metadata.lock().unwrap() = Some(metadata.clone());
+        Ok(metadata)
+    }
+
+    /// returns the key of the kid, the keys are fetched again for a unknown kid
+    /// as the provider may have rotated its keys
+    async fn key(&self, kid: Option<&str>) -> Result<(DecodingKey, Option<Algorithm>), String> {
+        let find = |jwks: &JwkSet| {
+            let jwk = match kid {
+                Some(kid) => jwks.find(kid),
+                None if jwks.keys.len() == 1 => jwks.keys.first(),
+                None => None,
+            }?;
+            Some(
+                DecodingKey::from_jwk(jwk)
+                    .map(|key| (key, jwk.common.algorithm))
+                    .map_err(|err| format!("invalid key of the provider: {err}")),
+            )
+        };
+        if let Some(key) = self.jwks.lock().unwrap().as_ref().and_then(find) {
+            return key;
+        }
+
+        let jwks_uri = self.metadata().await?.jwks_uri;
+        let jwks: JwkSet = self
+            .http
+            .get(&jwks_uri)
+            .send()
+            .await
+            .and_then(|response| response.error_for_status())
+            .map_err(|err| format!("unable to fetch the keys {jwks_uri}: {err}"))?
+            .json()
+            .await
+            .map_err(|err| format!("invalid keys {jwks_uri}: {err}"))?;
+        let key = find(&jwks)
+            .unwrap_or_else(|| Err("the id token is signed by a unknown key".to_owned()));
+        *self.jwks.lock().unwrap() = Some(jwks);
+        key
+    }
+
+    /// Starts a login and returns the url of the provider to redirect the browser to
+    pub async fn authorization_url(&self) -> Result<String, String> {
+        let metadata = self.metadata().await?;
+        let (state, nonce) = (random_token(), random_token());
+        let url = Url::parse_with_params(
+            &metadata.authorization_endpoint,
+            [
+                ("response_type", "code"),
+                ("client_id", &self.client_id),
+                ("redirect_uri", &self.redirect_uri),
+                ("scope", &self.scopes.join(" ")),
+                ("state", &state),
+                ("nonce", &nonce),
+            ],
+        )
+        .map_err(|err| format!("invalid authorization endpoint: {err}"))?;
+
+        let now = Instant::now();
+        let mut pending = self.pending.lock().unwrap();
+        if pending.len() >= MAX_PENDING_LOGINS {
+            pending.retain(|_, login| now.duration_since(login.started) < LOGIN_TIMEOUT);
+        }
+        pending.insert(
+            state,
+            PendingLogin {
+                nonce,
+                started: now,
+            },
+        );
+        Ok(url.to_string())
+    }
+
+    /// Completes the login of the state, exchanges the code and verifies the id token
+    pub async fn identity(&self, code: &str, state: &str) -> Result<Identity, String> {
+        let login = self
+            .pending
+            .lock()
+            .unwrap()
+            .remove(state)
+            .filter(|login| login.started.elapsed() < LOGIN_TIMEOUT)
+            .ok_or("The login is unknown or expired, please sign in again")?;
+
+        let token_endpoint = self.metadata().await?.token_endpoint;
+        let tokens: TokenResponse = self
+            .http
+            .post(&token_endpoint)
+            .basic_auth(&self.client_id, Some(&self.client_secret))
+            .form(&[
+                ("grant_type", "authorization_code"),
+                ("code", code),
+                ("redirect_uri", &self.redirect_uri),
+            ])
+            .send()
+            .await
+            .and_then(|response| response.error_for_status())
+            .map_err(|err| format!("unable to redeem the code: {err}"))?
+            .json()
+            .await
+            .map_err(|err| format!("invalid token response: {err}"))?;
+
+        let claims = self.verify(&tokens.id_token).await?;
+        if claims.nonce.as_deref() != Some(login.nonce.as_str()) {
+            return Err("The id token belongs to another login".to_owned());
+        }
+        if claims.email_verified == Some(false) {
+            return Err("The email is not verified by the provider".to_owned());
+        }
+        let email = claims
+            .email
+            .ok_or("The provider didn't share the email")?
+            .to_lowercase();
+        let groups = match claims.other.get(&self.groups_claim) {
+            Some(Value::Array(groups)) => groups
+                .iter()
+                .filter_map(|group| group.as_str().map(str::to_owned))
+                .collect(),
+            Some(Value::String(group)) => vec![group.clone()],
+            _ => Vec::new(),
+        };
+        Ok(Identity {
+            provider: OIDC,
+            subject: claims.sub,
+            name: claims
+                .name
+                .or(claims.preferred_username)
+                .unwrap_or_else(|| email.clone()),
+            email,
+            groups,
+        })
+    }
+
+    /// verifies the signature, issuer, audience and expiry of a id token
+    async fn verify(&self, id_token: &str) -> Result<IdTokenClaims, String> {
+        let invalid = |err: jsonwebtoken::errors::Error| format!("invalid id token: {err}");
+        let header = jsonwebtoken::decode_header(id_token).map_err(invalid)?;
+        // the client secret isn't meant to verify tokens
+        if matches!(
+            header.alg,
+            Algorithm::HS256 | Algorithm::HS384 | Algorithm::HS512
+        ) {
+            return Err("invalid id token: symmetric algorithms are not accepted".to_owned());
+        }
+        let (key, algorithm) = self.key(header.kid.as_deref()).await?;
+        if algorithm.is_some_and(|algorithm| algorithm != header.alg) {
+            return Err("invalid id token: the algorithm doesn't match the key".to_owned());
+        }
+
+        let mut validation = Validation::new(header.alg);
+        validation.set_issuer(&[&self.issuer, &format!("{}/", self.issuer)]);
+        validation.set_audience(&[&self.client_id]);
+        jsonwebtoken::decode::<IdTokenClaims>(id_token, &key, &validation)
+            .map(|token_data| token_data.claims)
+            .map_err(invalid)
+    }
+}
+
+/// returns the OIDC provider or responds with 404 if it isn't configured
+fn configured(providers: &AuthProviders) -> Result<&OidcProvider, HttpResponse> {
+    providers
+        .oidc
+        .as_ref()
+        .ok_or_else(|| HttpResponse::NotFound().body("OIDC is not configured"))
+}
+
+/// route to start a sign in at the OIDC provider
+#[get("/auth/oidc/login", wrap = "RateLimit::AUTH")]
+pub async fn oidc_login(providers: web::Data<AuthProviders>) -> HttpResponse {
+    let oidc = match configured(&providers) {
+        Ok(oidc) => oidc,
+        Err(response) => return response,
+    };
+    match oidc.authorization_url().await {
+        Ok(url) => HttpResponse::Found()
+            .insert_header((LOCATION, url))
+            .finish(),
+        Err(err) => {
+            eprintln!("unable to start a oidc login: {err}");
+            HttpResponse::BadGateway().body("The identity provider is unavailable")
+        }
+    }
+}
+
+/// helper struct to deserialize the callback, the provider sends a error instead of a code
+/// if the user declined the sign in
+#[derive(Deserialize)]
+pub struct CallbackQuery {
+    code: Option<String>,
+    state: Option<String>,
+    error: Option<String>,
+    error_description: Option<String>,
+}
+
+/// route the OIDC provider redirects to after the sign in. The user is provisioned,
+/// signed in and redirected to the frontend with the tokens in the fragment
+#[get("/auth/oidc/callback", wrap = "RateLimit::AUTH")]
+pub async fn oidc_callback(
+    query: web::Query<CallbackQuery>,
+    audit: Audit,
+    app_state: web::Data<AppState>,
+    keys: web::Data<JwtKeys>,
+    providers: web::Data<AuthProviders>,
+    db: web::Data<Surreal<Client>>,
+) -> HttpResponse {
+    let oidc = match configured(&providers) {
+        Ok(oidc) => oidc,
+        Err(response) => return response,
+    };
+    if let Some(error) = &query.error {
+        let description = query.error_description.as_deref().unwrap_or_default();
+        return HttpResponse::Unauthorized().body(format!("Sign in failed: {error} {description}"));
+    }
+    let (Some(code), Some(state)) = (&query.code, &query.state) else {
+        return HttpResponse::BadRequest().body("The callback needs a code and a state");
+    };
+
+    let identity = match oidc.identity(code, state).await {
+        Ok(identity) => identity,
+        Err(err) => return HttpResponse::Unauthorized().body(err),
+    };
+    let user_id = match super::provision(&app_state, &audit, &db, &identity).await {
+        Ok(user_id) => user_id,
+        Err(response) => return response,
+    };
+    match crate::app::user::open_session(&app_state, &keys, &db, &user_id).await {
+        Ok(user) => HttpResponse::Found()
+            .insert_header((
+                LOCATION,
+                format!(
+                    "{}#jwt={}&refresh_token={}",
+                    oidc.frontend_redirect,
+                    user.jwt.unwrap_or_default(),
+                    user.refresh_token.unwrap_or_default()
+                ),
+            ))
+            .finish(),
+        Err(response) => response,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::TcpListener;
+
+    use actix_web::{App, HttpServer};
+    use actix_web_httpauth::extractors::basic::BasicAuth;
+    use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine};
+    use jsonwebtoken::{EncodingKey, Header};
+    use rsa::{pkcs8::DecodePrivateKey, traits::PublicKeyParts, RsaPrivateKey};
+    use serde_json::json;
+
+    use super::*;
+
+    const IDP_KEY: &str = include_str!("../../testdata/oidc_idp.pem");
+
+    /// the issuer of the mock provider
+    struct Issuer(String);
+
+    #[get("/.well-known/openid-configuration")]
+    async fn discovery(issuer: web::Data<Issuer>) -> HttpResponse {
+        HttpResponse::Ok().json(json!({
+            "issuer": issuer.0,
+            "authorization_endpoint": format!("{}/authorize", issuer.0),
+            "token_endpoint": format!("{}/token", issuer.0),
+            "jwks_uri": format!("{}/jwks", issuer.0),
+        }))
+    }
+
+    #[get("/jwks")]
+    async fn jwks() -> HttpResponse {
+        let key = RsaPrivateKey::from_pkcs8_pem(IDP_KEY).unwrap();
+        HttpResponse::Ok().json(json!({ "keys": [{
+            "kty": "RSA",
+            "use": "sig",
+            "alg": "RS256",
+            "kid": "idp-1",
+            "n": URL_SAFE_NO_PAD.encode(key.n().to_bytes_be()),
+            "e": URL_SAFE_NO_PAD.encode(key.e().to_bytes_be()),
+        }]}))
+    }
+
+    /// helper struct to deserialize the token request
+    #[derive(Deserialize)]
+    struct TokenRequest {
+        code: String,
+    }
+
+    /// issues a id token for lucy to the client i40, the code is taken as nonce of the token.
+    /// Any client with the secret is accepted to test the audience check
+    #[actix_web::post("/token")]
+    async fn token(
+        auth: BasicAuth,
+        form: web::Form<TokenRequest>,
+        issuer: web::Data<Issuer>,
+    ) -> HttpResponse {
+        if auth.password() != Some("idp-secret") {
+            return HttpResponse::Unauthorized().finish();
+        }
+        let mut header = Header::new(Algorithm::RS256);
+        header.kid = Some("idp-1".to_owned());
+        let now = chrono::Utc::now().timestamp();
+        let claims = json!({
+            "iss": issuer.0,
+            "aud": "i40",
+            "sub": "248289761001",
+            "iat": now,
+            "exp": now + 300,
+            "nonce": form.code,
+            "email": "Lucy@cyber.night",
+            "email_verified": true,
+            "name": "Lucy",
+            "groups": ["i40-operators", "staff"],
+        });
+        let key = EncodingKey::from_rsa_pem(IDP_KEY.as_bytes()).unwrap();
+        HttpResponse::Ok().json(json!({
+            "access_token": "opaque",
+            "token_type": "Bearer",
+            "id_token": jsonwebtoken::encode(&header, &claims, &key).unwrap(),
+        }))
+    }
+
+    /// starts the mock provider and returns its issuer
+    fn mock_provider() -> String {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let issuer = format!("http://{}", listener.local_addr().unwrap());
+        let data = web::Data::new(Issuer(issuer.clone()));
+        let server = HttpServer::new(move || {
+            App::new()
+                .app_data(data.clone())
+                .service(discovery)
+                .service(jwks)
+                .service(token)
+        })
+        .workers(1)
+        .listen(listener)
+        .unwrap()
+        .run();
+        actix_web::rt::spawn(server);
+        issuer
+    }
+
+    fn provider(issuer: String, client_id: &str) -> OidcProvider {
+        OidcProvider::new(&OidcConfig {
+            issuer,
+            client_id: client_id.to_owned(),
+            client_secret: "idp-secret".to_owned(),
+            redirect_uri: "http://127.0.0.1:8080/auth/oidc/callback".to_owned(),
+            scopes: vec!["openid".to_owned(), "email".to_owned()],
+            groups_claim: "groups".to_owned(),
+            frontend_redirect: "/".to_owned(),
+        })
+    }
+
+    /// starts a login and returns its state and nonce
+    async fn login(provider: &OidcProvider) -> (String, String) {
+        let url = Url::parse(&provider.authorization_url().await.unwrap()).unwrap();
+        assert_eq!(url.path(), "/authorize");
+        let param = |name: &str| {
+            url.query_pairs()
+                .find(|(key, _)| key == name)
+                .map(|(_, value)| value.into_owned())
+                .unwrap()
+        };
+        assert_eq!(param("client_id"), provider.client_id);
+        (param("state"), param("nonce"))
+    }
+
+    #[actix_web::test]
+    async fn verifies_the_id_token() {
+        let provider = provider(mock_provider(), "i40");
+        let (state, nonce) = login(&provider).await;
+        let identity = provider.identity(&nonce, &state).await.unwrap();
+        assert_eq!(
+            identity,
+            Identity {
+                provider: OIDC,
+                subject: "248289761001".to_owned(),
+                email: "lucy@cyber.night".to_owned(),
+                name: "Lucy".to_owned(),
+                groups: vec!["i40-operators".to_owned(), "staff".to_owned()],
+            }
+        );
+    }
+
+    #[actix_web::test]
+    async fn rejects_unknown_and_reused_states() {
+        let provider = provider(mock_provider(), "i40");
+        let (state, nonce) = login(&provider).await;
+        assert!(provider.identity(&nonce, "forged").await.is_err());
+        assert!(provider.identity(&nonce, &state).await.is_ok());
+        assert!(provider.identity(&nonce, &state).await.is_err());
+    }
+
+    #[actix_web::test]
+    async fn rejects_the_nonce_of_another_login() {
+        let provider = provider(mock_provider(), "i40");
+        let (state, _) = login(&provider).await;
+        let (_, other_nonce) = login(&provider).await;
+        assert!(provider.identity(&other_nonce, &state).await.is_err());
+    }
+
+    #[actix_web::test]
+    async fn rejects_tokens_of_another_audience() {
+        let provider = provider(mock_provider(), "mes");
+        let (state, nonce) = login(&provider).await;
+        assert!(provider.identity(&nonce, &state).await.is_err());
+    }
+}
@@ -3,9 +3,12 @@
 //! `web::routes` is the central module for defining the api routes
 //!
 
-use crate::middleware::authorization::JWTAuthorization;
+use crate::{
+    app::acl::{station_thing, Access, StationPermission},
+    auth::ApiScope,
+    middleware::{authorization::JWTAuthorization, rate_limit::RateLimit, scope::RequireScope},
+};
 use actix_web::{get, post, web, HttpResponse};
-use common;
 use serde::Deserialize;
 use surrealdb::{engine::remote::ws::Client, Surreal};
 
@@ -14,46 +17,135 @@ pub fn config(app: &mut web::ServiceConfig) {
     app.service(
         web::scope("/api/v1")
             .wrap(JWTAuthorization)
+            .wrap(RateLimit::API)
             .service(crate::auth::decode)
+            .service(crate::app::session::sign_out)
+            .service(crate::app::session::revoke_user_sessions)
+            .service(crate::app::admin::get_users)
+            .service(crate::app::admin::create_user)
+            .service(crate::app::admin::update_user)
+            .service(crate::app::admin::deactivate_user)
+            .service(crate::app::admin::reactivate_user)
+            .service(crate::app::admin::reset_password)
+            .service(crate::app::lockout::get_lockouts)
+            .service(crate::app::lockout::unlock_account)
+            .service(crate::app::api_key::get_api_keys)
+            .service(crate::app::api_key::create_api_key)
+            .service(crate::app::api_key::revoke_api_key)
+            .service(crate::app::audit::get_audit_events)
+            .service(crate::app::acl::get_station_grants)
+            .service(crate::app::acl::create_station_grant)
+            .service(crate::app::acl::delete_station_grant)
+            .service(crate::app::user::get_me)
+            .service(crate::app::user::update_me)
+            .service(crate::app::user::change_password)
+            .service(crate::app::user::upload_avatar)
+            .service(crate::app::user::delete_avatar)
             .service(get_stations)
             .service(get_sensor_values)
             .service(get_sensor)
             .service(get_sensors)
+            .service(get_batch_values)
+            .service(crate::app::export::export)
+            .service(crate::app::import::import)
+            .service(crate::app::station::create_station)
+            .service(crate::app::station::update_station)
+            .service(crate::app::station::delete_station)
+            .service(crate::app::sensor::create_sensor)
+            .service(crate::app::sensor::update_sensor)
+            .service(crate::app::sensor::delete_sensor)
             .service(crate::api::get_measurments)
-            .service(crate::api::get_alarms),
+            .service(crate::api::get_alarms)
+            .service(crate::app::alarm::get_alarms)
+            .service(crate::app::alarm::acknowledge_alarm)
+            .service(crate::app::alarm::shelve_alarm)
+            .service(crate::app::alarm::unshelve_alarm)
+            .service(crate::app::alarm::create_alarm_comment)
+            .service(crate::app::alarm::get_alarm_history)
+            .service(crate::app::alarm::get_handover)
+            .service(crate::app::notification::get_subscriptions)
+            .service(crate::app::notification::create_subscription)
+            .service(crate::app::notification::delete_subscription)
+            .service(crate::app::notification::get_deliveries)
+            .service(crate::app::escalation::get_schedules)
+            .service(crate::app::escalation::get_on_call)
+            .service(crate::app::escalation::create_schedule)
+            .service(crate::app::escalation::update_schedule)
+            .service(crate::app::escalation::delete_schedule)
+            .service(crate::app::escalation::get_escalation_policies)
+            .service(crate::app::escalation::create_escalation_policy)
+            .service(crate::app::escalation::update_escalation_policy)
+            .service(crate::app::escalation::delete_escalation_policy)
+            .service(crate::app::escalation::get_escalation_timeline)
+            .service(crate::app::alarm_rule::get_alarm_rules)
+            .service(crate::app::alarm_rule::create_alarm_rule)
+            .service(crate::app::alarm_rule::update_alarm_rule)
+            .service(crate::app::alarm_rule::delete_alarm_rule)
+            .service(crate::api::get_systems),
     );
 }
 
-/// endpoint to retrieve all stations
-#[get("stations")]
-async fn get_stations(db: web::Data<Surreal<Client>>) -> HttpResponse {
-    let stations = common::Station::get_all(&db)
+/// helper struct to Deserialize the listing query, archived entries are only listed on request
+#[derive(Deserialize)]
+struct ArchivedQuery {
+    #[serde(default)]
+    archived: bool,
+}
+
+/// endpoint to retrieve all stations the caller may view
+#[get("stations", wrap = "RequireScope(ApiScope::ReadSensors)")]
+async fn get_stations(
+    query: web::Query<ArchivedQuery>,
+    access: Access,
+    db: web::Data<Surreal<Client>>,
+) -> HttpResponse {
+    let mut stations = common::Station::get_all(&db)
         .await
         .expect("Error retrieving stations");
+    stations.retain(|station| {
+        (query.archived || !station.is_archived())
+            && access.permits(station.get_id(), StationPermission::View)
+    });
     HttpResponse::Ok().json(stations)
 }
 
 /// endpoint to retrieve a sensor
-#[get("/sensor/{sensor}")]
-async fn get_sensor(sensor_id: web::Path<String>, db: web::Data<Surreal<Client>>) -> HttpResponse {
+#[get("/sensor/{sensor}", wrap = "RequireScope(ApiScope::ReadSensors)")]
+async fn get_sensor(
+    sensor_id: web::Path<String>,
+    access: Access,
+    db: web::Data<Surreal<Client>>,
+) -> HttpResponse {
     let sensor_id = sensor_id.into_inner();
     let sensor: Option<common::Sensor> = common::Sensor::get(&db, sensor_id)
         .await
         .expect("Error retrieving sensor from database");
+    // sensors of forbidden stations look like unknown sensors
+    let sensor =
+        sensor.filter(|sensor| access.permits(sensor.get_station(), StationPermission::View));
 
     HttpResponse::Ok().json(sensor)
 }
 
 /// endpoint to retrive all sensors with the latest value for a given station
-#[get("/station/{station}/sensors")]
+#[get(
+    "/station/{station}/sensors",
+    wrap = "RequireScope(ApiScope::ReadSensors)"
+)]
 async fn get_sensors(
     station_id: web::Path<String>,
+    query: web::Query<ArchivedQuery>,
+    access: Access,
     db: web::Data<Surreal<Client>>,
 ) -> HttpResponse {
     let station_id = station_id.into_inner();
-    let sensors: Vec<common::Sensor> = common::Sensor::get_by_station(&db, station_id)
+    if !access.permits(&station_thing(&station_id), StationPermission::View) {
+        return HttpResponse::NotFound().body("Station not found");
+    }
+    let mut sensors: Vec<common::Sensor> = common::Sensor::get_by_station(&db, station_id)
         .await
         .expect("Error retrieving sensors by station");
+    sensors.retain(|sensor| query.archived || !sensor.is_archived());
 
     HttpResponse::Ok().json(sensors)
 }
@@ -67,11 +159,23 @@ struct SensorQuery {
 }
 
 /// endpoint to retrive all values for a given sensor
-#[post("/sensor/{sensor}/values")]
+#[post(
+    "/sensor/{sensor}/values",
+    wrap = "RequireScope(ApiScope::ReadSensors)"
+)]
 async fn get_sensor_values(
     json: web::Json<SensorQuery>,
+    access: Access,
     db: web::Data<Surreal<Client>>,
 ) -> HttpResponse {
+    match access
+        .permits_sensor(&db, &json.sensor, StationPermission::View)
+        .await
+    {
+        Ok(true) => {}
+        Ok(false) => return HttpResponse::NotFound().body("Sensor not found"),
+        Err(err) => return HttpResponse::InternalServerError().body(err.to_string()),
+    }
     let to = chrono::Duration::minutes(
         json.to
             .parse::<i64>()
@@ -93,3 +197,106 @@ async fn get_sensor_values(
 
     HttpResponse::Ok().json(sensor)
 }
+
+/// upper bound of grid points per sensor for a batch query
+const MAX_GRID_POINTS: usize = 10_000;
+
+/// upper bound of sensors of a batch query
+const MAX_BATCH_SENSORS: usize = 50;
+
+/// helper struct to Deserialize the batch query payload.
+/// from and to are given in minutes before now, the interval in seconds
+#[derive(Deserialize)]
+struct BatchQuery {
+    sensors: Vec<String>,
+    from: i64,
+    to: i64,
+    interval: i64,
+    #[serde(default)]
+    format: common::resample::Layout,
+    #[serde(default)]
+    fill: common::resample::Fill,
+}
+
+/// endpoint to retrieve the values of multiple sensors resampled onto a shared time grid
+#[post("/sensors/values", wrap = "RequireScope(ApiScope::ReadSensors)")]
+async fn get_batch_values(
+    json: web::Json<BatchQuery>,
+    access: Access,
+    db: web::Data<Surreal<Client>>,
+) -> HttpResponse {
+    if json.sensors.is_empty() {
+        return HttpResponse::BadRequest().body("At least one sensor is required");
+    }
+    if json.sensors.len() > MAX_BATCH_SENSORS {
+        return HttpResponse::BadRequest().body(format!(
+            "At most {MAX_BATCH_SENSORS} sensors can be queried"
+        ));
+    }
+    for sensor in json.sensors.iter() {
+        match access
+            .permits_sensor(&db, sensor, StationPermission::View)
+            .await
+        {
+            Ok(true) => {}
+            Ok(false) => {
+                return HttpResponse::NotFound().body(format!("Sensor {sensor} not found"))
+            }
+            Err(err) => return HttpResponse::InternalServerError().body(err.to_string()),
+        }
+    }
+    if json.interval <= 0 {
+        return HttpResponse::BadRequest().body("The interval needs to be greater than 0");
+    }
+
+    let now = chrono::Utc::now();
+    let before_now = |minutes| {
+        chrono::TimeDelta::try_minutes(minutes).and_then(|minutes| now.checked_sub_signed(minutes))
+    };
+    let (Some(from), Some(to)) = (before_now(json.from), before_now(json.to)) else {
+        return HttpResponse::BadRequest().body("from and to need to be within the time range");
+    };
+    if from > to {
+        return HttpResponse::BadRequest().body("from needs to be before to");
+    }
+
+    let Some(interval) = chrono::TimeDelta::try_seconds(json.interval) else {
+        return HttpResponse::BadRequest().body("The interval is too long");
+    };
+    if (to - from).num_seconds() / json.interval >= MAX_GRID_POINTS as i64 {
+        return HttpResponse::BadRequest().body(format!(
+            "The time range contains more than {MAX_GRID_POINTS} intervals"
+        ));
+    }
+    let grid = common::resample::grid(from, to, interval);
+
+    let time_period = common::TimePeriod::between(from, to);
+    let mut series = Vec::with_capacity(json.sensors.len());
+    for sensor in json.sensors.iter() {
+        let mut values =
+            match common::SensorValue::get_within_timeperiod(&db, sensor.clone(), &time_period)
+                .await
+            {
+                Ok(values) => values,
+                Err(err) => return HttpResponse::InternalServerError().body(err.to_string()),
+            };
+        // the last value before the range is needed to fill the first grid points
+        if json.fill != common::resample::Fill::Null {
+            match common::SensorValue::get_latest_before(&db, sensor.clone(), from).await {
+                Ok(Some(value)) => values.insert(0, value),
+                Ok(None) => {}
+                Err(err) => return HttpResponse::InternalServerError().body(err.to_string()),
+            }
+        }
+        series.push((sensor.clone(), values));
+    }
+
+    match json.format {
+        common::resample::Layout::Long => {
+            HttpResponse::Ok().json(common::resample::long(&series, &grid, json.fill))
+        }
+        common::resample::Layout::Wide => {
+            HttpResponse::Ok().json(common::resample::wide(&series, &grid, json.fill))
+        }
+    }
+}